@@ -0,0 +1,82 @@
+use crate::daemon::DaemonConfig;
+use crate::error::{DiakonosError, Result};
+use crate::service::Service;
+use crate::unit::{read_vars_file, split_ignore_failure, validate_service_name, UnitFile};
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
+use std::collections::HashMap;
+use std::os::unix::process::ExitStatusExt;
+use std::process::ExitStatus;
+use tokio::signal::unix::{signal as listen, SignalKind};
+use tracing::info;
+
+/// Run the service `name` from the service directory in the foreground,
+/// without the daemon: its commands inherit the caller's stdin, stdout and
+/// stderr and run one after another until one not prefixed with `-` fails.
+/// `env` holds `KEY=VALUE` overrides as for `start --env`. Returns the exit
+/// status of the last command run.
+pub async fn run_attached(config: &DaemonConfig, name: &str, env: Vec<String>) -> Result<ExitStatus> {
+    validate_service_name(name)?;
+    let path = config.service_dir.join(format!("{}.service", name));
+    if !path.exists() {
+        return Err(DiakonosError::ServiceNotFound(name.to_string()));
+    }
+
+    let vars = match config.vars_file {
+        Some(ref vars_file) => read_vars_file(vars_file)?,
+        None => HashMap::new(),
+    };
+    let mut service = Service::new(UnitFile::from_file_with_vars(&path, &vars)?);
+    service.default_environment = config.environment.clone();
+    service.environment_overrides = env;
+
+    if let Some(condition) = service.unit.unmet_condition() {
+        return Err(DiakonosError::ConditionNotMet(condition));
+    }
+
+    let mut status = ExitStatus::from_raw(0);
+    for (command, cmd) in service.commands()? {
+        let mut cmd = tokio::process::Command::from(cmd);
+        let mut child = cmd
+            .spawn()
+            .map_err(|e| service.spawn_error(&command, cmd.as_std(), e))?;
+        let pid = child.id().map(|pid| Pid::from_raw(pid as i32));
+        info!("Running service {} attached (PID {})", name, pid.map_or(0, Pid::as_raw));
+
+        status = forward_signals_until_exit(&mut child, pid).await?;
+        if !status.success() && !split_ignore_failure(&command).0 {
+            break;
+        }
+    }
+
+    Ok(status)
+}
+
+/// Wait for `child` to exit, passing on `SIGTERM`, `SIGHUP`, `SIGUSR1` and
+/// `SIGUSR2` sent to the caller meanwhile. The child shares the terminal's
+/// process group, so `SIGINT` and `SIGQUIT` from the terminal reach it
+/// directly and are only kept from ending the caller first.
+async fn forward_signals_until_exit(child: &mut tokio::process::Child, pid: Option<Pid>) -> Result<ExitStatus> {
+    let mut interrupt = listen(SignalKind::interrupt())?;
+    let mut quit = listen(SignalKind::quit())?;
+    let mut terminate = listen(SignalKind::terminate())?;
+    let mut hangup = listen(SignalKind::hangup())?;
+    let mut user1 = listen(SignalKind::user_defined1())?;
+    let mut user2 = listen(SignalKind::user_defined2())?;
+
+    loop {
+        let received = tokio::select! {
+            status = child.wait() => return Ok(status?),
+            _ = interrupt.recv() => continue,
+            _ = quit.recv() => continue,
+            _ = terminate.recv() => Signal::SIGTERM,
+            _ = hangup.recv() => Signal::SIGHUP,
+            _ = user1.recv() => Signal::SIGUSR1,
+            _ = user2.recv() => Signal::SIGUSR2,
+        };
+
+        if let Some(pid) = pid {
+            let _ = signal::kill(pid, received);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+use crate::ipc::{Request, Response};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tokio::net::UnixStream;
+use tracing::warn;
+
+/// Append-only record of the requests that change what the daemon runs, one
+/// JSON object per line, kept apart from the daemon log so it stays readable
+/// when answering "who stopped this service, and when?"
+pub struct AuditLog {
+    file: Mutex<File>,
+    service_dir: PathBuf,
+}
+
+/// Who sent a request, from the control socket's peer credentials
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Peer {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub pid: Option<i32>,
+}
+
+impl Peer {
+    /// The credentials of the process on the other end of `stream`, or none
+    /// if they can't be read
+    pub fn of(stream: &UnixStream) -> Self {
+        match stream.peer_cred() {
+            Ok(cred) => Self {
+                uid: Some(cred.uid()),
+                gid: Some(cred.gid()),
+                pid: cred.pid(),
+            },
+            Err(_) => Self::default(),
+        }
+    }
+}
+
+/// An audited request, noted before the request is handled
+pub struct Operation {
+    name: &'static str,
+    service: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    /// RFC 3339, UTC
+    timestamp: String,
+    uid: Option<u32>,
+    gid: Option<u32>,
+    pid: Option<i32>,
+    operation: &'static str,
+    service: Option<&'a str>,
+    success: bool,
+    message: &'a str,
+    /// Tells apart entries of daemons for different service directories
+    service_dir: &'a Path,
+}
+
+impl Operation {
+    /// The operation `request` performs, if it is one worth auditing: those
+    /// that start, stop or reconfigure something, but not queries
+    pub fn of(request: &Request) -> Option<Self> {
+        let (name, service) = match request {
+            Request::Start { service, .. } => ("start", Some(service)),
+            Request::Stop {
+                service,
+                force: false,
+                ..
+            } => ("stop", Some(service)),
+            Request::Stop {
+                service,
+                force: true,
+                ..
+            } => ("force-stop", Some(service)),
+            Request::Restart { service, .. } => ("restart", Some(service)),
+            Request::Signal { service, .. } => ("signal", Some(service)),
+            Request::RunTransient { name, .. } => ("run", Some(name)),
+            Request::SetConfig { service, .. } => ("config-set", Some(service)),
+            Request::Reload { dry_run: false, .. } => ("reload", None),
+            Request::Apply => ("apply", None),
+            Request::Freeze => ("freeze", None),
+            Request::Unfreeze => ("unfreeze", None),
+            Request::Shutdown => ("shutdown", None),
+            _ => return None,
+        };
+
+        Some(Self {
+            name,
+            service: service.cloned(),
+        })
+    }
+}
+
+impl AuditLog {
+    /// Open the audit log for appending, creating it readable only by the
+    /// daemon's user
+    pub fn open(path: &Path, service_dir: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .mode(0o600)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            service_dir: service_dir.to_path_buf(),
+        })
+    }
+
+    /// Record the outcome of `operation`. A batch over a glob is recorded as
+    /// one entry per service it acted on.
+    pub fn record(&self, peer: &Peer, operation: &Operation, response: &Response) {
+        match response {
+            Response::Batch { results } => {
+                for result in results {
+                    self.write(
+                        peer,
+                        operation,
+                        Some(&result.service),
+                        result.success,
+                        &result.message,
+                    );
+                }
+            }
+            Response::Error { message } => self.write(
+                peer,
+                operation,
+                operation.service.as_deref(),
+                false,
+                message,
+            ),
+            Response::Ok { message } => {
+                self.write(peer, operation, operation.service.as_deref(), true, message)
+            }
+            _ => self.write(peer, operation, operation.service.as_deref(), true, ""),
+        }
+    }
+
+    fn write(
+        &self,
+        peer: &Peer,
+        operation: &Operation,
+        service: Option<&str>,
+        success: bool,
+        message: &str,
+    ) {
+        let entry = Entry {
+            timestamp: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            uid: peer.uid,
+            gid: peer.gid,
+            pid: peer.pid,
+            operation: operation.name,
+            service,
+            success,
+            message,
+            service_dir: &self.service_dir,
+        };
+
+        let line = match serde_json::to_string(&entry) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to encode audit entry: {}", e);
+                return;
+            }
+        };
+
+        // One write per entry, so lines from concurrent connections don't interleave
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = file.write_all(format!("{}\n", line).as_bytes()) {
+            warn!("Failed to write audit log: {}", e);
+        }
+    }
+}
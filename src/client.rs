@@ -1,56 +1,517 @@
 use crate::daemon::DaemonConfig;
 use crate::error::{DiakonosError, Result};
-use crate::ipc::{Request, Response};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use crate::ipc::{
+    decode_response, read_frame, write_frame, DaemonInfo, Request, RequestEnvelope, Response,
+    INVALID_REQUEST, LIST_PAGE_SIZE, MAX_RESPONSE_BYTES,
+};
+use crate::service::{ServiceDescription, ServiceState, ServiceStats};
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::BufReader;
+use tokio::net::unix::{OwnedReadHalf, OwnedWriteHalf};
 use tokio::net::UnixStream;
+use tokio::sync::Mutex;
 
+/// Connects to the daemon afresh for every request. Simple and stateless;
+/// prefer [`PersistentClient`] when sending many requests.
 pub struct Client {
     config: DaemonConfig,
+    next_id: AtomicU64,
 }
 
 impl Client {
     pub fn new(config: DaemonConfig) -> Self {
-        Self { config }
+        Self {
+            config,
+            next_id: AtomicU64::new(1),
+        }
     }
 
     pub async fn send_request(&self, request: Request) -> Result<Response> {
-        // Connect to daemon socket
-        let stream = UnixStream::connect(&self.config.socket_path)
-            .await
-            .map_err(|e| {
-                DiakonosError::StartError(format!(
-                    "Failed to connect to daemon at {:?}: {}",
-                    self.config.socket_path, e
-                ))
-            })?;
-
-        let (reader, mut writer) = stream.into_split();
-        let mut reader = BufReader::new(reader);
-
-        // Send request
-        let request_json = serde_json::to_string(&request)
-            .map_err(|e| DiakonosError::ParseError(format!("Failed to serialize request: {}", e)))?;
-
-        writer
-            .write_all(request_json.as_bytes())
-            .await
-            .map_err(|e| DiakonosError::StartError(format!("Failed to send request: {}", e)))?;
+        self.send_request_with_progress(request, |_| {}).await
+    }
 
-        writer
-            .write_all(b"\n")
-            .await
-            .map_err(|e| DiakonosError::StartError(format!("Failed to send request: {}", e)))?;
+    /// Send a request like [`send_request`](Self::send_request), passing the
+    /// daemon's progress messages to `on_progress` as they arrive
+    pub async fn send_request_with_progress(
+        &self,
+        request: Request,
+        mut on_progress: impl FnMut(String),
+    ) -> Result<Response> {
+        let (mut reader, id) = self.open(request).await?;
+        let mut frame = Vec::new();
+
+        next_response(&mut reader, &mut frame, id, &mut on_progress)
+            .await?
+            .ok_or_else(|| {
+                DiakonosError::ProtocolError(
+                    "Daemon closed the connection without responding".to_string(),
+                )
+            })
+    }
+
+    /// Every loaded service and its state, as a `List` response; see [`list_in_pages`]
+    pub async fn list(&self) -> Result<Response> {
+        list_in_pages(|request| self.send_request(request)).await
+    }
+
+    /// Send a request the daemon answers with a stream of responses (such as
+    /// following logs), passing each one to `on_response` until the daemon
+    /// closes the connection
+    pub async fn send_streaming_request(
+        &self,
+        request: Request,
+        mut on_response: impl FnMut(Response),
+    ) -> Result<()> {
+        let (mut reader, id) = self.open(request).await?;
+        let mut frame = Vec::new();
+
+        while let Some(response) = next_response(&mut reader, &mut frame, id, &mut |_| {}).await? {
+            on_response(response);
+        }
+
+        Ok(())
+    }
+
+    async fn open(&self, request: Request) -> Result<(BufReader<OwnedReadHalf>, u64)> {
+        let (reader, mut writer) = connect(&self.config).await?.into_split();
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        send(&mut writer, id, request).await?;
+
+        Ok((BufReader::new(reader), id))
+    }
+}
+
+/// Keeps one connection to the daemon open across requests, saving a
+/// connect per command for callers that send many, such as a TUI polling
+/// `Stats` or a script looping over services. Requests are sent one at a
+/// time. If the connection turns out to be closed (the daemon restarted,
+/// say), the request is retried once on a new one.
+///
+/// Streaming requests (`Logs` with `follow`) take over a connection; send
+/// those with [`Client`].
+pub struct PersistentClient {
+    config: DaemonConfig,
+    next_id: AtomicU64,
+    connection: Mutex<Option<Connection>>,
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl PersistentClient {
+    /// Create a client; it connects on the first request
+    pub fn new(config: DaemonConfig) -> Self {
+        Self {
+            config,
+            next_id: AtomicU64::new(1),
+            connection: Mutex::new(None),
+        }
+    }
+
+    pub async fn send(&self, request: Request) -> Result<Response> {
+        let mut connection = self.connection.lock().await;
+        let mut frame = Vec::new();
+
+        for attempt in 0..2 {
+            let reused = connection.is_some();
+            let current = match *connection {
+                Some(ref mut current) => current,
+                None => {
+                    let (reader, writer) = connect(&self.config).await?.into_split();
+                    connection.insert(Connection {
+                        reader: BufReader::new(reader),
+                        writer,
+                    })
+                }
+            };
+
+            let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+            let result = match send(&mut current.writer, id, request.clone()).await {
+                Ok(()) => next_response(&mut current.reader, &mut frame, id, &mut |_| {}).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(Some(response)) => return Ok(response),
+                // Only a connection kept from before may be stale; a new one
+                // failing means the daemon itself is in trouble
+                Ok(None) | Err(_) if reused && attempt == 0 => *connection = None,
+                Ok(None) => {
+                    *connection = None;
+                    return Err(DiakonosError::ProtocolError(
+                        "Daemon closed the connection without responding".to_string(),
+                    ));
+                }
+                Err(e) => {
+                    *connection = None;
+                    return Err(e);
+                }
+            }
+        }
 
-        // Read response
-        let mut line = String::new();
-        reader
-            .read_line(&mut line)
+        unreachable!("the second attempt always uses a new connection")
+    }
+}
+
+async fn connect(config: &DaemonConfig) -> Result<UnixStream> {
+    UnixStream::connect(&config.socket_path).await.map_err(|e| {
+        DiakonosError::StartError(format!(
+            "Failed to connect to daemon at {:?}: {}",
+            config.socket_path, e
+        ))
+    })
+}
+
+async fn send(writer: &mut OwnedWriteHalf, id: u64, request: Request) -> Result<()> {
+    let envelope = RequestEnvelope { id, request };
+    write_frame(writer, &envelope)
+        .await
+        .map_err(|e| DiakonosError::ProtocolError(format!("Failed to send request: {}", e)))
+}
+
+/// Read frames until the next response to request `id` arrives, skipping
+/// anything addressed elsewhere and passing progress to `on_progress`. A
+/// response without an id is a reply from a daemon that predates request ids
+/// (or a framing error). Returns `None` once the daemon closes the connection.
+async fn next_response(
+    reader: &mut BufReader<OwnedReadHalf>,
+    frame: &mut Vec<u8>,
+    id: u64,
+    on_progress: &mut impl FnMut(String),
+) -> Result<Option<Response>> {
+    loop {
+        let received = read_frame(reader, frame, MAX_RESPONSE_BYTES)
             .await
-            .map_err(|e| DiakonosError::StartError(format!("Failed to read response: {}", e)))?;
+            .map_err(|e| DiakonosError::ProtocolError(format!("Failed to read response: {}", e)))?;
+
+        if !received {
+            return Ok(None);
+        }
 
-        let response: Response = serde_json::from_str(&line.trim())
+        let envelope = decode_response(frame)
             .map_err(|e| DiakonosError::ParseError(format!("Failed to parse response: {}", e)))?;
 
-        Ok(response)
+        if envelope.id.is_none_or(|response_id| response_id == id) {
+            match envelope.response {
+                Response::Progress { message } => on_progress(message),
+                response => return Ok(Some(response)),
+            }
+        }
+    }
+}
+
+/// A typed API over the daemon's protocol for programs built on diakonos:
+/// each method sends one request and returns what the daemon answered, an
+/// error response becoming [`DiakonosError::RequestFailed`]. The connection
+/// is kept open and replaced if it breaks, as with [`PersistentClient`].
+///
+/// ```no_run
+/// use diakonos::client::ServiceClient;
+/// use diakonos::daemon::DaemonConfig;
+/// use std::time::Duration;
+///
+/// # async fn run() -> diakonos::error::Result<()> {
+/// let client = ServiceClient::new(DaemonConfig::default()).with_timeout(Duration::from_secs(10));
+/// client.start("web").await?;
+/// for (name, state) in client.list().await? {
+///     println!("{}: {}", name, state);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+///
+/// For requests without a method here, [`ServiceClient::send`] takes a raw
+/// [`Request`].
+pub struct ServiceClient {
+    client: PersistentClient,
+    /// For streaming requests, which take over a connection of their own
+    streaming: Client,
+    timeout: Option<Duration>,
+}
+
+/// What [`ServiceClient::follow_logs`] passes on as a service writes output
+#[derive(Debug, Clone, PartialEq)]
+pub enum LogEvent {
+    Line { service: String, line: String },
+    /// Lines skipped because they were read too slowly
+    Dropped { lines: usize },
+}
+
+impl ServiceClient {
+    /// Create a client; it connects on the first request
+    pub fn new(config: DaemonConfig) -> Self {
+        Self {
+            client: PersistentClient::new(config.clone()),
+            streaming: Client::new(config),
+            timeout: None,
+        }
+    }
+
+    /// Fail requests the daemon takes longer than `timeout` to answer with
+    /// [`DiakonosError::TimedOut`]. A request that itself waits, such as
+    /// [`ServiceClient::wait_for`], is given its wait on top.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Send a raw request, returning the daemon's response as is
+    pub async fn send(&self, request: Request) -> Result<Response> {
+        self.send_waiting(request, Duration::ZERO).await
+    }
+
+    /// Start a service and its dependencies, or every service a glob matches
+    pub async fn start(&self, service: &str) -> Result<()> {
+        self.start_with(service, None, Vec::new()).await
+    }
+
+    /// Start a service like [`ServiceClient::start`], returning once it is
+    /// up (or failing after `wait`), with `env` (`KEY=VALUE`) overriding its
+    /// environment
+    pub async fn start_with(&self, service: &str, wait: Option<Duration>, env: Vec<String>) -> Result<()> {
+        let request = Request::Start {
+            service: service.to_string(),
+            wait,
+            env,
+            no_supervise: false,
+        };
+        self.expect_ok(request, wait.unwrap_or_default()).await
+    }
+
+    pub async fn stop(&self, service: &str) -> Result<()> {
+        let request = Request::Stop {
+            service: service.to_string(),
+            wait: None,
+            force: false,
+        };
+        self.expect_ok(request, Duration::ZERO).await
+    }
+
+    pub async fn restart(&self, service: &str) -> Result<()> {
+        let request = Request::Restart {
+            service: service.to_string(),
+            wait: None,
+            no_supervise: false,
+        };
+        self.expect_ok(request, Duration::ZERO).await
+    }
+
+    pub async fn status(&self, service: &str) -> Result<ServiceState> {
+        let request = Request::Status {
+            service: service.to_string(),
+        };
+        match self.send(request).await? {
+            Response::Status { state, .. } => Ok(state),
+            other => unexpected("Status", other),
+        }
+    }
+
+    /// The PID of the service's main process, if it has one running
+    pub async fn pid(&self, service: &str) -> Result<Option<u32>> {
+        let request = Request::Pid {
+            service: service.to_string(),
+        };
+        match self.send(request).await? {
+            Response::Pid { pid, .. } => Ok(pid),
+            other => unexpected("Pid", other),
+        }
+    }
+
+    pub async fn describe(&self, service: &str) -> Result<ServiceDescription> {
+        let request = Request::Describe {
+            service: service.to_string(),
+        };
+        match self.send(request).await? {
+            Response::Describe { description } => Ok(*description),
+            other => unexpected("Describe", other),
+        }
+    }
+
+    /// Every loaded service and its state, in name order
+    pub async fn list(&self) -> Result<Vec<(String, ServiceState)>> {
+        match list_in_pages(|request| self.send(request)).await? {
+            Response::List { services } => Ok(services),
+            other => unexpected("List", other),
+        }
+    }
+
+    pub async fn stats(&self) -> Result<Vec<ServiceStats>> {
+        match self.send(Request::Stats).await? {
+            Response::Stats { stats } => Ok(stats),
+            other => unexpected("Stats", other),
+        }
+    }
+
+    /// Wait until `service` is in `state`, failing with
+    /// [`DiakonosError::TimedOut`] if that takes longer than `timeout`
+    pub async fn wait_for(&self, service: &str, state: ServiceState, timeout: Duration) -> Result<()> {
+        let request = Request::WaitFor {
+            service: service.to_string(),
+            target_state: state,
+            timeout,
+        };
+        self.expect_ok(request, timeout).await
+    }
+
+    /// The last `lines` lines (or all) of a service's output
+    pub async fn logs(&self, service: &str, lines: Option<usize>) -> Result<Vec<String>> {
+        let request = Request::Logs {
+            services: vec![service.to_string()],
+            lines,
+            since: None,
+            follow: false,
+        };
+        match self.send(request).await? {
+            Response::Logs { lines, .. } => Ok(lines),
+            other => unexpected("Logs", other),
+        }
+    }
+
+    /// Pass each line the services write from now on to `on_event`, until
+    /// the daemon closes the connection. Runs until then, so spawn or select
+    /// on it to stop following. Not subject to the client's timeout.
+    pub async fn follow_logs(&self, services: &[&str], mut on_event: impl FnMut(LogEvent)) -> Result<()> {
+        let request = Request::Logs {
+            services: services.iter().map(|service| service.to_string()).collect(),
+            lines: Some(0),
+            since: None,
+            follow: true,
+        };
+        let mut failure = None;
+        self.streaming
+            .send_streaming_request(request, |response| match response {
+                Response::Logs { service, lines } => {
+                    for line in lines {
+                        on_event(LogEvent::Line {
+                            service: service.clone(),
+                            line,
+                        });
+                    }
+                }
+                Response::InterleavedLogs { lines } => {
+                    for (service, line) in lines {
+                        on_event(LogEvent::Line { service, line });
+                    }
+                }
+                Response::LogsDropped { lines } => on_event(LogEvent::Dropped { lines }),
+                Response::Error { message } => failure = Some(DiakonosError::RequestFailed(message)),
+                _ => {}
+            })
+            .await?;
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Re-read the service directory
+    pub async fn reload(&self) -> Result<()> {
+        let request = Request::Reload {
+            dry_run: false,
+            soft: false,
+        };
+        self.expect_ok(request, Duration::ZERO).await
+    }
+
+    pub async fn daemon_info(&self) -> Result<DaemonInfo> {
+        match self.send(Request::DaemonInfo).await? {
+            Response::DaemonInfo { info } => Ok(info),
+            other => unexpected("DaemonInfo", other),
+        }
+    }
+
+    /// Check that the daemon is up and answering
+    pub async fn ping(&self) -> Result<()> {
+        match self.send(Request::Ping).await? {
+            Response::Pong => Ok(()),
+            other => unexpected("Ping", other),
+        }
+    }
+
+    /// Stop every service and the daemon
+    pub async fn shutdown(&self) -> Result<()> {
+        self.expect_ok(Request::Shutdown, Duration::ZERO).await
+    }
+
+    async fn send_waiting(&self, request: Request, wait: Duration) -> Result<Response> {
+        let Some(timeout) = self.timeout else {
+            return self.client.send(request).await;
+        };
+        let timeout = timeout.saturating_add(wait);
+        tokio::time::timeout(timeout, self.client.send(request))
+            .await
+            .unwrap_or_else(|_| {
+                Err(DiakonosError::TimedOut(format!(
+                    "after {} waiting for the daemon to respond",
+                    humantime::format_duration(timeout)
+                )))
+            })
+    }
+
+    /// Send a request answered with `Ok`, or with a `Batch` of `Ok`s for a glob
+    async fn expect_ok(&self, request: Request, wait: Duration) -> Result<()> {
+        match self.send_waiting(request, wait).await? {
+            Response::Ok { .. } => Ok(()),
+            Response::TimedOut { message } => Err(DiakonosError::TimedOut(
+                message.trim_start_matches("Timed out ").to_string(),
+            )),
+            Response::Batch { results } => {
+                let failures: Vec<String> = results
+                    .into_iter()
+                    .filter(|result| !result.success)
+                    .map(|result| format!("{}: {}", result.service, result.message))
+                    .collect();
+                if failures.is_empty() {
+                    Ok(())
+                } else {
+                    Err(DiakonosError::RequestFailed(failures.join("; ")))
+                }
+            }
+            other => unexpected("the request", other),
+        }
+    }
+}
+
+/// The error for a response that doesn't answer `request`. An `Error`
+/// response is the daemon refusing the request.
+fn unexpected<T>(request: &str, response: Response) -> Result<T> {
+    Err(match response {
+        Response::Error { message } => DiakonosError::RequestFailed(message),
+        other => DiakonosError::ProtocolError(format!("Unexpected response to {}: {:?}", request, other)),
+    })
+}
+
+/// Fetch every loaded service and its state with `send`, a `ListPage` at a
+/// time so no frame grows with the number of services, and answer them as
+/// one `List` in name order. Each page picks up after the last name of the
+/// one before, so services added or removed meanwhile don't shift the rest.
+/// A daemon that predates `ListPage`, and so can't decode it, is sent a
+/// single `List`; any other failure is returned as the daemon's response.
+async fn list_in_pages<F, Fut>(send: F) -> Result<Response>
+where
+    F: Fn(Request) -> Fut,
+    Fut: Future<Output = Result<Response>>,
+{
+    let mut services: Vec<(String, ServiceState)> = Vec::new();
+    loop {
+        let request = Request::ListPage {
+            after: services.last().map(|(name, _)| name.clone()),
+            limit: LIST_PAGE_SIZE,
+        };
+        match send(request).await? {
+            Response::ListPage { services: page, .. } => {
+                let done = page.len() < LIST_PAGE_SIZE;
+                services.extend(page);
+                if done {
+                    return Ok(Response::List { services });
+                }
+            }
+            Response::Error { ref message } if services.is_empty() && message.starts_with(INVALID_REQUEST) => {
+                return send(Request::List).await
+            }
+            other => return Ok(other),
+        }
     }
 }
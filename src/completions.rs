@@ -0,0 +1,84 @@
+use clap::Command;
+use clap_complete::{generate, Shell};
+use std::io::Write;
+
+/// Hidden subcommand the shell scripts call to list service names
+pub const COMPLETE_SERVICES_COMMAND: &str = "__complete-services";
+
+/// Subcommands whose first positional argument is a service name
+const SERVICE_COMMANDS: &[&str] = &["start", "stop", "restart", "status", "describe", "show", "signal", "logs", "deps", "wait-for", "history"];
+
+/// Write the completion script for `shell` to stdout.
+///
+/// The static part (subcommands and flags) is generated by `clap_complete`.
+/// For bash, zsh and fish a small wrapper is appended that completes service
+/// names by asking the running daemon; it never starts the daemon itself.
+pub fn print_completions(shell: Shell, cmd: &mut Command) {
+    let name = cmd.get_name().to_string();
+    let mut out = std::io::stdout();
+
+    generate(shell, cmd, &name, &mut out);
+
+    let dynamic = match shell {
+        Shell::Bash => bash_services(&name),
+        Shell::Zsh => zsh_services(&name),
+        Shell::Fish => fish_services(&name),
+        _ => return,
+    };
+
+    let _ = out.write_all(dynamic.as_bytes());
+}
+
+fn bash_services(name: &str) -> String {
+    format!(
+        r#"
+_{name}_with_services() {{
+    local i
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        case "${{COMP_WORDS[i]}}" in
+            {commands})
+                if [[ $i -eq $((COMP_CWORD - 1)) && "${{COMP_WORDS[COMP_CWORD]}}" != -* ]]; then
+                    COMPREPLY=( $(compgen -W "$({name} {helper} 2>/dev/null)" -- "${{COMP_WORDS[COMP_CWORD]}}") )
+                    return 0
+                fi
+                break
+                ;;
+        esac
+    done
+    _{name} "$@"
+}}
+complete -F _{name}_with_services -o nosort -o bashdefault -o default {name}
+"#,
+        name = name,
+        helper = COMPLETE_SERVICES_COMMAND,
+        commands = SERVICE_COMMANDS.join("|"),
+    )
+}
+
+fn zsh_services(name: &str) -> String {
+    format!(
+        r#"
+_{name}_with_services() {{
+    if (( CURRENT > 2 )) && [[ ${{words[CURRENT-1]}} == ({commands}) ]]; then
+        local -a services
+        services=(${{(f)"$({name} {helper} 2>/dev/null)"}})
+        _describe 'service' services && return
+    fi
+    _{name} "$@"
+}}
+compdef _{name}_with_services {name}
+"#,
+        name = name,
+        helper = COMPLETE_SERVICES_COMMAND,
+        commands = SERVICE_COMMANDS.join("|"),
+    )
+}
+
+fn fish_services(name: &str) -> String {
+    format!(
+        "complete -c {name} -n \"__fish_seen_subcommand_from {commands}\" -f -a \"({name} {helper} 2>/dev/null)\"\n",
+        name = name,
+        helper = COMPLETE_SERVICES_COMMAND,
+        commands = SERVICE_COMMANDS.join(" "),
+    )
+}
@@ -1,35 +1,321 @@
+use crate::audit::{AuditLog, Operation, Peer};
+use crate::history::History;
 use crate::error::Result;
-use crate::ipc::{Request, Response};
-use crate::manager::ServiceManager;
+use crate::ipc::{
+    decode_request, read_frame, write_frame, BatchResult, Request, Response, ResponseEnvelope,
+    INVALID_REQUEST, LIST_PAGE_SIZE, MAX_REQUEST_BYTES,
+};
+use crate::manager::{Progress, ServiceManager};
+use crate::reexec::Handoff;
+use crate::theme::StateColors;
 use daemonize::Daemonize;
-use std::path::PathBuf;
+use serde::Deserialize;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{UnixListener, UnixStream};
-use tracing::{error, info, warn};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tracing::{error, info, info_span, warn, Instrument};
 
+/// How often a followed log is checked for new output
+const LOG_FOLLOW_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Most of a followed log read at once
+const LOG_FOLLOW_CHUNK: u64 = 1024 * 1024;
+
+/// Batches of followed log lines queued for a client. Past this the client
+/// is reading too slowly, and lines are dropped rather than queued.
+const LOG_FOLLOW_BUFFER: usize = 16;
+
+/// Fewest starts at once allowed by default, so services not ordered after
+/// one another still start alongside each other on a machine with few CPUs
+const MIN_DEFAULT_CONCURRENT_STARTS: usize = 4;
+
+/// Where the syslog daemon receives messages unless `syslog_socket` says otherwise
+#[cfg(target_os = "macos")]
+pub const DEFAULT_SYSLOG_SOCKET: &str = "/var/run/syslog";
+#[cfg(not(target_os = "macos"))]
+pub const DEFAULT_SYSLOG_SOCKET: &str = "/dev/log";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct DaemonConfig {
     pub socket_path: PathBuf,
     pub pid_file: PathBuf,
     pub service_dir: PathBuf,
     pub log_file: PathBuf,
+
+    /// Directory holding each service's captured output
+    pub log_dir: PathBuf,
+
+    /// Seconds between supervision passes
+    pub supervision_interval: u64,
+
+    /// Seconds the daemon spends starting `AutoStart` services when it boots
+    pub startup_timeout: u64,
+
+    /// Services that may be starting at once, from being launched until
+    /// they are ready, so starting a target of dozens of services doesn't
+    /// swamp the machine. Defaults to the number of CPUs, but at least
+    /// `MIN_DEFAULT_CONCURRENT_STARTS`.
+    pub max_concurrent_starts: usize,
+
+    /// Target the daemon boots into: services `WantedBy` it start along with
+    /// `AutoStart` ones
+    pub default_target: String,
+
+    /// Order each service after the units it `Requires` or `Wants`, as if
+    /// they were also listed in its `After`. Turned off, only `After` orders
+    /// starts, and a dependency may come up alongside its dependents.
+    pub implicit_after_requires: bool,
+
+    pub log_format: LogFormat,
+
+    /// `KEY=value` pairs applied to every service before its own `Environment`
+    pub environment: Vec<String>,
+
+    /// Address to serve Prometheus metrics on, if any
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// URL to POST a JSON payload to when a service fails or recovers
+    pub webhook_url: Option<String>,
+
+    /// TOML file of `NAME = "value"` pairs that units can use as `${NAME}`,
+    /// re-read on reload
+    pub vars_file: Option<PathBuf>,
+
+    /// Append-only JSON-lines record of start, stop, restart and other
+    /// control requests: who sent them, when, and how they turned out
+    pub audit_log: PathBuf,
+
+    /// JSON-lines record of every service state transition, kept across
+    /// restarts of the daemon and capped at the newest
+    /// [`MAX_TRANSITIONS`](crate::history::MAX_TRANSITIONS)
+    pub history_file: PathBuf,
+
+    /// Where the syslog daemon receives messages, for services with
+    /// `StandardOutput = "syslog"`
+    pub syslog_socket: PathBuf,
+
+    /// Total bytes the service logs in `log_dir` may take, rotated segments
+    /// included. Over it, the oldest segments are deleted.
+    pub max_log_bytes: Option<u64>,
+
+    /// How the CLI colors service states; the daemon doesn't use it
+    pub colors: StateColors,
+
+    /// Config file this configuration was loaded from, if any
+    #[serde(skip)]
+    pub config_file: Option<PathBuf>,
+
+    /// Whether the socket was chosen with `--socket` rather than configured
+    /// or derived from the service directory
+    #[serde(skip)]
+    pub explicit_socket: bool,
+
+    /// Whether the config file sets the socket, PID file, daemon log,
+    /// service logs or history, even to the default, so they are kept rather
+    /// than derived from the service directory
+    #[serde(skip)]
+    pub configured_paths: bool,
+}
+
+/// Settings that [`DaemonConfig::namespace_by_service_dir`] derives unless
+/// the config file sets one of them
+const NAMESPACED_PATHS: [&str; 5] = ["socket_path", "pid_file", "log_file", "log_dir", "history_file"];
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Full,
+    Compact,
+    Json,
 }
 
 impl Default for DaemonConfig {
     fn default() -> Self {
-        let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-        let daemon_dir = PathBuf::from(home).join(".diakonos");
+        let daemon_dir = daemon_dir();
 
         Self {
             socket_path: daemon_dir.join("daemon.sock"),
             pid_file: daemon_dir.join("daemon.pid"),
             service_dir: PathBuf::from("./services"),
             log_file: daemon_dir.join("daemon.log"),
+            log_dir: daemon_dir.join("logs"),
+            audit_log: daemon_dir.join("audit.log"),
+            history_file: daemon_dir.join("history.jsonl"),
+            syslog_socket: PathBuf::from(DEFAULT_SYSLOG_SOCKET),
+            supervision_interval: 5,
+            startup_timeout: 300,
+            max_concurrent_starts: std::thread::available_parallelism()
+                .map_or(0, usize::from)
+                .max(MIN_DEFAULT_CONCURRENT_STARTS),
+            default_target: "default.target".to_string(),
+            implicit_after_requires: true,
+            log_format: LogFormat::Full,
+            environment: Vec::new(),
+            metrics_addr: None,
+            webhook_url: None,
+            max_log_bytes: None,
+            vars_file: None,
+            colors: StateColors::default(),
+            config_file: None,
+            explicit_socket: false,
+            configured_paths: false,
+        }
+    }
+}
+
+impl DaemonConfig {
+    /// Load the daemon configuration from `path`, or from `~/.diakonos/config.toml`
+    /// when no path is given. A missing default file yields the built-in defaults;
+    /// a missing explicit file is an error.
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        let (path, explicit) = match path {
+            Some(p) => (p.to_path_buf(), true),
+            None => (daemon_dir().join("config.toml"), false),
+        };
+
+        if !explicit && !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let content = std::fs::read_to_string(&path)?;
+        let mut config: DaemonConfig = toml::from_str(&content).map_err(|e| {
+            crate::error::DiakonosError::ConfigError(format!("{}: {}", path.display(), e))
+        })?;
+
+        // Valid TOML, or it wouldn't have parsed as a config
+        let table: toml::Table = toml::from_str(&content).unwrap_or_default();
+        config.configured_paths = NAMESPACED_PATHS.iter().any(|key| table.contains_key(*key));
+
+        config.config_file = Some(path);
+        Ok(config)
+    }
+
+    /// Use the control socket at `path`, keeping the daemon's PID and log
+    /// files and history beside it (`<name>.pid`, `<name>.log`,
+    /// `<name>.history.jsonl`) so that daemons on different sockets don't
+    /// mistake each other for themselves
+    pub fn set_socket_path(&mut self, path: PathBuf) {
+        self.pid_file = path.with_extension("pid");
+        self.log_file = path.with_extension("log");
+        self.history_file = path.with_extension("history.jsonl");
+        self.socket_path = path;
+        self.explicit_socket = true;
+    }
+
+    /// Give the daemon for this service directory its own socket, PID file,
+    /// daemon log, history and service logs in `~/.diakonos/daemons/<hash>`, where the
+    /// hash is of the directory's absolute path, so that projects don't share
+    /// a daemon. Does nothing if the socket was set with `--socket` or the
+    /// config file sets any of those locations.
+    pub fn namespace_by_service_dir(&mut self) {
+        if self.explicit_socket || self.configured_paths {
+            return;
         }
+
+        let service_dir = self
+            .service_dir
+            .canonicalize()
+            .unwrap_or_else(|_| self.service_dir.clone());
+        let namespace = daemon_dir()
+            .join("daemons")
+            .join(format!("{:016x}", path_hash(&service_dir)));
+
+        self.socket_path = namespace.join("daemon.sock");
+        self.pid_file = namespace.join("daemon.pid");
+        self.log_file = namespace.join("daemon.log");
+        self.log_dir = namespace.join("logs");
+        self.history_file = namespace.join("history.jsonl");
     }
 }
 
+/// `~/.diakonos`. Without a home directory (`HOME` unset and no passwd
+/// entry, as under some init systems) this is a per-user directory in the
+/// temp dir, so the location never depends on the working directory.
+fn daemon_dir() -> PathBuf {
+    match crate::unit::home_dir() {
+        Some(home) => home.join(".diakonos"),
+        None => std::env::temp_dir().join(format!("diakonos-{}", nix::unistd::getuid())),
+    }
+}
+
+/// FNV-1a of `path`. Unlike `DefaultHasher` it is stable across Rust
+/// releases, so a directory keeps finding the same daemon.
+fn path_hash(path: &Path) -> u64 {
+    use std::os::unix::ffi::OsStrExt;
+
+    path.as_os_str().as_bytes().iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
 pub fn start_daemon(config: DaemonConfig) -> Result<()> {
+    // Re-executed on SIGUSR2: already daemonized, and serving on the socket
+    if crate::reexec::is_reexec() {
+        return serve_reexecuted(config);
+    }
+
+    // The client that spawned this daemon waits for the socket, or for this
+    let error_path = startup_error_path(&config);
+    let result = daemonize_and_serve(config);
+    if let Err(ref e) = result {
+        let _ = std::fs::write(&error_path, e.to_string());
+    }
+    result
+}
+
+/// Run the daemon in this process rather than daemonizing, for a container
+/// or supervisor that expects its entrypoint to stay in the foreground. The
+/// PID file is written so that clients find the daemon. As PID 1 the daemon
+/// also stands in for init (see [`crate::init`]).
+pub fn run_foreground(config: DaemonConfig) -> Result<()> {
+    if crate::reexec::is_reexec() {
+        return serve_reexecuted(config);
+    }
+
+    if let Some(parent) = config.socket_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if config.socket_path.exists() {
+        std::fs::remove_file(&config.socket_path)?;
+    }
+    std::fs::write(&config.pid_file, format!("{}\n", std::process::id()))?;
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(serve(config, true, None));
+    error!("Daemon loop exited with result: {:?}", result);
+    result
+}
+
+/// Carry on as the daemon that re-executed this process on SIGUSR2, which
+/// was already serving on the socket
+fn serve_reexecuted(config: DaemonConfig) -> Result<()> {
+    let handoff = crate::reexec::take_handoff();
+    if handoff.is_none() {
+        warn!("Re-executed without a usable handoff, starting afresh");
+        let _ = std::fs::remove_file(&config.socket_path);
+    }
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let result = runtime.block_on(serve(config, true, handoff));
+    error!("Daemon loop exited with result: {:?}", result);
+    result
+}
+
+/// Where a daemon that fails leaves its error, beside its PID file, so that
+/// [`ensure_daemon_started`] can report it rather than time out
+fn startup_error_path(config: &DaemonConfig) -> PathBuf {
+    config.pid_file.with_extension("error")
+}
+
+fn daemonize_and_serve(config: DaemonConfig) -> Result<()> {
     // Create daemon directory if it doesn't exist
     if let Some(parent) = config.socket_path.parent() {
         std::fs::create_dir_all(parent)?;
@@ -56,7 +342,7 @@ pub fn start_daemon(config: DaemonConfig) -> Result<()> {
 
             // IMPORTANT: Create tokio runtime AFTER daemonization
             let runtime = tokio::runtime::Runtime::new().unwrap();
-            let result = runtime.block_on(run_daemon(config));
+            let result = runtime.block_on(serve(config, true, None));
             error!("Daemon loop exited with result: {:?}", result);
             result
         }
@@ -70,17 +356,91 @@ pub fn start_daemon(config: DaemonConfig) -> Result<()> {
     }
 }
 
-async fn run_daemon(config: DaemonConfig) -> Result<()> {
+/// Run the daemon in the foreground on the current Tokio runtime: load the
+/// services, supervise them, and serve requests on `config.socket_path`.
+/// Only returns if a core task dies. [`start_daemon`] calls this after
+/// daemonizing; call it directly to run the daemon in-process. The socket's
+/// directory must exist and no stale socket file may be left at its path.
+pub async fn run_daemon(config: DaemonConfig) -> Result<()> {
+    serve(config, false, None).await
+}
+
+/// Run the daemon. With `owns_process` it handles the process's signals: it
+/// reloads the service directory on SIGHUP and replaces itself with a new
+/// build of its binary on SIGUSR2, which a daemon embedded in another program
+/// can't do. `handoff` is from the daemon this one replaced.
+async fn serve(config: DaemonConfig, owns_process: bool, handoff: Option<Handoff>) -> Result<()> {
     info!("Daemon running with socket at {:?}", config.socket_path);
+    info!(
+        "Using service directory {:?}, PID file {:?}, service logs in {:?}, config {:?}",
+        std::path::absolute(&config.service_dir).unwrap_or_else(|_| config.service_dir.clone()),
+        config.pid_file,
+        config.log_dir,
+        config.config_file
+    );
+    if crate::unit::home_dir().is_none() {
+        warn!("No home directory found, using {:?} for daemon state", daemon_dir());
+    }
+
+    // Claim SIGHUP before loading anything, so one sent while the daemon
+    // starts up reloads once it's ready rather than killing it
+    let hangups = if owns_process { Some(signal(SignalKind::hangup())?) } else { None };
 
     // Create service manager
-    let manager = Arc::new(ServiceManager::new(config.service_dir.clone()));
+    let mut manager = ServiceManager::new(&config);
+    match History::open(&config.history_file) {
+        Ok(history) => manager.keep_history(history),
+        Err(e) => warn!("Failed to open history {:?}, not recording it: {}", config.history_file, e),
+    }
+    let manager = Arc::new(manager);
 
     // Load all services
     if let Err(e) = manager.load_all_services().await {
         warn!("Failed to load services: {}", e);
     }
 
+    // The daemon this one replaced already started AutoStart services
+    let inherited_listener = match handoff {
+        Some(handoff) => {
+            manager.adopt(handoff.services, handoff.restarts_frozen).await;
+            Some(handoff.listener)
+        }
+        None => {
+            // Bring up AutoStart services without holding up the control socket
+            let boot_manager = Arc::clone(&manager);
+            let startup_timeout = Duration::from_secs(config.startup_timeout);
+            tokio::spawn(async move {
+                boot_manager.start_autostart_services(startup_timeout).await;
+            });
+            None
+        }
+    };
+
+    // Watch files of services that restart on change
+    tokio::spawn(crate::watch::watch_services(Arc::clone(&manager)));
+
+    // Serve Prometheus metrics if configured
+    if let Some(addr) = config.metrics_addr {
+        tokio::spawn(crate::metrics::serve_metrics(addr, Arc::clone(&manager)));
+    }
+
+    tokio::spawn(Arc::clone(&manager).run_timers());
+
+    if manager.max_log_bytes().is_some() {
+        tokio::spawn(Arc::clone(&manager).run_log_cap());
+    }
+
+    // As a container's entrypoint, nothing else stops services or reaps orphans
+    if crate::init::is_init() {
+        info!("Running as PID 1, acting as init");
+        let manager = Arc::clone(&manager);
+        tokio::spawn(async move {
+            if let Err(e) = crate::init::run(manager).await {
+                error!("Failed to act as init: {}", e);
+            }
+        });
+    }
+
     // Start supervision task
     let manager_clone = Arc::clone(&manager);
     let supervision_handle = tokio::spawn(async move {
@@ -88,9 +448,56 @@ async fn run_daemon(config: DaemonConfig) -> Result<()> {
         error!("Supervision loop exited!");
     });
 
-    // Create Unix socket listener
-    let listener = UnixListener::bind(&config.socket_path)
-        .map_err(|e| crate::error::DiakonosError::StartError(format!("Failed to bind socket: {}", e)))?;
+    // Create Unix socket listener, or keep serving on the one passed across a re-exec
+    let listener = match inherited_listener {
+        Some(fd) => inherit_listener(fd),
+        None => UnixListener::bind(&config.socket_path),
+    }
+    .map_err(|e| crate::error::DiakonosError::StartError(format!("Failed to bind socket: {}", e)))?;
+
+    if owns_process {
+        let manager = Arc::clone(&manager);
+        let listener = listener.as_raw_fd();
+        let handoff_path = config.pid_file.with_extension("handoff");
+        let mut upgrades = signal(SignalKind::user_defined2())?;
+        tokio::spawn(async move {
+            while upgrades.recv().await.is_some() {
+                info!("Received SIGUSR2, re-executing");
+                let error = manager
+                    .hand_off(|services, frozen| {
+                        crate::reexec::exec(listener, services, frozen, &handoff_path)
+                    })
+                    .await;
+                error!("Failed to re-execute, carrying on: {}", error);
+            }
+        });
+    }
+
+    // Config management tools signal a reload the conventional way, after
+    // dropping unit files in place
+    if let Some(mut hangups) = hangups {
+        let manager = Arc::clone(&manager);
+        tokio::spawn(async move {
+            while hangups.recv().await.is_some() {
+                info!("Received SIGHUP, reloading services");
+                match manager.reload_all().await {
+                    Ok(summary) => info!("Reloaded services ({})", summary),
+                    Err(e) => error!("Failed to reload services, keeping current configuration: {}", e),
+                }
+            }
+        });
+    }
+
+    // Control requests are audited to their own file, apart from the daemon log
+    let service_dir =
+        std::path::absolute(&config.service_dir).unwrap_or_else(|_| config.service_dir.clone());
+    let audit = match AuditLog::open(&config.audit_log, &service_dir) {
+        Ok(audit) => Some(Arc::new(audit)),
+        Err(e) => {
+            warn!("Failed to open audit log {:?}, not auditing: {}", config.audit_log, e);
+            None
+        }
+    };
 
     info!("Listening for connections...");
 
@@ -100,15 +507,23 @@ async fn run_daemon(config: DaemonConfig) -> Result<()> {
             info!("Waiting for connection...");
             match listener.accept().await {
                 Ok((stream, _)) => {
+                    // Everything logged for this connection carries the peer's identity
+                    let span = connection_span(&stream);
+                    let _entered = span.enter();
                     info!("Connection accepted");
                     let manager = Arc::clone(&manager);
-                    tokio::spawn(async move {
-                        info!("Spawned connection handler");
-                        match handle_connection(stream, manager).await {
-                            Ok(_) => info!("Connection handled successfully"),
-                            Err(e) => error!("Error handling connection: {}", e),
+                    let audit = audit.clone();
+                    let open = manager.connection_opened();
+                    tokio::spawn(
+                        async move {
+                            let _open = open;
+                            match handle_connection(stream, manager, audit).await {
+                                Ok(_) => info!("Connection handled successfully"),
+                                Err(e) => error!("Error handling connection: {}", e),
+                            }
                         }
-                    });
+                        .instrument(span.clone()),
+                    );
                 }
                 Err(e) => {
                     error!("Error accepting connection: {}", e);
@@ -134,43 +549,111 @@ async fn run_daemon(config: DaemonConfig) -> Result<()> {
     ))
 }
 
+/// The listening socket a re-executed daemon inherited as descriptor `fd`
+fn inherit_listener(fd: RawFd) -> std::io::Result<UnixListener> {
+    // SAFETY: the previous daemon dup'd the socket for the handoff and exec'd
+    // this one with it open; nothing else owns it, and it is inherited once
+    let inherited = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+    // A copy with close-on-exec, so services don't inherit the socket
+    let listener = inherited.try_clone()?;
+    drop(inherited);
+    listener.set_nonblocking(true)?;
+    UnixListener::from_std(listener)
+}
+
+/// A span identifying the process on the other end of a connection by its
+/// credentials (`SO_PEERCRED`)
+fn connection_span(stream: &UnixStream) -> tracing::Span {
+    match stream.peer_cred() {
+        Ok(cred) => info_span!(
+            "connection",
+            uid = cred.uid(),
+            gid = cred.gid(),
+            pid = cred.pid().map(i64::from)
+        ),
+        Err(e) => {
+            warn!("Failed to read peer credentials: {}", e);
+            info_span!("connection", uid = tracing::field::Empty)
+        }
+    }
+}
+
 async fn handle_connection(
     stream: UnixStream,
     manager: Arc<ServiceManager>,
+    audit: Option<Arc<AuditLog>>,
 ) -> std::io::Result<()> {
+    let peer = Peer::of(&stream);
     let (reader, mut writer) = stream.into_split();
     let mut reader = BufReader::new(reader);
-    let mut line = String::new();
+    let mut frame = Vec::new();
 
-    while reader.read_line(&mut line).await? > 0 {
-        let request: Request = match serde_json::from_str(&line.trim()) {
-            Ok(req) => req,
+    loop {
+        match read_frame(&mut reader, &mut frame, MAX_REQUEST_BYTES).await {
+            Ok(true) => {}
+            Ok(false) => break,
             Err(e) => {
-                let response = Response::error(format!("Invalid request: {}", e));
-                let response_json = serde_json::to_string(&response).unwrap();
-                writer.write_all(response_json.as_bytes()).await?;
-                writer.write_all(b"\n").await?;
-                line.clear();
+                // The stream can't be resynchronized after a bad frame, so report and hang up
+                if e.kind() == std::io::ErrorKind::InvalidData {
+                    let response = Response::error(format!("{}: {}", INVALID_REQUEST, e));
+                    let _ = write_frame(&mut writer, &response).await;
+                }
+                return Err(e);
+            }
+        }
+
+        let (request, id) = match decode_request(&frame) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                let response = Response::error(format!("{}: {}", INVALID_REQUEST, e));
+                write_frame(&mut writer, &response).await?;
                 continue;
             }
         };
+        let request = resolve_aliases(request, &manager).await;
+
+        if let Request::Logs {
+            services,
+            lines,
+            since,
+            follow: true,
+        } = request
+        {
+            // Following takes over the connection until the client goes away
+            return follow_logs(&mut writer, id, &manager, &services, lines, since).await;
+        }
 
         let is_shutdown = matches!(request, Request::Shutdown);
-        let response = handle_request(request, &manager).await;
-        let response_json = match serde_json::to_string(&response) {
-            Ok(json) => json,
-            Err(e) => {
-                error!("Failed to serialize response: {}", e);
-                continue;
+        let operation = audit.as_ref().and_then(|_| Operation::of(&request));
+
+        // Progress goes out as it is made. Clients that don't tag requests
+        // with ids predate it and would take it for the response, so theirs
+        // is dropped.
+        let (progress, mut updates) = mpsc::unbounded_channel();
+        let handling = handle_request(request, &manager, progress);
+        tokio::pin!(handling);
+        let response = loop {
+            tokio::select! {
+                response = &mut handling => break response,
+                Some(message) = updates.recv() => {
+                    if id.is_some() {
+                        write_response(&mut writer, id, Response::Progress { message }).await?;
+                    }
+                }
             }
         };
+        while let Ok(message) = updates.try_recv() {
+            if id.is_some() {
+                write_response(&mut writer, id, Response::Progress { message }).await?;
+            }
+        }
 
-        if let Err(e) = writer.write_all(response_json.as_bytes()).await {
-            error!("Failed to write response: {}", e);
-            break;
+        if let (Some(audit), Some(operation)) = (&audit, &operation) {
+            audit.record(&peer, operation, &response);
         }
-        if let Err(e) = writer.write_all(b"\n").await {
-            error!("Failed to write newline: {}", e);
+
+        if let Err(e) = write_response(&mut writer, id, response).await {
+            error!("Failed to write response: {}", e);
             break;
         }
 
@@ -179,50 +662,446 @@ async fn handle_connection(
             let _ = writer.flush().await;
             std::process::exit(0);
         }
-
-        line.clear();
     }
 
     Ok(())
 }
 
-async fn handle_request(request: Request, manager: &ServiceManager) -> Response {
-    info!("Handling request: {:?}", request);
-    match request {
-        Request::Start { service } => {
-            info!("Starting service: {}", service);
-            match manager.start_service(&service).await {
-                Ok(_) => {
-                    info!("Service '{}' started successfully", service);
-                    Response::ok(format!("Service '{}' started successfully", service))
-                }
+/// Write a response, echoing the request id back to clients that sent one
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    id: Option<u64>,
+    response: Response,
+) -> std::io::Result<()> {
+    match id {
+        Some(id) => {
+            let envelope = ResponseEnvelope {
+                id: Some(id),
+                response,
+            };
+            write_frame(writer, &envelope).await
+        }
+        None => write_frame(writer, &response).await,
+    }
+}
+
+/// Send the selected tail of each service's log, then keep sending lines as
+/// they are appended, in order of arrival, until writing to the client fails
+async fn follow_logs<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    id: Option<u64>,
+    manager: &ServiceManager,
+    services: &[String],
+    lines: Option<usize>,
+    since: Option<Duration>,
+) -> std::io::Result<()> {
+    let mut logs = Vec::new();
+    for service in dedup(services) {
+        match manager.service_log_file(&service).await {
+            Ok(path) => logs.push((service, path, 0)),
+            Err(e) => {
+                let response = Response::error(format!("Failed to read logs for '{}': {}", service, e));
+                return write_response(writer, id, response).await;
+            }
+        }
+    }
+    let interleaved = logs.len() > 1;
+
+    let mut initial = Vec::new();
+    for (service, path, offset) in &mut logs {
+        let (lines, end) = read_logs_blocking(path.clone(), lines, since).await?;
+        *offset = end;
+        initial.push((service.clone(), lines));
+    }
+    write_response(writer, id, logs_response(initial, interleaved)).await?;
+
+    // Reading is decoupled from writing so a slow client can't hold up
+    // anything else, nor make the daemon buffer output without bound
+    let (sender, mut receiver) = mpsc::channel(LOG_FOLLOW_BUFFER);
+    let tail = tokio::spawn(tail_logs(logs, interleaved, sender));
+    let result = async {
+        while let Some(response) = receiver.recv().await {
+            write_response(writer, id, response).await?;
+        }
+        Ok(())
+    }
+    .await;
+    tail.abort();
+    result
+}
+
+/// Read lines appended to `logs` and queue them for a follower. While the
+/// queue is full, new lines are dropped and counted; the count is queued as
+/// a `LogsDropped` marker once there is room again.
+async fn tail_logs(
+    mut logs: Vec<(String, PathBuf, u64)>,
+    interleaved: bool,
+    sender: mpsc::Sender<Response>,
+) {
+    let mut dropped = 0;
+    let mut caught_up = true;
+
+    loop {
+        // A log with more to read is read again straight away
+        if caught_up {
+            tokio::time::sleep(LOG_FOLLOW_INTERVAL).await;
+        }
+        caught_up = true;
+
+        let mut appended = Vec::new();
+        for (service, path, offset) in &mut logs {
+            let path = path.clone();
+            let mut from = *offset;
+            let read = tokio::task::spawn_blocking(move || {
+                crate::logs::read_appended(&path, &mut from, LOG_FOLLOW_CHUNK)
+                    .map(|read| (read, from))
+            })
+            .await
+            .map_err(std::io::Error::other)
+            .and_then(|read| read);
+            let ((lines, more), new_offset) = match read {
+                Ok(read) => read,
                 Err(e) => {
-                    error!("Failed to start service '{}': {}", service, e);
-                    Response::error(format!("Failed to start service '{}': {}", service, e))
+                    warn!("Failed to follow log of {}: {}", service, e);
+                    return;
+                }
+            };
+            *offset = new_offset;
+            caught_up &= !more;
+
+            if !lines.is_empty() {
+                appended.push((service.clone(), lines));
+            }
+        }
+        if appended.is_empty() {
+            continue;
+        }
+
+        let count: usize = appended.iter().map(|(_, lines)| lines.len()).sum();
+        if dropped > 0 {
+            match sender.try_send(Response::LogsDropped { lines: dropped }) {
+                Ok(()) => dropped = 0,
+                Err(TrySendError::Full(_)) => {
+                    dropped += count;
+                    continue;
                 }
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+        match sender.try_send(logs_response(appended, interleaved)) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => dropped += count,
+            Err(TrySendError::Closed(_)) => return,
+        }
+    }
+}
+
+/// Lines read from services' logs as a response: `Logs` for one service,
+/// or `InterleavedLogs` when several were asked for
+fn logs_response(mut logs: Vec<(String, Vec<String>)>, interleaved: bool) -> Response {
+    match logs.pop() {
+        Some((service, lines)) if !interleaved && logs.is_empty() => {
+            Response::Logs { service, lines }
+        }
+        Some(last) => {
+            logs.push(last);
+            Response::InterleavedLogs {
+                lines: crate::logs::interleave(logs),
+            }
+        }
+        None => Response::InterleavedLogs { lines: Vec::new() },
+    }
+}
+
+/// `names` without repeats, in their original order
+fn dedup(names: &[String]) -> Vec<String> {
+    let mut unique: Vec<String> = Vec::new();
+    for name in names {
+        if !unique.contains(name) {
+            unique.push(name.clone());
+        }
+    }
+    unique
+}
+
+async fn read_logs_blocking(
+    path: PathBuf,
+    lines: Option<usize>,
+    since: Option<Duration>,
+) -> std::io::Result<(Vec<String>, u64)> {
+    tokio::task::spawn_blocking(move || crate::logs::read_logs(&path, lines, since))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+/// `request` with each service it names by an `Alias` named by its own name
+/// instead, so nothing past this point needs to know about aliases
+async fn resolve_aliases(mut request: Request, manager: &ServiceManager) -> Request {
+    match request {
+        Request::Start { ref mut service, .. }
+        | Request::Stop { ref mut service, .. }
+        | Request::Restart { ref mut service, .. }
+        | Request::Signal { ref mut service, .. }
+        | Request::Status { ref mut service }
+        | Request::Pid { ref mut service }
+        | Request::WaitFor { ref mut service, .. }
+        | Request::Describe { ref mut service }
+        | Request::Deps { ref mut service }
+        | Request::GetConfig { ref mut service, .. }
+        | Request::SetConfig { ref mut service, .. }
+        | Request::History {
+            service: Some(ref mut service),
+            ..
+        } => *service = manager.canonical_name(service).await,
+        Request::Logs { ref mut services, .. } => {
+            for service in services.iter_mut() {
+                *service = manager.canonical_name(service).await;
             }
         }
+        _ => {}
+    }
+    request
+}
 
-        Request::Stop { service } => match manager.stop_service(&service).await {
-            Ok(_) => Response::ok(format!("Service '{}' stopped successfully", service)),
-            Err(e) => Response::error(format!("Failed to stop service '{}': {}", service, e)),
+async fn handle_request(request: Request, manager: &Arc<ServiceManager>, progress: Progress) -> Response {
+    info!("Handling request: {:?}", request);
+    match request {
+        Request::Start {
+            service,
+            wait,
+            env,
+            no_supervise,
+        } => match manager.expand_pattern(&service).await {
+            Ok(Some(names)) => {
+                let mut results = Vec::new();
+                for name in names {
+                    let response = start(manager, name.clone(), wait, env.clone(), no_supervise, &progress).await;
+                    results.push(batch_result(name, response, &progress));
+                }
+                Response::Batch { results }
+            }
+            Ok(None) => start(manager, service, wait, env, no_supervise, &progress).await,
+            Err(e) => Response::error(format!("Failed to start '{}': {}", service, e)),
+        },
+
+        // Dependents first, the reverse of their start order
+        Request::Stop {
+            service,
+            wait,
+            force,
+        } => match manager.expand_pattern(&service).await {
+            Ok(Some(names)) => {
+                let mut results = Vec::new();
+                for name in names.into_iter().rev() {
+                    let response = stop(manager, name.clone(), wait, force).await;
+                    results.push(batch_result(name, response, &progress));
+                }
+                Response::Batch { results }
+            }
+            Ok(None) => stop(manager, service, wait, force).await,
+            Err(e) => Response::error(format!("Failed to stop '{}': {}", service, e)),
         },
 
-        Request::Restart { service } => match manager.restart_service(&service).await {
-            Ok(_) => Response::ok(format!("Service '{}' restarted successfully", service)),
-            Err(e) => Response::error(format!("Failed to restart service '{}': {}", service, e)),
+        Request::Restart {
+            service,
+            wait,
+            no_supervise,
+        } => match manager.expand_pattern(&service).await {
+            Ok(Some(names)) => {
+                let mut results = Vec::new();
+                for name in names {
+                    let response = restart(manager, name.clone(), wait, no_supervise).await;
+                    results.push(batch_result(name, response, &progress));
+                }
+                Response::Batch { results }
+            }
+            Ok(None) => restart(manager, service, wait, no_supervise).await,
+            Err(e) => Response::error(format!("Failed to restart '{}': {}", service, e)),
         },
 
+        Request::Signal { service, signal } => {
+            match manager.signal_service(&service, &signal).await {
+                Ok(_) => Response::ok(format!("Sent {} to service '{}'", signal, service)),
+                Err(e) => Response::error(format!("Failed to signal service '{}': {}", service, e)),
+            }
+        }
+
         Request::Status { service } => match manager.get_service_status(&service).await {
             Ok(state) => Response::Status { service, state },
             Err(e) => Response::error(format!("Failed to get status for '{}': {}", service, e)),
         },
 
+        Request::Pid { service } => match manager.service_pid(&service).await {
+            Ok(pid) => Response::Pid { service, pid },
+            Err(e) => Response::error(format!("Failed to get PID of '{}': {}", service, e)),
+        },
+
+        Request::WaitFor {
+            service,
+            target_state,
+            timeout,
+        } => match manager.wait_until_state(&service, target_state, timeout).await {
+            Ok(()) => Response::ok(format!("Service '{}' is {}", service, target_state)),
+            Err(crate::error::DiakonosError::TimedOut(message)) => Response::TimedOut {
+                message: format!("Timed out {}", message),
+            },
+            Err(e) => Response::error(format!("Failed to wait for '{}': {}", service, e)),
+        },
+
+        Request::GetConfig { service, key } => match manager.config_value(&service, &key).await {
+            Ok(value) => Response::Config { service, key, value },
+            Err(e) => Response::error(format!("Failed to get {} of '{}': {}", key, service, e)),
+        },
+
+        Request::SetConfig { service, key, value } => match manager.set_config(&service, &key, &value).await {
+            Ok((drop_in, false)) => Response::ok(format!(
+                "Set {} = {} for '{}' in {}",
+                key,
+                value,
+                service,
+                drop_in.display()
+            )),
+            Ok((drop_in, true)) => Response::ok(format!(
+                "Set {} = {} for '{}' in {}; restart it to apply",
+                key,
+                value,
+                service,
+                drop_in.display()
+            )),
+            Err(e) => Response::error(format!("Failed to set {} of '{}': {}", key, service, e)),
+        },
+
+        Request::Describe { service } => match manager.describe_service(&service).await {
+            Ok(description) => Response::Describe {
+                description: Box::new(description),
+            },
+            Err(e) => Response::error(format!("Failed to describe '{}': {}", service, e)),
+        },
+
         Request::List => {
             let services = manager.list_services().await;
             Response::List { services }
         }
 
+        Request::ListPage { after, limit } => {
+            let (services, total) = manager.list_page(after.as_deref(), limit.min(LIST_PAGE_SIZE)).await;
+            Response::ListPage { services, total }
+        }
+
+        Request::Logs {
+            services,
+            lines,
+            since,
+            ..
+        } => {
+            let services = dedup(&services);
+            let mut logs = Vec::new();
+            for service in &services {
+                let result = match manager.service_log_file(service).await {
+                    Ok(path) => read_logs_blocking(path, lines, since)
+                        .await
+                        .map_err(crate::error::DiakonosError::from),
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok((lines, _)) => logs.push((service.clone(), lines)),
+                    Err(e) => {
+                        return Response::error(format!(
+                            "Failed to read logs for '{}': {}",
+                            service, e
+                        ))
+                    }
+                }
+            }
+            logs_response(logs, services.len() > 1)
+        }
+
+        Request::Stats => Response::Stats {
+            stats: manager.service_stats().await,
+        },
+
+        Request::Deps { service } => match manager.service_dependencies(&service).await {
+            Ok(dependencies) => Response::Deps {
+                service,
+                dependencies,
+            },
+            Err(e) => Response::error(format!(
+                "Failed to resolve dependencies of '{}': {}",
+                service, e
+            )),
+        },
+
+        Request::DependencyGraph => Response::DependencyGraph {
+            graph: manager.dependency_graph().await,
+        },
+
+        Request::Diagnostics => Response::Diagnostics {
+            diagnostics: manager.diagnostics().await,
+        },
+
+        Request::Reload { dry_run: true, soft } => match manager.preview_reload(soft).await {
+            Ok(summary) => Response::ok(format!("Dry run, nothing changed ({})", summary)),
+            Err(e) => Response::error(format!("Failed to preview reload: {}", e)),
+        },
+
+        Request::Reload { dry_run: false, soft } => match if soft {
+            manager.soft_reload_all().await
+        } else {
+            manager.reload_all().await
+        } {
+            Ok(summary) => Response::ok(format!("Reloaded services ({})", summary)),
+            Err(e) => Response::error(format!(
+                "Failed to reload services, keeping current configuration: {}",
+                e
+            )),
+        },
+
+        Request::Apply => match manager.apply().await {
+            Ok(summary) if summary.succeeded() => Response::ok(format!("Applied ({})", summary)),
+            Ok(summary) => Response::error(format!("Applied, with failures ({})", summary)),
+            Err(e) => Response::error(format!("Failed to apply the service directory: {}", e)),
+        },
+
+        Request::RunTransient { name, unit } => {
+            let result = detached(manager, &name, |m, s| async move {
+                m.run_transient(&s, *unit).await
+            })
+            .await;
+            match result {
+                Ok(_) => Response::ok(format!("Started transient service '{}'", name)),
+                Err(e) => Response::error(format!("Failed to run '{}': {}", name, e)),
+            }
+        }
+
+        Request::Freeze => Response::ok(if manager.freeze_restarts(true) {
+            "Automatic restarts were already frozen".to_string()
+        } else {
+            "Froze automatic restarts".to_string()
+        }),
+
+        Request::Unfreeze => Response::ok(if manager.freeze_restarts(false) {
+            "Unfroze automatic restarts".to_string()
+        } else {
+            "Automatic restarts were not frozen".to_string()
+        }),
+
+        Request::History { service, since } => match manager.history(service.as_deref(), since) {
+            Some(transitions) => Response::History { transitions },
+            None => Response::error("No history is being recorded; see the daemon log for why"),
+        },
+
+        Request::LogUsage => match manager.log_usage().await {
+            Ok(usage) => Response::LogUsage {
+                usage,
+                max_bytes: manager.max_log_bytes(),
+            },
+            Err(e) => Response::error(format!("Failed to read log usage: {}", e)),
+        },
+
+        Request::DaemonInfo => Response::DaemonInfo {
+            info: manager.daemon_info().await,
+        },
+
         Request::Ping => Response::Pong,
 
         Request::Shutdown => {
@@ -232,6 +1111,133 @@ async fn handle_request(request: Request, manager: &ServiceManager) -> Response
     }
 }
 
+async fn start(
+    manager: &Arc<ServiceManager>,
+    service: String,
+    wait: Option<Duration>,
+    env: Vec<String>,
+    no_supervise: bool,
+    progress: &Progress,
+) -> Response {
+    info!("Starting service: {}", service);
+    let progress = progress.clone();
+    let result = detached(manager, &service, |m, s| async move {
+        m.start_service_reporting(&s, env, !no_supervise, progress).await?;
+        match wait {
+            Some(timeout) => m.wait_until_started(&s, timeout).await,
+            None => Ok(()),
+        }
+    })
+    .await;
+    match result {
+        Ok(_) => {
+            info!("Service '{}' started successfully", service);
+            Response::ok(format!("Service '{}' started successfully", service))
+        }
+        // Like systemd, an unmet condition skips the start without failing it
+        Err(crate::error::DiakonosError::ConditionNotMet(condition)) => Response::ok(format!(
+            "Service '{}' not started: condition not met ({})",
+            service, condition
+        )),
+        Err(e) => {
+            error!("Failed to start service '{}': {}", service, e);
+            Response::error(format!("Failed to start service '{}': {}", service, e))
+        }
+    }
+}
+
+async fn stop(
+    manager: &Arc<ServiceManager>,
+    service: String,
+    wait: Option<Duration>,
+    force: bool,
+) -> Response {
+    let result = detached(manager, &service, |m, s| async move {
+        if force {
+            m.force_stop_service(&s).await?;
+        } else {
+            m.stop_service(&s).await?;
+        }
+        match wait {
+            Some(timeout) => m.wait_until_stopped(&s, timeout).await,
+            None => Ok(()),
+        }
+    })
+    .await;
+    match result {
+        Ok(_) => Response::ok(format!("Service '{}' stopped successfully", service)),
+        Err(e) => Response::error(format!("Failed to stop service '{}': {}", service, e)),
+    }
+}
+
+async fn restart(
+    manager: &Arc<ServiceManager>,
+    service: String,
+    wait: Option<Duration>,
+    no_supervise: bool,
+) -> Response {
+    let result = detached(manager, &service, |m, s| async move {
+        if no_supervise {
+            m.restart_service_unsupervised(&s).await?;
+        } else {
+            m.restart_service(&s).await?;
+        }
+        match wait {
+            Some(timeout) => m.wait_until_started(&s, timeout).await,
+            None => Ok(()),
+        }
+    })
+    .await;
+    match result {
+        Ok(_) => Response::ok(format!("Service '{}' restarted successfully", service)),
+        Err(e) => Response::error(format!("Failed to restart service '{}': {}", service, e)),
+    }
+}
+
+/// `response` to an action on `service` as an entry of a `Batch`, reported
+/// to `progress` as the batch goes on
+fn batch_result(service: String, response: Response, progress: &Progress) -> BatchResult {
+    if let Response::Ok { ref message } | Response::Error { ref message } = response {
+        let _ = progress.send(message.clone());
+    }
+    match response {
+        Response::Ok { message } => BatchResult {
+            service,
+            success: true,
+            message,
+        },
+        Response::Error { message } => BatchResult {
+            service,
+            success: false,
+            message,
+        },
+        other => BatchResult {
+            service,
+            success: false,
+            message: format!("unexpected response: {:?}", other),
+        },
+    }
+}
+
+/// Run a state-changing operation on its own task so it runs to completion even
+/// if the connection handler awaiting it is dropped (e.g. the client goes away
+/// mid-request). Cancelling it part-way could leave a service stuck in
+/// `Starting` or `Stopping` with a process that may or may not exist.
+async fn detached<F, Fut>(manager: &Arc<ServiceManager>, service: &str, op: F) -> Result<()>
+where
+    F: FnOnce(Arc<ServiceManager>, String) -> Fut,
+    Fut: Future<Output = Result<()>> + Send + 'static,
+{
+    let task = tokio::spawn(op(Arc::clone(manager), service.to_string()).in_current_span());
+
+    task.await.unwrap_or_else(|e| {
+        Err(crate::error::DiakonosError::ProcessError(format!(
+            "Operation on '{}' aborted: {}",
+            service, e
+        )))
+    })
+}
+
 pub fn is_daemon_running(config: &DaemonConfig) -> bool {
     if !config.pid_file.exists() {
         return false;
@@ -266,22 +1272,47 @@ pub fn ensure_daemon_started(config: &DaemonConfig) -> Result<()> {
     let exe = std::env::current_exe()
         .map_err(|e| crate::error::DiakonosError::StartError(format!("Failed to get exe path: {}", e)))?;
 
-    std::process::Command::new(exe)
-        .arg("--daemon-start")
+    let mut cmd = std::process::Command::new(exe);
+    cmd.arg("--daemon-start")
         .arg("--service-dir")
-        .arg(&config.service_dir)
-        .spawn()
+        .arg(&config.service_dir);
+
+    // Otherwise the daemon derives the same socket from the same config file
+    // and service directory
+    if config.explicit_socket {
+        cmd.arg("--socket").arg(&config.socket_path);
+    }
+
+    if let Some(ref config_file) = config.config_file {
+        cmd.arg("--config").arg(config_file);
+    }
+
+    if let Some(addr) = config.metrics_addr {
+        cmd.arg("--metrics-addr").arg(addr.to_string());
+    }
+
+    // Left by an earlier daemon, either would be mistaken for this one's
+    let error_path = startup_error_path(config);
+    let _ = std::fs::remove_file(&error_path);
+    let _ = std::fs::remove_file(&config.socket_path);
+
+    cmd.spawn()
         .map_err(|e| crate::error::DiakonosError::StartError(format!("Failed to start daemon: {}", e)))?;
 
-    // Wait for daemon to start
+    // Wait for daemon to start, or to say why it didn't
     for _ in 0..50 {
         std::thread::sleep(std::time::Duration::from_millis(100));
         if config.socket_path.exists() {
             return Ok(());
         }
+        if let Ok(error) = std::fs::read_to_string(&error_path) {
+            let _ = std::fs::remove_file(&error_path);
+            return Err(crate::error::DiakonosError::DaemonExited(error.trim().to_string()));
+        }
     }
 
-    Err(crate::error::DiakonosError::StartError(
-        "Daemon failed to start within timeout".to_string(),
-    ))
+    Err(crate::error::DiakonosError::StartError(format!(
+        "Daemon failed to start within timeout; see {}",
+        config.log_file.display()
+    )))
 }
@@ -0,0 +1,213 @@
+use crate::client::Client;
+use crate::daemon::{is_daemon_running, DaemonConfig};
+use crate::error::DiakonosError;
+use crate::ipc::{Request, Response};
+use crate::manager::{Relation, ServiceManager};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tracing::instrument::WithSubscriber;
+use tracing::subscriber::NoSubscriber;
+
+/// How a check came out
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Pass,
+    /// Not broken, but likely to surprise
+    Warn,
+    Fail,
+}
+
+/// One finding of [`run_checks`], with a hint on how to fix it unless it passed
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Check {
+    pub outcome: Outcome,
+    pub message: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(message: String) -> Self {
+        Self {
+            outcome: Outcome::Pass,
+            message,
+            hint: None,
+        }
+    }
+
+    fn warn(message: String, hint: &str) -> Self {
+        Self {
+            outcome: Outcome::Warn,
+            message,
+            hint: Some(hint.to_string()),
+        }
+    }
+
+    fn fail(message: String, hint: &str) -> Self {
+        Self {
+            outcome: Outcome::Fail,
+            message,
+            hint: Some(hint.to_string()),
+        }
+    }
+}
+
+/// Check that the daemon for `config` answers (if it runs), that its files
+/// aren't left over from a dead one, and that every unit file in the service
+/// directory loads with its dependencies in order. Nothing is started, and
+/// the units are checked as they are on disk rather than as the daemon last
+/// loaded them.
+pub async fn run_checks(config: &DaemonConfig) -> Vec<Check> {
+    let mut checks = daemon_checks(config).await;
+    checks.extend(directory_checks(config));
+    // Loading logs every unit; the checks report what matters
+    checks.extend(unit_checks(config).with_subscriber(NoSubscriber::default()).await);
+    checks
+}
+
+async fn daemon_checks(config: &DaemonConfig) -> Vec<Check> {
+    let mut checks = Vec::new();
+
+    if !is_daemon_running(config) {
+        if config.pid_file.exists() {
+            checks.push(Check::warn(
+                format!("PID file {} names no running process", config.pid_file.display()),
+                "a daemon died without cleaning up; the next one replaces the file",
+            ));
+        }
+        if config.socket_path.exists() {
+            checks.push(Check::warn(
+                format!("Socket {} has no daemon behind it", config.socket_path.display()),
+                "a daemon died without cleaning up; the next one replaces the socket",
+            ));
+        }
+        checks.push(Check::pass(
+            "Daemon is not running; the next command starts it".to_string(),
+        ));
+        return checks;
+    }
+
+    match Client::new(config.clone()).send_request(Request::DaemonInfo).await {
+        Ok(Response::DaemonInfo { info }) => {
+            checks.push(Check::pass(format!(
+                "Daemon (PID {}) answers on {}",
+                info.pid,
+                config.socket_path.display()
+            )));
+
+            let version = env!("CARGO_PKG_VERSION");
+            if info.version != version {
+                checks.push(Check::warn(
+                    format!("Daemon runs diakonos {} but this is {}", info.version, version),
+                    "restart it with `diakonos kill` to run this version",
+                ));
+            }
+        }
+        Ok(other) => checks.push(Check::fail(
+            format!("Daemon answered a status request with {:?}", other),
+            "it may predate this client; restart it with `diakonos kill`",
+        )),
+        Err(e) => checks.push(Check::fail(
+            format!(
+                "Daemon (PID file {}) runs but doesn't answer on {}: {}",
+                config.pid_file.display(),
+                config.socket_path.display(),
+                e
+            ),
+            "if its socket was deleted, stop the process with `kill` and run any command to start a new one",
+        )),
+    }
+
+    checks
+}
+
+/// Whether the daemon can write its service logs
+fn directory_checks(config: &DaemonConfig) -> Vec<Check> {
+    // The daemon creates the log directory if it's missing
+    if !config.log_dir.exists() || is_writable(&config.log_dir) {
+        return vec![Check::pass(format!(
+            "Log directory {} is writable",
+            config.log_dir.display()
+        ))];
+    }
+
+    vec![Check::fail(
+        format!("Log directory {} isn't writable", config.log_dir.display()),
+        "fix its permissions, or set `log_dir` in the config",
+    )]
+}
+
+fn is_writable(path: &Path) -> bool {
+    nix::unistd::access(path, nix::unistd::AccessFlags::W_OK).is_ok()
+}
+
+async fn unit_checks(config: &DaemonConfig) -> Vec<Check> {
+    let manager = ServiceManager::new(config);
+    let mut checks = Vec::new();
+
+    if let Err(e) = manager.read_vars() {
+        let path = config.vars_file.as_deref().unwrap_or(Path::new(""));
+        checks.push(Check::fail(
+            format!("Vars file {} can't be read: {}", path.display(), e),
+            "fix the file, or remove `vars_file` from the config",
+        ));
+        return checks;
+    }
+
+    let names = match manager.scan_service_dir() {
+        Ok(names) => names,
+        Err(e) => {
+            checks.push(Check::fail(
+                format!("Service directory {} can't be read: {}", config.service_dir.display(), e),
+                "check that it exists and that you may read it, or pick another with --service-dir",
+            ));
+            return checks;
+        }
+    };
+    checks.push(Check::pass(format!(
+        "Service directory {} is readable",
+        config.service_dir.display()
+    )));
+
+    let mut loaded = Vec::new();
+    for name in names {
+        match manager.load_service(&name).await {
+            Ok(()) => loaded.push(name),
+            Err(e) => checks.push(Check::fail(
+                format!("Unit {}.service doesn't load: {}", name, e),
+                "fix the unit file, then run `diakonos reload`",
+            )),
+        }
+    }
+    checks.push(Check::pass(format!("{} unit file(s) load", loaded.len())));
+
+    let diagnostics = manager.diagnostics().await;
+    for diagnostic in &diagnostics {
+        let message = format!("Service {} {}", diagnostic.service, diagnostic.message);
+        let hint = "add the missing unit, or fix the name";
+        checks.push(match diagnostic.relation {
+            Relation::After => Check::warn(message, hint),
+            Relation::Requires | Relation::Wants => Check::fail(message, hint),
+        });
+    }
+
+    let mut cyclic = Vec::new();
+    for name in &loaded {
+        if let Err(DiakonosError::DependencyCycle) = manager.service_dependencies(name).await {
+            cyclic.push(name.as_str());
+        }
+    }
+    if !cyclic.is_empty() {
+        checks.push(Check::fail(
+            format!("Dependency cycle through {}", cyclic.join(", ")),
+            "drop one of the `Requires`, `Wants` or `After` entries that close the loop",
+        ));
+    } else if diagnostics.is_empty() {
+        checks.push(Check::pass(
+            "Dependencies refer to loaded units, without cycles".to_string(),
+        ));
+    }
+
+    checks
+}
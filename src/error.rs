@@ -5,24 +5,58 @@ pub enum DiakonosError {
     #[error("Service not found: {0}")]
     ServiceNotFound(String),
 
+    #[error("Invalid service name: {0}")]
+    InvalidServiceName(String),
+
     #[error("Service already exists: {0}")]
     ServiceAlreadyExists(String),
 
     #[error("Failed to parse unit file: {0}")]
     ParseError(String),
 
+    #[error("Unknown setting {0}")]
+    UnknownSetting(String),
+
+    #[error("Invalid config file: {0}")]
+    ConfigError(String),
+
     #[error("Failed to start service: {0}")]
     StartError(String),
 
+    #[error("Daemon exited during startup: {0}")]
+    DaemonExited(String),
+
     #[error("Failed to stop service: {0}")]
     StopError(String),
 
+    #[error("Service is not running: {0}")]
+    ServiceNotRunning(String),
+
+    #[error("Invalid signal: {0}")]
+    InvalidSignal(String),
+
+    #[error("Condition not met: {0}")]
+    ConditionNotMet(String),
+
     #[error("Dependency cycle detected")]
     DependencyCycle,
 
     #[error("Service dependency not met: {0}")]
     DependencyNotMet(String),
 
+    #[error("Output goes to syslog as '{0}'")]
+    LogsToSyslog(String),
+
+    #[error("Timed out {0}")]
+    TimedOut(String),
+
+    /// The daemon answered a request with an error, given as it worded it
+    #[error("{0}")]
+    RequestFailed(String),
+
+    #[error("Protocol error: {0}")]
+    ProtocolError(String),
+
     #[error("IO error: {0}")]
     IoError(#[from] std::io::Error),
 
@@ -0,0 +1,134 @@
+use crate::webhook::StateChange;
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
+
+/// Most transitions kept; recording more drops the oldest
+pub const MAX_TRANSITIONS: usize = 10_000;
+
+/// State transitions of services, one JSON object per line, kept across
+/// daemon restarts so "when did this last fail?" can be answered after the
+/// fact. The file is rewritten with only the newest `MAX_TRANSITIONS` once
+/// it holds twice that many, so it never grows without bound.
+pub struct History {
+    path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    transitions: VecDeque<StateChange>,
+    file: File,
+    /// Lines in the file, including those no longer in `transitions`
+    lines: usize,
+}
+
+impl History {
+    /// Open the history at `path`, creating it readable only by the daemon's
+    /// user, and read in what earlier daemons recorded. Lines that don't
+    /// parse, such as one cut short by a crash, are skipped.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let mut transitions = VecDeque::new();
+        let mut lines = 0;
+        match File::open(path) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    lines += 1;
+                    if let Ok(transition) = serde_json::from_str(&line?) {
+                        push_bounded(&mut transitions, transition);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            inner: Mutex::new(Inner {
+                transitions,
+                file: open_for_append(path)?,
+                lines,
+            }),
+        })
+    }
+
+    /// Append `transition`, compacting the file if it has grown too long
+    pub fn record(&self, transition: StateChange) {
+        let line = match serde_json::to_string(&transition) {
+            Ok(line) => line,
+            Err(e) => {
+                warn!("Failed to encode history entry: {}", e);
+                return;
+            }
+        };
+
+        let mut inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        push_bounded(&mut inner.transitions, transition);
+        if let Err(e) = inner.file.write_all(format!("{}\n", line).as_bytes()) {
+            warn!("Failed to write history {:?}: {}", self.path, e);
+            return;
+        }
+        inner.lines += 1;
+
+        if inner.lines >= 2 * MAX_TRANSITIONS {
+            if let Err(e) = self.compact(&mut inner) {
+                warn!("Failed to compact history {:?}: {}", self.path, e);
+            }
+        }
+    }
+
+    /// Recorded transitions, oldest first: of `service` only if given, and
+    /// only those from the last `since` if given
+    pub fn query(&self, service: Option<&str>, since: Option<Duration>) -> Vec<StateChange> {
+        let cutoff = since.and_then(|since| SystemTime::now().checked_sub(since));
+        let inner = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        inner
+            .transitions
+            .iter()
+            .filter(|t| service.is_none_or(|service| t.service == service))
+            .filter(|t| match cutoff {
+                Some(cutoff) => humantime::parse_rfc3339_weak(&t.timestamp).is_ok_and(|at| at >= cutoff),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Replace the file with one holding just the kept transitions, through
+    /// a temporary file so a crash midway leaves the old one intact
+    fn compact(&self, inner: &mut Inner) -> io::Result<()> {
+        let temporary = self.path.with_extension("tmp");
+        let mut contents = String::new();
+        for transition in &inner.transitions {
+            contents.push_str(&serde_json::to_string(transition).map_err(io::Error::other)?);
+            contents.push('\n');
+        }
+        std::fs::write(&temporary, contents)?;
+        std::fs::rename(&temporary, &self.path)?;
+
+        inner.file = open_for_append(&self.path)?;
+        inner.lines = inner.transitions.len();
+        Ok(())
+    }
+}
+
+fn push_bounded(transitions: &mut VecDeque<StateChange>, transition: StateChange) {
+    if transitions.len() == MAX_TRANSITIONS {
+        transitions.pop_front();
+    }
+    transitions.push_back(transition);
+}
+
+fn open_for_append(path: &Path) -> io::Result<File> {
+    use std::os::unix::fs::OpenOptionsExt;
+
+    OpenOptions::new().create(true).append(true).mode(0o600).open(path)
+}
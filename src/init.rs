@@ -0,0 +1,91 @@
+use crate::manager::ServiceManager;
+use nix::sys::wait::{waitpid, WaitPidFlag};
+use nix::unistd::Pid;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{debug, info};
+
+/// How often to look for orphans to reap when no `SIGCHLD` prompts it
+const REAP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Whether this process is PID 1, as a container's entrypoint is. Nothing
+/// else will then stop the services when the container is stopped, or reap
+/// the orphaned processes the kernel hands to PID 1.
+pub fn is_init() -> bool {
+    nix::unistd::getpid().as_raw() == 1
+}
+
+/// Act as init: reap orphaned processes until `SIGTERM` or `SIGINT`, then
+/// stop every service, dependents first, and exit. The kernel doesn't apply
+/// the default action of those signals to PID 1, so without this the daemon
+/// would ignore them until the container runtime gave up and killed it.
+pub async fn run(manager: Arc<ServiceManager>) -> std::io::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut children = signal(SignalKind::child())?;
+    let mut interval = tokio::time::interval(REAP_INTERVAL);
+    let mut zombies = HashSet::new();
+
+    let received = loop {
+        tokio::select! {
+            _ = terminate.recv() => break "SIGTERM",
+            _ = interrupt.recv() => break "SIGINT",
+            _ = children.recv() => {}
+            _ = interval.tick() => {}
+        }
+        zombies = reap_orphans(&zombies, &manager.main_pids().await);
+    };
+
+    info!("Received {} as PID 1, stopping all services", received);
+    manager.stop_all().await;
+    info!("All services stopped, exiting");
+    std::process::exit(0);
+}
+
+/// Reap this process's zombie children that were zombies already at the
+/// last pass (`previous`), other than services' main processes, which the
+/// supervisor waits for. A process the daemon spawned and waits for itself
+/// is reaped by that wait before it is seen twice. Returns the zombies to
+/// pass in next time.
+fn reap_orphans(previous: &HashSet<i32>, main_pids: &HashSet<u32>) -> HashSet<i32> {
+    let mut zombies = HashSet::new();
+    for pid in zombie_children() {
+        if main_pids.contains(&(pid as u32)) {
+            continue;
+        }
+        if !previous.contains(&pid) {
+            zombies.insert(pid);
+            continue;
+        }
+        if let Ok(status) = waitpid(Pid::from_raw(pid), Some(WaitPidFlag::WNOHANG)) {
+            debug!("Reaped orphaned process {}: {:?}", pid, status);
+        }
+    }
+    zombies
+}
+
+/// Children of this process that have exited but not been waited for, as
+/// listed in `/proc`
+fn zombie_children() -> Vec<i32> {
+    let this = std::process::id().to_string();
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse::<i32>().ok())
+        .filter(|pid| {
+            let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+                return false;
+            };
+            // The command name may contain spaces, so fields are counted after its closing paren
+            let mut fields = match stat.rsplit_once(')') {
+                Some((_, fields)) => fields.split_whitespace(),
+                None => return false,
+            };
+            fields.next() == Some("Z") && fields.next() == Some(this.as_str())
+        })
+        .collect()
+}
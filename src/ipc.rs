@@ -1,13 +1,129 @@
-use crate::service::ServiceState;
+use crate::logs::LogUsage;
+use crate::manager::{DependencyGraph, Diagnostic};
+use crate::service::{ServiceDescription, ServiceState, ServiceStats};
+use crate::unit::UnitFile;
+use crate::webhook::StateChange;
 use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest request frame the daemon accepts from a client
+pub const MAX_REQUEST_BYTES: usize = 1024 * 1024;
+
+/// Largest response frame the client accepts from the daemon
+pub const MAX_RESPONSE_BYTES: usize = 16 * 1024 * 1024;
+
+/// How many services the client asks for in each `ListPage`, and the most
+/// the daemon answers with
+pub const LIST_PAGE_SIZE: usize = 500;
+
+/// How the daemon's answer to a request it can't decode begins, as to one
+/// added in a later version
+pub const INVALID_REQUEST: &str = "Invalid request";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Request {
-    Start { service: String },
-    Stop { service: String },
-    Restart { service: String },
+    /// `wait` holds the response until the service is up, or fails after that
+    /// long. `env` holds `KEY=VALUE` overrides of the unit's environment.
+    /// `no_supervise` keeps the supervisor from restarting the service until
+    /// it is next started or restarted without it.
+    Start {
+        service: String,
+        #[serde(default)]
+        wait: Option<Duration>,
+        #[serde(default)]
+        env: Vec<String>,
+        #[serde(default)]
+        no_supervise: bool,
+    },
+    /// `wait` holds the response until the process has exited, or fails after
+    /// that long. `force` SIGKILLs the process group without a grace period.
+    Stop {
+        service: String,
+        #[serde(default)]
+        wait: Option<Duration>,
+        #[serde(default)]
+        force: bool,
+    },
+    /// `wait` and `no_supervise` are as for `Start`
+    Restart {
+        service: String,
+        #[serde(default)]
+        wait: Option<Duration>,
+        #[serde(default)]
+        no_supervise: bool,
+    },
     Status { service: String },
+    /// The PID of a service's main process
+    Pid { service: String },
+    Describe { service: String },
+    Signal { service: String, signal: String },
     List,
+    /// Up to `limit` services, at most [`LIST_PAGE_SIZE`], in name order
+    /// from the first named after `after` (from the first of all if `None`),
+    /// so a long list comes in bounded frames
+    ListPage { after: Option<String>, limit: usize },
+    Stats,
+    Deps { service: String },
+    /// `Requires`, `Wants` and `After` of every service
+    DependencyGraph,
+    /// References in `Requires`, `Wants` and `After` to units that aren't loaded
+    Diagnostics,
+    /// The value in effect of a setting of a service's unit, such as `Restart`
+    GetConfig { service: String, key: String },
+    /// Set a setting of a service's unit in its override drop-in, then reload
+    SetConfig {
+        service: String,
+        key: String,
+        value: String,
+    },
+    /// With `dry_run`, report what would change without applying it
+    Reload {
+        #[serde(default)]
+        dry_run: bool,
+        /// Stage changed units of active services for their next start
+        /// instead of leaving them unapplied
+        #[serde(default)]
+        soft: bool,
+    },
+    /// Reload the service directory and bring the services in line with it;
+    /// see [`crate::manager::ServiceManager::apply`]
+    Apply,
+    /// Register and start a service not backed by a unit file. It is
+    /// forgotten once it stops.
+    RunTransient { name: String, unit: Box<UnitFile> },
+    /// Hold the response until `service` (or every member of a target) is
+    /// in `target_state`, answering `TimedOut` if that takes over `timeout`
+    WaitFor {
+        service: String,
+        target_state: ServiceState,
+        timeout: Duration,
+    },
+    /// Captured output of services; see [`crate::logs::read_logs`] for how
+    /// `lines` and `since` select it from each. One service's output comes as
+    /// `Logs`, several services' as `InterleavedLogs`. With `follow`, lines
+    /// appended later are streamed as further responses until the client
+    /// disconnects.
+    Logs {
+        services: Vec<String>,
+        lines: Option<usize>,
+        since: Option<Duration>,
+        follow: bool,
+    },
+    /// Recorded state transitions, of `service` or of every service, from
+    /// the last `since` or as far back as they are kept
+    History {
+        service: Option<String>,
+        since: Option<Duration>,
+    },
+    /// Stop the supervisor restarting any service until `Unfreeze`
+    Freeze,
+    Unfreeze,
+    /// Disk space taken by each service's log
+    LogUsage,
+    DaemonInfo,
     Ping,
     Shutdown,
 }
@@ -16,11 +132,113 @@ pub enum Request {
 pub enum Response {
     Ok { message: String },
     Error { message: String },
+    /// A `WaitFor` whose service didn't reach the state in time
+    TimedOut { message: String },
+    /// How a long operation is getting on, such as each dependency a start
+    /// brings up. Any number may precede the response that ends a request;
+    /// they are only sent to clients that tag requests with ids.
+    Progress { message: String },
     Status { service: String, state: ServiceState },
+    /// `None` if the service has no process running
+    Pid { service: String, pid: Option<u32> },
     List { services: Vec<(String, ServiceState)> },
+    /// `total` is how many services there are across all pages
+    ListPage {
+        services: Vec<(String, ServiceState)>,
+        total: usize,
+    },
+    Stats { stats: Vec<ServiceStats> },
+    Deps { service: String, dependencies: Vec<String> },
+    DependencyGraph { graph: DependencyGraph },
+    Diagnostics { diagnostics: Vec<Diagnostic> },
+    Describe { description: Box<ServiceDescription> },
+    /// `None` if the setting isn't set
+    Config {
+        service: String,
+        key: String,
+        value: Option<String>,
+    },
+    Logs { service: String, lines: Vec<String> },
+    /// Lines of several services in timestamp order, as `(service, line)`
+    InterleavedLogs { lines: Vec<(String, String)> },
+    /// Followed log lines skipped because the client read too slowly
+    LogsDropped { lines: usize },
+    /// Oldest first
+    History { transitions: Vec<StateChange> },
+    /// Per-service log sizes, and the cap on their total if one is configured
+    LogUsage {
+        usage: Vec<LogUsage>,
+        max_bytes: Option<u64>,
+    },
+    DaemonInfo { info: DaemonInfo },
+    /// Outcome for each service a pattern matched, in the order they were
+    /// acted on
+    Batch { results: Vec<BatchResult> },
     Pong,
 }
 
+/// One service's outcome within a [`Response::Batch`]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub service: String,
+    pub success: bool,
+    pub message: String,
+}
+
+/// The daemon's own view of its health, for `daemon-status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DaemonInfo {
+    pub version: String,
+    pub pid: u32,
+    pub uptime_secs: u64,
+    pub service_dir: PathBuf,
+    pub services: usize,
+    pub running: usize,
+    pub failed: usize,
+    #[serde(default)]
+    pub restarts_frozen: bool,
+    /// Control connections open, counting the one asking
+    #[serde(default)]
+    pub open_connections: usize,
+}
+
+/// A request tagged with a client-chosen id that the daemon echoes back in the
+/// matching [`ResponseEnvelope`], so responses can be correlated with requests
+/// on a shared connection.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RequestEnvelope {
+    pub id: u64,
+    pub request: Request,
+}
+
+/// A response to an enveloped request. Messages the daemon pushes on its own
+/// (not in reply to a request) carry no id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponseEnvelope {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<u64>,
+    pub response: Response,
+}
+
+/// Decode a request frame. Both enveloped requests and bare `Request`s (from
+/// clients that predate request ids) are accepted; the id is `None` for the latter.
+pub fn decode_request(frame: &[u8]) -> serde_json::Result<(Request, Option<u64>)> {
+    if let Ok(envelope) = serde_json::from_slice::<RequestEnvelope>(frame) {
+        return Ok((envelope.request, Some(envelope.id)));
+    }
+
+    serde_json::from_slice(frame).map(|request| (request, None))
+}
+
+/// Decode a response frame, accepting both enveloped and bare responses
+pub fn decode_response(frame: &[u8]) -> serde_json::Result<ResponseEnvelope> {
+    if let Ok(envelope) = serde_json::from_slice::<ResponseEnvelope>(frame) {
+        return Ok(envelope);
+    }
+
+    serde_json::from_slice(frame).map(|response| ResponseEnvelope { id: None, response })
+}
+
 impl Response {
     pub fn ok(message: impl Into<String>) -> Self {
         Response::Ok {
@@ -34,3 +252,61 @@ impl Response {
         }
     }
 }
+
+/// Read one newline-terminated frame into `frame`, without the newline.
+///
+/// Returns `Ok(false)` on a clean end of stream. A frame longer than `max_len`
+/// fails with `InvalidData` before it is fully buffered, and a stream that ends
+/// part-way through a frame fails with `UnexpectedEof`.
+pub async fn read_frame<R>(reader: &mut R, frame: &mut Vec<u8>, max_len: usize) -> io::Result<bool>
+where
+    R: AsyncBufRead + Unpin,
+{
+    frame.clear();
+
+    loop {
+        let available = reader.fill_buf().await?;
+
+        if available.is_empty() {
+            if frame.is_empty() {
+                return Ok(false);
+            }
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed in the middle of a frame",
+            ));
+        }
+
+        let (chunk, complete) = match available.iter().position(|&b| b == b'\n') {
+            Some(i) => (&available[..i], true),
+            None => (available, false),
+        };
+
+        if frame.len() + chunk.len() > max_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("frame exceeds {} bytes", max_len),
+            ));
+        }
+
+        frame.extend_from_slice(chunk);
+        let consumed = chunk.len() + usize::from(complete);
+        reader.consume(consumed);
+
+        if complete {
+            return Ok(true);
+        }
+    }
+}
+
+/// Serialize `message` as JSON and write it as a single newline-terminated frame
+pub async fn write_frame<W, T>(writer: &mut W, message: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let mut frame = serde_json::to_vec(message)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    frame.push(b'\n');
+    writer.write_all(&frame).await
+}
@@ -0,0 +1,88 @@
+//! Diakonos is a small service manager in the spirit of systemd and PM2:
+//! services are described by TOML unit files and supervised by a long-running
+//! daemon that a thin CLI talks to over a Unix socket.
+//!
+//! The `diakonos` binary is built on this library, which can also be used to
+//! embed supervision in another program. The main entry point is
+//! [`ServiceManager`](manager::ServiceManager):
+//!
+//! ```no_run
+//! use diakonos::daemon::DaemonConfig;
+//! use diakonos::manager::ServiceManager;
+//! use std::sync::Arc;
+//!
+//! # async fn run() -> diakonos::error::Result<()> {
+//! let config = DaemonConfig {
+//!     service_dir: "/srv/services".into(),
+//!     ..DaemonConfig::default()
+//! };
+//!
+//! let manager = Arc::new(ServiceManager::new(&config));
+//! manager.load_all_services().await?;
+//! manager.start_service("web").await?;
+//!
+//! for (name, state) in manager.list_services().await {
+//!     println!("{}: {}", name, state);
+//! }
+//!
+//! // Restart services that exit, per their Restart= policy
+//! tokio::spawn(async move { manager.supervise().await });
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! To control a running daemon instead, use [`client::ServiceClient`], whose
+//! methods (`start`, `stop`, `list`, `follow_logs`, ...) return typed
+//! results. Underneath, it sends [`ipc::Request`]s, which can also be sent
+//! directly with [`client::Client`], which connects for each request, or
+//! with [`client::PersistentClient`], which keeps a connection open and is
+//! the better fit for sending many requests.
+
+// Supervision rests on Unix process groups and signals, and the daemon is
+// reached over a Unix socket, so there is nothing to build elsewhere. The
+// modules are left out so this is the only error instead of hundreds.
+#[cfg(not(unix))]
+compile_error!(
+    "diakonos only supports Unix-like systems (Linux, macOS, the BSDs): it manages \
+     processes through Unix signals and process groups and is controlled over a Unix \
+     socket. On Windows, build and run it under WSL."
+);
+
+#[cfg(unix)]
+pub mod attach;
+#[cfg(unix)]
+pub mod audit;
+#[cfg(unix)]
+pub mod client;
+#[cfg(unix)]
+pub mod daemon;
+#[cfg(unix)]
+pub mod doctor;
+#[cfg(unix)]
+pub mod error;
+#[cfg(unix)]
+pub mod history;
+#[cfg(unix)]
+pub mod init;
+#[cfg(unix)]
+pub mod ipc;
+#[cfg(unix)]
+pub mod logs;
+#[cfg(unix)]
+pub mod manager;
+#[cfg(unix)]
+pub mod metrics;
+#[cfg(unix)]
+pub mod reexec;
+#[cfg(unix)]
+pub mod sandbox;
+#[cfg(unix)]
+pub mod service;
+#[cfg(unix)]
+pub mod theme;
+#[cfg(unix)]
+pub mod unit;
+#[cfg(unix)]
+pub mod watch;
+#[cfg(unix)]
+pub mod webhook;
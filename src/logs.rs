@@ -0,0 +1,451 @@
+use crate::unit::SyslogLevel;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::os::fd::{AsFd, OwnedFd};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{info, warn};
+
+/// Lines returned by a logs query that gives neither a line count nor a time window
+pub const DEFAULT_LOG_LINES: usize = 100;
+
+/// How often a log being written is checked for having been rotated away
+const ROTATION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The syslog facility of service output, `daemon`
+const SYSLOG_FACILITY: u8 = 3;
+
+/// Destination for a service's captured stdout and stderr: a log file, in
+/// which each line is prefixed with an RFC 3339 timestamp so logs can be
+/// filtered by time, or the system log
+#[derive(Clone)]
+pub struct LogSink {
+    log: Arc<Mutex<Destination>>,
+}
+
+enum Destination {
+    File(OpenLog),
+    Syslog(Syslog),
+}
+
+/// Sends lines to the local syslog daemon as RFC 3164 messages. The syslog
+/// daemon (or journald) adds the timestamp and host.
+struct Syslog {
+    socket: UnixDatagram,
+    /// Where the syslog daemon receives messages, usually `/dev/log`
+    path: PathBuf,
+    identifier: String,
+    /// Facility `daemon` and the configured severity
+    priority: u8,
+    /// Whether a failed send was already reported, so a missing syslog daemon
+    /// is reported once rather than for every line
+    failed: bool,
+}
+
+impl Syslog {
+    fn send(&mut self, pid: u32, line: &str) {
+        let message = format!("<{}>{}[{}]: {}", self.priority, self.identifier, pid, line);
+
+        match self.socket.send_to(message.as_bytes(), &self.path) {
+            Ok(_) => self.failed = false,
+            Err(e) if !self.failed => {
+                warn!("Failed to send output of {} to syslog: {}", self.identifier, e);
+                self.failed = true;
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+struct OpenLog {
+    file: File,
+    path: PathBuf,
+    checked: Instant,
+}
+
+impl OpenLog {
+    /// Reopen the log if it was rotated since it was opened. Lines written
+    /// before this notices go to the rotated file, so none are lost.
+    fn reopen_if_rotated(&mut self) {
+        if self.checked.elapsed() < ROTATION_CHECK_INTERVAL {
+            return;
+        }
+        self.checked = Instant::now();
+
+        let identity = |metadata: std::fs::Metadata| (metadata.dev(), metadata.ino());
+        let current = std::fs::metadata(&self.path).ok().map(identity);
+        if current.is_some() && current == self.file.metadata().ok().map(identity) {
+            return;
+        }
+
+        match open_append(&self.path) {
+            Ok(file) => self.file = file,
+            Err(e) => warn!("Failed to reopen log {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+impl LogSink {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let log = OpenLog {
+            file: open_append(path)?,
+            path: path.to_path_buf(),
+            checked: Instant::now(),
+        };
+        Ok(Self {
+            log: Arc::new(Mutex::new(Destination::File(log))),
+        })
+    }
+
+    /// Send output to the syslog daemon listening on `socket`, tagged with
+    /// `identifier`, at `level`
+    pub fn syslog(socket: &Path, identifier: &str, level: SyslogLevel) -> io::Result<Self> {
+        let syslog = Syslog {
+            socket: UnixDatagram::unbound()?,
+            path: socket.to_path_buf(),
+            identifier: identifier.to_string(),
+            priority: SYSLOG_FACILITY * 8 + level.code(),
+            failed: false,
+        };
+        Ok(Self {
+            log: Arc::new(Mutex::new(Destination::Syslog(syslog))),
+        })
+    }
+
+    /// Pump the child's piped stdout and stderr into the log until they
+    /// close. Returns duplicates of the pipes' read ends, for handing them to
+    /// a re-executed daemon.
+    pub fn attach(&self, child: &mut Child) -> Vec<OwnedFd> {
+        let pid = child.id();
+        let mut pipes = Vec::new();
+        if let Some(stdout) = child.stdout.take() {
+            pipes.extend(stdout.as_fd().try_clone_to_owned().ok());
+            self.pump(stdout, pid);
+        }
+        if let Some(stderr) = child.stderr.take() {
+            pipes.extend(stderr.as_fd().try_clone_to_owned().ok());
+            self.pump(stderr, pid);
+        }
+        pipes
+    }
+
+    /// Pump a pipe of output of process `pid` taken over from the daemon
+    /// this one replaced
+    pub fn attach_pipe(&self, pipe: OwnedFd, pid: u32) {
+        self.pump(File::from(pipe), pid);
+    }
+
+    fn pump(&self, stream: impl Read + Send + 'static, pid: u32) {
+        let sink = self.clone();
+        std::thread::spawn(move || {
+            let mut reader = BufReader::new(stream);
+            let mut line = Vec::new();
+
+            loop {
+                line.clear();
+                match reader.read_until(b'\n', &mut line) {
+                    Ok(0) => break,
+                    Ok(_) => sink.write_line(&String::from_utf8_lossy(&line), pid),
+                    Err(e) => {
+                        warn!("Failed to read service output: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    fn write_line(&self, line: &str, pid: u32) {
+        let line = line.trim_end_matches(['\n', '\r']);
+
+        // Recover from poisoning: a panicked writer leaves at worst a partial line
+        let mut log = self.log.lock().unwrap_or_else(|e| e.into_inner());
+        match *log {
+            Destination::File(ref mut log) => {
+                let timestamp = humantime::format_rfc3339_millis(SystemTime::now());
+                log.reopen_if_rotated();
+                if let Err(e) = writeln!(log.file, "{} {}", timestamp, line) {
+                    warn!("Failed to write service log: {}", e);
+                }
+            }
+            Destination::Syslog(ref mut syslog) => syslog.send(pid, line),
+        }
+    }
+}
+
+/// Read the tail of a log. `since` keeps only lines written within that long
+/// ago and `lines` keeps at most that many of the most recent ones; with
+/// neither, the last [`DEFAULT_LOG_LINES`] are returned. Also returns the
+/// offset of the end of the file for following it afterwards.
+pub fn read_logs(
+    path: &Path,
+    lines: Option<usize>,
+    since: Option<Duration>,
+) -> io::Result<(Vec<String>, u64)> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e),
+    };
+
+    let mut content = Vec::new();
+    file.read_to_end(&mut content)?;
+    // Before decoding, which widens each invalid byte to a U+FFFD
+    let end = content.len() as u64;
+    let content = String::from_utf8_lossy(&content);
+
+    let cutoff = since.and_then(|since| SystemTime::now().checked_sub(since));
+    let mut selected: Vec<String> = Vec::new();
+    let mut keep = cutoff.is_none();
+
+    for line in content.lines() {
+        // Lines without a timestamp of their own follow the previous decision
+        if let Some(cutoff) = cutoff {
+            if let Some(timestamp) = line_timestamp(line) {
+                keep = timestamp >= cutoff;
+            }
+        }
+        if keep {
+            selected.push(line.to_string());
+        }
+    }
+
+    let limit = match (lines, since) {
+        (Some(lines), _) => lines,
+        (None, Some(_)) => usize::MAX,
+        (None, None) => DEFAULT_LOG_LINES,
+    };
+
+    if selected.len() > limit {
+        selected.drain(..selected.len() - limit);
+    }
+
+    Ok((selected, end))
+}
+
+/// Merge lines from several services' logs into one stream in timestamp
+/// order, each tagged with its service. Lines without a timestamp of their
+/// own stay after the line before them.
+pub fn interleave(logs: Vec<(String, Vec<String>)>) -> Vec<(String, String)> {
+    let mut tagged = Vec::new();
+    for (service, lines) in logs {
+        let mut last = SystemTime::UNIX_EPOCH;
+        for line in lines {
+            if let Some(timestamp) = line_timestamp(&line) {
+                last = timestamp;
+            }
+            tagged.push((last, service.clone(), line));
+        }
+    }
+
+    // Stable, so lines of one log keep their order
+    tagged.sort_by_key(|(timestamp, _, _)| *timestamp);
+    tagged
+        .into_iter()
+        .map(|(_, service, line)| (service, line))
+        .collect()
+}
+
+/// Read complete lines appended to a log since `offset`, up to `max_bytes`
+/// of them, advancing it. Also returns whether more remains to be read. A log
+/// that shrank (was truncated or replaced) is read again from the start.
+pub fn read_appended(
+    path: &Path,
+    offset: &mut u64,
+    max_bytes: u64,
+) -> io::Result<(Vec<String>, bool)> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), false)),
+        Err(e) => return Err(e),
+    };
+
+    let len = file.metadata()?.len();
+    if len < *offset {
+        *offset = 0;
+    }
+
+    file.seek(SeekFrom::Start(*offset))?;
+    let mut appended = Vec::new();
+    file.take(max_bytes).read_to_end(&mut appended)?;
+    let full = appended.len() as u64 == max_bytes;
+
+    // Leave a trailing partial line for the next read, unless it alone
+    // fills the chunk
+    let complete = match appended.iter().rposition(|&b| b == b'\n') {
+        Some(i) => i + 1,
+        None if full => appended.len(),
+        None => return Ok((Vec::new(), false)),
+    };
+
+    *offset += complete as u64;
+    let lines = String::from_utf8_lossy(&appended[..complete])
+        .lines()
+        .map(str::to_string)
+        .collect();
+    Ok((lines, *offset < len))
+}
+
+fn line_timestamp(line: &str) -> Option<SystemTime> {
+    let (timestamp, _) = line.split_once(' ')?;
+    humantime::parse_rfc3339(timestamp).ok()
+}
+
+/// Disk space taken by a service's log
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogUsage {
+    pub service: String,
+    /// Size of the log being written
+    pub bytes: u64,
+    /// Total size of its rotated segments (`<service>.log.1`, `.2`, ...)
+    pub rotated_bytes: u64,
+}
+
+/// A service's log or one of its rotated segments
+struct LogSegment {
+    service: String,
+    path: PathBuf,
+    /// `None` for the log being written, else its rotation number
+    number: Option<u32>,
+    bytes: u64,
+    modified: SystemTime,
+}
+
+/// Every log and rotated segment in `log_dir`
+fn log_segments(log_dir: &Path) -> io::Result<Vec<LogSegment>> {
+    let entries = match std::fs::read_dir(log_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut segments = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let Some(file_name) = file_name.to_str() else {
+            continue;
+        };
+
+        let (service, number) = match file_name.strip_suffix(".log") {
+            Some(service) => (service, None),
+            None => match file_name.rsplit_once(".log.") {
+                Some((service, number)) => match number.parse() {
+                    Ok(number) => (service, Some(number)),
+                    Err(_) => continue,
+                },
+                None => continue,
+            },
+        };
+
+        let metadata = entry.metadata()?;
+        if metadata.is_file() {
+            segments.push(LogSegment {
+                service: service.to_string(),
+                path: entry.path(),
+                number,
+                bytes: metadata.len(),
+                modified: metadata.modified()?,
+            });
+        }
+    }
+    Ok(segments)
+}
+
+/// Disk usage of each service's log in `log_dir`, by service name
+pub fn log_usage(log_dir: &Path) -> io::Result<Vec<LogUsage>> {
+    let mut usage: Vec<LogUsage> = Vec::new();
+    for segment in log_segments(log_dir)? {
+        let index = match usage.iter().position(|u| u.service == segment.service) {
+            Some(index) => index,
+            None => {
+                usage.push(LogUsage {
+                    service: segment.service.clone(),
+                    bytes: 0,
+                    rotated_bytes: 0,
+                });
+                usage.len() - 1
+            }
+        };
+        match segment.number {
+            Some(_) => usage[index].rotated_bytes += segment.bytes,
+            None => usage[index].bytes += segment.bytes,
+        }
+    }
+
+    usage.sort_by(|a, b| a.service.cmp(&b.service));
+    Ok(usage)
+}
+
+/// Rotate a log: `<path>.1` becomes `<path>.2` and so on, and the log
+/// itself becomes `<path>.1`. A [`LogSink`] writing it moves on to a new file.
+pub fn rotate(path: &Path) -> io::Result<()> {
+    let segment = |number: u32| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", number));
+        PathBuf::from(name)
+    };
+
+    let mut last = 0;
+    while segment(last + 1).exists() {
+        last += 1;
+    }
+    for number in (1..=last).rev() {
+        std::fs::rename(segment(number), segment(number + 1))?;
+    }
+    std::fs::rename(path, segment(1))
+}
+
+/// Keep the logs in `log_dir` within `max_bytes` in total: delete rotated
+/// segments, oldest first, and once there are none left, rotate the largest
+/// log so its segment can go next
+pub fn enforce_cap(log_dir: &Path, max_bytes: u64) -> io::Result<()> {
+    let mut segments = log_segments(log_dir)?;
+    let mut total: u64 = segments.iter().map(|s| s.bytes).sum();
+
+    while total > max_bytes {
+        let oldest = segments
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.number.is_some())
+            .min_by_key(|(_, s)| (s.modified, std::cmp::Reverse(s.number)))
+            .map(|(i, _)| i);
+
+        if let Some(oldest) = oldest {
+            let segment = segments.remove(oldest);
+            std::fs::remove_file(&segment.path)?;
+            total -= segment.bytes;
+            info!(
+                "Deleted log segment {} ({} bytes) to stay under the log size cap",
+                segment.path.display(),
+                segment.bytes
+            );
+            continue;
+        }
+
+        match segments.iter().filter(|s| s.bytes > 0).max_by_key(|s| s.bytes) {
+            Some(largest) => {
+                rotate(&largest.path)?;
+                info!("Rotated log {} to stay under the log size cap", largest.path.display());
+            }
+            None => break,
+        }
+        segments = log_segments(log_dir)?;
+        total = segments.iter().map(|s| s.bytes).sum();
+    }
+
+    Ok(())
+}
@@ -1,25 +1,61 @@
-mod client;
-mod daemon;
-mod error;
-mod ipc;
-mod manager;
-mod service;
-mod unit;
-
-use clap::{Parser, Subcommand};
-use client::Client;
-use daemon::{DaemonConfig, ensure_daemon_started, is_daemon_running, start_daemon};
-use ipc::{Request, Response};
+mod completions;
+mod render;
+
+use clap::{ArgGroup, Args, CommandFactory, Parser, Subcommand};
+use clap_complete::Shell;
+use diakonos::attach;
+use diakonos::client::Client;
+use diakonos::daemon::{
+    DaemonConfig, LogFormat, ensure_daemon_started, is_daemon_running, run_foreground, start_daemon,
+};
+use diakonos::doctor;
+use diakonos::ipc::{Request, Response};
+use diakonos::service::ServiceState;
+use diakonos::unit::{ServiceSection, UnitFile};
+use render::{ColorMode, DepsFormat, OutputFormat};
+use std::net::SocketAddr;
+use std::io::Write;
+use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
+use std::time::Duration;
+use tokio::signal::unix::{signal, Signal, SignalKind};
 use tracing::error;
 
 #[derive(Parser)]
 #[command(name = "diakonos")]
 #[command(about = "A PM2-like service manager", long_about = None)]
 struct Cli {
-    /// Directory containing service unit files
-    #[arg(short, long, default_value = "./services")]
-    service_dir: PathBuf,
+    /// Directory containing service unit files [default: ./services]
+    #[arg(short, long)]
+    service_dir: Option<PathBuf>,
+
+    /// Daemon config file [default: ~/.diakonos/config.toml]
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Control socket of the daemon to talk to, or start [default: ~/.diakonos/daemon.sock]
+    #[arg(long, global = true)]
+    socket: Option<PathBuf>,
+
+    /// Serve Prometheus metrics on this address (e.g. 127.0.0.1:9100)
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Suppress informational output; errors are still reported on stderr
+    #[arg(short, long, global = true)]
+    quiet: bool,
+
+    /// Output format for command results
+    #[arg(short, long, global = true, value_enum, default_value_t = OutputFormat::Table)]
+    output: OutputFormat,
+
+    /// When to color service states in tables (themed by the config file's [colors])
+    #[arg(long, global = true, value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+
+    /// Run the daemon in this process instead of in the background, e.g. as a container's entrypoint
+    #[arg(long, conflicts_with = "daemon_start")]
+    foreground: bool,
 
     /// Start in daemon mode (internal use only)
     #[arg(long, hide = true)]
@@ -29,48 +65,259 @@ struct Cli {
     command: Option<Commands>,
 }
 
+#[derive(Args)]
+struct WaitArgs {
+    /// Return only once the service is up (start, restart) or its process has exited (stop)
+    #[arg(long)]
+    wait: bool,
+
+    /// How long --wait may take before the command fails (e.g. 30s, 2m)
+    #[arg(long, requires = "wait", value_parser = humantime::parse_duration, default_value = "60s")]
+    timeout: Duration,
+}
+
+impl WaitArgs {
+    fn timeout(&self) -> Option<Duration> {
+        self.wait.then_some(self.timeout)
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Start a service
     Start {
-        /// Name of the service to start
+        /// Name of the service to start, or a glob such as 'worker-*'
         service: String,
+        /// Set or override an environment variable for this run (repeatable)
+        #[arg(long, value_name = "KEY=VALUE", value_parser = parse_env)]
+        env: Vec<String>,
+        /// Run the service in the foreground on this terminal, without the daemon
+        #[arg(long, conflicts_with = "wait")]
+        attach: bool,
+        /// Follow the service's output once it's started, until Ctrl-C (which leaves it running)
+        #[arg(long, conflicts_with = "attach")]
+        tail: bool,
+        /// Don't restart the service if it exits, until it's next started or restarted without this
+        #[arg(long, conflicts_with = "attach")]
+        no_supervise: bool,
+        #[command(flatten)]
+        wait: WaitArgs,
     },
     /// Stop a service
     Stop {
-        /// Name of the service to stop
+        /// Name of the service to stop, or a glob such as 'worker-*'
         service: String,
+        /// SIGKILL the service's process group at once, skipping ExecStop and the grace period
+        #[arg(long)]
+        force: bool,
+        #[command(flatten)]
+        wait: WaitArgs,
     },
     /// Restart a service
     Restart {
-        /// Name of the service to restart
+        /// Name of the service to restart, or a glob such as 'worker-*'
         service: String,
+        /// Don't restart the service if it exits, until it's next started or restarted without this
+        #[arg(long)]
+        no_supervise: bool,
+        #[command(flatten)]
+        wait: WaitArgs,
     },
     /// Show status of a service
     Status {
         /// Name of the service to check
         service: String,
+        /// Redraw the state, PID, uptime and restart count every second until interrupted
+        #[arg(long)]
+        watch: bool,
+    },
+    /// Wait until a service is in a state, exiting 124 if it isn't in time
+    WaitFor {
+        /// Name of the service, or a target whose members must all be in the state
+        service: String,
+        /// State to wait for: stopped, starting, running, stopping, or failed
+        #[arg(long)]
+        state: ServiceState,
+        /// How long to wait (e.g. 30s, 2m)
+        #[arg(long, value_parser = humantime::parse_duration, default_value = "60s")]
+        timeout: Duration,
+    },
+    /// Print the PID of a service's main process, failing if it isn't running
+    Pid {
+        /// Name of the service
+        service: String,
+    },
+    /// Show a service's configuration and runtime details (secrets are redacted)
+    #[command(visible_alias = "show")]
+    Describe {
+        /// Name of the service to describe
+        service: String,
+    },
+    /// Send a signal to a service's main process
+    Signal {
+        /// Name of the service to signal
+        service: String,
+        /// Signal name or number (e.g. SIGHUP, USR1, 15)
+        signal: String,
+    },
+    /// Show captured output of one or more services
+    Logs {
+        /// Names of the services; the output of several is interleaved by time
+        #[arg(required = true)]
+        services: Vec<String>,
+        /// Show at most this many of the most recent lines (default 100 without --since)
+        #[arg(short = 'n', long)]
+        lines: Option<usize>,
+        /// Only show output from this long ago onwards (e.g. 30s, 10m, 1h 30m)
+        #[arg(long, value_parser = humantime::parse_duration)]
+        since: Option<Duration>,
+        /// Keep printing output as the service writes it
+        #[arg(short, long)]
+        follow: bool,
+    },
+    /// Show when services changed state, and why, as recorded by the daemon
+    History {
+        /// Name of the service [default: every service]
+        service: Option<String>,
+        /// Only show transitions from this long ago onwards (e.g. 30s, 10m, 1h 30m)
+        #[arg(long, value_parser = humantime::parse_duration)]
+        since: Option<Duration>,
     },
     /// List all services
     List,
+    /// Show PID, restart count and uptime of every service
+    Stats,
+    /// One compact row per service: state, PID, uptime, restarts, CPU and memory
+    Ps,
+    /// Show the services a service depends on, in start order, or graph
+    /// the dependencies of every service
+    Deps {
+        /// Name of the service (or target)
+        service: Option<String>,
+        /// `dot` prints every service's `Requires`, `Wants` and `After` as a
+        /// Graphviz graph, for `dot -Tpng`
+        #[arg(long, value_enum, default_value_t = DepsFormat::List)]
+        format: DepsFormat,
+    },
+    /// Report dependencies on units that aren't loaded, exiting non-zero if there are any
+    Diagnose,
+    /// Read or change a setting of a service's unit, such as Restart
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+    /// Re-read unit files from the service directory
+    Reload {
+        /// Only report which units would be added, removed or changed
+        #[arg(long)]
+        dry_run: bool,
+        /// Apply changed units of running services when they next restart,
+        /// without restarting them now
+        #[arg(long)]
+        soft: bool,
+    },
+    /// Reload the service directory, then start, stop and restart services to match it
+    Apply,
+    /// Run a service that has no unit file in the service directory, until it stops
+    #[command(group = ArgGroup::new("source").required(true))]
+    Run {
+        /// Unit file to run, or `-` to read it from stdin
+        #[arg(group = "source")]
+        unit: Option<PathBuf>,
+        /// Command to run as a simple service instead of a unit file
+        #[arg(long, group = "source")]
+        exec: Option<String>,
+        /// Name of the service [default: the unit file's name, or run-<pid>]
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// Stop the supervisor restarting services, e.g. during a rollout
+    Freeze,
+    /// Let the supervisor restart services again after `freeze`
+    Unfreeze,
+    /// Show how much disk space each service's log takes
+    LogUsage,
     /// Show daemon status
     DaemonStatus,
+    /// Check the daemon, its files and every unit file, with hints for what's wrong
+    Doctor,
     /// Kill the daemon (stops all services)
     Kill,
+    /// Print a shell completion script
+    #[command(hide = true)]
+    Completions {
+        /// Shell to generate completions for
+        shell: Shell,
+    },
+    /// List service names for shell completion
+    #[command(name = completions::COMPLETE_SERVICES_COMMAND, hide = true)]
+    CompleteServices,
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Print the value of a setting in effect
+    Get {
+        /// Name of the service
+        service: String,
+        /// Setting such as Restart, or service.Restart naming its section
+        key: String,
+    },
+    /// Set a setting in the service's override drop-in
+    /// (<service-dir>/<service>.service.d/override.toml) and reload
+    Set {
+        /// Name of the service
+        service: String,
+        /// Setting and value, such as Restart=always or RestartSec=5min
+        #[arg(value_name = "KEY=VALUE", value_parser = parse_env)]
+        setting: String,
+    },
 }
 
 fn main() {
-    // Initialize tracing
-    tracing_subscriber::fmt()
+    let cli = Cli::parse();
+
+    // Load the config file, then let CLI flags override it
+    let mut config = match DaemonConfig::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Failed to load config: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if let Some(ref service_dir) = cli.service_dir {
+        config.service_dir = service_dir.clone();
+    }
+
+    if let Some(ref socket) = cli.socket {
+        config.set_socket_path(socket.clone());
+    }
+
+    if let Some(addr) = cli.metrics_addr {
+        config.metrics_addr = Some(addr);
+    }
+
+    // Initialize tracing. Diagnostics go to stderr so stdout carries only
+    // command output; the daemon redirects both to its log file.
+    let max_level = if cli.quiet && !cli.daemon_start && !cli.foreground {
+        tracing::Level::WARN
+    } else {
+        tracing::Level::INFO
+    };
+
+    let subscriber = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(max_level)
         .with_target(false)
         .with_thread_ids(false)
-        .with_level(true)
-        .init();
+        .with_level(true);
 
-    let cli = Cli::parse();
-
-    let mut config = DaemonConfig::default();
-    config.service_dir = cli.service_dir.clone();
+    match config.log_format {
+        LogFormat::Full => subscriber.init(),
+        LogFormat::Compact => subscriber.compact().init(),
+        LogFormat::Json => subscriber.json().init(),
+    }
 
     // Create service directory if it doesn't exist
     if !config.service_dir.exists() {
@@ -80,6 +327,9 @@ fn main() {
         }
     }
 
+    // Each service directory gets its own daemon unless told otherwise
+    config.namespace_by_service_dir();
+
     // Handle daemon start (internal) - use sync code path
     if cli.daemon_start {
         if let Err(e) = start_daemon(config) {
@@ -89,39 +339,122 @@ fn main() {
         return;
     }
 
+    if cli.foreground {
+        if cli.command.is_some() {
+            eprintln!("✗ Error: --foreground runs the daemon, so takes no command");
+            std::process::exit(1);
+        }
+        if let Err(e) = run_foreground(config) {
+            error!("Failed to run daemon: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Run client code with tokio runtime
     run_client(cli, config);
 }
 
 #[tokio::main]
 async fn run_client(cli: Cli, config: DaemonConfig) {
+    let quiet = cli.quiet;
+    let output = cli.output;
+    render::set_colors(cli.color, config.colors.clone());
 
     // Handle commands
     let command = cli.command.unwrap_or(Commands::List);
 
     match command {
-        Commands::DaemonStatus => {
+        Commands::Completions { shell } => {
+            completions::print_completions(shell, &mut Cli::command());
+            return;
+        }
+
+        Commands::CompleteServices => {
+            // Completion must stay fast and side-effect free, so never start the daemon
             if is_daemon_running(&config) {
-                println!("✓ Daemon is running");
-                println!("  Socket: {:?}", config.socket_path);
-                println!("  PID file: {:?}", config.pid_file);
-            } else {
-                println!("✗ Daemon is not running");
+                if let Ok(Response::List { services }) = Client::new(config).list().await {
+                    for (name, _) in services {
+                        println!("{}", name);
+                    }
+                }
             }
             return;
         }
 
+        Commands::DaemonStatus => {
+            // With --quiet only the exit code reports whether the daemon runs
+            if !is_daemon_running(&config) {
+                if !quiet {
+                    println!("✗ Daemon is not running");
+                }
+                std::process::exit(1);
+            }
+            if quiet {
+                return;
+            }
+
+            let socket_path = config.socket_path.clone();
+            let pid_file = config.pid_file.clone();
+            match Client::new(config).send_request(Request::DaemonInfo).await {
+                Ok(Response::DaemonInfo { info }) => {
+                    render::daemon_info(&info, &socket_path, &pid_file, output)
+                }
+                // The process is alive but not answering (or predates DaemonInfo)
+                Ok(_) | Err(_) => {
+                    println!("✓ Daemon is running (not responding to status requests)");
+                    println!("  Socket: {:?}", socket_path);
+                    println!("  PID file: {:?}", pid_file);
+                }
+            }
+            return;
+        }
+
+        Commands::Doctor => {
+            // Inspects the daemon rather than starting it
+            render::checks(&doctor::run_checks(&config).await, output, quiet);
+            return;
+        }
+
+        Commands::Start {
+            service,
+            env,
+            attach: true,
+            ..
+        } => {
+            // Skips the daemon entirely, so the service is neither supervised nor logged
+            match attach::run_attached(&config, &service, env).await {
+                // A service killed by a signal exits like it would in a shell
+                Ok(status) => {
+                    let code = status.code().or(status.signal().map(|signal| 128 + signal));
+                    std::process::exit(code.unwrap_or(1));
+                }
+                Err(e) => {
+                    eprintln!("✗ Error: Failed to run service '{}': {}", service, e);
+                    std::process::exit(1);
+                }
+            }
+        }
+
         Commands::Kill => {
             if !is_daemon_running(&config) {
-                println!("Daemon is not running");
+                if !quiet {
+                    println!("Daemon is not running");
+                }
                 return;
             }
 
-            println!("Killing daemon...");
+            if !quiet {
+                println!("Killing daemon...");
+            }
             let client = Client::new(config);
 
             match client.send_request(Request::Shutdown).await {
-                Ok(_) => println!("✓ Daemon killed"),
+                Ok(_) => {
+                    if !quiet {
+                        println!("✓ Daemon killed");
+                    }
+                }
                 Err(e) => {
                     eprintln!("Failed to kill daemon: {}", e);
                     std::process::exit(1);
@@ -142,17 +475,175 @@ async fn run_client(cli: Cli, config: DaemonConfig) {
     // Create client and send request
     let client = Client::new(config);
 
+    // A daemon left running across an upgrade still runs the old code
+    if std::env::var_os(NO_VERSION_CHECK_ENV).is_none_or(|v| v.is_empty()) {
+        warn_on_version_mismatch(&client).await;
+    }
+
     let request = match command {
-        Commands::Start { service } => Request::Start { service },
-        Commands::Stop { service } => Request::Stop { service },
-        Commands::Restart { service } => Request::Restart { service },
-        Commands::Status { service } => Request::Status { service },
-        Commands::List => Request::List,
+        Commands::Start {
+            service,
+            env,
+            wait,
+            tail: true,
+            no_supervise,
+            ..
+        } => {
+            let request = Request::Start {
+                service: service.clone(),
+                wait: wait.timeout(),
+                env,
+                no_supervise,
+            };
+            start_and_tail(&client, request, service, output, quiet).await;
+            return;
+        }
+        Commands::Start {
+            service,
+            env,
+            wait,
+            no_supervise,
+            ..
+        } => Request::Start {
+            service,
+            wait: wait.timeout(),
+            env,
+            no_supervise,
+        },
+        Commands::Stop {
+            service,
+            force,
+            wait,
+        } => Request::Stop {
+            service,
+            wait: wait.timeout(),
+            force,
+        },
+        Commands::Restart {
+            service,
+            no_supervise,
+            wait,
+        } => Request::Restart {
+            service,
+            wait: wait.timeout(),
+            no_supervise,
+        },
+        Commands::Status { service, watch: false } => Request::Status { service },
+        Commands::Status { service, watch: true } => {
+            watch_status(&client, service, output).await;
+            return;
+        }
+        Commands::WaitFor {
+            service,
+            state,
+            timeout,
+        } => Request::WaitFor {
+            service,
+            target_state: state,
+            timeout,
+        },
+        Commands::Pid { service } => Request::Pid { service },
+        Commands::Describe { service } => Request::Describe { service },
+        Commands::Signal { service, signal } => Request::Signal { service, signal },
+        Commands::List => {
+            match client.list().await {
+                Ok(response) => render::print_response(response, output, quiet),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Commands::Stats => Request::Stats,
+        Commands::Deps { service, format } => match (service, format) {
+            (Some(service), DepsFormat::List) => Request::Deps { service },
+            (None, DepsFormat::Dot) => Request::DependencyGraph,
+            (service, _) => {
+                let problem = match service {
+                    Some(_) => "--format dot graphs every service, so takes no service name",
+                    None => "deps needs a service name, or --format dot to graph every service",
+                };
+                eprintln!("✗ Error: {}", problem);
+                std::process::exit(1);
+            }
+        },
+        Commands::Ps => {
+            // The stats, with CPU and memory read from /proc here
+            match client.send_request(Request::Stats).await {
+                Ok(Response::Stats { stats }) => render::ps(&stats, output),
+                Ok(response) => render::print_response(response, output, quiet),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Commands::Diagnose => Request::Diagnostics,
+        Commands::Config {
+            action: ConfigAction::Get { service, key },
+        } => Request::GetConfig { service, key },
+        Commands::Config {
+            action: ConfigAction::Set { service, setting },
+        } => {
+            let (key, value) = setting.split_once('=').expect("checked by parse_env");
+            Request::SetConfig {
+                service,
+                key: key.to_string(),
+                value: value.to_string(),
+            }
+        }
+        Commands::Reload { dry_run, soft } => Request::Reload { dry_run, soft },
+        Commands::Apply => Request::Apply,
+        Commands::Freeze => Request::Freeze,
+        Commands::Unfreeze => Request::Unfreeze,
+        Commands::History { service, since } => Request::History { service, since },
+        Commands::LogUsage => Request::LogUsage,
+        Commands::Run { unit, exec, name } => match transient_unit(unit, exec, name) {
+            Ok((name, unit)) => Request::RunTransient {
+                name,
+                unit: Box::new(unit),
+            },
+            Err(e) => {
+                eprintln!("✗ Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        Commands::Logs {
+            services,
+            lines,
+            since,
+            follow,
+        } => {
+            let request = Request::Logs {
+                services,
+                lines,
+                since,
+                follow,
+            };
+            if !follow {
+                request
+            } else {
+                // The daemon keeps sending output until we're interrupted
+                let result = client
+                    .send_streaming_request(request, |response| render::print_response(response, output, quiet))
+                    .await;
+                if let Err(e) = result {
+                    eprintln!("Error: {}", e);
+                    std::process::exit(1);
+                }
+                return;
+            }
+        }
         _ => unreachable!(),
     };
 
-    match client.send_request(request).await {
-        Ok(response) => handle_response(response),
+    match client
+        .send_request_with_progress(request, |message| render::progress(&message, quiet))
+        .await
+    {
+        Ok(response) => render::print_response(response, output, quiet),
         Err(e) => {
             eprintln!("Error: {}", e);
             std::process::exit(1);
@@ -160,40 +651,195 @@ async fn run_client(cli: Cli, config: DaemonConfig) {
     }
 }
 
-fn handle_response(response: Response) {
-    match response {
-        Response::Ok { message } => {
-            println!("✓ {}", message);
+/// Set (to anything non-empty) to skip comparing the client's version with the daemon's
+const NO_VERSION_CHECK_ENV: &str = "DIAKONOS_NO_VERSION_CHECK";
+
+/// Warn on stderr if the daemon runs a different version of diakonos
+async fn warn_on_version_mismatch(client: &Client) {
+    let version = env!("CARGO_PKG_VERSION");
+    if let Ok(Response::DaemonInfo { info }) = client.send_request(Request::DaemonInfo).await {
+        if info.version != version {
+            let command: Vec<String> = std::env::args().collect();
+            eprintln!(
+                "⚠ Warning: the daemon runs diakonos {} but this is {}; restart it with `diakonos kill && {}` (set {}=1 to silence this)",
+                info.version,
+                version,
+                command.join(" "),
+                NO_VERSION_CHECK_ENV
+            );
         }
-        Response::Error { message } => {
-            eprintln!("✗ Error: {}", message);
-            std::process::exit(1);
+    }
+}
+
+/// Keep a line showing the service's status, redrawn in place every second
+/// until Ctrl-C
+async fn watch_status(client: &Client, service: String, output: OutputFormat) {
+    let mut interrupts = interrupts();
+    // Whether a status line is showing, to be ended before anything else
+    let mut drawn = false;
+    let end_line = |drawn: bool| {
+        if drawn {
+            println!();
         }
-        Response::Status { service, state } => {
-            println!("Service '{}' status: {:?}", service, state);
+    };
+
+    loop {
+        match client.send_request(Request::Describe { service: service.clone() }).await {
+            Ok(Response::Describe { description }) => {
+                print!("\r\x1b[2K{}", render::status_line(&description));
+                let _ = std::io::stdout().flush();
+                drawn = true;
+            }
+            // An error, such as the service having been removed, ends the watch
+            Ok(response) => {
+                end_line(drawn);
+                render::print_response(response, output, false);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                end_line(drawn);
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
         }
-        Response::List { services } => {
-            if services.is_empty() {
-                println!("No services loaded");
-            } else {
-                println!("\nLoaded services:");
-                println!("{:<30} {:<15}", "SERVICE", "STATE");
-                println!("{}", "-".repeat(45));
-
-                for (name, state) in services {
-                    let state_str = format!("{:?}", state);
-                    let colored_state = match state {
-                        service::ServiceState::Running => format!("\x1b[32m{}\x1b[0m", state_str),
-                        service::ServiceState::Failed => format!("\x1b[31m{}\x1b[0m", state_str),
-                        service::ServiceState::Stopped => format!("\x1b[90m{}\x1b[0m", state_str),
-                        _ => state_str,
-                    };
-                    println!("{:<30} {:<15}", name, colored_state);
-                }
+
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(1)) => {}
+            _ = interrupts.recv() => {
+                end_line(drawn);
+                return;
+            }
+        }
+    }
+}
+
+/// Follow a service's output from the end of its log, then start it, so
+/// all of the new run's output is shown and none of an earlier run's. The
+/// output is printed after the start's result, and until Ctrl-C, which
+/// leaves the service running.
+async fn start_and_tail(client: &Client, request: Request, service: String, output: OutputFormat, quiet: bool) {
+    if service.contains(['*', '?', '[']) {
+        eprintln!("✗ Error: --tail follows one service, so takes a name rather than a pattern like '{}'", service);
+        std::process::exit(1);
+    }
+
+    let mut interrupts = interrupts();
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let logs = Request::Logs {
+        services: vec![service],
+        lines: Some(0),
+        since: None,
+        follow: true,
+    };
+    let follow = client.send_streaming_request(logs, move |response| {
+        let _ = sender.send(response);
+    });
+
+    let start = async {
+        // The first response means the daemon is reading from the end of
+        // the log; it holds no lines, or an error
+        match receiver.recv().await {
+            Some(response @ Response::Error { .. }) => render::print_response(response, output, quiet),
+            Some(_) => {}
+            None => return,
+        }
+
+        match client
+            .send_request_with_progress(request, |message| render::progress(&message, quiet))
+            .await
+        {
+            // Exits on a failed start
+            Ok(response) => render::print_response(response, output, quiet),
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        }
+
+        loop {
+            tokio::select! {
+                response = receiver.recv() => match response {
+                    Some(response) => render::print_response(response, output, quiet),
+                    None => return,
+                },
+                _ = interrupts.recv() => return,
             }
         }
-        Response::Pong => {
-            println!("Daemon is alive");
+    };
+
+    tokio::pin!(start);
+    tokio::select! {
+        result = follow => match result {
+            // The daemon ended the stream, after an error, say; print what it sent
+            Ok(()) => start.await,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ = &mut start => {}
+    }
+}
+
+/// Ctrl-C, for commands that run until it. Listening from the start, so
+/// that one pressed while the command is busy isn't missed.
+fn interrupts() -> Signal {
+    match signal(SignalKind::interrupt()) {
+        Ok(interrupts) => interrupts,
+        Err(e) => {
+            eprintln!("Error: Failed to listen for Ctrl-C: {}", e);
+            std::process::exit(1);
         }
     }
 }
+
+/// Accept a `KEY=VALUE` environment assignment with a non-empty key
+fn parse_env(value: &str) -> Result<String, String> {
+    match value.split_once('=') {
+        Some((key, _)) if !key.is_empty() => Ok(value.to_string()),
+        _ => Err("expected KEY=VALUE".to_string()),
+    }
+}
+
+/// The name and unit for `run`: from a unit file, stdin (`-`), or `--exec`
+fn transient_unit(
+    path: Option<PathBuf>,
+    exec: Option<String>,
+    name: Option<String>,
+) -> Result<(String, UnitFile), String> {
+    let default_name = || format!("run-{}", std::process::id());
+
+    if let Some(command) = exec {
+        let unit = UnitFile {
+            unit: Default::default(),
+            service: ServiceSection {
+                exec_start: vec![command],
+                ..Default::default()
+            },
+            timer: None,
+            install: None,
+            name: String::new(),
+            source_path: None,
+        };
+        return Ok((name.unwrap_or_else(default_name), unit));
+    }
+
+    let path = path.ok_or("a unit file or --exec is required")?;
+    let content = if path.as_os_str() == "-" {
+        std::io::read_to_string(std::io::stdin()).map_err(|e| format!("reading stdin: {}", e))?
+    } else {
+        std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e))?
+    };
+    let origin = if path.as_os_str() == "-" {
+        "<stdin>".to_string()
+    } else {
+        path.display().to_string()
+    };
+    let unit = UnitFile::parse(&content, &origin).map_err(|e| e.to_string())?;
+
+    let name = name.unwrap_or_else(|| match path.file_stem().and_then(|s| s.to_str()) {
+        Some(stem) if path.as_os_str() != "-" => stem.to_string(),
+        _ => default_name(),
+    });
+    Ok((name, unit))
+}
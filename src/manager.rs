@@ -1,146 +1,1606 @@
+use crate::daemon::DaemonConfig;
 use crate::error::{DiakonosError, Result};
-use crate::service::{Service, ServiceState};
-use crate::unit::UnitFile;
+use crate::history::History;
+use crate::ipc::DaemonInfo;
+use crate::logs::LogUsage;
+use crate::reexec::AdoptedService;
+use crate::service::{parse_signal, Service, ServiceDescription, ServiceState, ServiceStats};
+use crate::unit::{validate_service_name, UnitFile, TARGET_SUFFIX};
+use crate::webhook::{StateChange, Webhook};
+use globset::Glob;
+use serde::{Deserialize, Serialize};
 use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+use tokio::sync::{Notify, OwnedSemaphorePermit, RwLock, RwLockWriteGuard, Semaphore};
+use tokio::task::AbortHandle;
 use tokio::time::{sleep, Duration};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
+/// Repeats of the same state transition within this window are coalesced
+/// into a single "flapping" log line instead of being logged one by one
+const FLAP_WINDOW: Duration = Duration::from_secs(60);
+
+/// How often a starting service is checked for readiness: a oneshot for
+/// completion, or the socket of one with `ReadyWhen`
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a `ReadyWhen` connection attempt may take before it counts as refused
+const READY_PROBE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long the supervisor waits for a pass's process checks before leaving
+/// services whose check hasn't finished for the next pass, so one stuck
+/// service can't stall the rest
+const STATUS_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often timers are checked for being due
+const TIMER_TICK: Duration = Duration::from_secs(1);
+
+/// How soon the supervisor checks again after being woken by an action, to
+/// catch a process that dies shortly after starting
+const WAKE_RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the logs are checked against `max_log_bytes`
+const LOG_CAP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How often `--wait` checks whether a service reached the requested state
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Where a long operation reports how it is getting on, one message at a time
+pub type Progress = tokio::sync::mpsc::UnboundedSender<String>;
+
+/// Owns the loaded services and everything done to them: loading unit files,
+/// starting and stopping in dependency order, and supervising processes.
+/// Methods take `&self`, so one manager is shared (in an `Arc`) between the
+/// supervisor and whoever issues commands.
 pub struct ServiceManager {
     services: Arc<RwLock<HashMap<String, Service>>>,
     service_dir: PathBuf,
+    supervision_interval: Duration,
+    default_environment: Vec<String>,
+    log_dir: PathBuf,
+    syslog_socket: PathBuf,
+    default_target: String,
+    implicit_after_requires: bool,
+    vars_file: Option<PathBuf>,
+    max_log_bytes: Option<u64>,
+    started_at: Instant,
+    /// Supervisor restarts waiting out their `RestartSec`, so a manual
+    /// action on the service can cancel them
+    pending_restarts: PendingRestarts,
+    webhook: Option<Webhook>,
+    /// Where state transitions are recorded, if anywhere; see
+    /// [`keep_history`](Self::keep_history)
+    history: Option<History>,
+    /// Start orders from `resolve_dependencies`. They only depend on the
+    /// loaded units, so the cache is cleared whenever those change, under
+    /// the `services` write lock.
+    dependency_orders: Mutex<HashMap<String, Vec<String>>>,
+    /// Set by `freeze`: the supervisor restarts nothing until `unfreeze`
+    restarts_frozen: Arc<AtomicBool>,
+    /// Wakes the supervisor ahead of its next pass, after an action that
+    /// changed a service's process
+    supervisor_wake: Notify,
+    /// Wakes the file watcher when the units change, so it watches their
+    /// current `WatchPaths`
+    watch_wake: Notify,
+    /// One permit per start in progress, from launching a service until it
+    /// is ready, up to `max_concurrent_starts`
+    start_permits: Arc<Semaphore>,
+    /// Control connections being served; see [`connection_opened`](Self::connection_opened)
+    open_connections: Arc<AtomicUsize>,
+}
+
+/// A control connection counted by [`ServiceManager::connection_opened`]
+pub struct OpenConnection(Arc<AtomicUsize>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
 }
 
-impl ServiceManager {
-    pub fn new(service_dir: PathBuf) -> Self {
-        Self {
-            services: Arc::new(RwLock::new(HashMap::new())),
-            service_dir,
-        }
+impl ServiceManager {
+    /// Create a manager for the units in `config.service_dir`. No units are
+    /// loaded until [`load_all_services`](Self::load_all_services) is called.
+    pub fn new(config: &DaemonConfig) -> Self {
+        Self {
+            services: Arc::new(RwLock::new(HashMap::new())),
+            service_dir: config.service_dir.clone(),
+            supervision_interval: Duration::from_secs(config.supervision_interval.max(1)),
+            default_environment: config.environment.clone(),
+            log_dir: config.log_dir.clone(),
+            syslog_socket: config.syslog_socket.clone(),
+            default_target: config.default_target.clone(),
+            implicit_after_requires: config.implicit_after_requires,
+            vars_file: config.vars_file.clone(),
+            max_log_bytes: config.max_log_bytes,
+            started_at: Instant::now(),
+            pending_restarts: PendingRestarts::default(),
+            webhook: config.webhook_url.clone().map(Webhook::new),
+            history: None,
+            dependency_orders: Mutex::new(HashMap::new()),
+            restarts_frozen: Arc::new(AtomicBool::new(false)),
+            supervisor_wake: Notify::new(),
+            watch_wake: Notify::new(),
+            start_permits: Arc::new(Semaphore::new(config.max_concurrent_starts.max(1))),
+            open_connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    fn unit_path(&self, name: &str) -> PathBuf {
+        self.service_dir.join(format!("{}.service", name))
+    }
+
+    fn log_path(&self, name: &str) -> PathBuf {
+        self.log_dir.join(format!("{}.log", name))
+    }
+
+    /// Have the supervisor check on services now rather than at its next
+    /// pass, so state seen right after an action is current
+    fn wake_supervisor(&self) {
+        self.supervisor_wake.notify_one();
+    }
+
+    /// Send `change` to the webhook, if one is configured
+    fn notify(&self, change: StateChange) {
+        if let Some(ref webhook) = self.webhook {
+            webhook.notify(change);
+        }
+    }
+
+    /// Record every state transition the supervisor sees, and each restart
+    /// it makes, in `history`
+    pub fn keep_history(&mut self, history: History) {
+        self.history = Some(history);
+    }
+
+    /// Recorded transitions, oldest first, of `service` or of every service,
+    /// from the last `since` or from as far back as they are kept. `None` if
+    /// no history is kept.
+    pub fn history(&self, service: Option<&str>, since: Option<Duration>) -> Option<Vec<StateChange>> {
+        self.history.as_ref().map(|history| history.query(service, since))
+    }
+
+    fn record_transition(&self, change: StateChange) {
+        if let Some(ref history) = self.history {
+            history.record(change);
+        }
+    }
+
+    /// The variables units may refer to as `${NAME}`, read afresh so edits
+    /// apply on the next load or reload
+    pub(crate) fn read_vars(&self) -> Result<HashMap<String, String>> {
+        match self.vars_file {
+            Some(ref path) => crate::unit::read_vars_file(path),
+            None => Ok(HashMap::new()),
+        }
+    }
+
+    fn new_service(&self, unit: UnitFile) -> Service {
+        let mut service = Service::new(unit);
+        service.default_environment = self.default_environment.clone();
+        service.log_file = Some(self.log_path(&service.unit.name));
+        service.syslog_socket = self.syslog_socket.clone();
+        service
+    }
+
+    /// Gather the running services for a re-exec and pass them to `exec`
+    /// with the services lock held, so that none starts or stops before the
+    /// exec replaces the daemon
+    pub async fn hand_off<T>(&self, exec: impl FnOnce(Vec<AdoptedService>, bool) -> T) -> T {
+        let services = self.services.write().await;
+        let handed_off = services.values().filter_map(Service::hand_off).collect();
+        exec(handed_off, self.restarts_frozen.load(Ordering::Relaxed))
+    }
+
+    /// Take over the services handed off by the daemon this one replaced.
+    /// Each keeps the unit it was started with, even if its file changed.
+    pub async fn adopt(self: &Arc<Self>, handed_off: Vec<AdoptedService>, restarts_frozen: bool) {
+        self.restarts_frozen.store(restarts_frozen, Ordering::Relaxed);
+
+        let mut services = self.services.write().await;
+        for adopted in handed_off {
+            let mut unit = adopted.unit.clone();
+            unit.name = adopted.name.clone();
+            unit.source_path = adopted.source_path.clone();
+
+            let name = unit.name.clone();
+            let mut service = self.new_service(unit);
+            service.adopt(adopted);
+
+            // The old daemon was waiting for its socket; carry on doing so
+            if service.state == ServiceState::Starting && !service.unit.is_oneshot() {
+                let manager = Arc::clone(self);
+                let name = name.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = manager.wait_until_ready(&name).await {
+                        error!("Failed to start service {}: {}", name, e);
+                    }
+                });
+            }
+            services.insert(name, service);
+        }
+        self.units_changed();
+    }
+
+    pub async fn load_service(&self, name: &str) -> Result<()> {
+        validate_service_name(name)?;
+        let path = self.unit_path(name);
+
+        if !path.exists() {
+            return Err(DiakonosError::ServiceNotFound(name.to_string()));
+        }
+
+        let unit = UnitFile::from_file_with_vars(&path, &self.read_vars()?)?;
+        let service = self.new_service(unit);
+
+        let mut services = self.services.write().await;
+        if services.contains_key(name) {
+            return Err(DiakonosError::ServiceAlreadyExists(name.to_string()));
+        }
+        self.check_aliases(&service.unit, &services)?;
+
+        services.insert(name.to_string(), service);
+        self.units_changed();
+        info!("Loaded service: {}", name);
+        Ok(())
+    }
+
+    /// Make sure none of the `Alias` names of `unit` could mean another
+    /// service: one loaded or with a unit file by that name, or one that has
+    /// it as an alias too
+    fn check_aliases(&self, unit: &UnitFile, services: &HashMap<String, Service>) -> Result<()> {
+        for alias in unit.unit.alias.iter().flatten() {
+            validate_service_name(alias)?;
+
+            let taken_by = if services.contains_key(alias) || self.unit_path(alias).exists() {
+                Some(format!("the name of service '{}'", alias))
+            } else {
+                services
+                    .values()
+                    .find(|other| other.unit.name != unit.name && other.unit.has_alias(alias))
+                    .map(|other| format!("an alias of '{}'", other.unit.name))
+            };
+            if let Some(taken_by) = taken_by {
+                return Err(DiakonosError::ServiceAlreadyExists(format!(
+                    "alias '{}' of '{}' is already {}",
+                    alias, unit.name, taken_by
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The service `name` refers to: the one of that name, or the one with it
+    /// as an `Alias`. A name that is neither is returned as it is.
+    pub async fn canonical_name(&self, name: &str) -> String {
+        resolve_alias(name, &*self.services.read().await).to_string()
+    }
+
+    /// Load every `.service` file in the service directory. Units that fail
+    /// to parse are logged and skipped; an unreadable vars file fails the load.
+    pub async fn load_all_services(&self) -> Result<()> {
+        self.read_vars()?;
+        for name in self.scan_service_dir()? {
+            if let Err(e) = self.load_service(&name).await {
+                warn!("Failed to load service {}: {}", name, e);
+            }
+        }
+
+        self.warn_about_dependencies().await;
+        Ok(())
+    }
+
+    /// Names of the unit files in the service directory. Failures are returned
+    /// rather than acted on, so callers keep their in-memory services (and the
+    /// processes behind them) through transient directory problems. A missing
+    /// directory is re-created but still reported as a failed scan, since an
+    /// empty directory would otherwise look like every unit was removed.
+    pub(crate) fn scan_service_dir(&self) -> Result<Vec<String>> {
+        if !self.service_dir.exists() {
+            warn!(
+                "Service directory {:?} is missing, re-creating it",
+                self.service_dir
+            );
+            std::fs::create_dir_all(&self.service_dir)?;
+            return Err(DiakonosError::IoError(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("service directory {:?} was missing", self.service_dir),
+            )));
+        }
+
+        let mut names = Vec::new();
+
+        for entry in std::fs::read_dir(&self.service_dir)? {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    warn!("Failed to read entry in {:?}: {}", self.service_dir, e);
+                    continue;
+                }
+            };
+
+            if path.extension().and_then(|s| s.to_str()) == Some("service") {
+                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
+                    names.push(name.to_string());
+                }
+            }
+        }
+
+        names.sort();
+        Ok(names)
+    }
+
+    /// Re-scan the service directory, adding new units, dropping removed ones
+    /// and updating changed ones. Active services are left alone: a changed
+    /// or removed unit of a running service is reported, not applied.
+    pub async fn reload_all(&self) -> Result<ReloadSummary> {
+        self.reload(true, false).await
+    }
+
+    /// Reload like [`reload_all`](Self::reload_all), but stage the changed
+    /// unit of an active service to replace its running one when it next
+    /// starts, so it needn't be restarted now
+    pub async fn soft_reload_all(&self) -> Result<ReloadSummary> {
+        self.reload(true, true).await
+    }
+
+    /// Report what a reload would do, including which units are unchanged,
+    /// without applying any of it
+    pub async fn preview_reload(&self, soft: bool) -> Result<ReloadSummary> {
+        self.reload(false, soft).await
+    }
+
+    async fn reload(&self, apply: bool, soft: bool) -> Result<ReloadSummary> {
+        let names = self.scan_service_dir()?;
+        let vars = self.read_vars()?;
+        let mut summary = ReloadSummary {
+            dry_run: !apply,
+            ..ReloadSummary::default()
+        };
+        let mut units = Vec::new();
+
+        for name in &names {
+            match UnitFile::from_file_with_vars(&self.unit_path(name), &vars) {
+                Ok(unit) => units.push(unit),
+                Err(e) => {
+                    warn!("Failed to reload service {}: {}", name, e);
+                    summary.failed.push(name.clone());
+                }
+            }
+        }
+
+        let mut services = self.services.write().await;
+
+        let removed: Vec<String> = services
+            .iter()
+            .filter(|(name, service)| !service.transient && !names.contains(name))
+            .map(|(name, _)| name.clone())
+            .collect();
+
+        for name in removed {
+            if services[&name].is_active() {
+                summary.pending.push(name);
+            } else {
+                if apply {
+                    services.remove(&name);
+                }
+                summary.removed.push(name);
+            }
+        }
+
+        for unit in units {
+            let name = unit.name.clone();
+            if let Err(e) = self.check_aliases(&unit, &services) {
+                warn!("Failed to reload service {}: {}", name, e);
+                summary.failed.push(name);
+                continue;
+            }
+
+            match services.get_mut(&name) {
+                None => {
+                    if apply {
+                        services.insert(name.clone(), self.new_service(unit));
+                    }
+                    summary.added.push(name);
+                }
+                Some(service) if service.unit == unit => {
+                    // The file is back to what runs, so nothing is left to apply
+                    if apply {
+                        service.pending_unit = None;
+                    }
+                    summary.unchanged.push(name)
+                }
+                // A transient service of the same name has to stop first
+                Some(service) if service.transient => summary.pending.push(name),
+                Some(service) if service.is_active() && soft => {
+                    if apply {
+                        service.pending_unit = Some(unit);
+                    }
+                    summary.staged.push(name);
+                }
+                Some(service) if service.is_active() => summary.pending.push(name),
+                Some(service) => {
+                    if apply {
+                        service.unit = unit;
+                        service.pending_unit = None;
+                    }
+                    summary.updated.push(name);
+                }
+            }
+        }
+
+        if apply {
+            self.units_changed();
+        }
+        drop(services);
+        if apply {
+            self.warn_about_dependencies().await;
+        }
+
+        summary.added.sort();
+        summary.removed.sort();
+        summary.updated.sort();
+        summary.pending.sort();
+        summary.staged.sort();
+        if apply {
+            info!("Reloaded services: {}", summary);
+        }
+        Ok(summary)
+    }
+
+    /// Make the services match the service directory, the way
+    /// [`reload_all`](Self::reload_all) makes the units match it: reload,
+    /// then stop active services whose unit was removed, restart those whose
+    /// unit changed, and start the services that start on boot but aren't
+    /// active, each in dependency order. A oneshot that has completed
+    /// successfully counts as up unless its unit changed. Services started by
+    /// hand and transient services are left alone, so applying twice in a row
+    /// does nothing the second time.
+    pub async fn apply(&self) -> Result<ApplySummary> {
+        let mut summary = ApplySummary {
+            reload: self.reload(true, false).await?,
+            ..ApplySummary::default()
+        };
+        let on_disk = self.scan_service_dir()?;
+
+        let mut to_stop = Vec::new();
+        {
+            let services = self.services.read().await;
+            for name in &summary.reload.pending {
+                match services.get(name) {
+                    Some(service) if service.transient => summary.skipped.push(name.clone()),
+                    Some(_) if on_disk.contains(name) => summary.restarted.push(name.clone()),
+                    Some(_) => summary.stopped.push(name.clone()),
+                    None => {}
+                }
+            }
+            to_stop.extend(summary.stopped.iter().chain(&summary.restarted).cloned());
+        }
+
+        // Dependents first, the reverse of their start order
+        for name in self.start_order().await.into_iter().rev() {
+            if to_stop.contains(&name) {
+                if let Err(e) = self.stop_service(&name).await {
+                    summary.stopped.retain(|n| *n != name);
+                    summary.restarted.retain(|n| *n != name);
+                    summary.errors.push((name, e.to_string()));
+                }
+            }
+        }
+
+        // Now stopped, their units can be replaced or dropped
+        let reload = self.reload(true, false).await?;
+        summary.reload.removed.extend(reload.removed);
+        summary.reload.pending.clear();
+
+        let to_start: Vec<String> = {
+            let services = self.services.read().await;
+            services
+                .values()
+                .filter(|service| {
+                    let name = &service.unit.name;
+                    if summary.restarted.contains(name) {
+                        return true;
+                    }
+                    let completed = service.unit.is_oneshot()
+                        && service.exit_status.is_some_and(|status| status.success())
+                        && !summary.reload.updated.contains(name);
+                    service.unit.starts_on_boot(&self.default_target) && !service.is_active() && !completed
+                })
+                .map(|service| service.unit.name.clone())
+                .collect()
+        };
+
+        for name in self.start_order().await {
+            if !to_start.contains(&name) {
+                continue;
+            }
+            // Started already as the dependency of another
+            let active = self.services.read().await.get(&name).is_some_and(Service::is_active);
+            if active {
+                if !summary.restarted.contains(&name) {
+                    summary.started.push(name);
+                }
+                continue;
+            }
+            match self.start_service(&name).await {
+                Ok(()) | Err(DiakonosError::ConditionNotMet(_)) => {
+                    if !summary.restarted.contains(&name) {
+                        summary.started.push(name);
+                    }
+                }
+                Err(e) => {
+                    summary.restarted.retain(|n| *n != name);
+                    summary.errors.push((name, e.to_string()));
+                }
+            }
+        }
+
+        summary.reload.removed.sort();
+        summary.started.sort();
+        summary.stopped.sort();
+        summary.restarted.sort();
+        info!("Applied the service directory: {}", summary);
+        Ok(summary)
+    }
+
+    /// Start a service after its dependencies, or every member of a target.
+    /// A service whose start conditions aren't met is skipped; only when that
+    /// is the requested service itself is `ConditionNotMet` returned.
+    pub async fn start_service(&self, name: &str) -> Result<()> {
+        self.start_service_with_env(name, Vec::new()).await
+    }
+
+    /// Start a service like [`start_service`](Self::start_service), setting
+    /// `KEY=value` overrides on top of its unit's environment. They replace
+    /// any from an earlier start, persist across automatic restarts, and
+    /// don't apply to dependencies.
+    pub async fn start_service_with_env(&self, name: &str, env: Vec<String>) -> Result<()> {
+        self.start_by_hand(name, env, true).await
+    }
+
+    /// Start a service like [`start_service_with_env`](Self::start_service_with_env),
+    /// but leave it unsupervised: whatever its `Restart` policy, it isn't
+    /// restarted when it exits until it is next started or restarted
+    /// without this. Its dependencies are supervised as usual.
+    pub async fn start_service_unsupervised(&self, name: &str, env: Vec<String>) -> Result<()> {
+        self.start_by_hand(name, env, false).await
+    }
+
+    /// Start a service like [`start_service_with_env`](Self::start_service_with_env),
+    /// or unsupervised unless `supervised`, sending `progress` a message as
+    /// each dependency is started and each start is waited for
+    pub async fn start_service_reporting(
+        &self,
+        name: &str,
+        env: Vec<String>,
+        supervised: bool,
+        progress: Progress,
+    ) -> Result<()> {
+        self.start_by_hand_reporting(name, env, supervised, Some(&progress)).await
+    }
+
+    async fn start_by_hand(&self, name: &str, env: Vec<String>, supervised: bool) -> Result<()> {
+        self.start_by_hand_reporting(name, env, supervised, None).await
+    }
+
+    async fn start_by_hand_reporting(
+        &self,
+        name: &str,
+        env: Vec<String>,
+        supervised: bool,
+        progress: Option<&Progress>,
+    ) -> Result<()> {
+        validate_service_name(name)?;
+
+        if let Some(service) = self.services.write().await.get_mut(name) {
+            service.handling_failure_of = None;
+            service.unsupervised = !supervised;
+        }
+        let result = self.start_with_dependencies(name, env, progress).await;
+        self.wake_supervisor();
+        result
+    }
+
+    async fn start_with_dependencies(&self, name: &str, env: Vec<String>, progress: Option<&Progress>) -> Result<()> {
+        if !env.is_empty() {
+            if is_target(name) {
+                return Err(DiakonosError::StartError(format!(
+                    "'{}' is a target; environment overrides apply to a single service",
+                    name
+                )));
+            }
+            if let Some(invalid) = env.iter().find(|e| !is_env_assignment(e)) {
+                return Err(DiakonosError::StartError(format!(
+                    "invalid environment override '{}', expected KEY=VALUE",
+                    invalid
+                )));
+            }
+        }
+
+        let report = |message: String| {
+            if let Some(progress) = progress {
+                let _ = progress.send(message);
+            }
+        };
+        let wait_until_ready = |service: String| {
+            report(format!("Waiting for '{}' to be ready", service));
+            async move { self.wait_until_ready(&service).await }
+        };
+
+        // A service starts once the units it is ordered after are ready;
+        // others come up alongside it and are waited for at the end. Each
+        // holds a start permit until it is ready.
+        let mut env = Some(env);
+        let mut starting: Vec<(String, OwnedSemaphorePermit)> = Vec::new();
+        let mut result = Ok(());
+        'start: for service in self.resolve_dependencies(name).await? {
+            let predecessors = {
+                let services = self.services.read().await;
+                let names: Vec<String> = starting.iter().map(|(name, _)| name.clone()).collect();
+                self.ordered_after(&service, &names, &services)
+            };
+            for predecessor in predecessors {
+                starting.retain(|(s, _)| *s != predecessor);
+                if let Err(e) = wait_until_ready(predecessor).await {
+                    result = Err(e);
+                    break 'start;
+                }
+            }
+
+            // Short of permits, see this start's own services through first,
+            // so it never waits on permits it holds itself
+            let permit = loop {
+                match Arc::clone(&self.start_permits).try_acquire_owned() {
+                    Ok(permit) => break permit,
+                    Err(_) if !starting.is_empty() => {
+                        let (oldest, _permit) = starting.remove(0);
+                        if let Err(e) = wait_until_ready(oldest).await {
+                            result = Err(e);
+                            break 'start;
+                        }
+                    }
+                    Err(_) => {
+                        debug!("Waiting for another start to finish before starting {}", service);
+                        break self.acquire_start_permit().await;
+                    }
+                }
+            };
+
+            let overrides = if service == name {
+                env.take()
+            } else {
+                report(format!("Starting '{}'", service));
+                None
+            };
+            match self.launch_service(&service, overrides).await {
+                Ok(true) => starting.push((service, permit)),
+                Ok(false) => {}
+                Err(DiakonosError::ConditionNotMet(_)) if service != name => {}
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            }
+        }
+
+        // Seen through even after a failure, so nothing is left `Starting`
+        for (service, _permit) in starting {
+            let ready = wait_until_ready(service).await;
+            if result.is_ok() {
+                result = ready;
+            }
+        }
+        result
+    }
+
+    /// A permit to start a service, once fewer than `max_concurrent_starts`
+    /// starts are in progress
+    async fn acquire_start_permit(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.start_permits)
+            .acquire_owned()
+            .await
+            .expect("start permits are never closed")
+    }
+
+    /// Start a single service without waiting for it to become ready.
+    /// `overrides`, if given, replace its environment overrides for this and
+    /// later launches. Returns whether it is still `Starting`, to be waited
+    /// for with [`wait_until_ready`](Self::wait_until_ready).
+    async fn launch_service(&self, name: &str, overrides: Option<Vec<String>>) -> Result<bool> {
+        self.cancel_pending_restart(name);
+
+        let mut services = self.services.write().await;
+
+        let service = services
+            .get_mut(name)
+            .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?;
+
+        // A start already under way is waited for rather than made again,
+        // which would spawn a second process
+        if matches!(service.state, ServiceState::Running | ServiceState::Starting) {
+            let starting = service.state == ServiceState::Starting;
+            return match overrides {
+                Some(env) if !env.is_empty() => Err(DiakonosError::StartError(format!(
+                    "'{}' is already {}; stop it first to start it with new environment overrides",
+                    name,
+                    if starting { "starting" } else { "running" }
+                ))),
+                _ => Ok(starting),
+            };
+        }
+
+        if service.apply_pending_unit() {
+            self.units_changed();
+        }
+
+        if let Some(condition) = service.unit.unmet_condition() {
+            info!("Skipping start of {}: condition not met ({})", name, condition);
+            return Err(DiakonosError::ConditionNotMet(condition));
+        }
+
+        if let Some(env) = overrides {
+            service.environment_overrides = env;
+        }
+
+        let (services, started) = start_unlocked(&self.services, services, name).await;
+        started?;
+        Ok(services.get(name).is_some_and(|service| service.state == ServiceState::Starting))
+    }
+
+    /// Wait for a started service to become ready, so its dependents may
+    /// start: a oneshot once its process has exited successfully, a service
+    /// with `ReadyWhen` once its socket accepts a connection. It is killed and
+    /// marked failed if that takes longer than its `TimeoutStartSec`. The lock
+    /// is released between polls so the daemon stays responsive while a long
+    /// job such as a migration runs.
+    async fn wait_until_ready(&self, name: &str) -> Result<()> {
+        let (timeout, ready_when) = {
+            let services = self.services.read().await;
+            let unit = &services
+                .get(name)
+                .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?
+                .unit;
+            (unit.start_timeout(), unit.ready_when())
+        };
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            sleep(READY_POLL_INTERVAL).await;
+
+            if let Some(ref ready_when) = ready_when {
+                let accepts = tokio::time::timeout(READY_PROBE_TIMEOUT, ready_when.accepts()).await;
+                if accepts.unwrap_or(false) {
+                    let mut services = self.services.write().await;
+                    let service = services
+                        .get_mut(name)
+                        .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?;
+                    if service.check_status().await == ServiceState::Starting {
+                        info!("Service {} is ready ({} accepts)", name, ready_when);
+                        service.state = ServiceState::Running;
+                        return Ok(());
+                    }
+                }
+            }
+
+            let mut services = self.services.write().await;
+            let service = services
+                .get_mut(name)
+                .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?;
+
+            match service.check_status().await {
+                ServiceState::Starting if deadline.is_some_and(|deadline| Instant::now() >= deadline) => {
+                    service.abort_start();
+                    let timeout = timeout.unwrap_or_default();
+                    return Err(DiakonosError::StartError(match ready_when {
+                        Some(ref ready_when) => format!(
+                            "service '{}' was not ready within {}s ({} did not accept)",
+                            name,
+                            timeout.as_secs(),
+                            ready_when
+                        ),
+                        None => format!(
+                            "oneshot service '{}' did not finish within {}s",
+                            name,
+                            timeout.as_secs()
+                        ),
+                    }));
+                }
+                ServiceState::Starting => continue,
+                state @ (ServiceState::Failed | ServiceState::Stopped) => {
+                    let reason = service
+                        .exit_status
+                        .map(|status| format!(" ({})", status))
+                        .unwrap_or_default();
+                    return match ready_when {
+                        Some(_) => Err(DiakonosError::StartError(format!(
+                            "service '{}' exited before it was ready{}",
+                            name, reason
+                        ))),
+                        None if state == ServiceState::Failed => Err(DiakonosError::StartError(
+                            format!("oneshot service '{}' failed{}", name, reason),
+                        )),
+                        None => Ok(()),
+                    };
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Wait until `name`, or every member of a target, is up: `Running`, or a
+    /// oneshot that finished successfully. Fails as soon as one fails, or
+    /// once `timeout` passes.
+    pub async fn wait_until_started(&self, name: &str, timeout: Duration) -> Result<()> {
+        self.wait_for_state(name, timeout, "start", |service| match service.state {
+            ServiceState::Running => Some(Ok(())),
+            ServiceState::Stopped if service.unit.is_oneshot() && service.exit_status.is_some() => {
+                Some(Ok(()))
+            }
+            ServiceState::Failed => Some(Err(())),
+            _ => None,
+        })
+        .await
+    }
+
+    /// Wait until `name`, or every member of a target, is stopped with its
+    /// process gone
+    pub async fn wait_until_stopped(&self, name: &str, timeout: Duration) -> Result<()> {
+        self.wait_for_state(name, timeout, "stop", |service| {
+            let stopped = matches!(service.state, ServiceState::Stopped | ServiceState::Failed);
+            (stopped && service.pid.is_none()).then_some(Ok(()))
+        })
+        .await
+    }
+
+    /// Wait until `name`, or every member of a target, is in `state`. A
+    /// service passing through other states on the way, such as failing and
+    /// being restarted, is waited for all the same.
+    pub async fn wait_until_state(&self, name: &str, state: ServiceState, timeout: Duration) -> Result<()> {
+        let action = format!("be {}", state);
+        self.wait_for_state(name, timeout, &action, |service| {
+            (service.state == state).then_some(Ok(()))
+        })
+        .await
+    }
+
+    /// Poll `name` (or a target's members) until `reached` reports an outcome
+    /// for all of them: `Some(Ok)` when the desired state was reached,
+    /// `Some(Err)` when it can no longer be
+    async fn wait_for_state(
+        &self,
+        name: &str,
+        timeout: Duration,
+        action: &str,
+        reached: impl Fn(&Service) -> Option<std::result::Result<(), ()>>,
+    ) -> Result<()> {
+        let names = if is_target(name) {
+            target_members(name, &*self.services.read().await)?
+        } else {
+            vec![name.to_string()]
+        };
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            // Checking only after a poll interval catches processes that exit right away
+            sleep(WAIT_POLL_INTERVAL).await;
+
+            let mut pending = None;
+            {
+                let mut services = self.services.write().await;
+                for name in &names {
+                    let service = services
+                        .get_mut(name)
+                        .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?;
+                    service.check_status().await;
+
+                    match reached(service) {
+                        Some(Ok(())) => {}
+                        Some(Err(())) => {
+                            let reason = service
+                                .exit_status
+                                .map(|status| format!(" ({})", status))
+                                .unwrap_or_default();
+                            return Err(DiakonosError::ProcessError(format!(
+                                "'{}' is {}{}",
+                                name, service.state, reason
+                            )));
+                        }
+                        None => {
+                            pending.get_or_insert_with(|| (name.clone(), service.state));
+                        }
+                    }
+                }
+            }
+
+            let Some((name, state)) = pending else {
+                return Ok(());
+            };
+
+            if Instant::now() >= deadline {
+                return Err(DiakonosError::TimedOut(format!(
+                    "after {}s waiting for '{}' to {} (still {})",
+                    timeout.as_secs(),
+                    name,
+                    action,
+                    state
+                )));
+            }
+        }
+    }
+
+    /// Start every `AutoStart` service, and every one `WantedBy` the default
+    /// target, in name order. A service that fails or times out is logged and
+    /// skipped; once `timeout` has passed overall, the start in progress is
+    /// abandoned and the remaining services are not started.
+    pub async fn start_autostart_services(&self, timeout: Duration) {
+        let mut names: Vec<String> = {
+            let services = self.services.read().await;
+            services
+                .values()
+                .filter(|s| s.unit.starts_on_boot(&self.default_target))
+                .map(|s| s.unit.name.clone())
+                .collect()
+        };
+        names.sort();
+
+        if names.is_empty() {
+            return;
+        }
+
+        info!("Starting {} service(s) on boot into {}", names.len(), self.default_target);
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut failed = Vec::new();
+
+        for (i, name) in names.iter().enumerate() {
+            match tokio::time::timeout_at(deadline, self.start_service(name)).await {
+                Ok(Ok(())) | Ok(Err(DiakonosError::ConditionNotMet(_))) => {}
+                Ok(Err(e)) => {
+                    warn!("Service {} failed to start on boot: {}", name, e);
+                    failed.push(name.clone());
+                }
+                Err(_) => {
+                    warn!(
+                        "Startup timeout of {}s reached, not starting the remaining services",
+                        timeout.as_secs()
+                    );
+                    self.abort_starting().await;
+                    failed.extend(names[i..].iter().cloned());
+                    break;
+                }
+            }
+        }
+
+        if failed.is_empty() {
+            info!("All boot services started");
+        } else {
+            error!(
+                "Services that did not come up during startup: {}",
+                failed.join(", ")
+            );
+        }
+    }
+
+    /// Fail any service left mid-start by an abandoned start
+    async fn abort_starting(&self) {
+        let mut services = self.services.write().await;
+        for service in services.values_mut() {
+            if service.state == ServiceState::Starting {
+                service.abort_start();
+            }
+        }
+    }
+
+    /// Register `unit` as a service called `name` that has no unit file and
+    /// start it. The service is forgotten once it has stopped (or failed and
+    /// isn't being restarted), or when the daemon exits.
+    pub async fn run_transient(&self, name: &str, unit: UnitFile) -> Result<()> {
+        validate_service_name(name)?;
+        let unit = unit.with_name(name, &self.read_vars()?)?;
+
+        {
+            let mut services = self.services.write().await;
+            if services.contains_key(name) {
+                return Err(DiakonosError::ServiceAlreadyExists(name.to_string()));
+            }
+
+            let mut service = self.new_service(unit);
+            service.transient = true;
+            services.insert(name.to_string(), service);
+            self.units_changed();
+        }
+
+        info!("Registered transient service: {}", name);
+        self.start_service(name).await
+    }
+
+    /// Stop a service, or every member of a target (dependents first)
+    pub async fn stop_service(&self, name: &str) -> Result<()> {
+        self.stop_with(name, false).await
+    }
+
+    /// Like [`stop_service`](Self::stop_service), but SIGKILL each process
+    /// group at once instead of asking the service to stop
+    pub async fn force_stop_service(&self, name: &str) -> Result<()> {
+        self.stop_with(name, true).await
+    }
+
+    async fn stop_with(&self, name: &str, force: bool) -> Result<()> {
+        let result = if is_target(name) {
+            self.stop_target(name, force).await
+        } else {
+            self.stop_service_internal(name, force).await
+        };
+        if result.is_ok() {
+            let stopped = if is_target(name) {
+                self.target_members_ordered(name).await.unwrap_or_default()
+            } else {
+                vec![name.to_string()]
+            };
+            self.stop_unneeded(stopped).await;
+        }
+        self.wake_supervisor();
+        result
+    }
+
+    /// Stop the `StopWhenUnneeded` dependencies of the `stopped` services
+    /// that no active service requires or wants any more, and in turn theirs.
+    /// Each service is looked at once, so a dependency cycle ends the chain.
+    async fn stop_unneeded(&self, stopped: Vec<String>) {
+        let mut queue = stopped;
+        let mut seen = HashSet::new();
+
+        while let Some(name) = queue.pop() {
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+
+            let unneeded: Vec<String> = {
+                let services = self.services.read().await;
+                let dependencies = match services.get(&name) {
+                    Some(service) => service.unit.dependencies(),
+                    None => continue,
+                };
+                dependencies
+                    .iter()
+                    .map(|dep| resolve_alias(dep.strip_suffix(".service").unwrap_or(dep), &services))
+                    .filter(|dep| {
+                        services
+                            .get(*dep)
+                            .is_some_and(|service| service.unit.stops_when_unneeded() && service.is_active())
+                            && !has_active_dependent(dep, &services)
+                    })
+                    .map(str::to_string)
+                    .collect()
+            };
+
+            for dependency in unneeded {
+                info!("Stopping service {}: nothing that needs it is running", dependency);
+                if let Err(e) = self.stop_service_internal(&dependency, false).await {
+                    warn!("Failed to stop unneeded service {}: {}", dependency, e);
+                }
+                queue.push(dependency);
+            }
+        }
+    }
+
+    async fn stop_target(&self, name: &str, force: bool) -> Result<()> {
+        for member in self.target_members_ordered(name).await?.iter().rev() {
+            self.stop_service_internal(member, force).await?;
+        }
+        Ok(())
+    }
+
+    async fn stop_service_internal(&self, name: &str, force: bool) -> Result<()> {
+        self.cancel_pending_restart(name);
+        let mut services = self.services.write().await;
+
+        let service = match services.get_mut(name) {
+            Some(service) => service,
+            None => return Err(not_found(name, &services)),
+        };
+
+        if force {
+            service.force_stop().await
+        } else {
+            service.stop().await
+        }
+    }
+
+    pub async fn restart_service(&self, name: &str) -> Result<()> {
+        self.restart_by_hand(name, true).await
+    }
+
+    /// Restart a service, leaving it unsupervised like
+    /// [`start_service_unsupervised`](Self::start_service_unsupervised)
+    pub async fn restart_service_unsupervised(&self, name: &str) -> Result<()> {
+        self.restart_by_hand(name, false).await
+    }
+
+    async fn restart_by_hand(&self, name: &str, supervised: bool) -> Result<()> {
+        if is_target(name) {
+            self.stop_service(name).await?;
+            return self.start_by_hand(name, Vec::new(), supervised).await;
+        }
+
+        self.cancel_pending_restart(name);
+        let result = {
+            let mut services = self.services.write().await;
+            match services.get_mut(name) {
+                Some(service) => {
+                    service.unsupervised = !supervised;
+                    match service.stop_for_restart().await {
+                        Ok(()) => start_unlocked(&self.services, services, name).await.1,
+                        Err(e) => Err(e),
+                    }
+                }
+                None => Err(not_found(name, &services)),
+            }
+        };
+        self.wake_supervisor();
+        result
+    }
+
+    pub async fn signal_service(&self, name: &str, signal: &str) -> Result<()> {
+        let signal = parse_signal(signal)?;
+        let services = self.services.read().await;
+
+        let service = services
+            .get(name)
+            .ok_or_else(|| not_found(name, &services))?;
+
+        // The signal may well end the process
+        service.send_signal(signal)?;
+        self.wake_supervisor();
+        Ok(())
+    }
+
+    pub async fn get_service_status(&self, name: &str) -> Result<ServiceState> {
+        let services = self.services.read().await;
+
+        let service = services
+            .get(name)
+            .ok_or_else(|| not_found(name, &services))?;
+
+        Ok(service.state)
+    }
+
+    /// The PID of the service's main process, if it has one running
+    pub async fn service_pid(&self, name: &str) -> Result<Option<u32>> {
+        let services = self.services.read().await;
+
+        let service = services
+            .get(name)
+            .ok_or_else(|| not_found(name, &services))?;
+
+        Ok(service.pid)
+    }
+
+    pub async fn describe_service(&self, name: &str) -> Result<ServiceDescription> {
+        let services = self.services.read().await;
+
+        let service = services
+            .get(name)
+            .ok_or_else(|| not_found(name, &services))?;
+
+        Ok(service.describe())
+    }
+
+    /// The value in effect of a setting of a service's unit, such as
+    /// `Restart`; see [`UnitFile::setting`]
+    pub async fn config_value(&self, name: &str, key: &str) -> Result<Option<String>> {
+        let services = self.services.read().await;
+
+        let service = services
+            .get(name)
+            .ok_or_else(|| not_found(name, &services))?;
+
+        service.unit.setting(key)
+    }
+
+    /// Set a setting of a service's unit in its override drop-in, leaving
+    /// the unit file as it is (see [`crate::unit::set_override`]), then
+    /// reload. Returns the drop-in's path, and whether the service is active
+    /// and so runs with the old value until it is restarted.
+    pub async fn set_config(&self, name: &str, key: &str, value: &str) -> Result<(PathBuf, bool)> {
+        validate_service_name(name)?;
+        let path = self.unit_path(name);
+        if !path.exists() {
+            let services = self.services.read().await;
+            return Err(match services.get(name) {
+                Some(_) => DiakonosError::StartError(format!("'{}' has no unit file to configure", name)),
+                None => not_found(name, &services),
+            });
+        }
+
+        let drop_in = crate::unit::set_override(&path, key, value, &self.read_vars()?)?;
+        info!("Set {} = {} for service {} in {}", key, value, name, drop_in.display());
+        let summary = self.reload_all().await?;
+        Ok((drop_in, summary.pending.iter().any(|pending| pending == name)))
+    }
+
+    /// Path of the file capturing a service's output
+    pub async fn service_log_file(&self, name: &str) -> Result<PathBuf> {
+        let services = self.services.read().await;
+
+        let service = services
+            .get(name)
+            .ok_or_else(|| not_found(name, &services))?;
+
+        if service.unit.logs_to_syslog() {
+            return Err(DiakonosError::LogsToSyslog(
+                service.unit.syslog_identifier().to_string(),
+            ));
+        }
+
+        Ok(service
+            .log_file
+            .clone()
+            .unwrap_or_else(|| self.log_path(name)))
+    }
+
+    pub async fn daemon_info(&self) -> DaemonInfo {
+        let services = self.services.read().await;
+        let count = |state| services.values().filter(|s| s.state == state).count();
+
+        DaemonInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            pid: std::process::id(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            service_dir: self.service_dir.clone(),
+            services: services.len(),
+            running: count(ServiceState::Running),
+            failed: count(ServiceState::Failed),
+            restarts_frozen: self.restarts_frozen.load(Ordering::Relaxed),
+            open_connections: self.open_connections.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Count a control connection as open until the returned guard is dropped
+    pub fn connection_opened(&self) -> OpenConnection {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(Arc::clone(&self.open_connections))
+    }
+
+    /// Bytes taken by each service's log and its rotated segments
+    pub async fn log_usage(&self) -> Result<Vec<LogUsage>> {
+        let log_dir = self.log_dir.clone();
+        let usage = tokio::task::spawn_blocking(move || crate::logs::log_usage(&log_dir))
+            .await
+            .map_err(|e| DiakonosError::ProcessError(e.to_string()))??;
+        Ok(usage)
+    }
+
+    /// The configured cap on the total size of service logs
+    pub fn max_log_bytes(&self) -> Option<u64> {
+        self.max_log_bytes
+    }
+
+    /// Stop or resume automatic restarts of every service, e.g. for the
+    /// duration of a controlled rollout. Returns whether they were frozen before.
+    pub fn freeze_restarts(&self, frozen: bool) -> bool {
+        let was_frozen = self.restarts_frozen.swap(frozen, Ordering::Relaxed);
+        if frozen != was_frozen {
+            info!("Automatic restarts {}", if frozen { "frozen" } else { "unfrozen" });
+        }
+        was_frozen
+    }
+
+    /// Name and state of every loaded service, in no particular order
+    pub async fn list_services(&self) -> Vec<(String, ServiceState)> {
+        let services = self.services.read().await;
+
+        services
+            .iter()
+            .map(|(name, service)| (name.clone(), service.state))
+            .collect()
+    }
+
+    /// Up to `limit` services named after `after`, or from the first if
+    /// `None`, in name order, and how many there are in all
+    pub async fn list_page(&self, after: Option<&str>, limit: usize) -> (Vec<(String, ServiceState)>, usize) {
+        let services = self.services.read().await;
+
+        let mut names: Vec<&String> = services
+            .keys()
+            .filter(|name| after.is_none_or(|after| name.as_str() > after))
+            .collect();
+        names.sort();
+        let page = names
+            .into_iter()
+            .take(limit)
+            .map(|name| (name.clone(), services[name].state))
+            .collect();
+        (page, services.len())
+    }
+
+    pub async fn service_stats(&self) -> Vec<ServiceStats> {
+        let services = self.services.read().await;
+
+        let mut stats: Vec<ServiceStats> = services.values().map(Service::stats).collect();
+        stats.sort_by(|a, b| a.name.cmp(&b.name));
+        stats
     }
 
-    pub async fn load_service(&self, name: &str) -> Result<()> {
-        let path = self.service_dir.join(format!("{}.service", name));
+    /// Main process of every service that has one, including processes
+    /// that have exited but which the supervisor hasn't waited for yet
+    pub async fn main_pids(&self) -> HashSet<u32> {
+        let services = self.services.read().await;
+        services.values().filter_map(|service| service.pid).collect()
+    }
 
-        if !path.exists() {
-            return Err(DiakonosError::ServiceNotFound(name.to_string()));
+    /// Stop every active service, dependents first, with automatic restarts
+    /// frozen so that none comes back, as when the daemon itself is told to
+    /// shut down
+    pub async fn stop_all(&self) {
+        self.freeze_restarts(true);
+        for name in self.start_order().await.into_iter().rev() {
+            let active = self.services.read().await.get(&name).is_some_and(Service::is_active);
+            if active {
+                if let Err(e) = self.stop_service(&name).await {
+                    error!("Failed to stop service {}: {}", name, e);
+                }
+            }
         }
+    }
 
-        let unit = UnitFile::from_file(&path)?;
-        let service = Service::new(unit);
-
-        let mut services = self.services.write().await;
-        if services.contains_key(name) {
-            return Err(DiakonosError::ServiceAlreadyExists(name.to_string()));
+    /// Every loaded service, dependencies first. With a dependency cycle
+    /// there is no such order, and they come in name order instead.
+    async fn start_order(&self) -> Vec<String> {
+        match self.expand_pattern("*").await {
+            Ok(Some(names)) => names,
+            Ok(None) | Err(DiakonosError::ServiceNotFound(_)) => Vec::new(),
+            Err(e) => {
+                warn!("Failed to order services by their dependencies: {}", e);
+                let mut names: Vec<String> =
+                    self.list_services().await.into_iter().map(|(name, _)| name).collect();
+                names.sort();
+                names
+            }
         }
+    }
 
-        services.insert(name.to_string(), service);
-        info!("Loaded service: {}", name);
-        Ok(())
+    /// Services with `RestartOnChange` enabled and the files they watch
+    pub async fn watch_targets(&self) -> Vec<(String, Vec<PathBuf>)> {
+        let services = self.services.read().await;
+
+        services
+            .iter()
+            .map(|(name, service)| (name.clone(), service.unit.watch_paths()))
+            .filter(|(_, paths)| !paths.is_empty())
+            .collect()
     }
 
-    pub async fn load_all_services(&self) -> Result<()> {
-        let entries = std::fs::read_dir(&self.service_dir)?;
+    /// Loaded services matching `pattern`, a glob such as `worker-*`, in
+    /// start order (dependencies first). `None` if `pattern` has no glob
+    /// syntax, or isn't a valid glob, and so names a single service or target.
+    pub async fn expand_pattern(&self, pattern: &str) -> Result<Option<Vec<String>>> {
+        if !pattern.contains(['*', '?', '[']) {
+            return Ok(None);
+        }
+        // Compiled before taking the lock; matching is linear in the name
+        let Ok(glob) = Glob::new(pattern) else {
+            return Ok(None);
+        };
+        let glob = glob.compile_matcher();
 
-        for entry in entries {
-            let entry = entry?;
-            let path = entry.path();
+        let mut matches: Vec<String> = self
+            .services
+            .read()
+            .await
+            .keys()
+            .filter(|name| glob.is_match(name))
+            .cloned()
+            .collect();
+        if matches.is_empty() {
+            return Err(DiakonosError::ServiceNotFound(format!(
+                "no service matches '{}'",
+                pattern
+            )));
+        }
+        matches.sort();
 
-            if path.extension().and_then(|s| s.to_str()) == Some("service") {
-                if let Some(name) = path.file_stem().and_then(|s| s.to_str()) {
-                    if let Err(e) = self.load_service(name).await {
-                        warn!("Failed to load service {}: {}", name, e);
-                    }
+        let mut ordered = Vec::new();
+        for name in &matches {
+            for service in self.resolve_dependencies(name).await? {
+                if matches.contains(&service) && !ordered.contains(&service) {
+                    ordered.push(service);
                 }
             }
         }
-
-        Ok(())
+        Ok(Some(ordered))
     }
 
-    pub async fn start_service(&self, name: &str) -> Result<()> {
-        // First resolve dependencies
-        let deps = self.resolve_dependencies(name).await?;
+    /// Services to start for `name`, dependencies first. For a target this
+    /// covers all of its members and their dependencies.
+    async fn resolve_dependencies(&self, name: &str) -> Result<Vec<String>> {
+        let services = self.services.read().await;
+
+        // Looked up and filled under the read lock, so an order is never
+        // cached from units that changed in the meantime
+        if let Some(order) = self.dependency_orders.lock().unwrap_or_else(|e| e.into_inner()).get(name) {
+            return Ok(order.clone());
+        }
+
+        let roots = if is_target(name) {
+            target_members(name, &services)?
+        } else if services.contains_key(name) {
+            vec![name.to_string()]
+        } else {
+            return Err(not_found(name, &services));
+        };
 
-        // Start dependencies first
-        for dep in deps {
-            if dep != name {
-                self.start_service_internal(&dep).await?;
+        let mut resolved = Vec::new();
+
+        for root in roots {
+            if !resolved.contains(&root) {
+                let mut visited = HashSet::new();
+                self.resolve_deps_recursive(&root, &services, &mut resolved, &mut visited)?;
             }
         }
 
-        // Then start the requested service
-        self.start_service_internal(name).await
-    }
+        let resolved = self.order_starts(resolved, &services)?;
 
-    async fn start_service_internal(&self, name: &str) -> Result<()> {
-        let mut services = self.services.write().await;
+        debug!("Resolved start order of {}: {:?}", name, resolved);
+        self.dependency_orders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(name.to_string(), resolved.clone());
+        Ok(resolved)
+    }
 
-        let service = services
-            .get_mut(name)
-            .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?;
+    /// Rearrange `resolved`, in which dependencies come first, so that each
+    /// service also comes after those it is ordered after, keeping the order
+    /// otherwise. Fails if the orderings form a cycle.
+    fn order_starts(&self, resolved: Vec<String>, services: &HashMap<String, Service>) -> Result<Vec<String>> {
+        let mut pending = resolved;
+        let mut ordered = Vec::with_capacity(pending.len());
 
-        if service.state == ServiceState::Running {
-            return Ok(());
+        while !pending.is_empty() {
+            let next = pending
+                .iter()
+                .position(|name| self.ordered_after(name, &pending, services).is_empty())
+                .ok_or(DiakonosError::DependencyCycle)?;
+            ordered.push(pending.remove(next));
         }
-
-        service.start().await
+        Ok(ordered)
     }
 
-    pub async fn stop_service(&self, name: &str) -> Result<()> {
-        let mut services = self.services.write().await;
+    /// The services among `among` that `name` must wait for before it
+    /// starts: those in its `After`, and, with `implicit_after_requires`,
+    /// those it requires or wants
+    fn ordered_after(&self, name: &str, among: &[String], services: &HashMap<String, Service>) -> Vec<String> {
+        let unit = match services.get(name) {
+            Some(service) => &service.unit,
+            None => return Vec::new(),
+        };
 
-        let service = services
-            .get_mut(name)
-            .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?;
+        let mut names = unit.unit.after.clone().unwrap_or_default();
+        if self.implicit_after_requires {
+            names.extend(unit.dependencies());
+        }
 
-        service.stop().await
+        let mut predecessors = Vec::new();
+        for other in &names {
+            let other = resolve_alias(other.strip_suffix(".service").unwrap_or(other), services);
+            if other != name && among.iter().any(|s| s == other) && !predecessors.iter().any(|p| p == other) {
+                predecessors.push(other.to_string());
+            }
+        }
+        predecessors
     }
 
-    pub async fn restart_service(&self, name: &str) -> Result<()> {
-        let mut services = self.services.write().await;
+    /// Forget cached start orders and have the file watcher look again.
+    /// Call with the `services` write lock held after adding, removing or
+    /// changing a unit.
+    fn units_changed(&self) {
+        self.dependency_orders
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.watch_wake.notify_one();
+    }
 
-        let service = services
-            .get_mut(name)
-            .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?;
+    /// Wait until the units have changed since the last call, by a load,
+    /// reload, or a staged unit taking effect, so that
+    /// [`watch_targets`](Self::watch_targets) may differ
+    pub async fn wait_for_unit_changes(&self) {
+        self.watch_wake.notified().await;
+    }
 
-        service.restart().await
+    /// The services `name` needs started before it, in start order. For a
+    /// target these are its members and their dependencies.
+    pub async fn service_dependencies(&self, name: &str) -> Result<Vec<String>> {
+        let mut order = self.resolve_dependencies(name).await?;
+        order.retain(|s| s != name);
+        Ok(order)
     }
 
-    pub async fn get_service_status(&self, name: &str) -> Result<ServiceState> {
+    /// How every service relates to the units it names in `Requires`, `Wants`
+    /// and `After`, whether or not those exist. A unit named by an `Alias`
+    /// appears under its own name.
+    pub async fn dependency_graph(&self) -> DependencyGraph {
         let services = self.services.read().await;
 
-        let service = services
-            .get(name)
-            .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?;
+        let mut graph = DependencyGraph::default();
+        for (name, service) in services.iter() {
+            graph.services.push(name.clone());
 
-        Ok(service.state)
+            let unit = &service.unit.unit;
+            let relations = [
+                (Relation::Requires, &unit.requires),
+                (Relation::Wants, &unit.wants),
+                (Relation::After, &unit.after),
+            ];
+            for (relation, names) in relations {
+                for dependency in names.iter().flatten() {
+                    let dependency = dependency.strip_suffix(".service").unwrap_or(dependency);
+                    graph.edges.push(DependencyEdge {
+                        from: name.clone(),
+                        to: resolve_alias(dependency, &services).to_string(),
+                        relation,
+                    });
+                }
+            }
+        }
+
+        graph.services.sort();
+        graph.edges.sort_by(|a, b| (&a.from, &a.to, a.relation).cmp(&(&b.from, &b.to, b.relation)));
+        graph
     }
 
-    pub async fn list_services(&self) -> Vec<(String, ServiceState)> {
+    /// Every reference in `Requires`, `Wants` or `After` to a unit that isn't
+    /// loaded, most likely a typo or a unit file that was never written
+    pub async fn diagnostics(&self) -> Vec<Diagnostic> {
+        let graph = self.dependency_graph().await;
         let services = self.services.read().await;
 
-        services
-            .iter()
-            .map(|(name, service)| (name.clone(), service.state))
+        graph
+            .edges
+            .into_iter()
+            .filter(|edge| !services.contains_key(&edge.to))
+            .map(|edge| {
+                let suggestion = did_you_mean(&edge.to, &services);
+                let (relation, consequence) = match edge.relation {
+                    Relation::Requires => ("requires", "; starting it will fail"),
+                    Relation::Wants => ("wants", "; starting it will fail"),
+                    Relation::After => ("is ordered after", ""),
+                };
+                let message = format!(
+                    "{} '{}', which isn't loaded{}{}",
+                    relation, edge.to, suggestion, consequence
+                );
+                Diagnostic {
+                    service: edge.from,
+                    dependency: edge.to,
+                    relation: edge.relation,
+                    message,
+                }
+            })
             .collect()
     }
 
-    async fn resolve_dependencies(&self, name: &str) -> Result<Vec<String>> {
-        let services = self.services.read().await;
-
-        let service = services
-            .get(name)
-            .ok_or_else(|| DiakonosError::ServiceNotFound(name.to_string()))?;
-
-        let mut resolved = Vec::new();
-        let mut visited = HashSet::new();
+    /// Log the problems [`diagnostics`](Self::diagnostics) finds, so they show
+    /// up when units are loaded rather than when a start fails
+    async fn warn_about_dependencies(&self) {
+        for diagnostic in self.diagnostics().await {
+            warn!("Service {} {}", diagnostic.service, diagnostic.message);
+        }
+    }
 
-        self.resolve_deps_recursive(name, &services, &mut resolved, &mut visited)?;
+    /// Members of a target in dependency order
+    async fn target_members_ordered(&self, target: &str) -> Result<Vec<String>> {
+        let members = {
+            let services = self.services.read().await;
+            target_members(target, &services)?
+        };
 
-        Ok(resolved)
+        let order = self.resolve_dependencies(target).await?;
+        Ok(order.into_iter().filter(|s| members.contains(s)).collect())
     }
 
     fn resolve_deps_recursive(
@@ -161,7 +1621,7 @@ impl ServiceManager {
 
             for dep in deps {
                 // Remove .service suffix if present
-                let dep_name = dep.strip_suffix(".service").unwrap_or(&dep);
+                let dep_name = resolve_alias(dep.strip_suffix(".service").unwrap_or(&dep), services);
 
                 if !resolved.contains(&dep_name.to_string()) {
                     if services.contains_key(dep_name) {
@@ -180,43 +1640,800 @@ impl ServiceManager {
         Ok(())
     }
 
-    pub async fn supervise(&self) {
+    fn has_pending_restart(&self, name: &str) -> bool {
+        self.pending_restarts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .contains_key(name)
+    }
+
+    /// Cancel a supervisor restart scheduled for `name`, if any. Called for
+    /// every manual start, stop or restart so the user's action wins.
+    fn cancel_pending_restart(&self, name: &str) {
+        let mut pending = self.pending_restarts.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(handle) = pending.remove(name) {
+            if !handle.is_finished() {
+                info!("Cancelled pending restart of service {}", name);
+            }
+            handle.abort();
+        }
+    }
+
+    /// Run timers forever: start each service with a `[timer]` section once
+    /// its `OnUnitActiveSec` has passed since it was last started. A service
+    /// that is still active when its timer elapses is started once it isn't.
+    pub async fn run_timers(self: Arc<Self>) {
+        loop {
+            sleep(TIMER_TICK).await;
+
+            let mut due = Vec::new();
+            for (name, service) in self.services.write().await.iter_mut() {
+                if service.timer_due() {
+                    // Counts as activated now, so the timer isn't due again
+                    // before the start below gets the lock
+                    service.last_activated = Instant::now();
+                    due.push(name.clone());
+                }
+            }
+
+            for name in due {
+                info!("Timer of service {} elapsed, starting it", name);
+                let manager = Arc::clone(&self);
+                tokio::spawn(async move {
+                    match manager.start_service(&name).await {
+                        Ok(()) => {}
+                        Err(DiakonosError::ConditionNotMet(condition)) => {
+                            info!("Timer run of {} skipped: condition not met ({})", name, condition)
+                        }
+                        Err(e) => error!("Timer run of {} failed: {}", name, e),
+                    }
+                });
+            }
+        }
+    }
+
+    /// Keep the service logs within `max_log_bytes` forever, pruning rotated
+    /// segments and rotating logs that outgrow it. Returns at once if no cap
+    /// is configured.
+    pub async fn run_log_cap(self: Arc<Self>) {
+        let Some(max_bytes) = self.max_log_bytes else {
+            return;
+        };
+
+        loop {
+            let log_dir = self.log_dir.clone();
+            match tokio::task::spawn_blocking(move || crate::logs::enforce_cap(&log_dir, max_bytes)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("Failed to enforce the log size cap: {}", e),
+                Err(e) => error!("Log size cap task panicked: {}", e),
+            }
+
+            sleep(LOG_CAP_INTERVAL).await;
+        }
+    }
+
+    /// Start the `OnFailure` units of `failed`, marking each as handling its
+    /// failure
+    async fn activate_failure_handlers(&self, failed: &str, handlers: &[String]) {
+        for handler in handlers {
+            info!("Service {} failed, starting its OnFailure unit {}", failed, handler);
+
+            if let Some(service) = self.services.write().await.get_mut(handler) {
+                service.handling_failure_of = Some(failed.to_string());
+            }
+            if let Err(e) = self.start_with_dependencies(handler, Vec::new(), None).await {
+                error!("Failed to start OnFailure unit {} of {}: {}", handler, failed, e);
+            }
+        }
+    }
+
+    /// Run the supervision loop forever: poll each service's process every
+    /// `supervision_interval`, restart exited services per their `Restart`
+    /// policy, and start the `OnFailure` units of failed ones. A start, stop,
+    /// restart or signal wakes it early, for a pass straight away and another
+    /// shortly after. Spawn this as a task alongside the manager's users.
+    pub async fn supervise(self: Arc<Self>) {
         info!("Starting supervision loop");
 
+        let mut transitions = TransitionLog::default();
+        // Services whose failure was notified, so their recovery is too
+        let mut failed = HashSet::new();
+        // Process checks that didn't finish in their pass
+        let mut stuck = HashMap::new();
+        let mut woken = false;
+
         loop {
-            sleep(Duration::from_secs(5)).await;
+            let interval = if woken {
+                self.supervision_interval.min(WAKE_RECHECK_INTERVAL)
+            } else {
+                self.supervision_interval
+            };
+            woken = tokio::select! {
+                _ = sleep(interval) => false,
+                _ = self.supervisor_wake.notified() => true,
+            };
 
-            let mut services = self.services.write().await;
+            // Processes are checked off the runtime and without the lock, so
+            // a check that blocks holds up neither the daemon nor the other
+            // services; its service is left for the next pass
+            let checks: Vec<_> = {
+                let mut services = self.services.write().await;
+                services
+                    .iter_mut()
+                    .map(|(name, service)| {
+                        if service.enforce_runtime_max() {
+                            // Notice the exit soon rather than a whole interval later
+                            self.wake_supervisor();
+                        }
+                        // A check still stuck from an earlier pass is waited on again
+                        let check = stuck.remove(name).or_else(|| {
+                            service
+                                .process_check()
+                                .map(|check| tokio::task::spawn_blocking(|| check.run()))
+                        });
+                        (name.clone(), check)
+                    })
+                    .collect()
+            };
+            // Left over only for services that are gone
+            stuck.clear();
+            let deadline = tokio::time::Instant::now() + STATUS_CHECK_TIMEOUT;
+            let mut failures = Vec::new();
 
-            for (name, service) in services.iter_mut() {
+            for (name, check) in checks {
+                let exit = match check {
+                    Some(mut check) => match tokio::time::timeout_at(deadline, &mut check).await {
+                        Ok(Ok(exit)) => Some(exit),
+                        Ok(Err(e)) => {
+                            error!("Checking the status of service {} failed: {}", name, e);
+                            continue;
+                        }
+                        Err(_) => {
+                            warn!(
+                                "Checking the status of service {} took longer than {:?}, skipping it this pass",
+                                name, STATUS_CHECK_TIMEOUT
+                            );
+                            stuck.insert(name, check);
+                            continue;
+                        }
+                    },
+                    None => None,
+                };
+
+                let mut services = self.services.write().await;
+                let Some(service) = services.get_mut(&name) else {
+                    continue;
+                };
                 let old_state = service.state;
-                let new_state = service.check_status().await;
+                let new_state = service.record_check(exit);
+                let name = &name;
+
+                // A supervisor restart sets Running itself, so recovery is
+                // spotted by state rather than as a transition
+                if new_state == ServiceState::Running && failed.remove(name) {
+                    self.notify(StateChange::new(name, ServiceState::Failed, new_state, None));
+                }
+
+                if service.reset_restarts_if_stable() {
+                    info!("Service {} has been stable, resetting its restart count", name);
+                }
 
                 if old_state != new_state {
-                    info!("Service {} changed state: {:?} -> {:?}", name, old_state, new_state);
+                    let exit = service
+                        .exit_status
+                        .map(|status| format!(" ({})", status))
+                        .unwrap_or_default();
+                    let quiet = !transitions.record(name, old_state, new_state, &exit);
+                    let reason = service.exit_status.map(|status| status.to_string());
+                    self.record_transition(StateChange::new(name, old_state, new_state, reason));
+
+                    if new_state == ServiceState::Failed && !quiet {
+                        let reason = service.exit_status.map(|status| status.to_string());
+                        self.notify(StateChange::new(name, old_state, new_state, reason));
+                        failed.insert(name.clone());
+                    }
+
+                    let handlers = service.unit.unit.on_failure.clone().unwrap_or_default();
+                    if new_state == ServiceState::Failed && !handlers.is_empty() {
+                        match service.handling_failure_of {
+                            Some(ref origin) => warn!(
+                                "Not starting OnFailure units of {}: it was started to handle the failure of {}",
+                                name, origin
+                            ),
+                            None => failures.push((name.clone(), handlers)),
+                        }
+                    }
 
                     // Handle restarts
-                    if (new_state == ServiceState::Stopped || new_state == ServiceState::Failed)
-                        && service.should_restart()
-                    {
+                    let restartable = (new_state == ServiceState::Stopped
+                        || new_state == ServiceState::Failed)
+                        && service.should_restart();
+                    let suppressed = restartable
+                        .then(|| restart_suppressed(service, &self.restarts_frozen))
+                        .flatten();
+                    if let Some(reason) = suppressed {
+                        info!("Not restarting service {}: {}", name, reason);
+                    } else if restartable {
                         let delay = service.get_restart_delay();
-                        info!("Service {} will restart in {:?}", name, delay);
+                        if !quiet {
+                            info!("Service {} will restart in {:?}", name, delay);
+                        }
 
                         let name_clone = name.clone();
                         let services_clone = Arc::clone(&self.services);
+                        let pending = Arc::clone(&self.pending_restarts);
+                        let frozen = Arc::clone(&self.restarts_frozen);
+                        let manager = Arc::clone(&self);
 
-                        tokio::spawn(async move {
+                        // Registered before the task can run, so it always finds its own entry
+                        let mut restarts = self.pending_restarts.lock().unwrap_or_else(|e| e.into_inner());
+                        let task = tokio::spawn(async move {
                             sleep(delay).await;
+                            // Held until the service is ready, and still cancellable while waiting
+                            let _permit = manager.acquire_start_permit().await;
+
+                            // Past this point the restart can't be cancelled; a
+                            // user action waits for the lock and runs after it
+                            if !claim_pending_restart(&pending, &name_clone) {
+                                return;
+                            }
+
                             let mut services = services_clone.write().await;
                             if let Some(service) = services.get_mut(&name_clone) {
-                                if let Err(e) = service.start().await {
+                                // Freezes and windows apply until the last moment
+                                if let Some(reason) = restart_suppressed(service, &frozen) {
+                                    info!("Not restarting service {}: {}", name_clone, reason);
+                                    return;
+                                }
+
+                                service.restart_count += 1;
+                                if quiet {
+                                    debug!(
+                                        "Restarting service {} (restart #{})",
+                                        name_clone, service.restart_count
+                                    );
+                                } else {
+                                    info!(
+                                        "Restarting service {} (restart #{})",
+                                        name_clone, service.restart_count
+                                    );
+                                }
+                                if service.apply_pending_unit() {
+                                    manager.units_changed();
+                                }
+                                let old_state = service.state;
+                                let started;
+                                (services, started) = start_unlocked(&services_clone, services, &name_clone).await;
+                                let Some(service) = services.get_mut(&name_clone) else {
+                                    return;
+                                };
+                                if service.state != old_state {
+                                    let reason =
+                                        format!("restarted by the supervisor (restart #{})", service.restart_count);
+                                    manager.record_transition(StateChange::new(
+                                        &name_clone,
+                                        old_state,
+                                        service.state,
+                                        Some(reason),
+                                    ));
+                                }
+                                if let Err(e) = started {
                                     error!("Failed to restart service {}: {}", name_clone, e);
+                                    return;
+                                }
+
+                                // Nothing else marks a `ReadyWhen` service running
+                                if service.state == ServiceState::Starting && !service.unit.is_oneshot() {
+                                    drop(services);
+                                    if let Err(e) = manager.wait_until_ready(&name_clone).await {
+                                        error!("Failed to restart service {}: {}", name_clone, e);
+                                    }
                                 }
                             }
                         });
+                        if let Some(previous) = restarts.insert(name.clone(), task.abort_handle()) {
+                            previous.abort();
+                        }
                     }
                 }
+
+                if service.transient && !service.is_active() && !self.has_pending_restart(name) {
+                    services.remove(name);
+                    self.units_changed();
+                    info!("Transient service {} has stopped, forgetting it", name);
+                }
+            }
+
+            // Handlers start through the manager, which needs the lock
+            for (name, handlers) in failures {
+                let manager = Arc::clone(&self);
+                tokio::spawn(async move {
+                    manager.activate_failure_handlers(&name, &handlers).await;
+                });
+            }
+        }
+    }
+}
+
+type PendingRestarts = Arc<Mutex<HashMap<String, AbortHandle>>>;
+
+type ServicesGuard<'a> = RwLockWriteGuard<'a, HashMap<String, Service>>;
+
+/// Start `name`, held by `services`, a write lock of `lock`. The lock is let
+/// go while its setup commands run, since one such as a migration may take a
+/// while, and taken again to spawn its main process, which is returned
+/// along with how the start went.
+async fn start_unlocked<'a>(
+    lock: &'a RwLock<HashMap<String, Service>>,
+    mut services: ServicesGuard<'a>,
+    name: &str,
+) -> (ServicesGuard<'a>, Result<()>) {
+    let setup = match services.get_mut(name) {
+        Some(service) if matches!(service.state, ServiceState::Running | ServiceState::Starting) => {
+            return (services, Ok(()))
+        }
+        Some(service) => service.begin_start(),
+        None => Err(DiakonosError::ServiceNotFound(name.to_string())),
+    };
+    let setup = match setup {
+        Ok(Some(setup)) => {
+            drop(services);
+            let ran = setup.run().await;
+            services = lock.write().await;
+            ran
+        }
+        Ok(None) => Ok(()),
+        Err(e) => return (services, Err(e)),
+    };
+
+    let started = match services.get_mut(name) {
+        Some(service) => service.finish_start(setup),
+        None => Err(DiakonosError::ServiceNotFound(name.to_string())),
+    };
+    (services, started)
+}
+
+/// Whether an active service other than `name` itself requires or wants it
+fn has_active_dependent(name: &str, services: &HashMap<String, Service>) -> bool {
+    services.iter().any(|(other, service)| {
+        other != name
+            && service.is_active()
+            && service
+                .unit
+                .dependencies()
+                .iter()
+                .any(|dep| resolve_alias(dep.strip_suffix(".service").unwrap_or(dep), services) == name)
+    })
+}
+
+/// Why the supervisor must not restart `service` now, if it must not
+fn restart_suppressed(service: &Service, frozen: &AtomicBool) -> Option<&'static str> {
+    if service.unsupervised {
+        Some("it was started with --no-supervise")
+    } else if frozen.load(Ordering::Relaxed) {
+        Some("restarts are frozen")
+    } else if !service.unit.in_restart_window(SystemTime::now()) {
+        Some("outside its RestartWindow")
+    } else {
+        None
+    }
+}
+
+/// Take the calling restart task's entry out of `pending`. Returns false if
+/// it was cancelled or replaced in the meantime.
+fn claim_pending_restart(pending: &PendingRestarts, name: &str) -> bool {
+    let mut pending = pending.lock().unwrap_or_else(|e| e.into_inner());
+    match pending.get(name) {
+        Some(handle) if handle.id() == tokio::task::id() => {
+            pending.remove(name);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// The name of the service `name` refers to, directly or as an `Alias`
+fn resolve_alias<'a>(name: &'a str, services: &'a HashMap<String, Service>) -> &'a str {
+    if services.contains_key(name) {
+        return name;
+    }
+
+    services
+        .values()
+        .find(|service| service.unit.has_alias(name))
+        .map_or(name, |service| service.unit.name.as_str())
+}
+
+/// `ServiceNotFound` for `name`, suggesting loaded services with similar names
+fn not_found(name: &str, services: &HashMap<String, Service>) -> DiakonosError {
+    DiakonosError::ServiceNotFound(format!("{}{}", name, did_you_mean(name, services)))
+}
+
+/// Up to three loaded services with names close to `name`, as
+/// ` (did you mean 'a' or 'b'?)`, or nothing if none are
+fn did_you_mean(name: &str, services: &HashMap<String, Service>) -> String {
+    // Allow about one typo per three characters
+    let max_distance = (name.chars().count() / 3).max(1);
+    let mut candidates: Vec<(usize, &String)> = services
+        .keys()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    candidates.sort();
+
+    let suggestions: Vec<String> = candidates
+        .iter()
+        .take(3)
+        .map(|(_, candidate)| format!("'{}'", candidate))
+        .collect();
+
+    match suggestions.split_last() {
+        None => String::new(),
+        Some((last, [])) => format!(" (did you mean {}?)", last),
+        Some((last, rest)) => format!(" (did you mean {} or {}?)", rest.join(", "), last),
+    }
+}
+
+/// Edits (insertions, deletions, substitutions, and swaps of adjacent
+/// characters) needed to turn `a` into `b`
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+
+    // rows[i][j] is the distance between the first i chars of a and j of b
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    rows[0] = (0..=b.len()).collect();
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+
+    rows[a.len()][b.len()]
+}
+
+/// Whether `entry` has the `KEY=value` form of an environment assignment
+fn is_env_assignment(entry: &str) -> bool {
+    matches!(entry.split_once('='), Some((key, _)) if !key.is_empty())
+}
+
+fn is_target(name: &str) -> bool {
+    name.ends_with(TARGET_SUFFIX)
+}
+
+/// Names of the services that are part of `target`, sorted
+fn target_members(target: &str, services: &HashMap<String, Service>) -> Result<Vec<String>> {
+    let mut members: Vec<String> = services
+        .values()
+        .filter(|service| service.unit.is_part_of(target))
+        .map(|service| service.unit.name.clone())
+        .collect();
+
+    if members.is_empty() {
+        return Err(DiakonosError::ServiceNotFound(target.to_string()));
+    }
+
+    members.sort();
+    Ok(members)
+}
+
+/// Services and the declared relations between them, for drawing
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct DependencyGraph {
+    /// Loaded services, sorted
+    pub services: Vec<String>,
+    /// Sorted by service, then dependency. A dependency need not be loaded.
+    pub edges: Vec<DependencyEdge>,
+}
+
+/// `from` names `to` in its `relation`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DependencyEdge {
+    pub from: String,
+    pub to: String,
+    pub relation: Relation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Relation {
+    Requires,
+    Wants,
+    After,
+}
+
+impl DependencyGraph {
+    /// The graph in Graphviz DOT, with an arrow from each service to what it
+    /// names: solid for `Requires`, dashed for `Wants` and dotted for `After`.
+    /// Units that aren't loaded are drawn in red.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph services {\n    rankdir=LR;\n    node [shape=box];\n");
+
+        for service in &self.services {
+            dot.push_str(&format!("    {};\n", dot_id(service)));
+        }
+
+        let mut missing: Vec<&String> = self
+            .edges
+            .iter()
+            .map(|edge| &edge.to)
+            .filter(|to| !self.services.contains(to))
+            .collect();
+        missing.sort();
+        missing.dedup();
+        for name in missing {
+            dot.push_str(&format!("    {} [color=red, fontcolor=red];\n", dot_id(name)));
+        }
+
+        for edge in &self.edges {
+            let style = match edge.relation {
+                Relation::Requires => "solid",
+                Relation::Wants => "dashed",
+                Relation::After => "dotted",
+            };
+            dot.push_str(&format!(
+                "    {} -> {} [style={}];\n",
+                dot_id(&edge.from),
+                dot_id(&edge.to),
+                style
+            ));
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+/// A unit that `service` names in its `relation` but that isn't loaded
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub service: String,
+    pub dependency: String,
+    pub relation: Relation,
+    /// The problem in words, following the service's name
+    pub message: String,
+}
+
+/// `name` as a quoted DOT identifier
+fn dot_id(name: &str) -> String {
+    format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// What a reload changed, or for a dry run, would change
+#[derive(Debug, Default)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub updated: Vec<String>,
+    /// Active services whose unit changed or was removed; not applied
+    pub pending: Vec<String>,
+    /// Active services whose changed unit a soft reload staged for their
+    /// next start
+    pub staged: Vec<String>,
+    /// Unit files that failed to parse; the previous version is kept
+    pub failed: Vec<String>,
+    pub unchanged: Vec<String>,
+    /// Nothing was applied
+    pub dry_run: bool,
+}
+
+impl std::fmt::Display for ReloadSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let labels = if self.dry_run {
+            [
+                "would add",
+                "would remove",
+                "would update",
+                "running, need a stop to apply",
+                "would apply at next restart",
+            ]
+        } else {
+            ["added", "removed", "updated", "running, not applied", "apply at next restart"]
+        };
+        let mut sections = vec![
+            (labels[0], &self.added),
+            (labels[1], &self.removed),
+            (labels[2], &self.updated),
+            (labels[3], &self.pending),
+            (labels[4], &self.staged),
+            ("failed to parse", &self.failed),
+        ];
+        // A real reload doesn't mention what it left alone
+        if self.dry_run {
+            sections.push(("unchanged", &self.unchanged));
+        }
+
+        let parts: Vec<String> = sections
+            .iter()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(label, names)| format!("{}: {}", label, names.join(", ")))
+            .collect();
+
+        if parts.is_empty() {
+            write!(f, "no changes")
+        } else {
+            write!(f, "{}", parts.join("; "))
+        }
+    }
+}
+
+/// What [`ServiceManager::apply`] did
+#[derive(Debug, Default)]
+pub struct ApplySummary {
+    /// The reload of the service directory, with the units of stopped and
+    /// restarted services applied
+    pub reload: ReloadSummary,
+    /// Services that start on boot and weren't active
+    pub started: Vec<String>,
+    /// Active services whose unit was removed
+    pub stopped: Vec<String>,
+    /// Active services whose unit changed
+    pub restarted: Vec<String>,
+    /// Transient services with a unit file of their name, left running
+    pub skipped: Vec<String>,
+    /// Services a stop or start failed for, and why
+    pub errors: Vec<(String, String)>,
+}
+
+impl ApplySummary {
+    /// Whether everything was applied
+    pub fn succeeded(&self) -> bool {
+        self.errors.is_empty() && self.reload.failed.is_empty()
+    }
+}
+
+impl std::fmt::Display for ApplySummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let errors: Vec<String> = self
+            .errors
+            .iter()
+            .map(|(name, error)| format!("{} ({})", name, error))
+            .collect();
+        let sections = [
+            ("added", &self.reload.added),
+            ("updated", &self.reload.updated),
+            ("stopped", &self.stopped),
+            ("removed", &self.reload.removed),
+            ("restarted", &self.restarted),
+            ("started", &self.started),
+            ("transient, left running", &self.skipped),
+            ("failed to parse", &self.reload.failed),
+            ("failed", &errors),
+        ];
+
+        let parts: Vec<String> = sections
+            .iter()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(label, names)| format!("{}: {}", label, names.join(", ")))
+            .collect();
+
+        if parts.is_empty() {
+            write!(f, "no changes")
+        } else {
+            write!(f, "{}", parts.join("; "))
+        }
+    }
+}
+
+/// Tracks the most recent transition of each service so that a service
+/// flapping between the same two states is logged once per window with a
+/// count, rather than once per transition.
+#[derive(Default)]
+struct TransitionLog {
+    last: HashMap<String, LastTransition>,
+}
+
+struct LastTransition {
+    from: ServiceState,
+    to: ServiceState,
+    window_start: Instant,
+    repeats: u32,
+}
+
+impl TransitionLog {
+    /// Record a transition, logging it unless it repeats the previous one
+    /// within the flap window. Returns whether it was logged.
+    fn record(&mut self, name: &str, from: ServiceState, to: ServiceState, detail: &str) -> bool {
+        if let Some(last) = self.last.get_mut(name) {
+            if last.from == from && last.to == to {
+                if last.window_start.elapsed() < FLAP_WINDOW {
+                    last.repeats += 1;
+                    return false;
+                }
+
+                if last.repeats > 0 {
+                    warn!(
+                        "Service {} is flapping: {} -> {} {} more times in the last {:?}{}",
+                        name, from, to, last.repeats, FLAP_WINDOW, detail
+                    );
+                    last.window_start = Instant::now();
+                    last.repeats = 0;
+                    return true;
+                }
+            } else if last.repeats > 0 {
+                warn!(
+                    "Service {} flapped {} -> {} {} more times before settling",
+                    name, last.from, last.to, last.repeats
+                );
             }
         }
+
+        info!("Service {} changed state: {} -> {}{}", name, from, to, detail);
+        self.last.insert(
+            name.to_string(),
+            LastTransition {
+                from,
+                to,
+                window_start: Instant::now(),
+                repeats: 0,
+            },
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_stuck_status_check_does_not_hold_up_other_services() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(dir.path().join("stuck.service"), "[unit]\n[service]\nExecStart = \"sleep 30\"\n").unwrap();
+        std::fs::write(
+            dir.path().join("flaky.service"),
+            "[unit]\n[service]\nExecStart = \"false\"\nRestart = \"always\"\nRestartSec = 0\n",
+        )
+        .unwrap();
+        let manager = Arc::new(ServiceManager::new(&DaemonConfig {
+            service_dir: dir.path().to_path_buf(),
+            log_dir: dir.path().join("logs"),
+            audit_log: dir.path().join("audit.log"),
+            history_file: dir.path().join("history.jsonl"),
+            supervision_interval: 1,
+            ..DaemonConfig::default()
+        }));
+        manager.load_all_services().await.unwrap();
+        manager.start_service("stuck").await.unwrap();
+
+        // Checking on "stuck" blocks until the lock on its process is let go
+        let process = manager.services.read().await["stuck"].process_lock().unwrap();
+        let (locked, release) = (std::sync::mpsc::channel(), std::sync::mpsc::channel::<()>());
+        let holder = std::thread::spawn(move || {
+            let _child = process.lock().unwrap();
+            locked.0.send(()).unwrap();
+            let _ = release.1.recv();
+        });
+        locked.1.recv().unwrap();
+
+        manager.start_service("flaky").await.unwrap();
+        let supervisor = tokio::spawn(Arc::clone(&manager).supervise());
+        let restarted = async {
+            loop {
+                let stats = manager.service_stats().await;
+                if stats.iter().any(|stats| stats.name == "flaky" && stats.restart_count > 0) {
+                    break;
+                }
+                sleep(Duration::from_millis(100)).await;
+            }
+        };
+        let outcome = tokio::time::timeout(STATUS_CHECK_TIMEOUT * 3, restarted).await;
+
+        release.0.send(()).unwrap();
+        holder.join().unwrap();
+        supervisor.abort();
+        manager.stop_all().await;
+        assert!(outcome.is_ok(), "flaky was never restarted while stuck was being checked");
     }
 }
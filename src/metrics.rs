@@ -0,0 +1,188 @@
+use crate::manager::ServiceManager;
+use crate::service::{ServiceState, ServiceStats};
+use nix::unistd::{sysconf, SysconfVar};
+use std::fmt::Write as _;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{error, info, warn};
+
+/// Largest request head we are willing to buffer from a scraper
+const MAX_REQUEST_BYTES: usize = 8 * 1024;
+
+const STATES: [ServiceState; 5] = [
+    ServiceState::Stopped,
+    ServiceState::Starting,
+    ServiceState::Running,
+    ServiceState::Stopping,
+    ServiceState::Failed,
+];
+
+/// CPU and memory usage of a process as reported by `/proc`
+#[derive(Debug, Clone, Copy)]
+pub struct ProcessUsage {
+    pub cpu_seconds: f64,
+    pub memory_bytes: u64,
+}
+
+/// Serve `/metrics` in Prometheus text format on `addr`
+pub async fn serve_metrics(addr: SocketAddr, manager: Arc<ServiceManager>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind metrics endpoint on {}: {}", addr, e);
+            return;
+        }
+    };
+
+    info!("Serving metrics on http://{}/metrics", addr);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let manager = Arc::clone(&manager);
+                tokio::spawn(async move {
+                    if let Err(e) = handle_scrape(stream, &manager).await {
+                        warn!("Error serving metrics: {}", e);
+                    }
+                });
+            }
+            Err(e) => {
+                error!("Error accepting metrics connection: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_scrape(mut stream: TcpStream, manager: &ServiceManager) -> std::io::Result<()> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 || request.len() + n > MAX_REQUEST_BYTES {
+            return Ok(());
+        }
+        request.extend_from_slice(&buf[..n]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split_whitespace();
+
+    let (status, body) = match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            ("200 OK", render_metrics(&manager.service_stats().await))
+        }
+        _ => ("404 Not Found", "Not Found\n".to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+fn render_metrics(stats: &[ServiceStats]) -> String {
+    let mut out = String::new();
+
+    out.push_str("# HELP diakonos_service_state Current state of the service (1 for the active state)\n");
+    out.push_str("# TYPE diakonos_service_state gauge\n");
+    for s in stats {
+        for state in STATES {
+            let _ = writeln!(
+                out,
+                "diakonos_service_state{{service=\"{}\",state=\"{}\"}} {}",
+                escape_label(&s.name),
+                state,
+                u8::from(s.state == state)
+            );
+        }
+    }
+
+    out.push_str("# HELP diakonos_service_restarts_total Automatic restarts performed by the supervisor\n");
+    out.push_str("# TYPE diakonos_service_restarts_total counter\n");
+    for s in stats {
+        let _ = writeln!(
+            out,
+            "diakonos_service_restarts_total{{service=\"{}\"}} {}",
+            escape_label(&s.name),
+            s.restart_count
+        );
+    }
+
+    out.push_str("# HELP diakonos_service_uptime_seconds Time since the current process started\n");
+    out.push_str("# TYPE diakonos_service_uptime_seconds gauge\n");
+    for s in stats {
+        let _ = writeln!(
+            out,
+            "diakonos_service_uptime_seconds{{service=\"{}\"}} {}",
+            escape_label(&s.name),
+            s.uptime_secs.unwrap_or(0)
+        );
+    }
+
+    let usage: Vec<(&ServiceStats, ProcessUsage)> = stats
+        .iter()
+        .filter_map(|s| Some((s, process_usage(s.pid?)?)))
+        .collect();
+
+    if !usage.is_empty() {
+        out.push_str("# HELP diakonos_service_cpu_seconds_total CPU time consumed by the main process\n");
+        out.push_str("# TYPE diakonos_service_cpu_seconds_total counter\n");
+        for (s, u) in &usage {
+            let _ = writeln!(
+                out,
+                "diakonos_service_cpu_seconds_total{{service=\"{}\"}} {}",
+                escape_label(&s.name),
+                u.cpu_seconds
+            );
+        }
+
+        out.push_str("# HELP diakonos_service_memory_bytes Resident memory of the main process\n");
+        out.push_str("# TYPE diakonos_service_memory_bytes gauge\n");
+        for (s, u) in &usage {
+            let _ = writeln!(
+                out,
+                "diakonos_service_memory_bytes{{service=\"{}\"}} {}",
+                escape_label(&s.name),
+                u.memory_bytes
+            );
+        }
+    }
+
+    out
+}
+
+/// Read CPU time and resident memory for `pid` from `/proc`. Returns `None`
+/// where procfs is unavailable or the process has gone away.
+pub fn process_usage(pid: u32) -> Option<ProcessUsage> {
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let statm = std::fs::read_to_string(format!("/proc/{}/statm", pid)).ok()?;
+
+    // The command name may contain spaces, so fields are counted after its closing paren
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let utime: u64 = fields.get(11)?.parse().ok()?;
+    let stime: u64 = fields.get(12)?.parse().ok()?;
+    let resident_pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+
+    let ticks = sysconf(SysconfVar::CLK_TCK).ok()??;
+    let page_size = sysconf(SysconfVar::PAGE_SIZE).ok()??;
+
+    Some(ProcessUsage {
+        cpu_seconds: (utime + stime) as f64 / ticks as f64,
+        memory_bytes: resident_pages * page_size as u64,
+    })
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
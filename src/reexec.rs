@@ -0,0 +1,149 @@
+use crate::error::{DiakonosError, Result};
+use crate::service::ServiceState;
+use crate::unit::UnitFile;
+use serde::{Deserialize, Serialize};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{info, warn};
+
+/// Set for the re-executed daemon to the path of its handoff file
+pub const HANDOFF_ENV: &str = "DIAKONOS_HANDOFF";
+
+/// What the daemon passes to the build of itself it execs on `SIGUSR2`.
+/// Being the same process, the new daemon is still the parent of every
+/// service, so it adopts them by PID, takes over the pipes carrying their
+/// output and keeps serving on the same listening socket.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Handoff {
+    /// The control socket's listening descriptor, kept open across the exec
+    pub listener: RawFd,
+    pub restarts_frozen: bool,
+    pub services: Vec<AdoptedService>,
+}
+
+/// A service whose process the new daemon takes over
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AdoptedService {
+    pub name: String,
+    pub source_path: Option<PathBuf>,
+    /// The unit the process was started with, which may differ from its file
+    pub unit: UnitFile,
+    pub transient: bool,
+    pub state: ServiceState,
+    pub pid: u32,
+    pub restart_count: u32,
+    pub uptime_ms: u64,
+    /// When the process was started, by the system clock
+    #[serde(default)]
+    pub start_time: Option<std::time::SystemTime>,
+    pub environment_overrides: Vec<String>,
+    pub handling_failure_of: Option<String>,
+    #[serde(default)]
+    pub unsupervised: bool,
+    /// Read ends of the pipes carrying the process's output, kept open
+    /// across the exec
+    pub pipes: Vec<RawFd>,
+}
+
+/// Whether this process was started by a daemon re-executing itself
+pub fn is_reexec() -> bool {
+    std::env::var_os(HANDOFF_ENV).is_some()
+}
+
+/// Read and remove the handoff file left by the daemon this one replaced, if
+/// any. The variable pointing to it is cleared so services don't inherit it.
+pub fn take_handoff() -> Option<Handoff> {
+    let path = PathBuf::from(std::env::var_os(HANDOFF_ENV)?);
+    std::env::remove_var(HANDOFF_ENV);
+
+    let content = std::fs::read_to_string(&path);
+    let _ = std::fs::remove_file(&path);
+    match content.map(|content| serde_json::from_str(&content)) {
+        Ok(Ok(handoff)) => Some(handoff),
+        Ok(Err(e)) => {
+            warn!("Ignoring unreadable handoff file {:?}: {}", path, e);
+            None
+        }
+        Err(e) => {
+            warn!("Failed to read handoff file {:?}: {}", path, e);
+            None
+        }
+    }
+}
+
+/// Write a handoff of `services` to `path` and exec the daemon's binary in
+/// place of this process, passing it the control socket `listener`. Only
+/// returns if that fails, in which case the daemon carries on as it was.
+pub fn exec(
+    listener: RawFd,
+    services: Vec<AdoptedService>,
+    restarts_frozen: bool,
+    path: &Path,
+) -> DiakonosError {
+    // Descriptors passed along are closed again if the exec fails
+    let passed: Vec<OwnedFd> = services
+        .iter()
+        .flat_map(|service| service.pipes.iter())
+        // SAFETY: `Service::hand_off` dup'd each pipe for this handoff alone,
+        // so it is open, nothing else owns it, and it is taken only here
+        .map(|&fd| unsafe { OwnedFd::from_raw_fd(fd) })
+        .collect();
+    let listener = match nix::unistd::dup(listener) {
+        // SAFETY: dup'd just now, so it is open and owned by nothing else
+        Ok(listener) => unsafe { OwnedFd::from_raw_fd(listener) },
+        Err(e) => return DiakonosError::StartError(format!("Failed to pass on the socket: {}", e)),
+    };
+
+    let handoff = Handoff {
+        listener: listener.as_raw_fd(),
+        restarts_frozen,
+        services,
+    };
+    let exe = match write_handoff(&handoff, path).and_then(|_| current_exe()) {
+        Ok(exe) => exe,
+        Err(e) => return e,
+    };
+
+    info!(
+        "Re-executing {:?}, handing off {} running services",
+        exe,
+        handoff.services.len()
+    );
+    let error = Command::new(&exe)
+        .args(std::env::args_os().skip(1))
+        .env(HANDOFF_ENV, path)
+        .exec();
+
+    let _ = std::fs::remove_file(path);
+    drop(passed);
+    DiakonosError::StartError(format!("Failed to execute {:?}: {}", exe, error))
+}
+
+fn write_handoff(handoff: &Handoff, path: &Path) -> Result<()> {
+    let content = serde_json::to_string(handoff)
+        .map_err(|e| DiakonosError::StartError(format!("Failed to encode handoff: {}", e)))?;
+
+    // Holds the unit of every service, so it is private like them
+    let _ = std::fs::remove_file(path);
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    std::io::Write::write_all(&mut file, content.as_bytes())?;
+    Ok(())
+}
+
+/// The daemon's binary. After an upgrade replaced it on disk, Linux reports
+/// the old file as `<path> (deleted)`; the new file at `<path>` is wanted.
+fn current_exe() -> Result<PathBuf> {
+    let exe = std::env::current_exe()?;
+    let exe = match exe.to_str().and_then(|exe| exe.strip_suffix(" (deleted)")) {
+        Some(path) => PathBuf::from(path),
+        None => exe,
+    };
+    Ok(exe)
+}
@@ -0,0 +1,928 @@
+use diakonos::doctor::{Check, Outcome};
+use diakonos::ipc::{BatchResult, DaemonInfo, Response};
+use diakonos::logs::LogUsage;
+use diakonos::manager::{Diagnostic, Relation};
+use diakonos::metrics::{process_usage, ProcessUsage};
+use diakonos::service::{ServiceDescription, ServiceState, ServiceStats};
+use diakonos::theme::StateColors;
+use diakonos::webhook::StateChange;
+use clap::ValueEnum;
+use serde_json::json;
+use std::io::IsTerminal;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// How command results are printed
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum OutputFormat {
+    /// Aligned, colored tables for people
+    #[default]
+    Table,
+    /// JSON for programs
+    Json,
+    /// Tab-separated fields, one record per line, for shell scripts
+    Plain,
+}
+
+/// When tables are colored
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum ColorMode {
+    /// When stdout is a terminal and `NO_COLOR` isn't set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+/// The colors of service states, or `None` for plain text; set once by `set_colors`
+static STATE_COLORS: OnceLock<Option<StateColors>> = OnceLock::new();
+
+/// Color tables with `colors` as `mode` says. Must be called before anything is printed.
+pub fn set_colors(mode: ColorMode, colors: StateColors) {
+    let enabled = match mode {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => {
+            std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty())
+        }
+    };
+    let _ = STATE_COLORS.set(enabled.then_some(colors));
+}
+
+/// The colors of service states, or `None` if output is plain
+fn state_colors() -> Option<&'static StateColors> {
+    STATE_COLORS.get_or_init(|| Some(StateColors::default())).as_ref()
+}
+
+/// What `deps` prints
+#[derive(Debug, Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum DepsFormat {
+    /// One service's dependencies in start order
+    #[default]
+    List,
+    /// Every service's dependencies as a Graphviz DOT graph
+    Dot,
+}
+
+/// Exit code of a `wait-for` that timed out, as with timeout(1); errors exit 1
+const TIMED_OUT_EXIT_CODE: i32 = 124;
+
+/// Print a progress message of a long operation. It goes to stderr, so
+/// stdout holds only the result.
+pub fn progress(message: &str, quiet: bool) {
+    if !quiet {
+        eprintln!("… {}", message);
+    }
+}
+
+/// Print a response: results on stdout, errors on stderr. `quiet` suppresses
+/// confirmations but never the data a command was asked to show.
+pub fn print_response(response: Response, format: OutputFormat, quiet: bool) {
+    match response {
+        Response::Ok { message } => {
+            if !quiet {
+                println!("✓ {}", message);
+            }
+        }
+        Response::Error { message } => {
+            eprintln!("✗ Error: {}", message);
+            std::process::exit(1);
+        }
+        Response::TimedOut { message } => {
+            eprintln!("✗ {}", message);
+            std::process::exit(TIMED_OUT_EXIT_CODE);
+        }
+        Response::Progress { message } => progress(&message, quiet),
+        Response::Status { service, state } => status(&service, state, format),
+        Response::Pid { service, pid } => print_pid(&service, pid, format),
+        Response::List { services } | Response::ListPage { services, .. } => list(&services, format),
+        Response::Stats { stats: s } => stats(&s, format),
+        Response::Deps {
+            service,
+            dependencies,
+        } => deps(&service, &dependencies, format),
+        Response::DependencyGraph { graph } => match format {
+            OutputFormat::Json => print_json(&graph),
+            OutputFormat::Table | OutputFormat::Plain => print!("{}", graph.to_dot()),
+        },
+        Response::Diagnostics { diagnostics: d } => diagnostics(&d, format, quiet),
+        Response::Describe { description } => describe(&description, format),
+        Response::Config { service, key, value } => config(&service, &key, value.as_deref(), format),
+        Response::Logs { lines, .. } => {
+            for line in lines {
+                println!("{}", line);
+            }
+        }
+        Response::InterleavedLogs { lines } => interleaved_logs(&lines, format),
+        Response::LogsDropped { lines } => {
+            eprintln!("... {} lines dropped: output arrived faster than it was read", lines)
+        }
+        Response::History { transitions } => history(&transitions, format),
+        Response::Batch { results } => batch(&results, format, quiet),
+        Response::LogUsage { usage, max_bytes } => log_usage(&usage, max_bytes, format),
+        Response::DaemonInfo { info } => {
+            println!("✓ Daemon is running");
+            daemon_details(&info);
+        }
+        Response::Pong => {
+            if !quiet {
+                println!("Daemon is alive");
+            }
+        }
+    }
+}
+
+fn status(service: &str, state: ServiceState, format: OutputFormat) {
+    match format {
+        OutputFormat::Table => println!("Service '{}' status: {}", service, state),
+        OutputFormat::Json => print_json(&json!({ "service": service, "state": state })),
+        OutputFormat::Plain => println!("{}\t{}", service, state),
+    }
+}
+
+/// The one-line status `status --watch` redraws: state, PID, uptime and restarts
+pub fn status_line(description: &ServiceDescription) -> String {
+    let mut line = format!("{}  {}", description.name, colored_state(description.state, 0));
+    if let Some(pid) = description.pid {
+        line.push_str(&format!("  PID {}", pid));
+    }
+    if let Some(uptime) = description.uptime_secs {
+        line.push_str(&format!("  up {}", compact_duration(uptime)));
+    }
+    let plural = if description.restart_count == 1 { "" } else { "s" };
+    line.push_str(&format!("  {} restart{}", description.restart_count, plural));
+    line
+}
+
+/// Print just the PID, for scripts, exiting non-zero if there is none
+fn print_pid(service: &str, pid: Option<u32>, format: OutputFormat) {
+    match (format, pid) {
+        (OutputFormat::Json, _) => print_json(&json!({ "service": service, "pid": pid })),
+        (_, Some(pid)) => println!("{}", pid),
+        (_, None) => eprintln!("✗ Error: service '{}' is not running", service),
+    }
+
+    if pid.is_none() {
+        std::process::exit(1);
+    }
+}
+
+/// Print a setting's value alone, for scripts; an unset one is empty but for
+/// the table format
+fn config(service: &str, key: &str, value: Option<&str>, format: OutputFormat) {
+    match (format, value) {
+        (OutputFormat::Json, _) => print_json(&json!({ "service": service, "key": key, "value": value })),
+        (_, Some(value)) => println!("{}", value),
+        (OutputFormat::Table, None) => println!("(not set)"),
+        (OutputFormat::Plain, None) => println!(),
+    }
+}
+
+fn list(services: &[(String, ServiceState)], format: OutputFormat) {
+    match format {
+        OutputFormat::Table => {
+            if services.is_empty() {
+                println!("No services loaded");
+                return;
+            }
+
+            println!("\nLoaded services:");
+            println!("{:<30} {:<15}", "SERVICE", "STATE");
+            println!("{}", "-".repeat(45));
+
+            for (name, state) in services {
+                println!("{:<30} {}", name, colored_state(*state, 15));
+            }
+        }
+        OutputFormat::Json => {
+            let services: Vec<_> = services
+                .iter()
+                .map(|(name, state)| json!({ "service": name, "state": state }))
+                .collect();
+            print_json(&services);
+        }
+        OutputFormat::Plain => {
+            for (name, state) in services {
+                println!("{}\t{}", name, state);
+            }
+        }
+    }
+}
+
+fn stats(stats: &[ServiceStats], format: OutputFormat) {
+    match format {
+        OutputFormat::Table => {
+            if stats.is_empty() {
+                println!("No services loaded");
+                return;
+            }
+
+            println!(
+                "{:<30} {:<15} {:<8} {:<10} {:<15}",
+                "SERVICE", "STATE", "PID", "RESTARTS", "UPTIME"
+            );
+            println!("{}", "-".repeat(78));
+
+            for s in stats {
+                println!(
+                    "{:<30} {} {:<8} {:<10} {}",
+                    s.name,
+                    colored_state(s.state, 15),
+                    s.pid.map(|p| p.to_string()).unwrap_or_else(|| "-".to_string()),
+                    s.restart_count,
+                    s.uptime_secs
+                        .map(|u| humantime::format_duration(Duration::from_secs(u)).to_string())
+                        .unwrap_or_else(|| "-".to_string())
+                );
+            }
+        }
+        OutputFormat::Json => print_json(&stats),
+        OutputFormat::Plain => {
+            // Absent values are empty fields so columns stay aligned for `cut`
+            for s in stats {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    s.name,
+                    s.state,
+                    s.pid.map(|p| p.to_string()).unwrap_or_default(),
+                    s.restart_count,
+                    s.uptime_secs.map(|u| u.to_string()).unwrap_or_default()
+                );
+            }
+        }
+    }
+}
+
+/// Print one row per service for `ps`, with the CPU time and resident memory
+/// of each main process. Columns are as wide as their widest value; on a
+/// terminal too narrow for all of them, the rightmost go first, down to the
+/// name and state.
+pub fn ps(stats: &[ServiceStats], format: OutputFormat) {
+    let usage: Vec<Option<ProcessUsage>> = stats
+        .iter()
+        .map(|s| s.pid.and_then(process_usage))
+        .collect();
+
+    match format {
+        OutputFormat::Table => {
+            if stats.is_empty() {
+                println!("No services loaded");
+                return;
+            }
+
+            let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+            // Header, whether right-aligned, and a cell per service
+            let columns: [(&str, bool, Vec<String>); 7] = [
+                ("NAME", false, stats.iter().map(|s| s.name.clone()).collect()),
+                ("STATE", false, stats.iter().map(|s| s.state.to_string()).collect()),
+                ("PID", true, stats.iter().map(|s| or_dash(s.pid.map(|p| p.to_string()))).collect()),
+                (
+                    "UPTIME",
+                    true,
+                    stats
+                        .iter()
+                        .map(|s| or_dash(s.uptime_secs.map(compact_duration)))
+                        .collect(),
+                ),
+                ("RESTARTS", true, stats.iter().map(|s| s.restart_count.to_string()).collect()),
+                (
+                    "CPU",
+                    true,
+                    usage.iter().map(|u| or_dash(u.map(|u| cpu_time(u.cpu_seconds)))).collect(),
+                ),
+                (
+                    "MEM",
+                    true,
+                    usage.iter().map(|u| or_dash(u.map(|u| human_bytes(u.memory_bytes)))).collect(),
+                ),
+            ];
+
+            let widths: Vec<usize> = columns
+                .iter()
+                .map(|(header, _, cells)| {
+                    cells
+                        .iter()
+                        .map(|cell| cell.chars().count())
+                        .chain([header.len()])
+                        .max()
+                        .unwrap_or(0)
+                })
+                .collect();
+
+            let shown = columns_that_fit(&widths, terminal_width().unwrap_or(usize::MAX));
+
+            let line = |cell: &dyn Fn(usize) -> String| {
+                let cells: Vec<String> = (0..shown).map(cell).collect();
+                println!("{}", cells.join("  ").trim_end());
+            };
+            let align = |column: usize, text: &str| {
+                let width = widths[column];
+                if columns[column].1 {
+                    format!("{:>width$}", text)
+                } else {
+                    format!("{:<width$}", text)
+                }
+            };
+
+            line(&|column| align(column, columns[column].0));
+            for (row, s) in stats.iter().enumerate() {
+                line(&|column| match column {
+                    1 => colored_state(s.state, widths[1]),
+                    _ => align(column, &columns[column].2[row]),
+                });
+            }
+        }
+        OutputFormat::Json => print_json(
+            &stats
+                .iter()
+                .zip(&usage)
+                .map(|(s, u)| {
+                    json!({
+                        "name": s.name,
+                        "state": s.state,
+                        "pid": s.pid,
+                        "uptime_secs": s.uptime_secs,
+                        "restart_count": s.restart_count,
+                        "cpu_seconds": u.map(|u| u.cpu_seconds),
+                        "memory_bytes": u.map(|u| u.memory_bytes),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        ),
+        OutputFormat::Plain => {
+            // In the order of the table's columns
+            for (s, u) in stats.iter().zip(&usage) {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    s.name,
+                    s.state,
+                    s.pid.map(|p| p.to_string()).unwrap_or_default(),
+                    s.uptime_secs.map(|u| u.to_string()).unwrap_or_default(),
+                    s.restart_count,
+                    u.map(|u| u.cpu_seconds.to_string()).unwrap_or_default(),
+                    u.map(|u| u.memory_bytes.to_string()).unwrap_or_default()
+                );
+            }
+        }
+    }
+}
+
+/// How many of the columns `widths`, from the left and two spaces apart, fit
+/// in `available` columns, never fewer than two: the name and state
+fn columns_that_fit(widths: &[usize], available: usize) -> usize {
+    let mut shown = widths.len();
+    while shown > 2 && widths[..shown].iter().sum::<usize>() + 2 * (shown - 1) > available {
+        shown -= 1;
+    }
+    shown
+}
+
+/// `secs` in its two largest units, such as `3d4h` or `5m12s`
+fn compact_duration(secs: u64) -> String {
+    let (days, hours, minutes, seconds) = (secs / 86_400, secs / 3_600 % 24, secs / 60 % 60, secs % 60);
+    match (days, hours, minutes) {
+        (0, 0, 0) => format!("{}s", seconds),
+        (0, 0, _) => format!("{}m{}s", minutes, seconds),
+        (0, _, _) => format!("{}h{}m", hours, minutes),
+        _ => format!("{}d{}h", days, hours),
+    }
+}
+
+/// CPU time to a tenth of a second while it's under a minute
+fn cpu_time(secs: f64) -> String {
+    if secs < 60.0 {
+        format!("{:.1}s", secs)
+    } else {
+        compact_duration(secs as u64)
+    }
+}
+
+nix::ioctl_read_bad!(window_size, nix::libc::TIOCGWINSZ, nix::libc::winsize);
+
+/// Columns to fit output into: `COLUMNS` if it is set, else the width of the
+/// terminal on stdout. `None` when stdout isn't a terminal, as when piped.
+fn terminal_width() -> Option<usize> {
+    if let Some(columns) = std::env::var("COLUMNS").ok().and_then(|c| c.parse().ok()) {
+        return Some(columns);
+    }
+
+    let mut size = nix::libc::winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    match unsafe { window_size(std::io::stdout().as_raw_fd(), &mut size) } {
+        Ok(_) if size.ws_col > 0 => Some(size.ws_col as usize),
+        _ => None,
+    }
+}
+
+/// Print the outcome for each service, exiting non-zero if any failed
+fn batch(results: &[BatchResult], format: OutputFormat, quiet: bool) {
+    match format {
+        OutputFormat::Table => {
+            for result in results {
+                if !result.success {
+                    eprintln!("✗ Error: {}", result.message);
+                } else if !quiet {
+                    println!("✓ {}", result.message);
+                }
+            }
+        }
+        OutputFormat::Json => print_json(&results),
+        OutputFormat::Plain => {
+            for result in results {
+                let outcome = if result.success { "ok" } else { "failed" };
+                println!("{}\t{}\t{}", result.service, outcome, result.message);
+            }
+        }
+    }
+
+    if results.iter().any(|result| !result.success) {
+        std::process::exit(1);
+    }
+}
+
+fn history(transitions: &[StateChange], format: OutputFormat) {
+    match format {
+        OutputFormat::Table => {
+            if transitions.is_empty() {
+                println!("No transitions recorded");
+                return;
+            }
+
+            println!("{:<22} {:<30} {:<21} REASON", "TIME", "SERVICE", "TRANSITION");
+            println!("{}", "-".repeat(90));
+
+            for t in transitions {
+                println!(
+                    "{:<22} {:<30} {:<8} -> {} {}",
+                    t.timestamp,
+                    t.service,
+                    t.old_state.to_string(),
+                    colored_state(t.new_state, 8),
+                    t.exit_reason.as_deref().unwrap_or("")
+                );
+            }
+        }
+        OutputFormat::Json => print_json(&transitions),
+        OutputFormat::Plain => {
+            for t in transitions {
+                println!(
+                    "{}\t{}\t{}\t{}\t{}",
+                    t.timestamp,
+                    t.service,
+                    t.old_state,
+                    t.new_state,
+                    t.exit_reason.as_deref().unwrap_or("")
+                );
+            }
+        }
+    }
+}
+
+fn log_usage(usage: &[LogUsage], max_bytes: Option<u64>, format: OutputFormat) {
+    match format {
+        OutputFormat::Table => {
+            if usage.is_empty() {
+                println!("No service logs");
+                return;
+            }
+
+            println!("{:<30} {:>12} {:>12}", "SERVICE", "LOG", "ROTATED");
+            println!("{}", "-".repeat(56));
+
+            for u in usage {
+                println!(
+                    "{:<30} {:>12} {:>12}",
+                    u.service,
+                    human_bytes(u.bytes),
+                    human_bytes(u.rotated_bytes)
+                );
+            }
+
+            let total: u64 = usage.iter().map(|u| u.bytes + u.rotated_bytes).sum();
+            match max_bytes {
+                Some(max) => println!("\nTotal: {} of {} allowed", human_bytes(total), human_bytes(max)),
+                None => println!("\nTotal: {} (no max_log_bytes configured)", human_bytes(total)),
+            }
+        }
+        OutputFormat::Json => print_json(&json!({ "services": usage, "max_bytes": max_bytes })),
+        OutputFormat::Plain => {
+            for u in usage {
+                println!("{}\t{}\t{}", u.service, u.bytes, u.rotated_bytes);
+            }
+        }
+    }
+}
+
+/// `bytes` in the largest binary unit that keeps it at or above one
+fn human_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+fn deps(service: &str, dependencies: &[String], format: OutputFormat) {
+    match format {
+        OutputFormat::Table => {
+            if dependencies.is_empty() {
+                println!("'{}' has no dependencies", service);
+                return;
+            }
+
+            println!("Dependencies of '{}', in start order:", service);
+            for dep in dependencies {
+                println!("  {}", dep);
+            }
+        }
+        OutputFormat::Json => {
+            print_json(&json!({ "service": service, "dependencies": dependencies }))
+        }
+        OutputFormat::Plain => {
+            for dep in dependencies {
+                println!("{}", dep);
+            }
+        }
+    }
+}
+
+/// Print each problem found, exiting non-zero if there are any
+fn diagnostics(diagnostics: &[Diagnostic], format: OutputFormat, quiet: bool) {
+    match format {
+        OutputFormat::Table => {
+            if diagnostics.is_empty() && !quiet {
+                println!("✓ Every dependency refers to a loaded unit");
+            }
+            for diagnostic in diagnostics {
+                println!("⚠ {} {}", diagnostic.service, diagnostic.message);
+            }
+        }
+        OutputFormat::Json => print_json(&diagnostics),
+        OutputFormat::Plain => {
+            for diagnostic in diagnostics {
+                let relation = match diagnostic.relation {
+                    Relation::Requires => "requires",
+                    Relation::Wants => "wants",
+                    Relation::After => "after",
+                };
+                println!("{}\t{}\t{}", diagnostic.service, relation, diagnostic.dependency);
+            }
+        }
+    }
+
+    if !diagnostics.is_empty() {
+        std::process::exit(1);
+    }
+}
+
+/// Print the outcome of each of `doctor`'s checks, exiting non-zero if any failed
+pub fn checks(checks: &[Check], format: OutputFormat, quiet: bool) {
+    match format {
+        OutputFormat::Table => {
+            for check in checks {
+                let symbol = match check.outcome {
+                    Outcome::Pass if quiet => continue,
+                    Outcome::Pass => "✓",
+                    Outcome::Warn => "⚠",
+                    Outcome::Fail => "✗",
+                };
+                println!("{} {}", symbol, check.message);
+                if let Some(ref hint) = check.hint {
+                    println!("  hint: {}", hint);
+                }
+            }
+        }
+        OutputFormat::Json => print_json(&checks),
+        OutputFormat::Plain => {
+            for check in checks {
+                let outcome = match check.outcome {
+                    Outcome::Pass => "pass",
+                    Outcome::Warn => "warn",
+                    Outcome::Fail => "fail",
+                };
+                println!("{}\t{}\t{}", outcome, check.message, check.hint.as_deref().unwrap_or(""));
+            }
+        }
+    }
+
+    if checks.iter().any(|check| check.outcome == Outcome::Fail) {
+        std::process::exit(1);
+    }
+}
+
+/// Print the daemon's status along with the client-side paths used to reach it
+pub fn daemon_info(info: &DaemonInfo, socket_path: &Path, pid_file: &Path, format: OutputFormat) {
+    match format {
+        OutputFormat::Table => {
+            println!("✓ Daemon is running");
+            daemon_details(info);
+            println!("  Socket:            {}", socket_path.display());
+            println!("  PID file:          {}", pid_file.display());
+        }
+        OutputFormat::Json => print_json(&json!({
+            "version": info.version,
+            "pid": info.pid,
+            "uptime_secs": info.uptime_secs,
+            "service_dir": info.service_dir,
+            "services": info.services,
+            "running": info.running,
+            "failed": info.failed,
+            "restarts_frozen": info.restarts_frozen,
+            "open_connections": info.open_connections,
+            "socket_path": socket_path,
+            "pid_file": pid_file,
+        })),
+        OutputFormat::Plain => {
+            println!("version\t{}", info.version);
+            println!("pid\t{}", info.pid);
+            println!("uptime_secs\t{}", info.uptime_secs);
+            println!("service_dir\t{}", info.service_dir.display());
+            println!("services\t{}", info.services);
+            println!("running\t{}", info.running);
+            println!("failed\t{}", info.failed);
+            println!("restarts_frozen\t{}", info.restarts_frozen);
+            println!("open_connections\t{}", info.open_connections);
+            println!("socket_path\t{}", socket_path.display());
+            println!("pid_file\t{}", pid_file.display());
+        }
+    }
+}
+
+fn daemon_details(info: &DaemonInfo) {
+    println!("  Version:           {}", info.version);
+    println!("  PID:               {}", info.pid);
+    println!(
+        "  Uptime:            {}",
+        humantime::format_duration(Duration::from_secs(info.uptime_secs))
+    );
+    println!("  Service directory: {}", info.service_dir.display());
+    println!(
+        "  Services:          {} loaded, {} running, {} failed",
+        info.services, info.running, info.failed
+    );
+    println!("  Connections:       {} open", info.open_connections);
+    if info.restarts_frozen {
+        println!("  Restarts:          frozen (see `diakonos unfreeze`)");
+    }
+}
+
+fn describe(d: &ServiceDescription, format: OutputFormat) {
+    match format {
+        OutputFormat::Table => describe_table(d),
+        OutputFormat::Json => print_json(d),
+        OutputFormat::Plain => describe_plain(d),
+    }
+}
+
+fn describe_table(d: &ServiceDescription) {
+    println!("Service '{}'", d.name);
+    if let Some(ref description) = d.description {
+        println!("  Description:       {}", description);
+    }
+    if let Some(ref unit_file) = d.unit_file {
+        println!("  Unit file:         {}", unit_file.display());
+    }
+    println!("  State:             {}", d.state);
+    if let Some(pid) = d.pid {
+        println!("  PID:               {}", pid);
+    }
+    if let Some(uptime) = d.uptime_secs {
+        println!("  Uptime:            {}", humantime::format_duration(Duration::from_secs(uptime)));
+    }
+    if let Some(ref started_at) = d.started_at {
+        println!("  Started at:        {}", started_at);
+    }
+    println!("  Restarts:          {}", d.restart_count);
+    if d.unsupervised {
+        println!("  Supervision:       off (started with --no-supervise)");
+    }
+    if let Some(next) = d.next_run_secs {
+        println!("  Next timer run:    in {}", humantime::format_duration(Duration::from_secs(next)));
+    }
+    for command in &d.exec_start {
+        println!("  ExecStart:         {}", command);
+    }
+    if let Some(ref wd) = d.working_directory {
+        println!("  WorkingDirectory:  {}", wd.display());
+    }
+    if let Some(ref log_file) = d.log_file {
+        println!("  Log file:          {}", log_file.display());
+    }
+    if !d.environment.is_empty() {
+        println!("  Environment:");
+        for env in &d.environment {
+            println!("    {}", env);
+        }
+    }
+    if !d.environment_overrides.is_empty() {
+        println!("  Overridden by --env:");
+        for env in &d.environment_overrides {
+            println!("    {}", env);
+        }
+    }
+    if !d.limits.is_empty() {
+        println!("  Effective limits (soft / hard):");
+        for limit in &d.limits {
+            let name = format!("{}:", limit.name);
+            println!("    {:<17}{} / {}", name, limit_value(limit.soft), limit_value(limit.hard));
+        }
+    }
+    if let Some(ref pending) = d.pending {
+        println!("  Pending restart (reloaded config, applied at the next start):");
+        for command in &pending.exec_start {
+            println!("    ExecStart:       {}", command);
+        }
+        if let Some(ref wd) = pending.working_directory {
+            println!("    Directory:       {}", wd.display());
+        }
+        for env in &pending.environment {
+            println!("    Environment:     {}", env);
+        }
+    }
+    if let Some(ref command) = d.last_command {
+        println!("  Last executed:");
+        println!("    Command:         {}", quoted_argv(&command.argv));
+        if let Some(ref wd) = command.working_directory {
+            println!("    Directory:       {}", wd.display());
+        }
+        for env in &command.environment {
+            println!("    Environment:     {}", env);
+        }
+    }
+}
+
+/// One `key\tvalue` line per field. A list repeats its key once per item,
+/// and an absent value is an empty field.
+fn describe_plain(d: &ServiceDescription) {
+    let path = |path: &Option<std::path::PathBuf>| path.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+    let number = |n: Option<u64>| n.map(|n| n.to_string()).unwrap_or_default();
+
+    println!("name\t{}", d.name);
+    println!("description\t{}", d.description.as_deref().unwrap_or_default());
+    println!("unit_file\t{}", path(&d.unit_file));
+    println!("state\t{}", d.state);
+    println!("pid\t{}", number(d.pid.map(u64::from)));
+    println!("uptime_secs\t{}", number(d.uptime_secs));
+    println!("started_at\t{}", d.started_at.as_deref().unwrap_or_default());
+    println!("restart_count\t{}", d.restart_count);
+    println!("unsupervised\t{}", d.unsupervised);
+    println!("next_run_secs\t{}", number(d.next_run_secs));
+    for command in &d.exec_start {
+        println!("exec_start\t{}", command);
+    }
+    println!("working_directory\t{}", path(&d.working_directory));
+    println!("log_file\t{}", path(&d.log_file));
+    for env in &d.environment {
+        println!("environment\t{}", env);
+    }
+    for env in &d.environment_overrides {
+        println!("environment_override\t{}", env);
+    }
+    for limit in &d.limits {
+        println!("limit\t{}\t{}\t{}", limit.name, limit_value(limit.soft), limit_value(limit.hard));
+    }
+    if let Some(ref pending) = d.pending {
+        for command in &pending.exec_start {
+            println!("pending_exec_start\t{}", command);
+        }
+        println!("pending_working_directory\t{}", path(&pending.working_directory));
+        for env in &pending.environment {
+            println!("pending_environment\t{}", env);
+        }
+    }
+    if let Some(ref command) = d.last_command {
+        println!("last_command\t{}", quoted_argv(&command.argv));
+        println!("last_working_directory\t{}", path(&command.working_directory));
+        for env in &command.environment {
+            println!("last_environment\t{}", env);
+        }
+    }
+}
+
+fn limit_value(value: Option<u64>) -> String {
+    value.map_or_else(|| "unlimited".to_string(), |v| v.to_string())
+}
+
+/// `argv` as a command line, quoting arguments that are empty or contain
+/// whitespace or quotes so argument boundaries are visible
+fn quoted_argv(argv: &[String]) -> String {
+    argv.iter()
+        .map(|arg| {
+            if arg.is_empty() || arg.contains(|c: char| c.is_whitespace() || c == '\'' || c == '"') {
+                format!("{:?}", arg)
+            } else {
+                arg.clone()
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Lines of several services, each prefixed with its service's name, in a
+/// color of its own in a table
+fn interleaved_logs(lines: &[(String, String)], format: OutputFormat) {
+    match format {
+        OutputFormat::Table => {
+            for (service, line) in lines {
+                match state_colors() {
+                    Some(_) => println!("\x1b[{}m{}\x1b[0m | {}", service_color(service), service, line),
+                    None => println!("{} | {}", service, line),
+                }
+            }
+        }
+        OutputFormat::Json => {
+            let lines: Vec<_> = lines
+                .iter()
+                .map(|(service, line)| json!({ "service": service, "line": line }))
+                .collect();
+            print_json(&lines);
+        }
+        OutputFormat::Plain => {
+            for (service, line) in lines {
+                println!("{}\t{}", service, line);
+            }
+        }
+    }
+}
+
+/// An ANSI color picked from the service's name, so it stays the same
+/// across runs
+fn service_color(service: &str) -> u8 {
+    const COLORS: [u8; 6] = [36, 33, 35, 32, 34, 31];
+    let hash = service
+        .bytes()
+        .fold(0usize, |hash, b| hash.wrapping_mul(31).wrapping_add(b as usize));
+    COLORS[hash % COLORS.len()]
+}
+
+/// The state padded to `width` columns, colored for a terminal. Padding is
+/// applied first since escape codes would otherwise count towards the width.
+fn colored_state(state: ServiceState, width: usize) -> String {
+    let state_str = format!("{:<width$}", state.to_string(), width = width);
+    match state_colors() {
+        Some(colors) => colors.style(state).paint(&state_str),
+        None => state_str,
+    }
+}
+
+fn print_json(value: &impl serde::Serialize) {
+    match serde_json::to_string_pretty(value) {
+        Ok(json) => println!("{}", json),
+        Err(e) => {
+            eprintln!("✗ Error: failed to encode output: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ps_drops_columns_from_the_right_down_to_name_and_state() {
+        let widths = [8, 7, 5, 6, 8, 5, 6];
+        // 45 columns of cells and 12 of gaps
+        assert_eq!(columns_that_fit(&widths, usize::MAX), 7);
+        assert_eq!(columns_that_fit(&widths, 57), 7);
+        assert_eq!(columns_that_fit(&widths, 56), 6);
+        assert_eq!(columns_that_fit(&widths, 49), 6);
+        assert_eq!(columns_that_fit(&widths, 48), 5);
+        assert_eq!(columns_that_fit(&widths, 24), 3);
+        assert_eq!(columns_that_fit(&widths, 23), 2);
+        assert_eq!(columns_that_fit(&widths, 0), 2);
+    }
+
+    #[test]
+    fn compact_durations_keep_the_two_largest_units() {
+        assert_eq!(compact_duration(0), "0s");
+        assert_eq!(compact_duration(59), "59s");
+        assert_eq!(compact_duration(60), "1m0s");
+        assert_eq!(compact_duration(5 * 60 + 12), "5m12s");
+        assert_eq!(compact_duration(3_600 + 59), "1h0m");
+        assert_eq!(compact_duration(23 * 3_600 + 59 * 60 + 59), "23h59m");
+        assert_eq!(compact_duration(3 * 86_400 + 4 * 3_600 + 30), "3d4h");
+    }
+
+    #[test]
+    fn cpu_time_shows_tenths_under_a_minute() {
+        assert_eq!(cpu_time(0.0), "0.0s");
+        assert_eq!(cpu_time(1.26), "1.3s");
+        assert_eq!(cpu_time(59.94), "59.9s");
+        assert_eq!(cpu_time(60.0), "1m0s");
+        assert_eq!(cpu_time(3_725.7), "1h2m");
+    }
+}
@@ -0,0 +1,126 @@
+use crate::error::{DiakonosError, Result};
+use crate::unit::{ProtectSystem, UnitFile};
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The private mount namespace a service runs in, from its `PrivateTmp`,
+/// `ProtectSystem` and `ReadOnlyPaths`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sandbox {
+    /// Mounted read-only, each over itself
+    read_only: Vec<PathBuf>,
+    /// Where an empty tmpfs is mounted
+    private_tmp: Vec<PathBuf>,
+}
+
+impl Sandbox {
+    /// The sandbox `unit` asks for, or `None` if it asks for none. Paths in
+    /// `ProtectSystem` that this system lacks, such as `/efi`, are skipped;
+    /// a missing path in `ReadOnlyPaths` is an error.
+    pub fn of(unit: &UnitFile) -> Result<Option<Self>> {
+        let service = &unit.service;
+        let protect_system = service.protect_system.unwrap_or_default();
+        let listed = service.read_only_paths.as_deref().unwrap_or_default();
+        let private_tmp = service.private_tmp.unwrap_or(false);
+        if protect_system == ProtectSystem::No && listed.is_empty() && !private_tmp {
+            return Ok(None);
+        }
+
+        let mut read_only: Vec<PathBuf> = protect_system
+            .paths()
+            .iter()
+            .map(PathBuf::from)
+            .filter(|path| path.exists())
+            .collect();
+        for path in listed {
+            let path = unit.resolve_path(path);
+            if !path.exists() {
+                return Err(DiakonosError::StartError(format!(
+                    "ReadOnlyPaths: {} does not exist",
+                    path.display()
+                )));
+            }
+            read_only.push(path);
+        }
+
+        let private_tmp = match private_tmp {
+            true => ["/tmp", "/var/tmp"]
+                .into_iter()
+                .map(PathBuf::from)
+                .filter(|dir| dir.is_dir())
+                .collect(),
+            false => Vec::new(),
+        };
+
+        Ok(Some(Self {
+            read_only,
+            private_tmp,
+        }))
+    }
+
+    /// Have `cmd` set the sandbox up in its child before it execs. Only root
+    /// may create the mount namespace, so this fails for any other user.
+    #[cfg(all(target_os = "linux", feature = "sandbox"))]
+    pub fn apply(&self, cmd: &mut Command) -> Result<()> {
+        use std::os::unix::process::CommandExt;
+
+        let uid = nix::unistd::geteuid();
+        if !uid.is_root() {
+            return Err(DiakonosError::StartError(format!(
+                "PrivateTmp, ProtectSystem and ReadOnlyPaths need the daemon to run as root, \
+                 but it runs as uid {}",
+                uid
+            )));
+        }
+
+        let sandbox = self.clone();
+        // SAFETY: `enter` only makes system calls, on paths prepared here in
+        // the parent
+        unsafe {
+            cmd.pre_exec(move || sandbox.enter());
+        }
+        Ok(())
+    }
+
+    /// Fail: sandboxing needs Linux and the `sandbox` feature
+    #[cfg(not(all(target_os = "linux", feature = "sandbox")))]
+    pub fn apply(&self, _cmd: &mut Command) -> Result<()> {
+        let reason = if cfg!(target_os = "linux") {
+            "this diakonos was built without the `sandbox` feature"
+        } else {
+            "they are only supported on Linux"
+        };
+        Err(DiakonosError::StartError(format!(
+            "PrivateTmp, ProtectSystem and ReadOnlyPaths can't be applied: {}",
+            reason
+        )))
+    }
+
+    /// Move the calling process into a mount namespace of its own and mount
+    /// the sandbox there. Runs in the forked child.
+    #[cfg(all(target_os = "linux", feature = "sandbox"))]
+    fn enter(&self) -> std::io::Result<()> {
+        use nix::mount::{mount, MsFlags};
+        use nix::sched::{unshare, CloneFlags};
+
+        const NONE: Option<&str> = None;
+
+        unshare(CloneFlags::CLONE_NEWNS)?;
+        // Keep what is mounted below from propagating back to the host
+        mount(NONE, "/", NONE, MsFlags::MS_REC | MsFlags::MS_PRIVATE, NONE)?;
+
+        // A read-only mount takes a bind mount to remount, as the original
+        // may be shared with the host or not be a mount point at all
+        for path in &self.read_only {
+            mount(Some(path), path, NONE, MsFlags::MS_BIND | MsFlags::MS_REC, NONE)?;
+            let flags = MsFlags::MS_BIND | MsFlags::MS_REMOUNT | MsFlags::MS_RDONLY;
+            mount(NONE, path, NONE, flags, NONE)?;
+        }
+
+        for dir in &self.private_tmp {
+            let flags = MsFlags::MS_NOSUID | MsFlags::MS_NODEV;
+            mount(Some("tmpfs"), dir, Some("tmpfs"), flags, Some("mode=1777"))?;
+        }
+        Ok(())
+    }
+}
@@ -1,13 +1,27 @@
 use crate::error::{DiakonosError, Result};
-use crate::unit::UnitFile;
+use crate::logs::LogSink;
+use crate::reexec::AdoptedService;
+use crate::sandbox::Sandbox;
+use crate::unit::{split_ignore_failure, KillMode, StandardInput, UnitFile};
 use nix::sys::signal::{self, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
 use nix::unistd::Pid;
-use std::process::{Child, Command};
-use std::sync::{Arc, Mutex};
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime};
 use tokio::time::{sleep, Duration};
-use tracing::{error, info, warn};
+use tracing::{debug, error, info, warn};
 
+/// Lifecycle state of a service. It is sent over IPC and printed as a fixed
+/// lowercase name (`running`, `failed`, ...) that scripts can rely on.
 #[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ServiceState {
     Stopped,
     Starting,
@@ -16,12 +30,278 @@ pub enum ServiceState {
     Failed,
 }
 
+impl std::str::FromStr for ServiceState {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "stopped" => Ok(ServiceState::Stopped),
+            "starting" => Ok(ServiceState::Starting),
+            "running" => Ok(ServiceState::Running),
+            "stopping" => Ok(ServiceState::Stopping),
+            "failed" => Ok(ServiceState::Failed),
+            _ => Err(format!(
+                "unknown state '{}' (expected stopped, starting, running, stopping, or failed)",
+                s
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for ServiceState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            ServiceState::Stopped => "stopped",
+            ServiceState::Starting => "starting",
+            ServiceState::Running => "running",
+            ServiceState::Stopping => "stopping",
+            ServiceState::Failed => "failed",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Point-in-time runtime information about a service
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ServiceStats {
+    pub name: String,
+    pub state: ServiceState,
+    pub pid: Option<u32>,
+    pub restart_count: u32,
+    pub uptime_secs: Option<u64>,
+}
+
+/// Configuration and runtime details of a service for `describe`. Secret
+/// environment values are redacted.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ServiceDescription {
+    pub name: String,
+    pub description: Option<String>,
+    pub unit_file: Option<PathBuf>,
+    pub state: ServiceState,
+    pub pid: Option<u32>,
+    pub restart_count: u32,
+    pub uptime_secs: Option<u64>,
+    /// When the current process was started (RFC 3339), by the system clock
+    #[serde(default)]
+    pub started_at: Option<String>,
+    pub exec_start: Vec<String>,
+    pub working_directory: Option<PathBuf>,
+    pub environment: Vec<String>,
+    /// Environment given with `start --env`, included in `environment` too
+    #[serde(default)]
+    pub environment_overrides: Vec<String>,
+    /// Started with `--no-supervise`, so not restarted when it exits
+    #[serde(default)]
+    pub unsupervised: bool,
+    pub log_file: Option<PathBuf>,
+    /// Seconds until a timer next starts the service
+    #[serde(default)]
+    pub next_run_secs: Option<u64>,
+    /// The command most recently executed for the service
+    #[serde(default)]
+    pub last_command: Option<ExecutedCommand>,
+    /// Configuration staged by a soft reload, applied at the next start
+    #[serde(default)]
+    pub pending: Option<PendingConfig>,
+    /// Resource limits in effect for the running process, as the kernel
+    /// reports them
+    #[serde(default)]
+    pub limits: Vec<ResourceLimit>,
+}
+
+/// A resource limit of a process; `None` is unlimited
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct ResourceLimit {
+    /// The resource, as in `RLIMIT_NOFILE` without the prefix
+    pub name: String,
+    pub soft: Option<u64>,
+    pub hard: Option<u64>,
+}
+
+/// The parts of a staged unit that `describe` shows next to the running ones
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct PendingConfig {
+    pub exec_start: Vec<String>,
+    pub working_directory: Option<PathBuf>,
+    pub environment: Vec<String>,
+}
+
+/// A command as it was executed: after specifier expansion, splitting into
+/// arguments and resolving paths
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ExecutedCommand {
+    pub argv: Vec<String>,
+    pub working_directory: Option<PathBuf>,
+    /// Variables set on top of the daemon's own environment, with secret
+    /// values redacted
+    pub environment: Vec<String>,
+}
+
+impl ExecutedCommand {
+    fn new(cmd: &Command, secrets: &[(String, String)]) -> Self {
+        let argv = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect();
+        let environment = cmd
+            .get_envs()
+            .filter_map(|(key, value)| {
+                let key = key.to_string_lossy();
+                let value = if secrets.iter().any(|(secret, _)| *secret == key) {
+                    REDACTED.into()
+                } else {
+                    value?.to_string_lossy()
+                };
+                Some(format!("{}={}", key, value))
+            })
+            .collect();
+
+        Self {
+            argv,
+            working_directory: cmd.get_current_dir().map(Path::to_path_buf),
+            environment,
+        }
+    }
+}
+
+/// How long a stopping process gets to exit after SIGTERM before SIGKILL
+const STOP_GRACE_PERIOD: Duration = Duration::from_secs(3);
+
+/// How long to wait for a process to disappear after SIGKILL
+const KILL_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// How often a stopping process is checked for having exited
+const EXIT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Shown in place of secret environment values
+pub const REDACTED: &str = "<redacted>";
+
+/// Limits shown by `describe`: the rows of `/proc/<pid>/limits` and the
+/// names they go by
+const SHOWN_LIMITS: &[(&str, &str)] = &[
+    ("Max open files", "NOFILE"),
+    ("Max processes", "NPROC"),
+    ("Max core file size", "CORE"),
+    ("Max locked memory", "MEMLOCK"),
+    ("Max address space", "AS"),
+];
+
 pub struct Service {
     pub unit: UnitFile,
     pub state: ServiceState,
     pub pid: Option<u32>,
-    process: Option<Arc<Mutex<Child>>>,
-    restart_count: u32,
+    pub restart_count: u32,
+    /// When the current process was started. Uptime is measured from this
+    /// monotonic clock, so setting the system clock doesn't skew it.
+    pub started_at: Option<Instant>,
+    /// The wall-clock time the current process was started, only for
+    /// showing when; meaningful while `started_at` is set
+    pub start_time: Option<SystemTime>,
+    /// How the last process exited, if it has
+    pub exit_status: Option<ExitStatus>,
+    /// Environment applied before the unit's own `Environment`
+    pub default_environment: Vec<String>,
+    /// Environment given with `start --env`, applied over the unit's own. It
+    /// is kept across restarts until the service is next started by hand.
+    pub environment_overrides: Vec<String>,
+    /// File that captures the service's stdout and stderr
+    pub log_file: Option<PathBuf>,
+    /// Socket of the syslog daemon, for a service whose `StandardOutput` is
+    /// `syslog`
+    pub syslog_socket: PathBuf,
+    /// The service whose failure this one was started to handle, until it is
+    /// next started by hand. Its own failure then activates no `OnFailure`
+    /// units, so handlers can't trigger each other in a loop.
+    pub handling_failure_of: Option<String>,
+    /// Started with `--no-supervise`: the supervisor doesn't restart it
+    /// until it is next started or restarted without
+    pub unsupervised: bool,
+    /// When the service was last started, or loaded if it never was. Its
+    /// timer counts from here.
+    pub last_activated: Instant,
+    /// Registered with `run` rather than loaded from a unit file; forgotten
+    /// once it stops
+    pub transient: bool,
+    /// The command most recently executed, kept for `describe` even if it
+    /// failed to start
+    pub last_command: Option<ExecutedCommand>,
+    /// A changed unit read by a soft reload while the service was active. It
+    /// replaces `unit` when the service next starts, for whatever reason.
+    pub pending_unit: Option<UnitFile>,
+    process: Option<MainProcess>,
+    /// When the current process was sent SIGTERM for outliving `RuntimeMaxSec`
+    runtime_max_signalled: Option<Instant>,
+    /// Duplicates of the read ends of the pipes carrying the main process's
+    /// output, so a re-executed daemon can keep logging it
+    output_pipes: Vec<OwnedFd>,
+    /// PID of the setup command running while the service starts, or 0,
+    /// shared with its [`Setup`] so that stopping the service kills it
+    setup_pid: Arc<AtomicU32>,
+}
+
+/// The setup commands of a oneshot service, the `ExecStart` commands before
+/// the last, prepared by [`Service::begin_start`] to run one after another
+/// apart from the service, so that a long one such as a migration doesn't
+/// hold up the daemon
+pub struct Setup {
+    unit: UnitFile,
+    commands: Vec<(String, Command)>,
+    sink: Option<LogSink>,
+    running: Arc<AtomicU32>,
+}
+
+/// A service's main process: spawned by this daemon, or adopted from the
+/// daemon it replaced by re-executing
+#[derive(Clone)]
+enum MainProcess {
+    Spawned(Arc<Mutex<Child>>),
+    Adopted(Pid),
+}
+
+/// A check of whether a service's process has exited, made apart from the
+/// service, off the async runtime: it takes the process's lock, which may be
+/// held. See [`Service::process_check`].
+pub struct ProcessCheck {
+    service: String,
+    process: MainProcess,
+}
+
+/// The outcome of a [`ProcessCheck`], for [`Service::record_check`]
+pub type CheckedExit = (ProcessCheck, io::Result<Option<ExitStatus>>);
+
+impl ProcessCheck {
+    /// The process's exit status if it has exited, reaping it. May block.
+    pub fn run(self) -> CheckedExit {
+        let exit = self.process.try_wait(&self.service);
+        (self, exit)
+    }
+}
+
+impl MainProcess {
+    /// Whether this is the same process as `other`
+    fn is(&self, other: &MainProcess) -> bool {
+        match (self, other) {
+            (MainProcess::Spawned(a), MainProcess::Spawned(b)) => Arc::ptr_eq(a, b),
+            (MainProcess::Adopted(a), MainProcess::Adopted(b)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// The process's exit status if it has exited, reaping it
+    fn try_wait(&self, service: &str) -> io::Result<Option<ExitStatus>> {
+        match self {
+            MainProcess::Spawned(child) => lock_child(service, child).try_wait(),
+            // Still a child of this process, since exec keeps the PID
+            MainProcess::Adopted(pid) => match waitpid(*pid, Some(WaitPidFlag::WNOHANG))? {
+                WaitStatus::Exited(_, code) => Ok(Some(ExitStatus::from_raw(code << 8))),
+                WaitStatus::Signaled(_, signal, core_dumped) => Ok(Some(ExitStatus::from_raw(
+                    signal as i32 | if core_dumped { 0x80 } else { 0 },
+                ))),
+                _ => Ok(None),
+            },
+        }
+    }
 }
 
 impl Service {
@@ -30,60 +310,276 @@ impl Service {
             unit,
             state: ServiceState::Stopped,
             pid: None,
-            process: None,
             restart_count: 0,
+            started_at: None,
+            start_time: None,
+            exit_status: None,
+            default_environment: Vec::new(),
+            environment_overrides: Vec::new(),
+            log_file: None,
+            syslog_socket: PathBuf::from(crate::daemon::DEFAULT_SYSLOG_SOCKET),
+            handling_failure_of: None,
+            unsupervised: false,
+            last_activated: Instant::now(),
+            transient: false,
+            last_command: None,
+            pending_unit: None,
+            process: None,
+            runtime_max_signalled: None,
+            output_pipes: Vec::new(),
+            setup_pid: Arc::new(AtomicU32::new(0)),
         }
     }
 
     pub async fn start(&mut self) -> Result<()> {
-        if self.state == ServiceState::Running {
+        if matches!(self.state, ServiceState::Running | ServiceState::Starting) {
             return Ok(());
         }
 
+        let setup = match self.begin_start()? {
+            Some(setup) => setup.run().await,
+            None => Ok(()),
+        };
+        self.finish_start(setup)
+    }
+
+    /// Mark the service `Starting` and prepare its setup commands, if it has
+    /// any, for the caller to [`run`](Setup::run) before
+    /// [`finish_start`](Self::finish_start) spawns the main process
+    pub fn begin_start(&mut self) -> Result<Option<Setup>> {
         info!("Starting service: {}", self.unit.name);
         self.state = ServiceState::Starting;
+        self.last_activated = Instant::now();
+        self.process = None;
+        self.pid = None;
+
+        self.prepare_setup().inspect_err(|_| self.state = ServiceState::Failed)
+    }
+
+    fn prepare_setup(&mut self) -> Result<Option<Setup>> {
+        let commands = &self.unit.service.exec_start;
+        let setup = match commands.split_last() {
+            Some((_, [])) => return Ok(None),
+            Some((_, setup)) => setup,
+            None => return Err(DiakonosError::StartError("Empty ExecStart".to_string())),
+        };
+
+        if !self.unit.is_oneshot() {
+            return Err(DiakonosError::StartError(
+                "Multiple ExecStart commands require Type = \"oneshot\"".to_string(),
+            ));
+        }
+
+        let secrets = match self.unit.secret_file() {
+            Some(path) => crate::unit::read_secret_file(&path)?,
+            None => Vec::new(),
+        };
+        let sink = self.open_log();
+        let commands = setup
+            .iter()
+            .map(|command| Ok((command.clone(), self.prepare_command(command, &secrets, sink.is_some())?)))
+            .collect::<Result<_>>()?;
+
+        Ok(Some(Setup {
+            unit: self.unit.clone(),
+            commands,
+            sink,
+            running: Arc::clone(&self.setup_pid),
+        }))
+    }
 
-        let exec_start = self.unit.service.exec_start.clone();
-        let parts: Vec<&str> = exec_start.split_whitespace().collect();
+    /// Spawn the main process of a service that
+    /// [`begin_start`](Self::begin_start) marked `Starting`, given how its
+    /// setup commands went. Fails if the service was stopped meanwhile.
+    pub fn finish_start(&mut self, setup: Result<()>) -> Result<()> {
+        if self.state != ServiceState::Starting {
+            return Err(DiakonosError::StartError(format!(
+                "service '{}' was stopped while running its setup commands",
+                self.unit.name
+            )));
+        }
+
+        let child = match setup.and_then(|()| self.spawn_main()) {
+            Ok(child) => child,
+            Err(e) => {
+                self.state = ServiceState::Failed;
+                return Err(e);
+            }
+        };
+
+        self.pid = Some(child.id());
+        self.started_at = Some(Instant::now());
+        self.start_time = Some(SystemTime::now());
+        self.exit_status = None;
+        self.runtime_max_signalled = None;
+        self.process = Some(MainProcess::Spawned(Arc::new(Mutex::new(child))));
+
+        // A oneshot service stays Starting until its process exits, and one
+        // with `ReadyWhen` until its socket accepts
+        if !self.unit.is_oneshot() && self.unit.ready_when().is_none() {
+            self.state = ServiceState::Running;
+        }
+
+        info!(
+            "Service {} started with PID {}",
+            self.unit.name,
+            self.pid.unwrap()
+        );
+
+        Ok(())
+    }
+
+    /// Spawn the main process, the last `ExecStart` command
+    fn spawn_main(&mut self) -> Result<Child> {
+        let main = self
+            .unit
+            .service
+            .exec_start
+            .last()
+            .cloned()
+            .ok_or_else(|| DiakonosError::StartError("Empty ExecStart".to_string()))?;
+        let secrets = match self.unit.secret_file() {
+            Some(path) => crate::unit::read_secret_file(&path)?,
+            None => Vec::new(),
+        };
+        let sink = self.open_log();
+
+        let mut cmd = self.prepare_command(&main, &secrets, sink.is_some())?;
+        self.last_command = Some(ExecutedCommand::new(&cmd, &secrets));
+        let (child, pipes) = spawn_prepared(&mut cmd, self.unit.standard_input(), sink.as_ref())
+            .map_err(|e| self.spawn_error(&main, &cmd, e))?;
+        self.output_pipes = pipes;
+
+        Ok(child)
+    }
+
+    /// Open the service's log or connect to the system log, falling back to
+    /// inherited output if it can't be
+    fn open_log(&self) -> Option<LogSink> {
+        if self.unit.logs_to_syslog() {
+            let level = self.unit.service.syslog_level.unwrap_or_default();
+            return match LogSink::syslog(&self.syslog_socket, self.unit.syslog_identifier(), level) {
+                Ok(sink) => Some(sink),
+                Err(e) => {
+                    warn!("Failed to open syslog socket for service {}: {}", self.unit.name, e);
+                    None
+                }
+            };
+        }
+
+        let path = self.log_file.as_ref()?;
+        match LogSink::open(path) {
+            Ok(sink) => Some(sink),
+            Err(e) => {
+                warn!(
+                    "Failed to open log {} for service {}: {}",
+                    path.display(),
+                    self.unit.name,
+                    e
+                );
+                None
+            }
+        }
+    }
+
+    /// `command` set up to run as the service, with `secrets` in its
+    /// environment, its `StandardInput`, and its output piped if `captured`
+    fn prepare_command(&self, command: &str, secrets: &[(String, String)], captured: bool) -> Result<Command> {
+        let mut cmd = self.build_command(command)?;
+        cmd.envs(secrets.iter().map(|(key, value)| (key, value)));
+
+        // Its own process group, so a forced stop can kill whatever it spawned
+        cmd.process_group(0);
+
+        match self.unit.standard_input() {
+            StandardInput::Null => cmd.stdin(Stdio::null()),
+            StandardInput::File(ref path) => cmd.stdin(File::open(path).map_err(|e| {
+                DiakonosError::StartError(format!(
+                    "'{}': failed to open StandardInput {}: {}",
+                    command,
+                    path.display(),
+                    e
+                ))
+            })?),
+            StandardInput::Text(_) => cmd.stdin(Stdio::piped()),
+        };
+        if captured {
+            cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+
+        Ok(cmd)
+    }
+
+    /// The error for `cmd`, built from `command`, failing to spawn, saying
+    /// why where that can be told
+    pub fn spawn_error(&self, command: &str, cmd: &Command, error: io::Error) -> DiakonosError {
+        spawn_error(&self.unit, command, cmd, error)
+    }
+
+    fn build_command(&self, command: &str) -> Result<Command> {
+        let parts: Vec<&str> = split_ignore_failure(command).1.split_whitespace().collect();
 
         if parts.is_empty() {
             return Err(DiakonosError::StartError("Empty ExecStart".to_string()));
         }
 
-        let mut cmd = Command::new(parts[0]);
+        // Relative program paths resolve against the unit file's directory
+        let program = if parts[0].contains('/') {
+            self.unit.resolve_path(Path::new(parts[0]))
+        } else {
+            PathBuf::from(parts[0])
+        };
+
+        let mut cmd = Command::new(program);
         if parts.len() > 1 {
             cmd.args(&parts[1..]);
         }
 
         // Set working directory if specified
-        if let Some(ref wd) = self.unit.service.working_directory {
+        if let Some(wd) = self.unit.working_directory() {
             cmd.current_dir(wd);
         }
 
-        // Set environment variables if specified
-        if let Some(ref env_vars) = self.unit.service.environment {
-            for env in env_vars {
-                if let Some((key, value)) = env.split_once('=') {
-                    cmd.env(key, value);
-                }
+        if let Some(sandbox) = Sandbox::of(&self.unit)? {
+            sandbox.apply(&mut cmd)?;
+        }
+
+        // Set environment variables, daemon defaults first so the unit can
+        // override them, and `start --env` last so it overrides both
+        for env in self.environment() {
+            if let Some((key, value)) = env.split_once('=') {
+                cmd.env(key, value);
             }
         }
 
-        let child = cmd
-            .spawn()
-            .map_err(|e| DiakonosError::StartError(e.to_string()))?;
+        Ok(cmd)
+    }
 
-        self.pid = Some(child.id());
-        self.process = Some(Arc::new(Mutex::new(child)));
-        self.state = ServiceState::Running;
+    /// The commands of `ExecStart`, set up as the daemon would run them but
+    /// for the caller to spawn, as `start --attach` does. Setup commands of a
+    /// oneshot come first and the main process last, each with the line of
+    /// `ExecStart` it runs.
+    pub fn commands(&self) -> Result<Vec<(String, Command)>> {
+        let secrets = match self.unit.secret_file() {
+            Some(path) => crate::unit::read_secret_file(&path)?,
+            None => Vec::new(),
+        };
 
-        info!(
-            "Service {} started with PID {}",
-            self.unit.name,
-            self.pid.unwrap()
-        );
+        let commands = &self.unit.service.exec_start;
+        if commands.len() > 1 && !self.unit.is_oneshot() {
+            return Err(DiakonosError::StartError(
+                "Multiple ExecStart commands require Type = \"oneshot\"".to_string(),
+            ));
+        }
 
-        Ok(())
+        commands
+            .iter()
+            .map(|command| {
+                let mut cmd = self.build_command(command)?;
+                cmd.envs(secrets.iter().map(|(key, value)| (key, value)));
+                Ok((command.clone(), cmd))
+            })
+            .collect()
     }
 
     pub async fn stop(&mut self) -> Result<()> {
@@ -96,7 +592,7 @@ impl Service {
 
         // First try custom stop command if specified
         if let Some(ref exec_stop) = self.unit.service.exec_stop {
-            let parts: Vec<&str> = exec_stop.split_whitespace().collect();
+            let parts: Vec<&str> = split_ignore_failure(exec_stop).1.split_whitespace().collect();
             if !parts.is_empty() {
                 let mut cmd = Command::new(parts[0]);
                 if parts.len() > 1 {
@@ -107,56 +603,168 @@ impl Service {
             }
         }
 
-        // Then send SIGTERM to the process
+        // Then send SIGTERM to the process, or its group
         if let Some(pid) = self.pid {
             let pid = Pid::from_raw(pid as i32);
-            if let Err(e) = signal::kill(pid, Signal::SIGTERM) {
+            let kill_mode = self.unit.service.kill_mode.unwrap_or_default();
+            let group = kill_mode != KillMode::Process;
+            let term = match kill_mode {
+                KillMode::ControlGroup => signal_group(pid, Signal::SIGTERM),
+                KillMode::Mixed | KillMode::Process => signal::kill(pid, Signal::SIGTERM),
+            };
+
+            if let Err(e) = term {
                 warn!("Failed to send SIGTERM to PID {}: {}", pid, e);
             } else {
-                // Wait a bit for graceful shutdown
-                sleep(Duration::from_secs(3)).await;
-
-                // If still running, send SIGKILL
-                if signal::kill(pid, Signal::SIGTERM).is_ok() {
-                    warn!("Process {} did not respond to SIGTERM, sending SIGKILL", pid);
-                    let _ = signal::kill(pid, Signal::SIGKILL);
+                // Give it a while to shut down gracefully, then force it
+                if !self.wait_for_exit(STOP_GRACE_PERIOD, group).await {
+                    if group {
+                        warn!("Process group {} still running after SIGTERM, sending SIGKILL", pid);
+                        let _ = signal_group(pid, Signal::SIGKILL);
+                    } else {
+                        warn!("Process {} did not respond to SIGTERM, sending SIGKILL", pid);
+                        let _ = signal::kill(pid, Signal::SIGKILL);
+                    }
+                    if !self.wait_for_exit(KILL_GRACE_PERIOD, group).await {
+                        error!("Process {} did not exit after SIGKILL", pid);
+                    }
                 }
             }
         }
 
+        self.mark_stopped();
+        Ok(())
+    }
+
+    /// Stop the service at once: SIGKILL its whole process group, skipping
+    /// `ExecStop` and the grace period, for a service that is wedged
+    pub async fn force_stop(&mut self) -> Result<()> {
+        if self.state == ServiceState::Stopped {
+            return Ok(());
+        }
+
+        info!("Force-stopping service: {}", self.unit.name);
+        self.state = ServiceState::Stopping;
+
+        if let Some(pid) = self.pid {
+            let pid = Pid::from_raw(pid as i32);
+            if let Err(e) = signal_group(pid, Signal::SIGKILL) {
+                warn!("Failed to send SIGKILL to PID {}: {}", pid, e);
+            }
+            if !self.wait_for_exit(KILL_GRACE_PERIOD, false).await {
+                error!("Process {} did not exit after SIGKILL", pid);
+            }
+        }
+
+        self.mark_stopped();
+        Ok(())
+    }
+
+    fn mark_stopped(&mut self) {
+        self.kill_setup();
         self.pid = None;
+        self.started_at = None;
         self.process = None;
+        self.output_pipes.clear();
         self.state = ServiceState::Stopped;
 
         info!("Service {} stopped", self.unit.name);
-        Ok(())
+    }
+
+    /// Wait up to `timeout` for the main process to exit, reaping it so it
+    /// doesn't linger as a zombie, and with `group` for the rest of its
+    /// process group too. Returns whether they exited.
+    async fn wait_for_exit(&self, timeout: Duration, group: bool) -> bool {
+        let process = match self.process {
+            Some(ref process) => process.clone(),
+            None => return true,
+        };
+        let pgid = self.pid.map(|pid| pid as i32);
+
+        let deadline = tokio::time::Instant::now() + timeout;
+        loop {
+            if let Ok(Some(_)) | Err(_) = process.try_wait(&self.unit.name) {
+                match pgid {
+                    Some(pgid) if group && group_is_alive(pgid) => {}
+                    _ => return true,
+                }
+            }
+            if tokio::time::Instant::now() >= deadline {
+                return false;
+            }
+            sleep(EXIT_POLL_INTERVAL).await;
+        }
     }
 
     pub async fn restart(&mut self) -> Result<()> {
+        self.stop_for_restart().await?;
+        self.start().await
+    }
+
+    /// Stop the service, and give it a moment, before it is started again
+    pub async fn stop_for_restart(&mut self) -> Result<()> {
         info!("Restarting service: {}", self.unit.name);
         self.stop().await?;
         sleep(Duration::from_secs(1)).await;
-        self.start().await?;
         Ok(())
     }
 
     pub async fn check_status(&mut self) -> ServiceState {
-        if let Some(ref process) = self.process {
-            let mut child = process.lock().unwrap();
-            match child.try_wait() {
+        let exit = self.process_check().map(ProcessCheck::run);
+        self.record_check(exit)
+    }
+
+    /// A check of the service's process for having exited, if it has one,
+    /// to [`run`](ProcessCheck::run) without holding the service
+    pub fn process_check(&self) -> Option<ProcessCheck> {
+        self.process.clone().map(|process| ProcessCheck {
+            service: self.unit.name.clone(),
+            process,
+        })
+    }
+
+    /// Update the state from a [`ProcessCheck`] that was run, or from none
+    /// if the service had no process. A check of a process the service has
+    /// since replaced or let go of is ignored.
+    pub fn record_check(&mut self, exit: Option<CheckedExit>) -> ServiceState {
+        let mut finished_oneshot = false;
+
+        let current = exit.filter(|(check, _)| {
+            self.process
+                .as_ref()
+                .is_some_and(|process| process.is(&check.process))
+        });
+        if let Some((_, exit)) = current {
+            match exit {
                 Ok(Some(status)) => {
-                    if status.success() {
-                        info!("Service {} exited successfully", self.unit.name);
-                        self.state = ServiceState::Stopped;
+                    // Logged by the supervisor, which rate-limits repeated transitions
+                    // Outliving `RuntimeMaxSec` is a failure however the process exits
+                    let clean = (self.unit.ignores_failure()
+                        || status.code().is_some_and(|code| self.unit.is_success_exit(code)))
+                        && self.runtime_max_signalled.is_none();
+                    if clean {
+                        debug!("Service {} exited successfully ({})", self.unit.name, status);
+                        self.state = if self.unit.is_oneshot()
+                            && self.unit.service.remain_after_exit.unwrap_or(false)
+                        {
+                            ServiceState::Running
+                        } else {
+                            ServiceState::Stopped
+                        };
                     } else {
-                        error!(
-                            "Service {} failed with exit code: {:?}",
-                            self.unit.name,
-                            status.code()
-                        );
+                        debug!("Service {} failed with {}", self.unit.name, status);
                         self.state = ServiceState::Failed;
                     }
+                    self.exit_status = Some(status);
                     self.pid = None;
+                    self.started_at = None;
+                    finished_oneshot = self.unit.is_oneshot();
+                }
+                Ok(None) if self.unit.is_oneshot() => {
+                    // Still running its one job
+                }
+                Ok(None) if self.state == ServiceState::Starting && self.unit.ready_when().is_some() => {
+                    // Running, but not ready until its socket accepts
                 }
                 Ok(None) => {
                     // Still running
@@ -166,12 +774,300 @@ impl Service {
                     error!("Error checking service {} status: {}", self.unit.name, e);
                     self.state = ServiceState::Failed;
                     self.pid = None;
+                    self.started_at = None;
                 }
             }
         }
+
+        // A finished oneshot keeps its outcome until it is started again
+        if finished_oneshot {
+            self.process = None;
+            self.output_pipes.clear();
+        }
+
         self.state
     }
 
+    /// Give up on a start that didn't complete in time: kill the process and
+    /// mark the service failed
+    pub fn abort_start(&mut self) {
+        if let Some(pid) = self.pid {
+            warn!("Killing service {} (PID {}) after start timeout", self.unit.name, pid);
+            let _ = signal::kill(Pid::from_raw(pid as i32), Signal::SIGKILL);
+        }
+
+        // Reap the killed process so it doesn't linger as a zombie
+        if let Some(process) = self.process.take() {
+            let _ = process.try_wait(&self.unit.name);
+        }
+        self.output_pipes.clear();
+        self.kill_setup();
+
+        self.pid = None;
+        self.started_at = None;
+        self.state = ServiceState::Failed;
+    }
+
+    /// Kill the setup command running while the service starts, if any,
+    /// along with its process group. Its [`Setup`] then fails.
+    fn kill_setup(&self) {
+        let pid = self.setup_pid.swap(0, Ordering::SeqCst);
+        if pid != 0 {
+            warn!("Killing the setup command of service {} (PID {})", self.unit.name, pid);
+            let _ = signal_group(Pid::from_raw(pid as i32), Signal::SIGKILL);
+        }
+    }
+
+    /// The lock on the process the service spawned, for tests to hold
+    #[cfg(test)]
+    pub(crate) fn process_lock(&self) -> Option<Arc<Mutex<Child>>> {
+        match &self.process {
+            Some(MainProcess::Spawned(process)) => Some(Arc::clone(process)),
+            _ => None,
+        }
+    }
+
+    /// Send `signal` to the service's main process
+    pub fn send_signal(&self, signal: Signal) -> Result<()> {
+        let pid = match self.pid {
+            Some(pid) if self.state == ServiceState::Running => pid,
+            _ => return Err(DiakonosError::ServiceNotRunning(self.unit.name.clone())),
+        };
+
+        info!("Sending {} to service {} (PID {})", signal, self.unit.name, pid);
+        signal::kill(Pid::from_raw(pid as i32), signal)
+            .map_err(|e| DiakonosError::ProcessError(format!("Failed to send {}: {}", signal, e)))
+    }
+
+    /// Whether the service has (or is bringing up or down) a process
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self.state,
+            ServiceState::Starting | ServiceState::Running | ServiceState::Stopping
+        )
+    }
+
+    /// When its timer next starts the service, if it has one
+    pub fn next_timer_run(&self) -> Option<Instant> {
+        self.unit
+            .timer_interval()
+            .map(|interval| self.last_activated + interval)
+    }
+
+    /// Whether the service's timer has elapsed and it isn't already active
+    pub fn timer_due(&self) -> bool {
+        !self.is_active()
+            && self
+                .next_timer_run()
+                .is_some_and(|next| next <= Instant::now())
+    }
+
+    /// Stop a service that has been up for its `RuntimeMaxSec`, without
+    /// waiting: SIGTERM now, and SIGKILL if it is still running a grace
+    /// period later. Called on each supervisor pass, which notices the exit
+    /// as a failure. A process that exits first is never signalled, as the
+    /// limit only applies to a running one. Returns whether it sent a signal.
+    pub fn enforce_runtime_max(&mut self) -> bool {
+        let max = match self.unit.runtime_max() {
+            Some(max) => max,
+            None => return false,
+        };
+        let pid = match self.pid {
+            Some(pid) if self.state == ServiceState::Running && self.uptime().is_some_and(|up| up >= max) => {
+                Pid::from_raw(pid as i32)
+            }
+            _ => return false,
+        };
+
+        let signal = match self.runtime_max_signalled {
+            None => {
+                info!(
+                    "Service {} has run for its RuntimeMaxSec ({:?}), stopping it",
+                    self.unit.name, max
+                );
+                self.runtime_max_signalled = Some(Instant::now());
+                Signal::SIGTERM
+            }
+            Some(signalled) if signalled.elapsed() >= STOP_GRACE_PERIOD => {
+                warn!("Service {} did not respond to SIGTERM, sending SIGKILL", self.unit.name);
+                Signal::SIGKILL
+            }
+            Some(_) => return false,
+        };
+
+        let result = match self.unit.service.kill_mode.unwrap_or_default() {
+            KillMode::ControlGroup => signal_group(pid, signal),
+            KillMode::Mixed if signal == Signal::SIGKILL => signal_group(pid, signal),
+            KillMode::Mixed | KillMode::Process => signal::kill(pid, signal),
+        };
+        if let Err(e) = result {
+            warn!("Failed to send {} to PID {}: {}", signal, pid, e);
+        }
+        true
+    }
+
+    /// How long the current process has been running
+    pub fn uptime(&self) -> Option<Duration> {
+        self.started_at.map(|started| started.elapsed())
+    }
+
+    pub fn stats(&self) -> ServiceStats {
+        ServiceStats {
+            name: self.unit.name.clone(),
+            state: self.state,
+            pid: self.pid,
+            restart_count: self.restart_count,
+            uptime_secs: self.uptime().map(|u| u.as_secs()),
+        }
+    }
+
+    /// What a re-executed daemon needs to take over the running main
+    /// process, or `None` if there is none. The output pipes are duplicated
+    /// without close-on-exec, so that they survive the exec.
+    pub fn hand_off(&self) -> Option<AdoptedService> {
+        let pid = self.pid?;
+        if self.process.is_none() || !self.is_active() {
+            return None;
+        }
+
+        let pipes = self
+            .output_pipes
+            .iter()
+            .filter_map(|pipe| nix::unistd::dup(pipe.as_raw_fd()).ok())
+            .collect();
+        Some(AdoptedService {
+            name: self.unit.name.clone(),
+            source_path: self.unit.source_path.clone(),
+            unit: self.unit.clone(),
+            transient: self.transient,
+            state: self.state,
+            pid,
+            restart_count: self.restart_count,
+            uptime_ms: self.uptime().map_or(0, |uptime| uptime.as_millis() as u64),
+            start_time: self.start_time,
+            environment_overrides: self.environment_overrides.clone(),
+            handling_failure_of: self.handling_failure_of.clone(),
+            unsupervised: self.unsupervised,
+            pipes,
+        })
+    }
+
+    /// Take over a process handed off by the daemon this one replaced, and
+    /// resume logging its output
+    pub fn adopt(&mut self, adopted: AdoptedService) {
+        self.state = adopted.state;
+        self.pid = Some(adopted.pid);
+        self.restart_count = adopted.restart_count;
+        self.started_at = Instant::now().checked_sub(Duration::from_millis(adopted.uptime_ms));
+        // Handed off by a daemon that predates it: work it out from the uptime
+        self.start_time = adopted
+            .start_time
+            .or_else(|| SystemTime::now().checked_sub(Duration::from_millis(adopted.uptime_ms)));
+        self.environment_overrides = adopted.environment_overrides;
+        self.handling_failure_of = adopted.handling_failure_of;
+        self.unsupervised = adopted.unsupervised;
+        self.transient = adopted.transient;
+        self.process = Some(MainProcess::Adopted(Pid::from_raw(adopted.pid as i32)));
+
+        // Inherited without close-on-exec; keep copies that have it, so
+        // processes started later don't inherit them too
+        let inherited: Vec<OwnedFd> = adopted
+            .pipes
+            .into_iter()
+            // SAFETY: the previous daemon dup'd each pipe for the handoff and
+            // exec'd this one with it open; nothing else here owns it, and
+            // the handoff is adopted once
+            .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) })
+            .collect();
+        self.output_pipes = inherited.iter().filter_map(|pipe| pipe.try_clone().ok()).collect();
+        drop(inherited);
+
+        if let Some(sink) = self.open_log() {
+            for pipe in &self.output_pipes {
+                match pipe.try_clone() {
+                    Ok(pipe) => sink.attach_pipe(pipe, adopted.pid),
+                    Err(e) => warn!("Failed to resume logging service {}: {}", self.unit.name, e),
+                }
+            }
+        }
+
+        info!("Adopted service {} (PID {})", self.unit.name, adopted.pid);
+    }
+
+    /// Switch to the unit staged by a soft reload, if there is one. Returns
+    /// whether the unit changed.
+    pub fn apply_pending_unit(&mut self) -> bool {
+        match self.pending_unit.take() {
+            Some(unit) => {
+                info!("Applying reloaded unit of service {}", self.unit.name);
+                self.unit = unit;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The environment in the order it is applied, later entries winning
+    fn environment(&self) -> impl Iterator<Item = &String> {
+        self.environment_with(&self.unit)
+    }
+
+    /// The environment the service would have with `unit`
+    fn environment_with<'a>(&'a self, unit: &'a UnitFile) -> impl Iterator<Item = &'a String> {
+        let unit_env = unit.service.environment.iter().flatten();
+        self.default_environment
+            .iter()
+            .chain(unit_env)
+            .chain(&self.environment_overrides)
+    }
+
+    pub fn describe(&self) -> ServiceDescription {
+        let mut environment: Vec<String> = self.environment().cloned().collect();
+
+        // Only the keys of secrets are shown; an unreadable file is reported
+        // here and fails the next start
+        if let Some(path) = self.unit.secret_file() {
+            match crate::unit::read_secret_file(&path) {
+                Ok(secrets) => environment.extend(
+                    secrets
+                        .into_iter()
+                        .map(|(key, _)| format!("{}={}", key, REDACTED)),
+                ),
+                Err(e) => environment.push(format!("# {}", e)),
+            }
+        }
+
+        ServiceDescription {
+            name: self.unit.name.clone(),
+            description: self.unit.unit.description.clone(),
+            unit_file: self.unit.source_path.clone(),
+            state: self.state,
+            pid: self.pid,
+            restart_count: self.restart_count,
+            uptime_secs: self.uptime().map(|u| u.as_secs()),
+            started_at: self
+                .start_time
+                .filter(|_| self.started_at.is_some())
+                .map(|time| humantime::format_rfc3339_seconds(time).to_string()),
+            exec_start: self.unit.service.exec_start.clone(),
+            working_directory: self.unit.working_directory(),
+            environment,
+            environment_overrides: self.environment_overrides.clone(),
+            unsupervised: self.unsupervised,
+            log_file: self.log_file.clone(),
+            next_run_secs: self
+                .next_timer_run()
+                .map(|next| next.saturating_duration_since(Instant::now()).as_secs()),
+            last_command: self.last_command.clone(),
+            pending: self.pending_unit.as_ref().map(|unit| PendingConfig {
+                exec_start: unit.service.exec_start.clone(),
+                working_directory: unit.working_directory(),
+                environment: self.environment_with(unit).cloned().collect(),
+            }),
+            limits: self.pid.map(read_limits).unwrap_or_default(),
+        }
+    }
+
     pub fn should_restart(&self) -> bool {
         use crate::unit::RestartPolicy;
 
@@ -181,6 +1077,17 @@ impl Service {
             .restart
             .unwrap_or(RestartPolicy::No);
 
+        // An exit code saying a restart wouldn't help, such as a config error
+        if let Some(code) = self.exit_status.and_then(|status| status.code()) {
+            if self.unit.prevents_restart(code) {
+                info!(
+                    "Not restarting service {}: exit code {} is in RestartPreventExitStatus",
+                    self.unit.name, code
+                );
+                return false;
+            }
+        }
+
         match policy {
             RestartPolicy::Always => true,
             RestartPolicy::OnFailure => self.state == ServiceState::Failed,
@@ -189,6 +1096,304 @@ impl Service {
     }
 
     pub fn get_restart_delay(&self) -> Duration {
-        Duration::from_secs(self.unit.service.restart_sec.unwrap_or(5))
+        let base = self.unit.service.restart_sec.unwrap_or(Duration::from_secs(5));
+        match self.unit.service.restart_max_delay_sec {
+            Some(max) => base
+                .saturating_mul(2u32.saturating_pow(self.restart_count))
+                .min(max.max(base)),
+            None => base,
+        }
+    }
+
+    /// Forget past restarts once the current process has been up for
+    /// `RestartResetSec`. Returns whether the count was reset.
+    pub fn reset_restarts_if_stable(&mut self) -> bool {
+        let Some(threshold) = self.unit.service.restart_reset_sec else {
+            return false;
+        };
+
+        let stable = self.state == ServiceState::Running
+            && self.uptime().is_some_and(|uptime| uptime >= threshold);
+        if stable && self.restart_count > 0 {
+            self.restart_count = 0;
+            return true;
+        }
+        false
+    }
+}
+
+impl Setup {
+    /// Run the setup commands in order, each to completion, until one not
+    /// prefixed with `-` fails. Together they get the unit's
+    /// `TimeoutStartSec`; the command running when it passes is killed.
+    pub async fn run(self) -> Result<()> {
+        let name = &self.unit.name;
+        let timeout = self.unit.start_timeout();
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+
+        for (command, mut cmd) in self.commands {
+            info!("Running '{}' for service {}", command, name);
+            let (mut child, _pipes) = spawn_prepared(&mut cmd, self.unit.standard_input(), self.sink.as_ref())
+                .map_err(|e| spawn_error(&self.unit, &command, &cmd, e))?;
+            let pid = child.id();
+            self.running.store(pid, Ordering::SeqCst);
+
+            let wait = tokio::task::spawn_blocking(move || child.wait());
+            let waited = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, wait).await.ok(),
+                None => Some(wait.await),
+            };
+
+            // Cleared by the service if it was stopped, which killed the command
+            if self.running.swap(0, Ordering::SeqCst) != pid {
+                return Err(DiakonosError::StartError(format!(
+                    "'{}' was killed as service '{}' stopped",
+                    command, name
+                )));
+            }
+            let Some(waited) = waited else {
+                warn!("Killing '{}' for service {} after start timeout", command, name);
+                let _ = signal_group(Pid::from_raw(pid as i32), Signal::SIGKILL);
+                return Err(DiakonosError::StartError(format!(
+                    "'{}' did not finish within {}s",
+                    command,
+                    timeout.unwrap_or_default().as_secs()
+                )));
+            };
+            let status = waited
+                .map_err(|e| DiakonosError::ProcessError(e.to_string()))?
+                .map_err(|e| DiakonosError::StartError(format!("'{}': {}", command, e)))?;
+
+            if !status.success() {
+                if split_ignore_failure(&command).0 {
+                    warn!("Ignoring failure of '{}' for service {} ({})", command, name, status);
+                    continue;
+                }
+                return Err(DiakonosError::StartError(format!(
+                    "'{}' exited with {}",
+                    command, status
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Spawn `cmd`, prepared with [`Service::prepare_command`], feeding it
+/// `input` and logging its output to `sink`. Returns the child and the read
+/// ends of its output pipes.
+fn spawn_prepared(
+    cmd: &mut Command,
+    input: StandardInput,
+    sink: Option<&LogSink>,
+) -> io::Result<(Child, Vec<OwnedFd>)> {
+    let mut child = cmd.spawn()?;
+    if let (StandardInput::Text(text), Some(stdin)) = (input, child.stdin.take()) {
+        feed_stdin(stdin, text);
+    }
+    let pipes = match sink {
+        Some(sink) => sink.attach(&mut child),
+        None => Vec::new(),
+    };
+
+    Ok((child, pipes))
+}
+
+/// The error for `cmd`, built from `command` of `unit`, failing to spawn,
+/// saying why where that can be told
+fn spawn_error(unit: &UnitFile, command: &str, cmd: &Command, error: io::Error) -> DiakonosError {
+    // Exec doesn't fail with EPERM, but setting up a sandbox does
+    // without CAP_SYS_ADMIN, as for root in an unprivileged container
+    let sandboxed = Sandbox::of(unit).is_ok_and(|sandbox| sandbox.is_some());
+    if sandboxed && error.raw_os_error() == Some(nix::errno::Errno::EPERM as i32) {
+        return DiakonosError::StartError(format!(
+            "'{}': failed to set up its sandbox, which needs CAP_SYS_ADMIN ({})",
+            command, error
+        ));
+    }
+
+    let explanation = unit
+        .resolve_program(&cmd.get_program().to_string_lossy())
+        .and_then(|program| explain_spawn_error(&program, &error));
+    match explanation {
+        Some(explanation) => DiakonosError::StartError(format!("'{}': {} ({})", command, explanation, error)),
+        None => DiakonosError::StartError(format!("'{}': {}", command, error)),
+    }
+}
+
+/// Write `text`, ending in a newline, to a process's stdin and close it.
+/// Written from a thread of its own, so a process that reads slowly, or not
+/// at all, holds up nothing.
+fn feed_stdin(mut stdin: ChildStdin, mut text: String) {
+    if !text.ends_with('\n') {
+        text.push('\n');
+    }
+
+    std::thread::spawn(move || {
+        // A process that exits without reading it all is no error
+        let _ = stdin.write_all(text.as_bytes());
+    });
+}
+
+/// Parse a signal given by name (`SIGHUP`, `HUP`, case-insensitive) or number (`1`)
+pub fn parse_signal(value: &str) -> Result<Signal> {
+    let value = value.trim();
+
+    if let Ok(number) = value.parse::<i32>() {
+        return Signal::try_from(number)
+            .map_err(|_| DiakonosError::InvalidSignal(value.to_string()));
+    }
+
+    let upper = value.to_ascii_uppercase();
+    let name = if upper.starts_with("SIG") {
+        upper
+    } else {
+        format!("SIG{}", upper)
+    };
+
+    name.parse::<Signal>()
+        .map_err(|_| DiakonosError::InvalidSignal(value.to_string()))
+}
+
+/// Explain a failure to execute `program` that the OS error alone leaves
+/// cryptic: a script without the execute bit, or a `#!` line naming an
+/// interpreter that doesn't exist
+fn explain_spawn_error(program: &Path, error: &std::io::Error) -> Option<String> {
+    use std::io::Read;
+    use std::os::unix::fs::PermissionsExt;
+
+    let metadata = std::fs::metadata(program).ok()?;
+    match error.kind() {
+        std::io::ErrorKind::PermissionDenied if metadata.is_dir() => {
+            Some(format!("{} is a directory", program.display()))
+        }
+        std::io::ErrorKind::PermissionDenied if metadata.permissions().mode() & 0o111 == 0 => {
+            Some(format!("{} exists but is not executable; chmod +x it", program.display()))
+        }
+        // The program exists, so what is missing is its interpreter
+        std::io::ErrorKind::NotFound => {
+            let mut head = Vec::new();
+            std::fs::File::open(program)
+                .ok()?
+                .take(256)
+                .read_to_end(&mut head)
+                .ok()?;
+            let head = String::from_utf8_lossy(&head);
+            let line = head.split('\n').next()?.strip_prefix("#!")?;
+
+            if line.ends_with('\r') {
+                return Some(format!(
+                    "the #! line of {} ends in a carriage return; convert it to Unix line endings",
+                    program.display()
+                ));
+            }
+            let interpreter = line.split_whitespace().next()?;
+            Some(format!(
+                "interpreter {} from the #! line of {} was not found",
+                interpreter,
+                program.display()
+            ))
+        }
+        _ => None,
+    }
+}
+
+/// Send `signal` to the process group led by `pid`, or to `pid` alone if it
+/// leads none (a process spawned before services got groups of their own)
+fn signal_group(pid: Pid, signal: Signal) -> nix::Result<()> {
+    signal::killpg(pid, signal).or_else(|_| signal::kill(pid, signal))
+}
+
+/// The effective limits of process `pid`, read from `/proc/<pid>/limits`.
+/// Empty where `/proc` isn't available or the process is gone.
+fn read_limits(pid: u32) -> Vec<ResourceLimit> {
+    let Ok(content) = std::fs::read_to_string(format!("/proc/{}/limits", pid)) else {
+        return Vec::new();
+    };
+    let value = |field: Option<&str>| field.and_then(|v| v.parse().ok());
+
+    SHOWN_LIMITS
+        .iter()
+        .filter_map(|(row, name)| {
+            let line = content.lines().find(|line| line.starts_with(row))?;
+            let mut fields = line[row.len()..].split_whitespace();
+            Some(ResourceLimit {
+                name: name.to_string(),
+                soft: value(fields.next()),
+                hard: value(fields.next()),
+            })
+        })
+        .collect()
+}
+
+/// Whether any process in the group `pgid` is still running. Zombies don't
+/// count: an orphan is only reaped once its new parent gets to it. Without
+/// `/proc`, any process in the group counts.
+fn group_is_alive(pgid: i32) -> bool {
+    let Ok(entries) = std::fs::read_dir("/proc") else {
+        return signal::killpg(Pid::from_raw(pgid), None).is_ok();
+    };
+
+    entries.flatten().any(|entry| {
+        let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse::<i32>().ok()) else {
+            return false;
+        };
+        let Ok(stat) = std::fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+            return false;
+        };
+        // After the parenthesised command name: state, parent PID, group
+        let mut fields = stat.rsplit_once(") ").map(|(_, rest)| rest).unwrap_or("").split(' ');
+        let state = fields.next();
+        let group = fields.nth(1).and_then(|g| g.parse::<i32>().ok());
+        group == Some(pgid) && state != Some("Z")
+    })
+}
+
+/// Lock a service's process. A panic while it was held leaves the `Child`
+/// itself intact, so the lock is recovered rather than letting one panic
+/// break every later status check.
+fn lock_child<'a>(name: &str, process: &'a Mutex<Child>) -> MutexGuard<'a, Child> {
+    process.lock().unwrap_or_else(|poisoned| {
+        warn!("Recovering the process lock of service {} after a panic", name);
+        process.clear_poison();
+        poisoned.into_inner()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn a_poisoned_process_lock_is_recovered() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("web.service");
+        std::fs::write(&path, "[unit]\n[service]\nExecStart = \"sleep 30\"\n").unwrap();
+        let mut service = Service::new(UnitFile::from_file(&path).unwrap());
+        service.start().await.unwrap();
+
+        // As a panic while holding it would
+        let process = service.process_lock().unwrap();
+        let _ = std::thread::spawn({
+            let process = Arc::clone(&process);
+            move || {
+                let _child = process.lock();
+                panic!("poisoning the process lock");
+            }
+        })
+        .join();
+        assert!(process.is_poisoned());
+
+        drop(lock_child("web", &process));
+        assert!(!process.is_poisoned());
+        assert_eq!(service.check_status().await, ServiceState::Running);
+
+        service.send_signal(Signal::SIGKILL).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while service.check_status().await != ServiceState::Failed {
+            assert!(Instant::now() < deadline, "exit was never noticed");
+            sleep(Duration::from_millis(100)).await;
+        }
     }
 }
@@ -0,0 +1,118 @@
+use crate::service::ServiceState;
+use serde::Deserialize;
+use std::str::FromStr;
+
+/// Color names and the ANSI SGR parameters that select them
+const COLORS: [(&str, u8); 18] = [
+    ("black", 30),
+    ("red", 31),
+    ("green", 32),
+    ("yellow", 33),
+    ("blue", 34),
+    ("magenta", 35),
+    ("cyan", 36),
+    ("white", 37),
+    ("gray", 90),
+    ("grey", 90),
+    ("bright-black", 90),
+    ("bright-red", 91),
+    ("bright-green", 92),
+    ("bright-yellow", 93),
+    ("bright-blue", 94),
+    ("bright-magenta", 95),
+    ("bright-cyan", 96),
+    ("bright-white", 97),
+];
+
+/// Style names and the ANSI SGR parameters that select them
+const STYLES: [(&str, u8); 5] = [
+    ("bold", 1),
+    ("dim", 2),
+    ("italic", 3),
+    ("underline", 4),
+    ("reverse", 7),
+];
+
+/// How the CLI shows each service state in tables, as the `[colors]` table
+/// of the config file. Unset states keep the default scheme.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateColors {
+    pub stopped: TextStyle,
+    pub starting: TextStyle,
+    pub running: TextStyle,
+    pub stopping: TextStyle,
+    pub failed: TextStyle,
+}
+
+impl Default for StateColors {
+    fn default() -> Self {
+        Self {
+            stopped: TextStyle(vec![90]),
+            starting: TextStyle::default(),
+            running: TextStyle(vec![32]),
+            stopping: TextStyle::default(),
+            failed: TextStyle(vec![31]),
+        }
+    }
+}
+
+impl StateColors {
+    pub fn style(&self, state: ServiceState) -> &TextStyle {
+        match state {
+            ServiceState::Stopped => &self.stopped,
+            ServiceState::Starting => &self.starting,
+            ServiceState::Running => &self.running,
+            ServiceState::Stopping => &self.stopping,
+            ServiceState::Failed => &self.failed,
+        }
+    }
+}
+
+/// A terminal text style given as space-separated color and style names,
+/// such as `"bold blue"`. The empty string is plain text.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct TextStyle(Vec<u8>);
+
+impl TextStyle {
+    /// `text` wrapped in the escape codes that show it in this style
+    pub fn paint(&self, text: &str) -> String {
+        if self.0.is_empty() {
+            return text.to_string();
+        }
+        let sgr: Vec<String> = self.0.iter().map(u8::to_string).collect();
+        format!("\x1b[{}m{}\x1b[0m", sgr.join(";"), text)
+    }
+}
+
+impl FromStr for TextStyle {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split_whitespace()
+            .map(|name| {
+                let name = name.to_ascii_lowercase();
+                COLORS
+                    .iter()
+                    .chain(STYLES.iter())
+                    .find(|(known, _)| *known == name)
+                    .map(|&(_, sgr)| sgr)
+                    .ok_or_else(|| {
+                        let known: Vec<&str> = COLORS.iter().chain(STYLES.iter()).map(|(known, _)| *known).collect();
+                        format!("unknown color or style '{}' (expected any of {})", name, known.join(", "))
+                    })
+            })
+            .collect::<Result<_, _>>()
+            .map(TextStyle)
+    }
+}
+
+impl TryFrom<String> for TextStyle {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
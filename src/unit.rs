@@ -1,15 +1,42 @@
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Suffix of target names. A target is a group of services with no process
+/// of its own, made up of the units that declare themselves `PartOf` it.
+pub const TARGET_SUFFIX: &str = ".target";
+
+/// Default for `TimeoutStartSec`, as in systemd
+pub const DEFAULT_TIMEOUT_START_SEC: u64 = 90;
+
+/// Drop-in in a unit's drop-in directory that `config set` writes settings
+/// to; see [`set_override`]
+pub const OVERRIDE_FILE: &str = "override.toml";
+
+/// Sections of a unit file, in the order a bare setting name is looked up
+const SECTIONS: [&str; 4] = ["service", "unit", "install", "timer"];
+
+// Unknown keys are rejected rather than ignored, so a misspelled setting is
+// reported instead of silently having no effect
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct UnitFile {
     pub unit: UnitSection,
+    /// Defaults to empty so a missing section is reported as a missing `ExecStart`
+    #[serde(default)]
     pub service: ServiceSection,
+    pub timer: Option<TimerSection>,
+    pub install: Option<InstallSection>,
     #[serde(skip)]
     pub name: String,
+    /// File the unit was loaded from; relative paths in the unit resolve against its directory
+    #[serde(skip)]
+    pub source_path: Option<PathBuf>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct UnitSection {
     #[serde(rename = "Description")]
     pub description: Option<String>,
@@ -22,65 +49,468 @@ pub struct UnitSection {
 
     #[serde(rename = "Wants")]
     pub wants: Option<Vec<String>>,
+
+    /// Other names the service answers to in commands and dependencies
+    #[serde(rename = "Alias")]
+    pub alias: Option<Vec<String>>,
+
+    /// Targets this service belongs to (e.g. `web.target`)
+    #[serde(rename = "PartOf")]
+    pub part_of: Option<Vec<String>>,
+
+    /// Units to start when this service fails
+    #[serde(rename = "OnFailure")]
+    pub on_failure: Option<Vec<String>>,
+
+    /// Stop the service once no active service `Requires` or `Wants` it,
+    /// when one that did is stopped
+    #[serde(rename = "StopWhenUnneeded")]
+    pub stop_when_unneeded: Option<bool>,
+
+    /// Start conditions: paths that must exist, be directories, or be
+    /// non-empty files. A leading `!` negates a check. If any is unmet, a
+    /// start is skipped rather than failed.
+    #[serde(rename = "ConditionPathExists", default, deserialize_with = "string_or_list")]
+    pub condition_path_exists: Vec<String>,
+
+    #[serde(rename = "ConditionPathIsDirectory", default, deserialize_with = "string_or_list")]
+    pub condition_path_is_directory: Vec<String>,
+
+    #[serde(rename = "ConditionFileNotEmpty", default, deserialize_with = "string_or_list")]
+    pub condition_file_not_empty: Vec<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct ServiceSection {
     #[serde(rename = "Type")]
     pub service_type: Option<ServiceType>,
 
-    #[serde(rename = "ExecStart")]
-    pub exec_start: String,
+    /// Commands to run, as a single string or a list. Only `oneshot` services may
+    /// list several; all but the last run to completion before the last is started.
+    /// A command prefixed with `-` may fail without failing the service.
+    /// Required; see [`UnitFile::parse`].
+    #[serde(rename = "ExecStart", default, deserialize_with = "string_or_list")]
+    pub exec_start: Vec<String>,
+
+    /// Keep a successfully exited `oneshot` service active (`Running`)
+    #[serde(rename = "RemainAfterExit")]
+    pub remain_after_exit: Option<bool>,
+
+    /// How long a start may take before the service is marked failed. Only
+    /// waits for readiness (a `oneshot` finishing, or `ReadyWhen`) are bounded.
+    /// `0` or `"infinity"` leaves them unbounded.
+    #[serde(rename = "TimeoutStartSec", default, with = "optional_limit")]
+    pub timeout_start_sec: Option<Duration>,
 
+    /// A socket the service accepts connections on once it is ready, as
+    /// `tcp:HOST:PORT` or `unix:PATH`. Until then it stays `Starting`.
+    #[serde(rename = "ReadyWhen")]
+    pub ready_when: Option<ReadyWhen>,
+
+    /// Start the service when the daemon starts
+    #[serde(rename = "AutoStart")]
+    pub auto_start: Option<bool>,
+
+    /// Run before a stop signals the process; a `-` prefix is stripped
     #[serde(rename = "ExecStop")]
     pub exec_stop: Option<String>,
 
+    /// Which processes a stop signals; see [`KillMode`]
+    #[serde(rename = "KillMode")]
+    pub kill_mode: Option<KillMode>,
+
     #[serde(rename = "Restart")]
     pub restart: Option<RestartPolicy>,
 
-    #[serde(rename = "RestartSec")]
-    pub restart_sec: Option<u64>,
+    #[serde(rename = "RestartSec", default, with = "optional_duration")]
+    pub restart_sec: Option<Duration>,
+
+    /// Back off exponentially: each consecutive restart doubles the delay,
+    /// starting from `RestartSec`, up to this long
+    #[serde(rename = "RestartMaxDelaySec", default, with = "optional_duration")]
+    pub restart_max_delay_sec: Option<Duration>,
+
+    /// How long a service must stay up before its restart count, and with it
+    /// the backoff, are reset to zero
+    #[serde(rename = "RestartResetSec", default, with = "optional_duration")]
+    pub restart_reset_sec: Option<Duration>,
+
+    /// How long the service may run before the supervisor stops it. The stop
+    /// counts as a failure, so `Restart = "on-failure"` starts it afresh.
+    /// `0` or `"infinity"` lets it run indefinitely.
+    #[serde(rename = "RuntimeMaxSec", default, with = "optional_limit")]
+    pub runtime_max_sec: Option<Duration>,
+
+    /// Times of day (UTC) the supervisor may restart the service, such as
+    /// `["09:00-17:00"]`. Outside them a stopped service is left stopped.
+    #[serde(rename = "RestartWindow")]
+    pub restart_window: Option<Vec<TimeWindow>>,
+
+    /// Nonzero exit codes that count as a clean exit, so the service is
+    /// `Stopped` rather than `Failed`
+    #[serde(rename = "SuccessExitStatus")]
+    pub success_exit_status: Option<Vec<u8>>,
+
+    /// Exit codes after which the service is never restarted, whatever the
+    /// `Restart` policy
+    #[serde(rename = "RestartPreventExitStatus")]
+    pub restart_prevent_exit_status: Option<Vec<u8>>,
 
     #[serde(rename = "WorkingDirectory")]
     pub working_directory: Option<PathBuf>,
 
-    #[serde(rename = "Environment")]
+    /// `KEY=value` strings, or a table of values (`{ KEY = "value" }`),
+    /// which is read into the same form. A value may span several lines.
+    #[serde(rename = "Environment", default, deserialize_with = "environment_list_or_table")]
     pub environment: Option<Vec<String>>,
 
+    /// File of `KEY=value` secrets added to the environment. It must not be
+    /// readable by group or others, and its values are never shown.
+    #[serde(rename = "EnvironmentFileSecret")]
+    pub environment_file_secret: Option<PathBuf>,
+
     #[serde(rename = "User")]
     pub user: Option<String>,
+
+    /// Give the service a `/tmp` and `/var/tmp` of its own, empty at each
+    /// start. Like the other sandboxing options, it needs the `sandbox`
+    /// feature, Linux, and a daemon running as root.
+    #[serde(rename = "PrivateTmp")]
+    pub private_tmp: Option<bool>,
+
+    /// Mount the system's directories read-only for the service; see
+    /// [`ProtectSystem`]
+    #[serde(rename = "ProtectSystem")]
+    pub protect_system: Option<ProtectSystem>,
+
+    /// Paths the service sees read-only, relative ones resolved against the
+    /// unit file's directory
+    #[serde(rename = "ReadOnlyPaths")]
+    pub read_only_paths: Option<Vec<PathBuf>>,
+
+    /// What the service reads on stdin; see [`StandardInput`]
+    #[serde(rename = "StandardInput")]
+    pub standard_input: Option<StandardInput>,
+
+    /// Where stdout and stderr go; see [`StandardOutput`]
+    #[serde(rename = "StandardOutput")]
+    pub standard_output: Option<StandardOutput>,
+
+    /// Tag of the service's lines in the system log; its name by default
+    #[serde(rename = "SyslogIdentifier")]
+    pub syslog_identifier: Option<String>,
+
+    /// Priority of the service's lines in the system log; `info` by default
+    #[serde(rename = "SyslogLevel")]
+    pub syslog_level: Option<SyslogLevel>,
+
+    #[serde(rename = "RestartOnChange")]
+    pub restart_on_change: Option<bool>,
+
+    #[serde(rename = "WatchPaths")]
+    pub watch_paths: Option<Vec<PathBuf>>,
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+/// How the service is started when the daemon boots
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstallSection {
+    /// Targets that start the service when the daemon boots into them
+    /// (`default.target` unless configured otherwise), as a name or a list
+    #[serde(rename = "WantedBy", default, deserialize_with = "string_or_list")]
+    pub wanted_by: Vec<String>,
+}
+
+/// Starts the service periodically, typically a `oneshot` job
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimerSection {
+    /// How long after the service was last started (or loaded, if it never
+    /// was) to start it again
+    #[serde(rename = "OnUnitActiveSec", with = "duration")]
+    pub on_unit_active_sec: Duration,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum ServiceType {
+    #[default]
     Simple,
     Forking,
     Oneshot,
 }
 
-impl Default for ServiceType {
-    fn default() -> Self {
-        ServiceType::Simple
+/// Which of a service's processes are signalled when it is stopped. Each
+/// service runs in a process group of its own, which holds any processes it
+/// spawns unless they leave it.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum KillMode {
+    /// SIGTERM, then SIGKILL if the group outlives the grace period, to every
+    /// process in the group
+    ControlGroup,
+    /// SIGTERM to the main process only, so it can stop its children itself,
+    /// then SIGKILL to whatever is left of the group after the grace period
+    Mixed,
+    /// SIGTERM and SIGKILL to the main process only; its children are left
+    /// running
+    #[default]
+    Process,
+}
+
+/// How much of the filesystem a service sees read-only. Mounts below these
+/// directories, such as `/proc` and `/dev` below `/`, keep their own access.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ProtectSystem {
+    #[default]
+    No,
+    /// `/usr`, `/boot` and `/efi`
+    Yes,
+    /// `/etc` as well
+    Full,
+    /// The whole filesystem
+    Strict,
+}
+
+impl ProtectSystem {
+    /// The directories made read-only
+    pub fn paths(self) -> &'static [&'static str] {
+        match self {
+            ProtectSystem::No => &[],
+            ProtectSystem::Yes => &["/usr", "/boot", "/efi"],
+            ProtectSystem::Full => &["/usr", "/boot", "/efi", "/etc"],
+            ProtectSystem::Strict => &["/"],
+        }
+    }
+}
+
+/// Where a service's stdout and stderr go
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StandardOutput {
+    /// `<name>.log` in the daemon's `log_dir`, each line timestamped
+    #[default]
+    Log,
+    /// The system log (`/dev/log`), tagged with `SyslogIdentifier`
+    Syslog,
+}
+
+/// A syslog severity, from most to least severe
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogLevel {
+    Emerg,
+    Alert,
+    Crit,
+    Err,
+    Warning,
+    Notice,
+    #[default]
+    Info,
+    Debug,
+}
+
+impl SyslogLevel {
+    /// The severity's number in the syslog protocol
+    pub fn code(self) -> u8 {
+        self as u8
     }
 }
 
-#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+/// A daily range of time, written `HH:MM-HH:MM` in UTC. A range that ends
+/// before it starts wraps past midnight (`22:00-06:00`).
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeWindow {
+    /// Minutes after midnight
+    start: u32,
+    end: u32,
+}
+
+impl TimeWindow {
+    /// Whether `time` falls within the window, including its start but not its end
+    pub fn contains(&self, time: std::time::SystemTime) -> bool {
+        let secs = time
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+        let minute = ((secs % 86_400) / 60) as u32;
+
+        if self.start <= self.end {
+            (self.start..self.end).contains(&minute)
+        } else {
+            minute >= self.start || minute < self.end
+        }
+    }
+}
+
+impl TryFrom<String> for TimeWindow {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let minutes = |time: &str| {
+            let (hours, minutes) = time.trim().split_once(':')?;
+            let hours: u32 = hours.parse().ok()?;
+            let minutes: u32 = minutes.parse().ok().filter(|m| *m < 60)?;
+            Some(hours * 60 + minutes).filter(|m| *m <= 24 * 60)
+        };
+
+        value
+            .split_once('-')
+            .and_then(|(start, end)| {
+                Some(TimeWindow {
+                    start: minutes(start)?,
+                    end: minutes(end)?,
+                })
+            })
+            .ok_or_else(|| format!("invalid time window '{}', expected HH:MM-HH:MM", value))
+    }
+}
+
+impl From<TimeWindow> for String {
+    fn from(window: TimeWindow) -> Self {
+        format!(
+            "{:02}:{:02}-{:02}:{:02}",
+            window.start / 60,
+            window.start % 60,
+            window.end / 60,
+            window.end % 60
+        )
+    }
+}
+
+/// A socket that accepts connections once a service is ready
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ReadyWhen {
+    /// `HOST:PORT` to connect to over TCP
+    Tcp(String),
+    Unix(PathBuf),
+}
+
+impl ReadyWhen {
+    /// Whether a connection to the socket succeeds now
+    pub async fn accepts(&self) -> bool {
+        match self {
+            ReadyWhen::Tcp(addr) => tokio::net::TcpStream::connect(addr).await.is_ok(),
+            ReadyWhen::Unix(path) => tokio::net::UnixStream::connect(path).await.is_ok(),
+        }
+    }
+}
+
+impl std::fmt::Display for ReadyWhen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReadyWhen::Tcp(addr) => write!(f, "tcp:{}", addr),
+            ReadyWhen::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl TryFrom<String> for ReadyWhen {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        let invalid = || {
+            format!(
+                "invalid ReadyWhen '{}', expected tcp:HOST:PORT or unix:PATH",
+                value
+            )
+        };
+
+        match value.split_once(':') {
+            Some(("tcp", addr)) => match addr.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(ReadyWhen::Tcp(addr.to_string()))
+                }
+                _ => Err(invalid()),
+            },
+            Some(("unix", path)) if !path.is_empty() => Ok(ReadyWhen::Unix(PathBuf::from(path))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl From<ReadyWhen> for String {
+    fn from(ready_when: ReadyWhen) -> Self {
+        ready_when.to_string()
+    }
+}
+
+/// What a service reads on stdin
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum StandardInput {
+    /// `null`: `/dev/null`, so a read ends at once rather than blocking on
+    /// whatever the daemon was started with
+    #[default]
+    Null,
+    /// `file:PATH`, a file read from the start
+    File(PathBuf),
+    /// `text:TEXT`, fed to the service like a shell here-string, ending in a
+    /// newline
+    Text(String),
+}
+
+impl std::fmt::Display for StandardInput {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StandardInput::Null => write!(f, "null"),
+            StandardInput::File(path) => write!(f, "file:{}", path.display()),
+            StandardInput::Text(text) => write!(f, "text:{}", text),
+        }
+    }
+}
+
+impl TryFrom<String> for StandardInput {
+    type Error = String;
+
+    fn try_from(value: String) -> std::result::Result<Self, Self::Error> {
+        match value.split_once(':') {
+            _ if value == "null" => Ok(StandardInput::Null),
+            Some(("file", path)) if !path.is_empty() => Ok(StandardInput::File(PathBuf::from(path))),
+            Some(("text", text)) => Ok(StandardInput::Text(text.to_string())),
+            _ => Err(format!(
+                "invalid StandardInput '{}', expected null, file:PATH or text:TEXT",
+                value
+            )),
+        }
+    }
+}
+
+impl From<StandardInput> for String {
+    fn from(input: StandardInput) -> Self {
+        input.to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "kebab-case")]
 pub enum RestartPolicy {
     Always,
     OnFailure,
+    #[default]
     No,
 }
 
-impl Default for RestartPolicy {
-    fn default() -> Self {
-        RestartPolicy::No
-    }
-}
-
 impl UnitFile {
     pub fn from_file(path: &std::path::Path) -> crate::error::Result<Self> {
+        Self::from_file_with_vars(path, &HashMap::new())
+    }
+
+    /// Load a unit like [`from_file`](Self::from_file), substituting `${NAME}`
+    /// with the daemon-wide `vars`
+    pub fn from_file_with_vars(
+        path: &std::path::Path,
+        vars: &HashMap<String, String>,
+    ) -> crate::error::Result<Self> {
+        Self::load(path, vars, &read_drop_ins(path)?)
+    }
+
+    /// Load the unit file at `path` with `drop_ins` merged over it
+    fn load(path: &Path, vars: &HashMap<String, String>, drop_ins: &[(String, String)]) -> crate::error::Result<Self> {
         let content = std::fs::read_to_string(path)?;
         let name = path
             .file_stem()
@@ -88,13 +518,296 @@ impl UnitFile {
             .unwrap_or("unknown")
             .to_string();
 
-        let mut unit: UnitFile = toml::from_str(&content)
-            .map_err(|e| crate::error::DiakonosError::ParseError(e.to_string()))?;
+        let mut unit = Self::parse_with_drop_ins(&content, &path.display().to_string(), drop_ins)?;
 
         unit.name = name;
+        unit.source_path = Some(path.canonicalize()?);
+        unit.expand_specifiers(vars)?;
         Ok(unit)
     }
 
+    /// Parse a unit from TOML. Errors name the `origin` (usually the file)
+    /// and the line and column at fault on a single line, e.g.
+    /// `web.service:5:1: unknown field \`ExecStrat\` (did you mean \`ExecStart\`?)`.
+    pub fn parse(content: &str, origin: &str) -> crate::error::Result<Self> {
+        Self::parse_with_drop_ins(content, origin, &[])
+    }
+
+    /// Parse a unit like [`parse`](Self::parse) with drop-ins, given as
+    /// `(content, origin)` pairs, merged over it in order. A setting in a
+    /// drop-in replaces the one before it; the rest of its section is kept.
+    pub fn parse_with_drop_ins(
+        content: &str,
+        origin: &str,
+        drop_ins: &[(String, String)],
+    ) -> crate::error::Result<Self> {
+        let mut unit: UnitFile = toml::from_str(content).map_err(|e| locate_error(e, content, origin))?;
+
+        if !drop_ins.is_empty() {
+            let mut merged: toml::Table = toml::from_str(content).map_err(|e| locate_error(e, content, origin))?;
+            for (content, origin) in drop_ins {
+                let drop_in: toml::Table = toml::from_str(content).map_err(|e| locate_error(e, content, origin))?;
+                for (section, settings) in drop_in {
+                    match (merged.get_mut(&section), settings) {
+                        (Some(toml::Value::Table(merged)), toml::Value::Table(settings)) => merged.extend(settings),
+                        (_, settings) => {
+                            merged.insert(section, settings);
+                        }
+                    }
+                }
+
+                // Checked after each drop-in, so an error names the one at fault
+                unit = UnitFile::deserialize(toml::Value::Table(merged.clone())).map_err(|e| {
+                    crate::error::DiakonosError::ParseError(format!("{}: {}", origin, suggest_field(e.message())))
+                })?;
+            }
+        }
+
+        if unit.service.exec_start.is_empty() {
+            return Err(crate::error::DiakonosError::ParseError(format!(
+                "{}: [service] has no ExecStart; set it to the command to run",
+                origin
+            )));
+        }
+        if unit.service.exec_start.len() > 1 && !unit.is_oneshot() {
+            return Err(crate::error::DiakonosError::ParseError(format!(
+                "{}: [service] lists several ExecStart commands, which needs Type = \"oneshot\"",
+                origin
+            )));
+        }
+
+        Ok(unit)
+    }
+
+    /// The value of a setting, such as `Restart` or `service.Restart`, in
+    /// TOML, with strings unquoted; `None` if it isn't set
+    pub fn setting(&self, key: &str) -> crate::error::Result<Option<String>> {
+        let (section, field) = locate_setting(key)?;
+        let unit = toml::Value::try_from(self)
+            .map_err(|e| crate::error::DiakonosError::ParseError(format!("{}: {}", self.name, e)))?;
+        Ok(unit
+            .get(section)
+            .and_then(|settings| settings.get(field))
+            .map(|value| match value {
+                toml::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            }))
+    }
+
+    /// Name a unit that wasn't read from a file, such as a transient
+    /// service's, and expand its specifiers and `vars`. Relative paths in it
+    /// resolve against the daemon's working directory.
+    pub fn with_name(mut self, name: &str, vars: &HashMap<String, String>) -> crate::error::Result<Self> {
+        self.name = name.to_string();
+        self.expand_specifiers(vars)?;
+        Ok(self)
+    }
+
+    /// The instance part of a templated unit name (`web@8080` -> `8080`)
+    pub fn instance(&self) -> &str {
+        self.name.split_once('@').map(|(_, i)| i).unwrap_or("")
+    }
+
+    /// Expand `%` specifiers and `${NAME}` variables in `ExecStart`,
+    /// `WorkingDirectory`, `Environment`, `EnvironmentFileSecret`, a
+    /// `ReadyWhen` socket path and the `Condition*` paths
+    fn expand_specifiers(&mut self, vars: &HashMap<String, String>) -> crate::error::Result<()> {
+        let specifiers = Specifiers {
+            name: self.name.clone(),
+            instance: self.instance().to_string(),
+            home: home_dir().map(|h| h.display().to_string()).unwrap_or_default(),
+            runtime_dir: runtime_dir().display().to_string(),
+            vars,
+        };
+
+        for command in &mut self.service.exec_start {
+            *command = specifiers.expand(command)?;
+        }
+
+        for path in [
+            &mut self.service.working_directory,
+            &mut self.service.environment_file_secret,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let Some(raw) = path.to_str() {
+                *path = PathBuf::from(specifiers.expand(raw)?);
+            }
+        }
+
+        for env in self.service.environment.iter_mut().flatten() {
+            *env = specifiers.expand(env)?;
+        }
+
+        if let Some(ref mut identifier) = self.service.syslog_identifier {
+            *identifier = specifiers.expand(identifier)?;
+        }
+
+        if let Some(ReadyWhen::Unix(ref mut path)) = self.service.ready_when {
+            if let Some(raw) = path.to_str() {
+                *path = PathBuf::from(specifiers.expand(raw)?);
+            }
+        }
+
+        match self.service.standard_input {
+            Some(StandardInput::File(ref mut path)) => {
+                if let Some(raw) = path.to_str() {
+                    *path = PathBuf::from(specifiers.expand(raw)?);
+                }
+            }
+            Some(StandardInput::Text(ref mut text)) => *text = specifiers.expand(text)?,
+            _ => {}
+        }
+
+        let conditions = [
+            &mut self.unit.condition_path_exists,
+            &mut self.unit.condition_path_is_directory,
+            &mut self.unit.condition_file_not_empty,
+        ];
+        for path in conditions.into_iter().flatten() {
+            *path = specifiers.expand(path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Resolve a path from the unit against the directory containing the unit
+    /// file, so it doesn't depend on the daemon's working directory
+    pub fn resolve_path(&self, path: &Path) -> PathBuf {
+        match self.source_path.as_deref().and_then(Path::parent) {
+            Some(base) if path.is_relative() => base.join(path),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    /// How long a start may take to become ready, or `None` if it may take
+    /// as long as it needs (`TimeoutStartSec` of `0` or `"infinity"`)
+    pub fn start_timeout(&self) -> Option<Duration> {
+        Some(
+            self.service
+                .timeout_start_sec
+                .unwrap_or(Duration::from_secs(DEFAULT_TIMEOUT_START_SEC)),
+        )
+        .filter(|timeout| !timeout.is_zero())
+    }
+
+    /// How long the service may run before it is stopped, if it is limited
+    pub fn runtime_max(&self) -> Option<Duration> {
+        self.service.runtime_max_sec.filter(|limit| !limit.is_zero())
+    }
+
+    /// Whether exiting with `code` counts as a clean exit: zero, or listed
+    /// in `SuccessExitStatus`
+    pub fn is_success_exit(&self, code: i32) -> bool {
+        code == 0 || listed(&self.service.success_exit_status, code)
+    }
+
+    /// Whether a failure of the main process is ignored, as a `-` prefix on
+    /// the last `ExecStart` command asks
+    pub fn ignores_failure(&self) -> bool {
+        self.service
+            .exec_start
+            .last()
+            .is_some_and(|command| split_ignore_failure(command).0)
+    }
+
+    /// Whether exiting with `code` rules out a restart
+    pub fn prevents_restart(&self, code: i32) -> bool {
+        listed(&self.service.restart_prevent_exit_status, code)
+    }
+
+    /// Whether the service's output goes to the system log rather than its log file
+    pub fn logs_to_syslog(&self) -> bool {
+        self.service.standard_output == Some(StandardOutput::Syslog)
+    }
+
+    /// The tag of the service's lines in the system log
+    pub fn syslog_identifier(&self) -> &str {
+        self.service.syslog_identifier.as_deref().unwrap_or(&self.name)
+    }
+
+    /// How often a timer starts the service, if it has one
+    pub fn timer_interval(&self) -> Option<Duration> {
+        self.timer
+            .as_ref()
+            .map(|timer| timer.on_unit_active_sec)
+            .filter(|interval| !interval.is_zero())
+    }
+
+    /// Whether the supervisor may restart the service at `time`: always,
+    /// unless a `RestartWindow` excludes it
+    pub fn in_restart_window(&self, time: std::time::SystemTime) -> bool {
+        match self.service.restart_window {
+            Some(ref windows) => windows.iter().any(|window| window.contains(time)),
+            None => true,
+        }
+    }
+
+    pub fn is_oneshot(&self) -> bool {
+        self.service.service_type == Some(ServiceType::Oneshot)
+    }
+
+    /// The readiness check of a long-running service, with a relative socket
+    /// path resolved against the unit's directory. Oneshot services are ready
+    /// when they finish, so they have none.
+    pub fn ready_when(&self) -> Option<ReadyWhen> {
+        match self.service.ready_when {
+            _ if self.is_oneshot() => None,
+            Some(ReadyWhen::Unix(ref path)) => Some(ReadyWhen::Unix(self.resolve_path(path))),
+            ref ready_when => ready_when.clone(),
+        }
+    }
+
+    pub fn working_directory(&self) -> Option<PathBuf> {
+        self.service
+            .working_directory
+            .as_deref()
+            .map(|wd| self.resolve_path(wd))
+    }
+
+    /// The first `Condition*` that doesn't hold, as written in the unit
+    /// (e.g. `ConditionPathExists=!/etc/maintenance`), or `None` if all do
+    pub fn unmet_condition(&self) -> Option<String> {
+        let checks: [(&str, &Vec<String>, PathCheck); 3] = [
+            ("ConditionPathExists", &self.unit.condition_path_exists, |p| p.exists()),
+            ("ConditionPathIsDirectory", &self.unit.condition_path_is_directory, |p| p.is_dir()),
+            ("ConditionFileNotEmpty", &self.unit.condition_file_not_empty, |p| {
+                std::fs::metadata(p).is_ok_and(|m| m.is_file() && m.len() > 0)
+            }),
+        ];
+
+        for (field, values, check) in checks {
+            for value in values {
+                let (negated, path) = match value.strip_prefix('!') {
+                    Some(path) => (true, path),
+                    None => (false, value.as_str()),
+                };
+
+                if check(&self.resolve_path(Path::new(path))) == negated {
+                    return Some(format!("{}={}", field, value));
+                }
+            }
+        }
+
+        None
+    }
+
+    /// What the service reads on stdin, with a file's path resolved
+    pub fn standard_input(&self) -> StandardInput {
+        match self.service.standard_input {
+            Some(StandardInput::File(ref path)) => StandardInput::File(self.resolve_path(path)),
+            ref input => input.clone().unwrap_or_default(),
+        }
+    }
+
+    pub fn secret_file(&self) -> Option<PathBuf> {
+        self.service
+            .environment_file_secret
+            .as_deref()
+            .map(|path| self.resolve_path(path))
+    }
+
     pub fn dependencies(&self) -> Vec<String> {
         let mut deps = Vec::new();
 
@@ -109,7 +822,622 @@ impl UnitFile {
         deps
     }
 
+    /// Whether the service declares itself `PartOf` the target (`web.target`).
+    /// Membership may be written with or without the `.target` suffix.
+    pub fn is_part_of(&self, target: &str) -> bool {
+        self.unit
+            .part_of
+            .iter()
+            .flatten()
+            .any(|t| same_target(t, target))
+    }
+
+    pub fn stops_when_unneeded(&self) -> bool {
+        self.unit.stop_when_unneeded.unwrap_or(false)
+    }
+
+    /// Whether `name` is one of the service's `Alias` names
+    pub fn has_alias(&self, name: &str) -> bool {
+        self.unit.alias.iter().flatten().any(|alias| alias == name)
+    }
+
+    /// Whether the service is `WantedBy` the target, written with or without
+    /// the `.target` suffix
+    pub fn is_wanted_by(&self, target: &str) -> bool {
+        self.install
+            .iter()
+            .flat_map(|install| &install.wanted_by)
+            .any(|t| same_target(t, target))
+    }
+
+    /// Whether the service starts when the daemon boots into `target`: it is
+    /// `AutoStart`, or `WantedBy` the target
+    pub fn starts_on_boot(&self, target: &str) -> bool {
+        self.service.auto_start.unwrap_or(false) || self.is_wanted_by(target)
+    }
+
+    /// Files whose modification should restart the service. Empty unless
+    /// `RestartOnChange` is set; defaults to the `ExecStart` binary.
+    pub fn watch_paths(&self) -> Vec<PathBuf> {
+        if !self.service.restart_on_change.unwrap_or(false) {
+            return Vec::new();
+        }
+
+        if let Some(paths) = &self.service.watch_paths {
+            return paths.iter().map(|p| self.resolve_path(p)).collect();
+        }
+
+        self.service
+            .exec_start
+            .last()
+            .and_then(|command| split_ignore_failure(command).1.split_whitespace().next())
+            .and_then(|program| self.resolve_program(program))
+            .into_iter()
+            .collect()
+    }
+
     pub fn ordering_dependencies(&self) -> Vec<String> {
         self.unit.after.clone().unwrap_or_default()
     }
+
+    /// Resolve the program of a command: paths containing `/` resolve against
+    /// the unit's directory, bare names are looked up on `PATH`
+    pub fn resolve_program(&self, program: &str) -> Option<PathBuf> {
+        if program.contains('/') {
+            return Some(self.resolve_path(Path::new(program)));
+        }
+
+        search_path(program)
+    }
+}
+
+/// A TOML parse error of `content`, read from `origin`, as a single line
+/// naming the line and column at fault, and the key for a bad value
+fn locate_error(e: toml::de::Error, content: &str, origin: &str) -> crate::error::DiakonosError {
+    let message = suggest_field(e.message());
+    let Some(span) = e.span() else {
+        return crate::error::DiakonosError::ParseError(format!("{}: {}", origin, message));
+    };
+
+    let before = &content[..span.start.min(content.len())];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rsplit('\n').next().unwrap_or("");
+    let column = line_start.chars().count() + 1;
+
+    // For a bad value, name the key it was given for
+    let key = line_start
+        .split_once('=')
+        .map(|(key, _)| key.trim())
+        .filter(|key| !key.is_empty() && !message.contains(&format!("`{}`", key)));
+    crate::error::DiakonosError::ParseError(match key {
+        Some(key) => format!("{}:{}:{}: {}: {}", origin, line, column, key, message),
+        None => format!("{}:{}:{}: {}", origin, line, column, message),
+    })
+}
+
+/// Directory of drop-ins for the unit file at `path`, such as
+/// `web.service.d` for `web.service`
+pub fn drop_in_dir(path: &Path) -> PathBuf {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".d");
+    PathBuf::from(dir)
+}
+
+/// The drop-ins of the unit file at `path`, as `(content, origin)` pairs in
+/// the order they apply: every `*.toml` file in its drop-in directory, by
+/// name, so a later one overrides an earlier one
+fn read_drop_ins(path: &Path) -> crate::error::Result<Vec<(String, String)>> {
+    let entries = match std::fs::read_dir(drop_in_dir(path)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut files = Vec::new();
+    for entry in entries {
+        let file = entry?.path();
+        if file.extension().and_then(|s| s.to_str()) == Some("toml") && file.is_file() {
+            files.push(file);
+        }
+    }
+    files.sort();
+
+    files
+        .into_iter()
+        .map(|file| Ok((std::fs::read_to_string(&file)?, file.display().to_string())))
+        .collect()
+}
+
+/// Set `key` (a setting such as `Restart`, or `service.Restart` naming its
+/// section) to `value` in the [`OVERRIDE_FILE`] drop-in of the unit file at
+/// `path`, leaving the unit file itself untouched. A drop-in named after it
+/// still overrides it. `value` is read as TOML
+/// (`5`, `true`, `["a", "b"]`) if it is valid TOML, and as a string
+/// otherwise. Nothing is written unless the unit loads with the change.
+/// Returns the drop-in's path.
+pub fn set_override(
+    path: &Path,
+    key: &str,
+    value: &str,
+    vars: &HashMap<String, String>,
+) -> crate::error::Result<PathBuf> {
+    let (section, field) = locate_setting(key)?;
+    let file = drop_in_dir(path).join(OVERRIDE_FILE);
+    let origin = file.display().to_string();
+
+    let mut settings: toml::Table = match std::fs::read_to_string(&file) {
+        Ok(content) => toml::from_str(&content).map_err(|e| locate_error(e, &content, &origin))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => toml::Table::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let value = format!("value = {}", value)
+        .parse::<toml::Table>()
+        .ok()
+        .and_then(|mut parsed| parsed.remove("value"))
+        .unwrap_or_else(|| toml::Value::String(value.to_string()));
+    match settings
+        .entry(section)
+        .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+    {
+        toml::Value::Table(section) => {
+            section.insert(field.to_string(), value);
+        }
+        _ => {
+            return Err(crate::error::DiakonosError::ParseError(format!(
+                "{}: `{}` is not a section",
+                origin, section
+            )))
+        }
+    }
+    let content = toml::to_string(&settings)
+        .map_err(|e| crate::error::DiakonosError::ParseError(format!("{}: {}", origin, e)))?;
+
+    let mut drop_ins = read_drop_ins(path)?;
+    match drop_ins.iter_mut().find(|(_, existing)| *existing == origin) {
+        Some(drop_in) => drop_in.0 = content.clone(),
+        None => {
+            drop_ins.push((content.clone(), origin));
+            drop_ins.sort_by(|a, b| a.1.cmp(&b.1));
+        }
+    }
+    UnitFile::load(path, vars, &drop_ins)?;
+
+    std::fs::create_dir_all(drop_in_dir(path))?;
+    std::fs::write(&file, content)?;
+    Ok(file)
+}
+
+/// The section a setting belongs in and its name there, from `key`: a name
+/// such as `Restart`, or one qualified by its section, as `service.Restart`
+fn locate_setting(key: &str) -> crate::error::Result<(&'static str, &str)> {
+    let (section, field) = match key.split_once('.') {
+        Some((section, field)) => (Some(section), field),
+        None => (None, key),
+    };
+    if let Some(section) = section.filter(|section| !SECTIONS.contains(section)) {
+        return Err(crate::error::DiakonosError::UnknownSetting(format!(
+            "`{}`: there is no section `{}`",
+            key, section
+        )));
+    }
+
+    let mut suggestion = None;
+    for candidate in SECTIONS {
+        if section.is_some_and(|section| section != candidate) {
+            continue;
+        }
+        match section_setting(candidate, field) {
+            Ok(()) => return Ok((candidate, field)),
+            Err(message) if suggestion.is_none() && message.contains("did you mean") => suggestion = Some(message),
+            Err(_) => {}
+        }
+    }
+
+    Err(crate::error::DiakonosError::UnknownSetting(
+        suggestion
+            .as_deref()
+            .and_then(|message| message.strip_prefix("unknown field "))
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("`{}`", key)),
+    ))
+}
+
+/// `Ok` if `field` is a setting of `section`, or else serde's message
+/// saying it isn't, with a suggestion if one is close
+fn section_setting(section: &str, field: &str) -> std::result::Result<(), String> {
+    let mut probe = toml::Table::new();
+    probe.insert(field.to_string(), toml::Value::Boolean(false));
+    let probe = toml::Value::Table(probe);
+
+    // Only whether the field is known matters, not whether the probe is a
+    // valid value for it
+    let result = match section {
+        "unit" => UnitSection::deserialize(probe).map(drop),
+        "service" => ServiceSection::deserialize(probe).map(drop),
+        "install" => InstallSection::deserialize(probe).map(drop),
+        _ => TimerSection::deserialize(probe).map(drop),
+    };
+    match result {
+        Err(e) if e.message().starts_with("unknown field") => Err(suggest_field(e.message())),
+        _ => Ok(()),
+    }
+}
+
+/// Shorten serde's "unknown field `X`, expected one of `A`, `B`, ..." to a
+/// suggestion of the closest known field, if one is close enough to be a typo
+fn suggest_field(message: &str) -> String {
+    let message = message.trim();
+    let Some((unknown, expected)) = message.split_once(", expected ") else {
+        return message.to_string();
+    };
+    let Some(field) = unknown.strip_prefix("unknown field `").and_then(|f| f.strip_suffix('`')) else {
+        return message.to_string();
+    };
+
+    // Allow about one typo per three characters, as for service names, and
+    // any difference in case
+    let max_distance = (field.chars().count() / 3).max(1);
+    let closest = expected
+        .split('`')
+        .skip(1)
+        .step_by(2)
+        .map(|candidate| {
+            let distance = crate::manager::edit_distance(&field.to_lowercase(), &candidate.to_lowercase());
+            (distance, candidate)
+        })
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min();
+
+    match closest {
+        Some((_, candidate)) => format!("{} (did you mean `{}`?)", unknown, candidate),
+        None => message.to_string(),
+    }
+}
+
+/// Check that `name` names a unit inside the service directory: it must not
+/// be empty, `.` or `..`, or contain a path separator or NUL
+pub fn validate_service_name(name: &str) -> crate::error::Result<()> {
+    if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\0']) {
+        return Err(crate::error::DiakonosError::InvalidServiceName(format!(
+            "{:?} (names may not contain '/' or be '.' or '..')",
+            name
+        )));
+    }
+    Ok(())
+}
+
+/// Read the daemon-wide variables units refer to as `${NAME}`: a TOML file of
+/// `NAME = "value"` pairs
+pub fn read_vars_file(path: &Path) -> crate::error::Result<HashMap<String, String>> {
+    let invalid = |reason: String| {
+        crate::error::DiakonosError::ConfigError(format!("vars file {}: {}", path.display(), reason))
+    };
+
+    let content = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    toml::from_str(&content).map_err(|e| invalid(e.message().to_string()))
+}
+
+/// Read `KEY=value` pairs from a secrets file. Blank lines and `#` comments
+/// are skipped. Fails if the file is accessible to group or others, so a
+/// misconfigured secret is noticed instead of silently used.
+pub fn read_secret_file(path: &Path) -> crate::error::Result<Vec<(String, String)>> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let invalid = |reason: String| {
+        crate::error::DiakonosError::StartError(format!(
+            "secret file {}: {}",
+            path.display(),
+            reason
+        ))
+    };
+
+    let mode = std::fs::metadata(path)
+        .map_err(|e| invalid(e.to_string()))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        return Err(invalid(format!("mode is {:o}, expected 600", mode & 0o777)));
+    }
+
+    let content = std::fs::read_to_string(path).map_err(|e| invalid(e.to_string()))?;
+    let mut vars = Vec::new();
+
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        // Never echo the line itself, it holds a secret
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid(format!("line {}: expected KEY=value", number + 1)))?;
+        vars.push((key.trim().to_string(), value.trim().to_string()));
+    }
+
+    Ok(vars)
+}
+
+type PathCheck = fn(&Path) -> bool;
+
+/// Values substituted for systemd-style `%` specifiers
+struct Specifiers<'a> {
+    /// `%n`: full unit name
+    name: String,
+    /// `%i`: instance name, empty for non-template units
+    instance: String,
+    /// `%h`: home directory of the daemon user
+    home: String,
+    /// `%t`: runtime directory
+    runtime_dir: String,
+    /// `${NAME}`: the daemon's `vars_file`
+    vars: &'a HashMap<String, String>,
+}
+
+impl Specifiers<'_> {
+    /// Expand specifiers and variables in one pass, so substituted values are
+    /// taken literally. A `${NAME}` not in the vars file is left for the
+    /// process (or a shell it runs) to make of.
+    fn expand(&self, value: &str) -> crate::error::Result<String> {
+        let mut out = String::with_capacity(value.len());
+        let mut chars = value.chars();
+
+        while let Some(c) = chars.next() {
+            if c == '$' && chars.as_str().starts_with('{') {
+                let rest = &chars.as_str()[1..];
+                let var = rest.find('}').and_then(|end| Some((end, self.vars.get(&rest[..end])?)));
+                if let Some((end, var)) = var {
+                    out.push_str(var);
+                    chars = rest[end + 1..].chars();
+                    continue;
+                }
+            }
+
+            if c != '%' {
+                out.push(c);
+                continue;
+            }
+
+            match chars.next() {
+                Some('n') => out.push_str(&self.name),
+                Some('i') => out.push_str(&self.instance),
+                Some('h') => out.push_str(&self.home),
+                Some('t') => out.push_str(&self.runtime_dir),
+                Some('%') => out.push('%'),
+                Some(other) => {
+                    return Err(crate::error::DiakonosError::ParseError(format!(
+                        "unknown specifier '%{}' in \"{}\"",
+                        other, value
+                    )))
+                }
+                None => {
+                    return Err(crate::error::DiakonosError::ParseError(format!(
+                        "incomplete specifier at end of \"{}\"",
+                        value
+                    )))
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// The daemon user's home directory: `$HOME`, falling back to the passwd entry
+pub fn home_dir() -> Option<PathBuf> {
+    if let Some(home) = std::env::var_os("HOME").filter(|h| !h.is_empty()) {
+        return Some(PathBuf::from(home));
+    }
+
+    nix::unistd::User::from_uid(nix::unistd::getuid())
+        .ok()
+        .flatten()
+        .map(|user| user.dir)
+}
+
+/// `$XDG_RUNTIME_DIR`, or `/run` for root and the temp dir for other users
+pub fn runtime_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty()) {
+        return PathBuf::from(dir);
+    }
+
+    if nix::unistd::getuid().is_root() {
+        PathBuf::from("/run")
+    } else {
+        std::env::temp_dir()
+    }
+}
+
+fn search_path(program: &str) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .map(|dir| dir.join(program))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Whether two target names are the same, ignoring the `.target` suffix
+fn same_target(a: &str, b: &str) -> bool {
+    a.strip_suffix(TARGET_SUFFIX).unwrap_or(a) == b.strip_suffix(TARGET_SUFFIX).unwrap_or(b)
+}
+
+/// Split the `-` prefix, which makes a failure of the command count as
+/// success, off a command: whether it was there, and the command without it
+pub fn split_ignore_failure(command: &str) -> (bool, &str) {
+    match command.strip_prefix('-') {
+        Some(command) => (true, command),
+        None => (false, command),
+    }
+}
+
+/// Whether exit code `code` is one of `codes`
+fn listed(codes: &Option<Vec<u8>>, code: i32) -> bool {
+    codes
+        .iter()
+        .flatten()
+        .any(|&listed| i32::from(listed) == code)
+}
+
+fn environment_list_or_table<'de, D>(deserializer: D) -> std::result::Result<Option<Vec<String>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    use serde::de::Error;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum ListOrTable {
+        List(Vec<String>),
+        Table(BTreeMap<String, String>),
+    }
+
+    let table = match Option::<ListOrTable>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(ListOrTable::List(entries)) => return Ok(Some(entries)),
+        Some(ListOrTable::Table(table)) => table,
+    };
+
+    if let Some(key) = table.keys().find(|key| key.is_empty() || key.contains('=')) {
+        return Err(D::Error::custom(format!(
+            "invalid environment variable name '{}'",
+            key
+        )));
+    }
+    Ok(Some(
+        table
+            .into_iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect(),
+    ))
+}
+
+fn string_or_list<'de, D>(deserializer: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(command) => vec![command],
+        StringOrList::List(commands) => commands,
+    })
+}
+
+/// A duration in a unit file: a whole number of seconds, or a string with
+/// unit suffixes such as `"500ms"`, `"30s"`, `"5min"` or `"1h 30min"`. A
+/// string without a suffix is seconds too.
+struct UnitDuration(Duration);
+
+/// A [`UnitDuration`] that bounds something, where `"infinity"` lifts the
+/// bound. It is kept as zero, which lifts it too.
+struct UnitLimit(Duration);
+
+impl<'de> Deserialize<'de> for UnitDuration {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let visitor = DurationVisitor { limit: false };
+        deserializer.deserialize_any(visitor).map(UnitDuration)
+    }
+}
+
+impl<'de> Deserialize<'de> for UnitLimit {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let visitor = DurationVisitor { limit: true };
+        deserializer.deserialize_any(visitor).map(UnitLimit)
+    }
+}
+
+/// Reads a [`UnitDuration`], or with `limit` a [`UnitLimit`]
+struct DurationVisitor {
+    limit: bool,
+}
+
+impl serde::de::Visitor<'_> for DurationVisitor {
+    type Value = Duration;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("a number of seconds or a duration such as \"500ms\" or \"5min\"")?;
+        if self.limit {
+            f.write_str(", or \"infinity\"")?;
+        }
+        Ok(())
+    }
+
+    fn visit_u64<E: serde::de::Error>(self, secs: u64) -> std::result::Result<Duration, E> {
+        Ok(Duration::from_secs(secs))
+    }
+
+    fn visit_i64<E: serde::de::Error>(self, secs: i64) -> std::result::Result<Duration, E> {
+        u64::try_from(secs)
+            .map(Duration::from_secs)
+            .map_err(|_| E::custom(format!("invalid duration {}: must not be negative", secs)))
+    }
+
+    fn visit_str<E: serde::de::Error>(self, value: &str) -> std::result::Result<Duration, E> {
+        let value = value.trim();
+        if self.limit && value.eq_ignore_ascii_case("infinity") {
+            return Ok(Duration::ZERO);
+        }
+        if let Ok(secs) = value.parse::<u64>() {
+            return Ok(Duration::from_secs(secs));
+        }
+        humantime::parse_duration(value)
+            .map_err(|e| E::custom(format!("invalid duration '{}': {}", value, e)))
+    }
+}
+
+/// A required [`UnitDuration`] field, written back in the same form so a
+/// unit sent over the control socket reads the same
+mod duration {
+    use super::UnitDuration;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(&humantime::format_duration(*value))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        UnitDuration::deserialize(deserializer).map(|duration| duration.0)
+    }
+}
+
+/// An optional [`UnitDuration`] field; see [`duration`]
+mod optional_duration {
+    use super::UnitDuration;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Duration;
+
+    pub fn serialize<S: Serializer>(value: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match value {
+            Some(value) => super::duration::serialize(value, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<UnitDuration>::deserialize(deserializer)?.map(|duration| duration.0))
+    }
+}
+
+/// An optional [`UnitLimit`] field, where zero means no limit; see [`duration`]
+mod optional_limit {
+    use super::UnitLimit;
+    use serde::{Deserialize, Deserializer};
+    use std::time::Duration;
+
+    pub use super::optional_duration::serialize;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<UnitLimit>::deserialize(deserializer)?.map(|limit| limit.0))
+    }
 }
@@ -0,0 +1,159 @@
+use crate::manager::ServiceManager;
+use crate::service::ServiceState;
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tokio::time::{sleep_until, Duration, Instant};
+use tracing::{error, info, warn};
+
+/// Quiet period after the last change before a restart is triggered, so an
+/// editor or deploy tool writing a file in several steps causes one restart.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// Watch the `WatchPaths` of every service with `RestartOnChange` enabled and
+/// restart running services when one of their files changes. What is
+/// watched follows the units as they are loaded and reloaded.
+pub async fn watch_services(manager: Arc<ServiceManager>) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<PathBuf>();
+    let mut watched = WatchSet::new(tx);
+    watched.update(&manager).await;
+
+    let mut pending: HashMap<String, Instant> = HashMap::new();
+
+    loop {
+        let next = pending.values().min().copied();
+
+        tokio::select! {
+            path = rx.recv() => {
+                let Some(path) = path else { break };
+                for (name, paths) in &watched.targets {
+                    if paths.contains(&path) {
+                        pending.insert(name.clone(), Instant::now() + DEBOUNCE);
+                    }
+                }
+            }
+            _ = manager.wait_for_unit_changes() => {
+                watched.update(&manager).await;
+                pending.retain(|name, _| watched.targets.iter().any(|(target, _)| target == name));
+            }
+            _ = sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                let now = Instant::now();
+                let due: Vec<String> = pending
+                    .iter()
+                    .filter(|(_, deadline)| **deadline <= now)
+                    .map(|(name, _)| name.clone())
+                    .collect();
+
+                for name in due {
+                    pending.remove(&name);
+                    restart_on_change(&manager, &name).await;
+                }
+            }
+        }
+    }
+
+    warn!("File watcher exited");
+}
+
+/// The watched files of each service, and the directories watched for them
+struct WatchSet {
+    targets: Vec<(String, Vec<PathBuf>)>,
+    dirs: HashSet<PathBuf>,
+    /// Created once there is something to watch
+    watcher: Option<RecommendedWatcher>,
+    changes: mpsc::UnboundedSender<PathBuf>,
+}
+
+impl WatchSet {
+    fn new(changes: mpsc::UnboundedSender<PathBuf>) -> Self {
+        Self {
+            targets: Vec::new(),
+            dirs: HashSet::new(),
+            watcher: None,
+            changes,
+        }
+    }
+
+    /// Watch what the loaded units ask for now, and stop watching the rest
+    async fn update(&mut self, manager: &ServiceManager) {
+        self.targets = manager
+            .watch_targets()
+            .await
+            .into_iter()
+            .map(|(name, paths)| (name, paths.iter().filter_map(|p| normalize(p)).collect()))
+            .collect();
+
+        // Watch parent directories rather than the files themselves so that
+        // replace-on-save (write to temp file, then rename) is still noticed
+        let wanted: HashSet<PathBuf> = self
+            .targets
+            .iter()
+            .flat_map(|(_, paths)| paths)
+            .filter_map(|path| path.parent().map(Path::to_path_buf))
+            .collect();
+        if wanted == self.dirs {
+            return;
+        }
+
+        let watcher = match self.watcher {
+            Some(ref mut watcher) => watcher,
+            None => {
+                let changes = self.changes.clone();
+                let watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+                    // Reads of a watched file (e.g. the service loading it) are not changes
+                    match res {
+                        Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                            for path in event.paths {
+                                let _ = changes.send(path);
+                            }
+                        }
+                        _ => {}
+                    }
+                });
+                match watcher {
+                    Ok(watcher) => self.watcher.insert(watcher),
+                    Err(e) => {
+                        error!("Failed to create file watcher: {}", e);
+                        return;
+                    }
+                }
+            }
+        };
+
+        for dir in self.dirs.difference(&wanted) {
+            let _ = watcher.unwatch(dir);
+        }
+        let mut dirs = HashSet::new();
+        for dir in wanted {
+            if !self.dirs.contains(&dir) {
+                if let Err(e) = watcher.watch(&dir, RecursiveMode::NonRecursive) {
+                    warn!("Failed to watch {:?}: {}", dir, e);
+                    continue;
+                }
+            }
+            dirs.insert(dir);
+        }
+        self.dirs = dirs;
+    }
+}
+
+async fn restart_on_change(manager: &ServiceManager, name: &str) {
+    if !matches!(manager.get_service_status(name).await, Ok(ServiceState::Running)) {
+        return;
+    }
+
+    info!("Watched files of service {} changed, restarting", name);
+    if let Err(e) = manager.restart_service(name).await {
+        error!("Failed to restart service {} after change: {}", name, e);
+    }
+}
+
+/// Make `path` absolute with a canonical parent directory so it compares equal
+/// to the paths reported by the watcher. The file itself need not exist.
+fn normalize(path: &Path) -> Option<PathBuf> {
+    let absolute = std::env::current_dir().ok()?.join(path);
+    let dir = absolute.parent()?.canonicalize().ok()?;
+    Some(dir.join(absolute.file_name()?))
+}
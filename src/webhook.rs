@@ -0,0 +1,68 @@
+use crate::service::ServiceState;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+use tracing::{debug, warn};
+
+/// How long a webhook delivery may take before it is abandoned
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A service failing or recovering, as POSTed to the webhook, or any state
+/// transition, as kept in the [history](crate::history::History)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateChange {
+    pub service: String,
+    pub old_state: ServiceState,
+    pub new_state: ServiceState,
+    /// How the process exited, for a failure or stop, or what restarted it
+    pub exit_reason: Option<String>,
+    /// RFC 3339, UTC
+    pub timestamp: String,
+}
+
+impl StateChange {
+    pub fn new(
+        service: &str,
+        old_state: ServiceState,
+        new_state: ServiceState,
+        exit_reason: Option<String>,
+    ) -> Self {
+        Self {
+            service: service.to_string(),
+            old_state,
+            new_state,
+            exit_reason,
+            timestamp: humantime::format_rfc3339_seconds(SystemTime::now()).to_string(),
+        }
+    }
+}
+
+/// Delivers state changes to the `webhook_url` from the daemon config
+pub struct Webhook {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        let client = reqwest::Client::builder()
+            .timeout(DELIVERY_TIMEOUT)
+            .build()
+            .unwrap_or_default();
+        Self { client, url }
+    }
+
+    /// POST `change` as JSON in the background. Delivery is best effort: a
+    /// failure is logged and never holds up the caller.
+    pub fn notify(&self, change: StateChange) {
+        let request = self.client.post(&self.url).json(&change);
+        tokio::spawn(async move {
+            match request.send().await.and_then(|r| r.error_for_status()) {
+                Ok(_) => debug!(
+                    "Sent webhook for {} ({} -> {})",
+                    change.service, change.old_state, change.new_state
+                ),
+                Err(e) => warn!("Failed to send webhook for {}: {}", change.service, e),
+            }
+        });
+    }
+}
@@ -0,0 +1,3437 @@
+//! Round trips through a real daemon: each test runs the daemon in-process on
+//! a socket in a temporary directory and talks to it with the real client.
+
+use diakonos::client::{Client, LogEvent, PersistentClient, ServiceClient};
+use diakonos::daemon::{run_daemon, DaemonConfig};
+use diakonos::doctor::{run_checks, Outcome};
+use diakonos::error::DiakonosError;
+use diakonos::history::History;
+use diakonos::ipc::{
+    decode_request, decode_response, read_frame, write_frame, Request, RequestEnvelope, Response,
+    ResponseEnvelope,
+};
+use diakonos::manager::{Relation, ServiceManager};
+use diakonos::service::{ServiceState, ServiceStats};
+use diakonos::unit::UnitFile;
+use nix::sys::signal::Signal;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tempfile::TempDir;
+use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+use tokio::time::sleep;
+
+struct TestDaemon {
+    dir: TempDir,
+    client: Client,
+    task: JoinHandle<()>,
+}
+
+impl TestDaemon {
+    /// Start a daemon serving the given `(name, unit file)` pairs
+    async fn start(units: &[(&str, &str)]) -> Self {
+        Self::start_with(units, |_| {}).await
+    }
+
+    /// Start a daemon like `start`, adjusting its configuration first
+    async fn start_with(units: &[(&str, &str)], configure: impl FnOnce(&mut DaemonConfig)) -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let service_dir = dir.path().join("services");
+        std::fs::create_dir(&service_dir).unwrap();
+
+        for (name, unit) in units {
+            std::fs::write(service_dir.join(format!("{}.service", name)), unit).unwrap();
+        }
+
+        let mut config = DaemonConfig {
+            socket_path: dir.path().join("daemon.sock"),
+            pid_file: dir.path().join("daemon.pid"),
+            service_dir,
+            log_file: dir.path().join("daemon.log"),
+            log_dir: dir.path().join("logs"),
+            audit_log: dir.path().join("audit.log"),
+            history_file: dir.path().join("history.jsonl"),
+            supervision_interval: 1,
+            ..DaemonConfig::default()
+        };
+        configure(&mut config);
+
+        let task = tokio::spawn({
+            let config = config.clone();
+            async move {
+                let _ = run_daemon(config).await;
+            }
+        });
+
+        let client = Client::new(config.clone());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !matches!(client.send_request(Request::Ping).await, Ok(Response::Pong)) {
+            assert!(Instant::now() < deadline, "daemon did not come up");
+            sleep(Duration::from_millis(20)).await;
+        }
+
+        Self { dir, client, task }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    async fn request(&self, request: Request) -> Response {
+        self.client.send_request(request).await.unwrap()
+    }
+
+    async fn start_service(&self, service: &str) -> Response {
+        self.request(Request::Start {
+            service: service.to_string(),
+            wait: None,
+            env: Vec::new(),
+            no_supervise: false,
+        })
+        .await
+    }
+
+    async fn stop_service(&self, service: &str) -> Response {
+        self.request(Request::Stop {
+            service: service.to_string(),
+            wait: None,
+            force: false,
+        })
+        .await
+    }
+
+    async fn state(&self, service: &str) -> ServiceState {
+        match self.stats(service).await {
+            Some(stats) => stats.state,
+            None => panic!("service '{}' is not loaded", service),
+        }
+    }
+
+    async fn stats(&self, service: &str) -> Option<ServiceStats> {
+        match self.request(Request::Stats).await {
+            Response::Stats { stats } => stats.into_iter().find(|s| s.name == service),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    async fn dependencies(&self, service: &str) -> Vec<String> {
+        match self.request(Request::Deps { service: service.to_string() }).await {
+            Response::Deps { dependencies, .. } => dependencies,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    /// Poll until `service` is in `state`, failing after `timeout`
+    async fn wait_for_state(&self, service: &str, state: ServiceState, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let current = self.state(service).await;
+            if current == state {
+                return;
+            }
+            assert!(
+                Instant::now() < deadline,
+                "'{}' is {:?}, expected {:?}",
+                service,
+                current,
+                state
+            );
+            sleep(Duration::from_millis(50)).await;
+        }
+    }
+}
+
+impl Drop for TestDaemon {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+fn simple(exec_start: &str) -> String {
+    format!("[unit]\n[service]\nExecStart = \"{}\"\n", exec_start)
+}
+
+fn assert_ok(response: Response) -> String {
+    match response {
+        Response::Ok { message } => message,
+        other => panic!("expected Ok, got {:?}", other),
+    }
+}
+
+fn assert_error(response: Response) -> String {
+    match response {
+        Response::Error { message } => message,
+        other => panic!("expected Error, got {:?}", other),
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn start_status_list_and_stop() {
+    let daemon = TestDaemon::start(&[("web", &simple("sleep 30")), ("idle", &simple("sleep 30"))]).await;
+
+    assert_ok(daemon.start_service("web").await);
+
+    match daemon.request(Request::Status { service: "web".to_string() }).await {
+        Response::Status { service, state } => {
+            assert_eq!(service, "web");
+            assert_eq!(state, ServiceState::Running);
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    match daemon.request(Request::List).await {
+        Response::List { mut services } => {
+            services.sort_by(|a, b| a.0.cmp(&b.0));
+            assert_eq!(
+                services,
+                vec![
+                    ("idle".to_string(), ServiceState::Stopped),
+                    ("web".to_string(), ServiceState::Running),
+                ]
+            );
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    let pid = daemon.stats("web").await.unwrap().pid.unwrap();
+    assert_ok(daemon.stop_service("web").await);
+
+    let stats = daemon.stats("web").await.unwrap();
+    assert_eq!(stats.state, ServiceState::Stopped);
+    assert_eq!(stats.pid, None);
+    assert!(!Path::new(&format!("/proc/{}", pid)).exists(), "process {} was not reaped", pid);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unknown_service_is_an_error() {
+    let daemon = TestDaemon::start(&[]).await;
+
+    let message = assert_error(daemon.start_service("missing").await);
+    assert!(message.contains("not found"), "{}", message);
+    assert_error(daemon.request(Request::Status { service: "missing".to_string() }).await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unknown_names_suggest_close_matches() {
+    let daemon = TestDaemon::start(&[
+        ("web", &simple("sleep 30")),
+        ("web2", &simple("sleep 30")),
+        ("database", &simple("sleep 30")),
+    ])
+    .await;
+
+    let message = assert_error(daemon.request(Request::Status { service: "wbe".to_string() }).await);
+    assert!(message.contains("did you mean 'web'?"), "{}", message);
+
+    let message = assert_error(daemon.start_service("databse").await);
+    assert!(message.contains("did you mean 'database'?"), "{}", message);
+
+    let message = assert_error(daemon.stop_service("webb").await);
+    assert!(message.contains("did you mean 'web' or 'web2'?"), "{}", message);
+
+    let message = assert_error(daemon.stop_service("cache").await);
+    assert!(!message.contains("did you mean"), "{}", message);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn names_escaping_the_service_dir_are_rejected() {
+    let daemon = TestDaemon::start(&[]).await;
+    // A unit just outside the service directory that must never be loaded
+    std::fs::write(daemon.path("evil.service"), simple("sleep 30")).unwrap();
+
+    for name in ["../evil", "../../etc/passwd", "/etc/passwd", "a/b", "..", ".", ""] {
+        let message = assert_error(daemon.start_service(name).await);
+        assert!(message.contains("Invalid service name"), "{}: {}", name, message);
+    }
+
+    let manager = ServiceManager::new(&DaemonConfig {
+        service_dir: daemon.path("services"),
+        ..DaemonConfig::default()
+    });
+    for name in ["../evil", "sub/../../evil"] {
+        assert!(matches!(
+            manager.load_service(name).await,
+            Err(DiakonosError::InvalidServiceName(_))
+        ));
+    }
+    assert!(manager.list_services().await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stop_returns_once_the_process_exits() {
+    let daemon = TestDaemon::start(&[("web", &simple("sleep 30"))]).await;
+    assert_ok(daemon.start_service("web").await);
+
+    // `sleep` exits on the first SIGTERM, so stopping must not wait out the grace period
+    let started = Instant::now();
+    assert_ok(daemon.stop_service("web").await);
+    assert!(started.elapsed() < Duration::from_secs(2), "stop took {:?}", started.elapsed());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn waiting_start_reports_a_failing_service() {
+    let daemon = TestDaemon::start(&[("broken", &simple("false"))]).await;
+
+    let message = assert_error(
+        daemon
+            .request(Request::Start {
+                service: "broken".to_string(),
+                wait: Some(Duration::from_secs(5)),
+                env: Vec::new(),
+                no_supervise: false,
+            })
+            .await,
+    );
+    assert!(message.contains("failed"), "{}", message);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn list_comes_in_pages_in_name_order() {
+    let daemon = TestDaemon::start(&[
+        ("web", &simple("sleep 30")),
+        ("api", &simple("sleep 30")),
+        ("db", &simple("sleep 30")),
+    ])
+    .await;
+    assert_ok(daemon.start_service("db").await);
+
+    let page = |after: Option<&str>| Request::ListPage {
+        after: after.map(str::to_string),
+        limit: 2,
+    };
+    match daemon.request(page(None)).await {
+        Response::ListPage { services, total } => {
+            assert_eq!(total, 3);
+            assert_eq!(
+                services,
+                [
+                    ("api".to_string(), ServiceState::Stopped),
+                    ("db".to_string(), ServiceState::Running),
+                ]
+            );
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    // A service added before the cursor doesn't shift the next page
+    std::fs::write(daemon.path("services/cache.service"), simple("sleep 30")).unwrap();
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+    match daemon.request(page(Some("db"))).await {
+        Response::ListPage { services, total } => {
+            assert_eq!(total, 4);
+            assert_eq!(services, [("web".to_string(), ServiceState::Stopped)]);
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    match daemon.client.list().await.unwrap() {
+        Response::List { services } => {
+            let names: Vec<&str> = services.iter().map(|(name, _)| name.as_str()).collect();
+            assert_eq!(names, ["api", "cache", "db", "web"]);
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    // A stand-in daemon that fails `ListPage` with `message`, and answers `List`
+    let stand_in = |name: &str, message: &'static str| {
+        let socket = daemon.path(name);
+        let listener = UnixListener::bind(&socket).unwrap();
+        let server = tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let (reader, mut writer) = stream.into_split();
+                let mut reader = BufReader::new(reader);
+                let mut frame = Vec::new();
+                assert!(read_frame(&mut reader, &mut frame, usize::MAX).await.unwrap());
+                let (request, id) = decode_request(&frame).unwrap();
+                let response = match request {
+                    Request::ListPage { .. } => Response::error(message),
+                    Request::List => Response::List {
+                        services: vec![("old".to_string(), ServiceState::Running)],
+                    },
+                    other => panic!("unexpected request: {:?}", other),
+                };
+                write_frame(&mut writer, &ResponseEnvelope { id, response }).await.unwrap();
+            }
+        });
+        let client = Client::new(DaemonConfig {
+            socket_path: socket,
+            ..DaemonConfig::default()
+        });
+        (client, server)
+    };
+
+    // One from before `ListPage` is sent a plain `List`
+    let (client, server) = stand_in("old.sock", "Invalid request: unknown variant `ListPage`");
+    match client.list().await.unwrap() {
+        Response::List { services } => assert_eq!(services, [("old".to_string(), ServiceState::Running)]),
+        other => panic!("unexpected response: {:?}", other),
+    }
+    server.abort();
+
+    // Other failures are passed on
+    let (client, server) = stand_in("failing.sock", "Too many connections");
+    match client.list().await.unwrap() {
+        Response::Error { message } => assert_eq!(message, "Too many connections"),
+        other => panic!("unexpected response: {:?}", other),
+    }
+    server.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oneshot_runs_to_completion() {
+    let daemon = TestDaemon::start(&[
+        ("job", "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"true\"\n"),
+        (
+            "setup",
+            "[unit]\n[service]\nType = \"oneshot\"\nRemainAfterExit = true\nExecStart = \"true\"\n",
+        ),
+        ("bad", "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"false\"\n"),
+    ])
+    .await;
+
+    assert_ok(daemon.start_service("job").await);
+    assert_eq!(daemon.state("job").await, ServiceState::Stopped);
+
+    assert_ok(daemon.start_service("setup").await);
+    assert_eq!(daemon.state("setup").await, ServiceState::Running);
+
+    assert_error(daemon.start_service("bad").await);
+    assert_eq!(daemon.state("bad").await, ServiceState::Failed);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn commands_prefixed_with_a_dash_may_fail() {
+    let daemon = TestDaemon::start(&[
+        (
+            "cleanup",
+            "[unit]\n[service]\nType = \"oneshot\"\nExecStart = [\"-false\", \"touch done\"]\nWorkingDirectory = \".\"\n",
+        ),
+        (
+            "best-effort",
+            "[unit]\n[service]\nType = \"oneshot\"\nRemainAfterExit = true\nExecStart = \"-false\"\n",
+        ),
+        ("strict", "[unit]\n[service]\nType = \"oneshot\"\nExecStart = [\"false\", \"true\"]\n"),
+    ])
+    .await;
+
+    assert_ok(daemon.start_service("cleanup").await);
+    assert_eq!(daemon.state("cleanup").await, ServiceState::Stopped);
+    assert!(daemon.path("services/done").exists());
+
+    assert_ok(daemon.start_service("best-effort").await);
+    assert_eq!(daemon.state("best-effort").await, ServiceState::Running);
+
+    assert_error(daemon.start_service("strict").await);
+    assert_eq!(daemon.state("strict").await, ServiceState::Failed);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn setup_commands_run_without_holding_up_the_daemon_and_within_the_start_timeout() {
+    let daemon = TestDaemon::start(&[
+        (
+            "migrate",
+            "[unit]\n[service]\nType = \"oneshot\"\nExecStart = [\"sleep 2\", \"true\"]\n",
+        ),
+        (
+            "stuck",
+            "[unit]\n[service]\nType = \"oneshot\"\nExecStart = [\"sleep 30\", \"true\"]\nTimeoutStartSec = 1\n",
+        ),
+        ("web", &simple("sleep 30")),
+    ])
+    .await;
+
+    // The daemon carries on while the setup command runs
+    let meanwhile = async {
+        sleep(Duration::from_millis(300)).await;
+        let begun = Instant::now();
+        assert_eq!(daemon.state("migrate").await, ServiceState::Starting);
+        assert_ok(daemon.start_service("web").await);
+        assert!(begun.elapsed() < Duration::from_secs(1), "took {:?}", begun.elapsed());
+    };
+    let (migrated, ()) = tokio::join!(daemon.start_service("migrate"), meanwhile);
+    assert_ok(migrated);
+    assert_eq!(daemon.state("migrate").await, ServiceState::Stopped);
+
+    let begun = Instant::now();
+    let message = assert_error(daemon.start_service("stuck").await);
+    assert!(message.contains("'sleep 30' did not finish within 1s"), "{}", message);
+    assert!(begun.elapsed() < Duration::from_secs(5), "took {:?}", begun.elapsed());
+    assert_eq!(daemon.state("stuck").await, ServiceState::Failed);
+
+    // A list on another type fails to load, as a dry run reports
+    std::fs::write(
+        daemon.path("services/web.service"),
+        "[unit]\n[service]\nExecStart = [\"true\", \"sleep 30\"]\n",
+    )
+    .unwrap();
+    let message = assert_ok(daemon.request(Request::Reload { dry_run: true, soft: false }).await);
+    assert!(message.contains("failed to parse: web"), "{}", message);
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn starting_a_oneshot_that_is_starting_waits_for_the_same_run() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let daemon = TestDaemon::start(&[(
+        "job",
+        "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"./job.sh\"\nWorkingDirectory = \".\"\n",
+    )])
+    .await;
+    let script = daemon.path("services/job.sh");
+    std::fs::write(&script, "#!/bin/sh\necho run >> runs\nsleep 1\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let second = async {
+        sleep(Duration::from_millis(300)).await;
+        assert_eq!(daemon.state("job").await, ServiceState::Starting);
+        daemon.start_service("job").await
+    };
+    let (first, second) = tokio::join!(daemon.start_service("job"), second);
+    assert_ok(first);
+    assert_ok(second);
+
+    assert_eq!(daemon.state("job").await, ServiceState::Stopped);
+    assert_eq!(std::fs::read_to_string(daemon.path("services/runs")).unwrap(), "run\n");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dependencies_start_first() {
+    let daemon = TestDaemon::start(&[
+        ("db", &simple("sleep 30")),
+        (
+            "web",
+            "[unit]\nRequires = [\"db\"]\n[service]\nExecStart = \"sleep 30\"\n",
+        ),
+    ])
+    .await;
+
+    assert_eq!(daemon.dependencies("web").await, vec!["db"]);
+
+    assert_ok(daemon.start_service("web").await);
+    assert_eq!(daemon.state("db").await, ServiceState::Running);
+    assert_eq!(daemon.state("web").await, ServiceState::Running);
+
+    assert_ok(daemon.stop_service("web").await);
+    assert_ok(daemon.stop_service("db").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn long_starts_report_progress_before_the_response() {
+    let daemon = TestDaemon::start(&[
+        (
+            "migrate",
+            "[unit]\n[service]\nType = \"oneshot\"\nRemainAfterExit = true\nExecStart = \"sleep 0.3\"\n",
+        ),
+        (
+            "web",
+            "[unit]\nRequires = [\"migrate\"]\nAfter = [\"migrate\"]\n[service]\nExecStart = \"sleep 30\"\n",
+        ),
+        ("other", &simple("sleep 30")),
+    ])
+    .await;
+
+    let start = |service: &str| Request::Start {
+        service: service.to_string(),
+        wait: None,
+        env: Vec::new(),
+        no_supervise: false,
+    };
+    let mut progress = Vec::new();
+    let response = daemon
+        .client
+        .send_request_with_progress(start("web"), |message| progress.push(message))
+        .await
+        .unwrap();
+    assert_ok(response);
+    assert_eq!(progress, ["Starting 'migrate'", "Waiting for 'migrate' to be ready"]);
+    assert_eq!(daemon.state("web").await, ServiceState::Running);
+
+    // A client from before request ids gets the response alone
+    let mut stream = UnixStream::connect(daemon.path("daemon.sock")).await.unwrap();
+    let stop = Request::Stop {
+        service: "*".to_string(),
+        wait: None,
+        force: false,
+    };
+    write_frame(&mut stream, &stop).await.unwrap();
+    let mut reader = BufReader::new(stream);
+    let mut frame = Vec::new();
+    assert!(read_frame(&mut reader, &mut frame, usize::MAX).await.unwrap());
+    match decode_response(&frame).unwrap().response {
+        Response::Batch { results } => assert!(results.iter().all(|result| result.success), "{:?}", results),
+        other => panic!("unexpected response: {:?}", other),
+    }
+}
+
+/// The environment of a running process, as `KEY=value` entries
+fn process_environment(pid: u32) -> Vec<String> {
+    std::fs::read(format!("/proc/{}/environ", pid))
+        .unwrap()
+        .split(|b| *b == 0)
+        .map(|entry| String::from_utf8_lossy(entry).into_owned())
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn start_env_overrides_persist_across_restarts() {
+    let daemon = TestDaemon::start(&[(
+        "web",
+        "[unit]\n[service]\nExecStart = \"sleep 30\"\nEnvironment = [\"GREETING=hello\"]\nRestart = \"always\"\nRestartSec = 0\n",
+    )])
+    .await;
+
+    let env = vec!["GREETING=hi".to_string(), "PORT=8081".to_string()];
+    assert_ok(
+        daemon
+            .request(Request::Start {
+                service: "web".to_string(),
+                wait: None,
+                env: env.clone(),
+                no_supervise: false,
+            })
+            .await,
+    );
+
+    let pid = daemon.stats("web").await.unwrap().pid.unwrap();
+    let environment = process_environment(pid);
+    assert!(environment.contains(&"GREETING=hi".to_string()), "{:?}", environment);
+    assert!(environment.contains(&"PORT=8081".to_string()), "{:?}", environment);
+
+    match daemon.request(Request::Describe { service: "web".to_string() }).await {
+        Response::Describe { description } => assert_eq!(description.environment_overrides, env),
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    // The supervisor's restart keeps the overrides
+    assert_ok(
+        daemon
+            .request(Request::Signal {
+                service: "web".to_string(),
+                signal: "KILL".to_string(),
+            })
+            .await,
+    );
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let restarted = loop {
+        match daemon.stats("web").await.unwrap().pid {
+            Some(new_pid) if new_pid != pid => break new_pid,
+            _ => {
+                assert!(Instant::now() < deadline, "service was never restarted");
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    };
+    assert!(process_environment(restarted).contains(&"GREETING=hi".to_string()));
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn transient_services_are_forgotten_once_stopped() {
+    let daemon = TestDaemon::start(&[]).await;
+    let unit: UnitFile = toml::from_str("[unit]\n[service]\nExecStart = \"sleep 30\"\n").unwrap();
+
+    assert_ok(
+        daemon
+            .request(Request::RunTransient {
+                name: "adhoc".to_string(),
+                unit: Box::new(unit.clone()),
+            })
+            .await,
+    );
+    assert_eq!(daemon.state("adhoc").await, ServiceState::Running);
+
+    // Not backed by a file, but a reload mustn't drop it while it runs
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+    assert_eq!(daemon.state("adhoc").await, ServiceState::Running);
+
+    assert_error(
+        daemon
+            .request(Request::RunTransient {
+                name: "adhoc".to_string(),
+                unit: Box::new(unit),
+            })
+            .await,
+    );
+
+    assert_ok(daemon.stop_service("adhoc").await);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while daemon.stats("adhoc").await.is_some() {
+        assert!(Instant::now() < deadline, "transient service was never forgotten");
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_invalidates_cached_start_orders() {
+    let daemon = TestDaemon::start(&[
+        ("db", &simple("sleep 30")),
+        ("cache", &simple("sleep 30")),
+        ("web", "[unit]\nRequires = [\"db\"]\n[service]\nExecStart = \"sleep 30\"\n"),
+    ])
+    .await;
+
+    // Resolved once, then answered from the cache
+    assert_eq!(daemon.dependencies("web").await, vec!["db"]);
+    assert_eq!(daemon.dependencies("web").await, vec!["db"]);
+
+    std::fs::write(
+        daemon.path("services").join("web.service"),
+        "[unit]\nRequires = [\"cache\", \"db\"]\n[service]\nExecStart = \"sleep 30\"\n",
+    )
+    .unwrap();
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+    assert_eq!(daemon.dependencies("web").await, vec!["cache", "db"]);
+
+    assert_ok(daemon.start_service("web").await);
+    assert_eq!(daemon.state("cache").await, ServiceState::Running);
+    for name in ["web", "cache", "db"] {
+        assert_ok(daemon.stop_service(name).await);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn supervisor_restarts_a_failed_service() {
+    let daemon = TestDaemon::start(&[(
+        "flaky",
+        "[unit]\n[service]\nExecStart = \"false\"\nRestart = \"always\"\nRestartSec = 0\n",
+    )])
+    .await;
+    assert_ok(daemon.start_service("flaky").await);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while daemon.stats("flaky").await.unwrap().restart_count == 0 {
+        assert!(Instant::now() < deadline, "service was never restarted");
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    assert_ok(daemon.stop_service("flaky").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn listed_exit_codes_count_as_success_or_prevent_restarts() {
+    let daemon = TestDaemon::start(&[
+        (
+            "misconfigured",
+            "[unit]\n[service]\nExecStart = \"false\"\nRestart = \"always\"\nRestartSec = 0\nRestartPreventExitStatus = [1]\n",
+        ),
+        (
+            "partial",
+            "[unit]\n[service]\nExecStart = \"false\"\nRestart = \"on-failure\"\nRestartSec = 0\nSuccessExitStatus = [1]\n",
+        ),
+    ])
+    .await;
+    assert_ok(daemon.start_service("misconfigured").await);
+    assert_ok(daemon.start_service("partial").await);
+
+    daemon.wait_for_state("misconfigured", ServiceState::Failed, Duration::from_secs(5)).await;
+    daemon.wait_for_state("partial", ServiceState::Stopped, Duration::from_secs(5)).await;
+
+    // A few supervision passes, any of which would have restarted them
+    sleep(Duration::from_secs(3)).await;
+    for name in ["misconfigured", "partial"] {
+        let stats = daemon.stats(name).await.unwrap();
+        assert_eq!(stats.restart_count, 0, "'{}' was restarted", name);
+    }
+    assert_eq!(daemon.state("misconfigured").await, ServiceState::Failed);
+    assert_eq!(daemon.state("partial").await, ServiceState::Stopped);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn restart_count_resets_once_stable() {
+    let daemon = TestDaemon::start(&[(
+        "web",
+        "[unit]\n[service]\nExecStart = \"sleep 30\"\nRestart = \"always\"\nRestartSec = 0\nRestartResetSec = 1\n",
+    )])
+    .await;
+    assert_ok(daemon.start_service("web").await);
+
+    assert_ok(
+        daemon
+            .request(Request::Signal {
+                service: "web".to_string(),
+                signal: "KILL".to_string(),
+            })
+            .await,
+    );
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while daemon.stats("web").await.unwrap().restart_count == 0 {
+        assert!(Instant::now() < deadline, "service was never restarted");
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while daemon.stats("web").await.unwrap().restart_count != 0 {
+        assert!(Instant::now() < deadline, "restart count was never reset");
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stop_cancels_a_pending_restart() {
+    let daemon = TestDaemon::start(&[(
+        "flaky",
+        "[unit]\n[service]\nExecStart = \"false\"\nRestart = \"always\"\nRestartSec = 2\n",
+    )])
+    .await;
+    assert_ok(daemon.start_service("flaky").await);
+
+    // Once the supervisor has seen the failure, a restart is pending
+    daemon
+        .wait_for_state("flaky", ServiceState::Failed, Duration::from_secs(3))
+        .await;
+    assert_ok(daemon.stop_service("flaky").await);
+
+    sleep(Duration::from_secs(3)).await;
+    let stats = daemon.stats("flaky").await.unwrap();
+    assert_eq!(stats.state, ServiceState::Stopped);
+    assert_eq!(stats.restart_count, 0);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn failure_starts_on_failure_units_without_looping() {
+    let daemon = TestDaemon::start(&[]).await;
+    let runs = daemon.path("runs");
+
+    // Each unit records that it ran, then fails; each is the other's handler
+    for (name, handler) in [("primary", "fallback"), ("fallback", "primary")] {
+        let script = daemon.path(&format!("{}.sh", name));
+        std::fs::write(&script, format!("echo {} >> {}\nexit 1\n", name, runs.display())).unwrap();
+        let unit = format!(
+            "[unit]\nOnFailure = [\"{}\"]\n[service]\nExecStart = \"sh {}\"\n",
+            handler,
+            script.display()
+        );
+        std::fs::write(daemon.path("services").join(format!("{}.service", name)), unit).unwrap();
+    }
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+
+    assert_ok(daemon.start_service("primary").await);
+    daemon
+        .wait_for_state("fallback", ServiceState::Failed, Duration::from_secs(5))
+        .await;
+
+    // The handler's failure must not start `primary` again
+    sleep(Duration::from_secs(2)).await;
+    let runs = std::fs::read_to_string(runs).unwrap();
+    assert_eq!(runs.lines().collect::<Vec<_>>(), vec!["primary", "fallback"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn timer_starts_a_oneshot_periodically() {
+    let daemon = TestDaemon::start(&[]).await;
+    let runs = daemon.path("runs");
+    let script = daemon.path("job.sh");
+    std::fs::write(&script, format!("echo run >> {}\n", runs.display())).unwrap();
+    let unit = format!(
+        "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"sh {}\"\n[timer]\nOnUnitActiveSec = 1\n",
+        script.display()
+    );
+    std::fs::write(daemon.path("services").join("job.service"), unit).unwrap();
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+
+    let deadline = Instant::now() + Duration::from_secs(8);
+    loop {
+        let count = std::fs::read_to_string(&runs).map(|r| r.lines().count()).unwrap_or(0);
+        if count >= 2 {
+            break;
+        }
+        assert!(Instant::now() < deadline, "timer ran {} times", count);
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Accept one HTTP request on `listener`, answer 200, and return its body
+async fn receive_webhook(listener: &TcpListener) -> serde_json::Value {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut request = Vec::new();
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "webhook connection closed early");
+        request.extend_from_slice(&buf[..n]);
+
+        let text = String::from_utf8_lossy(&request);
+        if let Some((head, body)) = text.split_once("\r\n\r\n") {
+            let length = head
+                .lines()
+                .find_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    name.eq_ignore_ascii_case("content-length")
+                        .then(|| value.trim().parse::<usize>().unwrap())
+                })
+                .unwrap();
+            if body.len() >= length {
+                stream
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\n\r\n")
+                    .await
+                    .unwrap();
+                return serde_json::from_str(body).unwrap();
+            }
+        }
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn webhook_reports_failure_and_recovery() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hook", listener.local_addr().unwrap());
+    let daemon = TestDaemon::start_with(
+        &[(
+            "web",
+            "[unit]\n[service]\nExecStart = \"sleep 30\"\nRestart = \"always\"\nRestartSec = 0\n",
+        )],
+        |config| config.webhook_url = Some(url),
+    )
+    .await;
+    assert_ok(daemon.start_service("web").await);
+
+    assert_ok(
+        daemon
+            .request(Request::Signal {
+                service: "web".to_string(),
+                signal: "KILL".to_string(),
+            })
+            .await,
+    );
+
+    let failure = tokio::time::timeout(Duration::from_secs(5), receive_webhook(&listener))
+        .await
+        .expect("no failure webhook");
+    assert_eq!(failure["service"], "web");
+    assert_eq!(failure["old_state"], "running");
+    assert_eq!(failure["new_state"], "failed");
+    assert!(failure["exit_reason"].as_str().unwrap().contains("SIGKILL"), "{}", failure);
+    assert!(failure["timestamp"].is_string());
+
+    let recovery = tokio::time::timeout(Duration::from_secs(5), receive_webhook(&listener))
+        .await
+        .expect("no recovery webhook");
+    assert_eq!(recovery["old_state"], "failed");
+    assert_eq!(recovery["new_state"], "running");
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unmet_conditions_skip_the_start() {
+    let daemon = TestDaemon::start(&[]).await;
+    let marker = daemon.path("marker");
+    std::fs::write(&marker, "").unwrap();
+
+    let units = [
+        ("present", format!("ConditionPathExists = \"{}\"", marker.display())),
+        ("absent", format!("ConditionPathExists = \"!{}\"", marker.display())),
+        ("nonempty", format!("ConditionFileNotEmpty = \"{}\"", marker.display())),
+        ("directory", format!("ConditionPathIsDirectory = \"{}\"", daemon.path("").display())),
+    ];
+    for (name, condition) in &units {
+        let unit = format!("[unit]\n{}\n[service]\nExecStart = \"sleep 30\"\n", condition);
+        std::fs::write(daemon.path("services").join(format!("{}.service", name)), unit).unwrap();
+    }
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+
+    assert_ok(daemon.start_service("present").await);
+    assert_eq!(daemon.state("present").await, ServiceState::Running);
+
+    assert_ok(daemon.start_service("directory").await);
+    assert_eq!(daemon.state("directory").await, ServiceState::Running);
+
+    // Skipped starts are reported, but not as failures
+    for name in ["absent", "nonempty"] {
+        let message = assert_ok(daemon.start_service(name).await);
+        assert!(message.contains("condition not met"), "{}", message);
+        assert_eq!(daemon.state(name).await, ServiceState::Stopped);
+    }
+
+    assert_ok(daemon.stop_service("present").await);
+    assert_ok(daemon.stop_service("directory").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn logs_of_several_services_are_interleaved() {
+    let daemon = TestDaemon::start(&[
+        ("first", "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"echo one\"\n"),
+        ("second", "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"echo two\"\n"),
+    ])
+    .await;
+
+    assert_ok(daemon.start_service("first").await);
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    assert_ok(daemon.start_service("second").await);
+
+    let request = Request::Logs {
+        services: vec!["second".to_string(), "first".to_string()],
+        lines: None,
+        since: None,
+        follow: false,
+    };
+    match daemon.request(request).await {
+        Response::InterleavedLogs { lines } => {
+            let services: Vec<_> = lines.iter().map(|(service, _)| service.as_str()).collect();
+            assert_eq!(services, ["first", "second"]);
+            assert!(lines[0].1.ends_with("one"));
+            assert!(lines[1].1.ends_with("two"));
+        }
+        other => panic!("expected interleaved logs, got {:?}", other),
+    }
+}
+
+#[test]
+fn service_states_have_a_stable_wire_format() {
+    let states = [
+        (ServiceState::Stopped, "stopped"),
+        (ServiceState::Starting, "starting"),
+        (ServiceState::Running, "running"),
+        (ServiceState::Stopping, "stopping"),
+        (ServiceState::Failed, "failed"),
+    ];
+    for (state, name) in states {
+        let json = serde_json::to_string(&state).unwrap();
+        assert_eq!(json, format!("\"{}\"", name));
+        assert_eq!(serde_json::from_str::<ServiceState>(&json).unwrap(), state);
+        assert_eq!(state.to_string(), name);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn frozen_or_out_of_window_services_are_not_restarted() {
+    // A window starting two hours from now, so it never includes the test
+    let minute = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() / 60 % 1440;
+    let window = |offset: u64| {
+        let m = (minute + offset) % 1440;
+        format!("{:02}:{:02}", m / 60, m % 60)
+    };
+    let closed = format!(
+        "[unit]\n[service]\nExecStart = \"false\"\nRestart = \"always\"\nRestartSec = 0\nRestartWindow = [\"{}-{}\"]\n",
+        window(120),
+        window(180)
+    );
+    let daemon = TestDaemon::start(&[
+        ("closed", &closed),
+        (
+            "flaky",
+            "[unit]\n[service]\nExecStart = \"false\"\nRestart = \"always\"\nRestartSec = 0\n",
+        ),
+    ])
+    .await;
+
+    assert_ok(daemon.request(Request::Freeze).await);
+    assert_ok(daemon.start_service("closed").await);
+    assert_ok(daemon.start_service("flaky").await);
+
+    sleep(Duration::from_secs(3)).await;
+    for name in ["closed", "flaky"] {
+        let stats = daemon.stats(name).await.unwrap();
+        assert_eq!(stats.state, ServiceState::Failed, "{}", name);
+        assert_eq!(stats.restart_count, 0, "{}", name);
+    }
+
+    // Unfrozen, only the service outside its window stays down
+    assert_ok(daemon.request(Request::Unfreeze).await);
+    assert_ok(daemon.start_service("closed").await);
+    assert_ok(daemon.start_service("flaky").await);
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while daemon.stats("flaky").await.unwrap().restart_count == 0 {
+        assert!(Instant::now() < deadline, "service was never restarted");
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(daemon.stats("closed").await.unwrap().restart_count, 0);
+
+    assert_ok(daemon.stop_service("flaky").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn globs_act_on_matching_services_in_dependency_order() {
+    let daemon = TestDaemon::start(&[
+        ("db", &simple("sleep 30")),
+        (
+            "worker-a",
+            "[unit]\nRequires = [\"worker-b\"]\n[service]\nExecStart = \"sleep 30\"\n",
+        ),
+        ("worker-b", &simple("sleep 30")),
+        ("web", &simple("sleep 30")),
+    ])
+    .await;
+
+    let batch = |response| match response {
+        Response::Batch { results } => results
+            .into_iter()
+            .map(|result| {
+                assert!(result.success, "{}", result.message);
+                result.service
+            })
+            .collect::<Vec<_>>(),
+        other => panic!("expected a batch, got {:?}", other),
+    };
+
+    let started = batch(daemon.start_service("worker-*").await);
+    assert_eq!(started, ["worker-b", "worker-a"]);
+    assert_eq!(daemon.state("db").await, ServiceState::Stopped);
+    assert_eq!(daemon.state("web").await, ServiceState::Stopped);
+
+    let stopped = batch(daemon.stop_service("worker-[ab]").await);
+    assert_eq!(stopped, ["worker-a", "worker-b"]);
+    assert_eq!(daemon.state("worker-a").await, ServiceState::Stopped);
+
+    let message = assert_error(daemon.start_service("cache-?").await);
+    assert!(message.contains("no service matches"), "{}", message);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn globs_with_many_stars_match_quickly() {
+    let name = "a".repeat(40);
+    let daemon = TestDaemon::start(&[(&name, &simple("sleep 30"))]).await;
+
+    // Backtracking over every split point for each star never finishes here
+    let pattern = format!("{}b", "*a".repeat(12));
+    let response = tokio::time::timeout(Duration::from_secs(5), daemon.start_service(&pattern))
+        .await
+        .expect("matching the pattern held up the daemon");
+    let message = assert_error(response);
+    assert!(message.contains("no service matches"), "{}", message);
+    assert_eq!(daemon.state(&name).await, ServiceState::Stopped);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn describe_shows_the_command_as_executed() {
+    let daemon = TestDaemon::start(&[
+        (
+            "web",
+            "[unit]\n[service]\nExecStart = \"env NAME=%n sleep 30\"\nWorkingDirectory = \"/tmp\"\nEnvironment = [\"PORT=8080\"]\n",
+        ),
+        ("broken", &simple("./missing-binary --flag")),
+    ])
+    .await;
+
+    let last_command = |description: Response| match description {
+        Response::Describe { description } => description.last_command,
+        other => panic!("unexpected response: {:?}", other),
+    };
+
+    let describe = |service: &str| Request::Describe {
+        service: service.to_string(),
+    };
+    assert!(last_command(daemon.request(describe("web")).await).is_none());
+
+    assert_ok(daemon.start_service("web").await);
+    let command = last_command(daemon.request(describe("web")).await).unwrap();
+    assert_eq!(command.argv, ["env", "NAME=web", "sleep", "30"]);
+    assert_eq!(command.working_directory, Some(PathBuf::from("/tmp")));
+    assert!(command.environment.contains(&"PORT=8080".to_string()), "{:?}", command.environment);
+
+    // The start time is shown by the system clock, alongside the uptime
+    let started_at = |description: Response| match description {
+        Response::Describe { description } => description.started_at,
+        other => panic!("unexpected response: {:?}", other),
+    };
+    let started = started_at(daemon.request(describe("web")).await).unwrap();
+    let started = humantime::parse_rfc3339(&started).unwrap();
+    assert!(SystemTime::now().duration_since(started).unwrap() < Duration::from_secs(60));
+    assert_ok(daemon.stop_service("web").await);
+    assert!(started_at(daemon.request(describe("web")).await).is_none());
+
+    // Recorded even though it couldn't be executed
+    assert_error(daemon.start_service("broken").await);
+    let command = last_command(daemon.request(describe("broken")).await).unwrap();
+    assert!(Path::new(&command.argv[0]).is_absolute(), "{:?}", command.argv);
+    assert!(command.argv[0].ends_with("services/./missing-binary"), "{:?}", command.argv);
+    assert_eq!(command.argv[1], "--flag");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn watched_files_follow_reloads() {
+    let daemon = TestDaemon::start(&[("web", &simple("sleep 30"))]).await;
+    let config = daemon.path("services/app.conf");
+    std::fs::write(&config, "a").unwrap();
+
+    // Nothing was watched when the daemon started
+    std::fs::write(
+        daemon.path("services/web.service"),
+        format!(
+            "[unit]\n[service]\nExecStart = \"sleep 30\"\nRestartOnChange = true\nWatchPaths = [{:?}]\n",
+            config
+        ),
+    )
+    .unwrap();
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+    assert_ok(daemon.start_service("web").await);
+    let pid = daemon.stats("web").await.unwrap().pid;
+    sleep(Duration::from_millis(300)).await;
+
+    std::fs::write(&config, "b").unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = daemon.stats("web").await.unwrap();
+        if stats.state == ServiceState::Running && stats.pid != pid {
+            break;
+        }
+        assert!(Instant::now() < deadline, "web was never restarted");
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn reload_dry_run_reports_without_applying() {
+    let daemon = TestDaemon::start(&[
+        ("db", &simple("sleep 30")),
+        ("old", &simple("sleep 30")),
+        ("same", &simple("sleep 30")),
+        ("web", &simple("sleep 30")),
+    ])
+    .await;
+    assert_ok(daemon.start_service("web").await);
+
+    let services = daemon.path("services");
+    std::fs::write(services.join("db.service"), simple("sleep 60")).unwrap();
+    std::fs::write(services.join("web.service"), simple("sleep 60")).unwrap();
+    std::fs::write(services.join("new.service"), simple("sleep 30")).unwrap();
+    std::fs::remove_file(services.join("old.service")).unwrap();
+
+    let message = assert_ok(daemon.request(Request::Reload { dry_run: true, soft: false }).await);
+    assert_eq!(
+        message,
+        "Dry run, nothing changed (would add: new; would remove: old; would update: db; \
+         running, need a stop to apply: web; unchanged: same)"
+    );
+
+    // Nothing was applied
+    assert_eq!(daemon.state("old").await, ServiceState::Stopped);
+    assert_error(daemon.request(Request::Status { service: "new".to_string() }).await);
+    let message = assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+    assert!(message.contains("added: new"), "{}", message);
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[test]
+fn following_a_log_with_invalid_utf8_starts_at_its_real_end() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("web.log");
+    std::fs::write(&path, b"bad \xff\xfe bytes\n").unwrap();
+
+    let (lines, mut offset) = diakonos::logs::read_logs(&path, None, None).unwrap();
+    assert_eq!(lines, ["bad \u{fffd}\u{fffd} bytes"]);
+    assert_eq!(offset, std::fs::metadata(&path).unwrap().len());
+
+    let mut log = std::fs::OpenOptions::new().append(true).open(&path).unwrap();
+    std::io::Write::write_all(&mut log, b"next\n").unwrap();
+    let (lines, _) = diakonos::logs::read_appended(&path, &mut offset, 1024).unwrap();
+    assert_eq!(lines, ["next"]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn slow_log_followers_get_a_drop_marker_instead_of_stalling() {
+    let daemon = TestDaemon::start(&[("chatty", &simple("yes diakonos"))]).await;
+    assert_ok(daemon.start_service("chatty").await);
+
+    let mut stream = UnixStream::connect(daemon.path("daemon.sock")).await.unwrap();
+    let request = RequestEnvelope {
+        id: 1,
+        request: Request::Logs {
+            services: vec!["chatty".to_string()],
+            lines: Some(0),
+            since: None,
+            follow: true,
+        },
+    };
+    write_frame(&mut stream, &request).await.unwrap();
+
+    // Read nothing for a while; the daemon keeps answering others meanwhile
+    for _ in 0..10 {
+        sleep(Duration::from_millis(200)).await;
+        assert!(matches!(daemon.request(Request::Ping).await, Response::Pong));
+    }
+
+    let mut reader = BufReader::new(stream);
+    let mut frame = Vec::new();
+    let deadline = Instant::now() + Duration::from_secs(20);
+    loop {
+        assert!(Instant::now() < deadline, "no lines were reported dropped");
+        assert!(read_frame(&mut reader, &mut frame, usize::MAX).await.unwrap());
+        match decode_response(&frame).unwrap().response {
+            Response::LogsDropped { lines } => {
+                assert!(lines > 0);
+                break;
+            }
+            Response::Logs { .. } => {}
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    assert_ok(daemon.stop_service("chatty").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unrunnable_scripts_are_explained() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let daemon = TestDaemon::start(&[
+        ("plain", &simple("./plain.sh")),
+        ("missing", &simple("./missing.sh")),
+        ("crlf", &simple("./crlf.sh")),
+    ])
+    .await;
+
+    let services = daemon.path("services");
+    let scripts = [
+        ("plain.sh", "#!/bin/sh\nsleep 30\n", 0o644),
+        ("missing.sh", "#!/no/such/shell\nsleep 30\n", 0o755),
+        ("crlf.sh", "#!/bin/sh\r\nsleep 30\r\n", 0o755),
+    ];
+    for (name, content, mode) in scripts {
+        let path = services.join(name);
+        std::fs::write(&path, content).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode)).unwrap();
+    }
+
+    let message = assert_error(daemon.start_service("plain").await);
+    assert!(message.contains("exists but is not executable; chmod +x it"), "{}", message);
+
+    let message = assert_error(daemon.start_service("missing").await);
+    assert!(message.contains("interpreter /no/such/shell"), "{}", message);
+
+    let message = assert_error(daemon.start_service("crlf").await);
+    assert!(message.contains("carriage return"), "{}", message);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn daemon_info_counts_open_connections() {
+    let daemon = TestDaemon::start(&[]).await;
+    // Connections closed by clients are counted until the daemon notices
+    let settles_at = |expected: usize| {
+        let daemon = &daemon;
+        async move {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            loop {
+                let open = match daemon.request(Request::DaemonInfo).await {
+                    Response::DaemonInfo { info } => info.open_connections,
+                    other => panic!("unexpected response: {:?}", other),
+                };
+                if open == expected {
+                    return;
+                }
+                assert!(Instant::now() < deadline, "{} connections open, expected {}", open, expected);
+                sleep(Duration::from_millis(20)).await;
+            }
+        }
+    };
+
+    // The request asking counts itself
+    settles_at(1).await;
+
+    let held = PersistentClient::new(DaemonConfig {
+        socket_path: daemon.path("daemon.sock"),
+        ..DaemonConfig::default()
+    });
+    assert!(matches!(held.send(Request::Ping).await.unwrap(), Response::Pong));
+    settles_at(2).await;
+
+    drop(held);
+    settles_at(1).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn persistent_client_reuses_and_replaces_its_connection() {
+    let daemon = TestDaemon::start(&[("web", &simple("sleep 30"))]).await;
+    let client = PersistentClient::new(DaemonConfig {
+        socket_path: daemon.path("daemon.sock"),
+        ..DaemonConfig::default()
+    });
+
+    assert!(matches!(client.send(Request::Ping).await.unwrap(), Response::Pong));
+    let start = Request::Start {
+        service: "web".to_string(),
+        wait: None,
+        env: Vec::new(),
+        no_supervise: false,
+    };
+    assert_ok(client.send(start).await.unwrap());
+    match client.send(Request::Status { service: "web".to_string() }).await.unwrap() {
+        Response::Status { state, .. } => assert_eq!(state, ServiceState::Running),
+        other => panic!("unexpected response: {:?}", other),
+    }
+    assert_ok(daemon.stop_service("web").await);
+
+    // A stand-in daemon that hangs up after answering once per connection
+    let socket = daemon.path("flaky.sock");
+    let listener = UnixListener::bind(&socket).unwrap();
+    let server = tokio::spawn(async move {
+        for _ in 0..2 {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (reader, mut writer) = stream.into_split();
+            let mut reader = BufReader::new(reader);
+            let mut frame = Vec::new();
+            assert!(read_frame(&mut reader, &mut frame, usize::MAX).await.unwrap());
+            let (_, id) = decode_request(&frame).unwrap();
+            let envelope = ResponseEnvelope {
+                id,
+                response: Response::Pong,
+            };
+            write_frame(&mut writer, &envelope).await.unwrap();
+        }
+    });
+
+    let client = PersistentClient::new(DaemonConfig {
+        socket_path: socket,
+        ..DaemonConfig::default()
+    });
+    for _ in 0..2 {
+        assert!(matches!(client.send(Request::Ping).await.unwrap(), Response::Pong));
+    }
+    server.await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn service_client_returns_typed_results() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let daemon = TestDaemon::start(&[
+        ("web", &simple("sleep 30")),
+        ("talk", &format!("{}WorkingDirectory = \".\"\n", simple("./talk.sh"))),
+    ])
+    .await;
+    let script = daemon.path("services").join("talk.sh");
+    std::fs::write(&script, "#!/bin/sh\nsleep 0.5\necho hello\necho world\nexec sleep 30\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let client = ServiceClient::new(DaemonConfig {
+        socket_path: daemon.path("daemon.sock"),
+        ..DaemonConfig::default()
+    })
+    .with_timeout(Duration::from_secs(10));
+
+    client.ping().await.unwrap();
+    client.start("web").await.unwrap();
+    assert_eq!(client.status("web").await.unwrap(), ServiceState::Running);
+    assert!(client.pid("web").await.unwrap().is_some());
+    assert_eq!(client.describe("web").await.unwrap().exec_start, ["sleep 30"]);
+    assert_eq!(
+        client.list().await.unwrap(),
+        [
+            ("talk".to_string(), ServiceState::Stopped),
+            ("web".to_string(), ServiceState::Running)
+        ]
+    );
+
+    // The daemon's refusals come back as errors in its own words
+    match client.start("nope").await {
+        Err(DiakonosError::RequestFailed(message)) => assert!(message.contains("nope"), "{}", message),
+        other => panic!("expected a refusal, got {:?}", other),
+    }
+    assert!(matches!(
+        client.wait_for("web", ServiceState::Stopped, Duration::from_millis(200)).await,
+        Err(DiakonosError::TimedOut(_))
+    ));
+    client.stop("web").await.unwrap();
+    client.wait_for("web", ServiceState::Stopped, Duration::from_secs(5)).await.unwrap();
+
+    // Followed output arrives line by line
+    client.start("talk").await.unwrap();
+    let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+    let follow = client.follow_logs(&["talk"], move |event| {
+        let _ = sender.send(event);
+    });
+    tokio::pin!(follow);
+    let mut lines = Vec::new();
+    let collect = async {
+        while lines.len() < 2 {
+            tokio::select! {
+                result = &mut follow => panic!("stopped following: {:?}", result),
+                Some(event) = receiver.recv() => match event {
+                    LogEvent::Line { service, line } => {
+                        assert_eq!(service, "talk");
+                        lines.push(line);
+                    }
+                    other => panic!("unexpected event: {:?}", other),
+                },
+            }
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), collect).await.unwrap();
+    assert!(lines[0].ends_with("hello"), "{:?}", lines);
+    assert!(lines[1].ends_with("world"), "{:?}", lines);
+    client.stop("talk").await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn oldest_rotated_logs_are_pruned_to_stay_under_the_cap() {
+    let daemon = TestDaemon::start_with(&[], |config| {
+        config.max_log_bytes = Some(3000);
+
+        std::fs::create_dir_all(&config.log_dir).unwrap();
+        let write = |name: &str, bytes: usize, age: u64| {
+            let file = std::fs::File::create(config.log_dir.join(name)).unwrap();
+            std::io::Write::write_all(&mut &file, &vec![b'x'; bytes]).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age))
+                .unwrap();
+        };
+        write("a.log", 1000, 0);
+        write("a.log.1", 1500, 60);
+        write("b.log", 1200, 0);
+        write("b.log.1", 400, 30);
+    })
+    .await;
+
+    // 4100 bytes in total: deleting the oldest segment, a.log.1, is enough
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let usage = match daemon.request(Request::LogUsage).await {
+            Response::LogUsage { usage, max_bytes } => {
+                assert_eq!(max_bytes, Some(3000));
+                usage
+            }
+            other => panic!("unexpected response: {:?}", other),
+        };
+        let sizes: Vec<_> = usage
+            .iter()
+            .map(|u| (u.service.as_str(), u.bytes, u.rotated_bytes))
+            .collect();
+        if sizes == [("a", 1000, 0), ("b", 1200, 400)] {
+            break;
+        }
+        assert!(Instant::now() < deadline, "logs not pruned: {:?}", sizes);
+        sleep(Duration::from_millis(50)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn services_with_ready_when_start_once_their_socket_accepts() {
+    // A free port, bound by the "server" only after a delay
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let db = format!(
+        "[unit]\n[service]\nExecStart = \"sleep 30\"\nReadyWhen = \"tcp:127.0.0.1:{}\"\n",
+        port
+    );
+    let daemon = TestDaemon::start(&[
+        ("db", &db),
+        (
+            "app",
+            "[unit]\nRequires = [\"db\"]\nAfter = [\"db\"]\n[service]\nExecStart = \"sleep 30\"\n",
+        ),
+        (
+            "never",
+            "[unit]\n[service]\nExecStart = \"sleep 30\"\nReadyWhen = \"unix:never.sock\"\nTimeoutStartSec = 1\n",
+        ),
+    ])
+    .await;
+
+    let listener = tokio::spawn(async move {
+        sleep(Duration::from_millis(1500)).await;
+        let listener = TcpListener::bind(("127.0.0.1", port)).await.unwrap();
+        sleep(Duration::from_secs(30)).await;
+        drop(listener);
+    });
+
+    // The dependent's start waits for the dependency's socket
+    let started = Instant::now();
+    let (response, (db_state, app_state)) = tokio::join!(daemon.start_service("app"), async {
+        sleep(Duration::from_millis(700)).await;
+        (daemon.state("db").await, daemon.state("app").await)
+    });
+    assert!(matches!(response, Response::Ok { .. }), "{:?}", response);
+    assert!(started.elapsed() >= Duration::from_millis(1500));
+    assert_eq!(db_state, ServiceState::Starting);
+    assert_eq!(app_state, ServiceState::Stopped);
+    assert_eq!(daemon.state("db").await, ServiceState::Running);
+    assert_eq!(daemon.state("app").await, ServiceState::Running);
+
+    // A socket that never accepts fails the start once TimeoutStartSec passes
+    match daemon.start_service("never").await {
+        Response::Error { message } => assert!(message.contains("not ready within 1s"), "{}", message),
+        other => panic!("unexpected response: {:?}", other),
+    }
+    assert_eq!(daemon.state("never").await, ServiceState::Failed);
+
+    listener.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn forced_stops_kill_the_process_group_without_waiting() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let daemon = TestDaemon::start(&[("wedged", &simple("./wedged.sh"))]).await;
+
+    // Ignores SIGTERM, and has a child of its own
+    let child_pid_file = daemon.path("child.pid");
+    let script = daemon.path("services").join("wedged.sh");
+    std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\ntrap '' TERM\nsleep 300 &\necho $! > {}\nwait\n",
+            child_pid_file.display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    assert_ok(daemon.start_service("wedged").await);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let child_pid: i32 = loop {
+        match std::fs::read_to_string(&child_pid_file).map(|pid| pid.trim().parse()) {
+            Ok(Ok(pid)) => break pid,
+            _ => assert!(Instant::now() < deadline, "child never started"),
+        }
+        sleep(Duration::from_millis(20)).await;
+    };
+
+    let started = Instant::now();
+    assert_ok(
+        daemon
+            .request(Request::Stop {
+                service: "wedged".to_string(),
+                wait: None,
+                force: true,
+            })
+            .await,
+    );
+    assert!(
+        started.elapsed() < Duration::from_secs(2),
+        "forced stop took {:?}",
+        started.elapsed()
+    );
+    assert_eq!(daemon.state("wedged").await, ServiceState::Stopped);
+
+    // The child is gone too
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        if !process_is_alive(child_pid) {
+            break;
+        }
+        assert!(Instant::now() < deadline, "child {} survived the forced stop", child_pid);
+        sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[test]
+fn unit_parse_errors_name_the_line_and_field() {
+    let dir = tempfile::tempdir().unwrap();
+    let parse_error = |name: &str, content: &str| {
+        let path = dir.path().join(format!("{}.service", name));
+        std::fs::write(&path, content).unwrap();
+        match UnitFile::from_file(&path) {
+            Err(DiakonosError::ParseError(message)) => {
+                let location = format!("{}:", path.display());
+                assert!(message.starts_with(&location), "{}", message);
+                assert!(!message.contains('\n'), "{}", message);
+                message[location.len()..].to_string()
+            }
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    };
+
+    assert_eq!(
+        parse_error("typo", "[unit]\nDescription = \"x\"\n\n[service]\nExecStrat = \"sleep 1\"\n"),
+        "5:1: unknown field `ExecStrat` (did you mean `ExecStart`?)"
+    );
+    assert_eq!(
+        parse_error("section", "[Unit]\n[service]\nExecStart = \"sleep 1\"\n"),
+        "1:2: unknown field `Unit` (did you mean `unit`?)"
+    );
+    assert!(parse_error("value", "[unit]\n[service]\nExecStart = \"sleep 1\"\nRestartSec = true\n")
+        .starts_with("4:14: RestartSec: invalid type"));
+    assert!(parse_error("duration", "[unit]\n[service]\nExecStart = \"sleep 1\"\nRestartSec = \"five\"\n")
+        .starts_with("4:14: RestartSec: invalid duration 'five'"));
+    assert_eq!(
+        parse_error("missing", "[unit]\n[service]\nRestart = \"always\"\n"),
+        " [service] has no ExecStart; set it to the command to run"
+    );
+    assert_eq!(
+        parse_error("list", "[unit]\n[service]\nExecStart = [\"true\", \"sleep 1\"]\n"),
+        " [service] lists several ExecStart commands, which needs Type = \"oneshot\""
+    );
+}
+
+#[test]
+fn specifiers_expand_when_a_unit_is_named() {
+    let expand = |name: &str, exec_start: &str| {
+        UnitFile::parse(&simple(exec_start), "specifiers.service")
+            .unwrap()
+            .with_name(name, &Default::default())
+            .map(|unit| unit.service.exec_start[0].clone())
+    };
+    let home = diakonos::unit::home_dir().unwrap();
+    let runtime_dir = diakonos::unit::runtime_dir();
+
+    assert_eq!(expand("web@8080", "serve %n %i").unwrap(), "serve web@8080 8080");
+    assert_eq!(expand("web", "serve --port=%i").unwrap(), "serve --port=");
+    assert_eq!(
+        expand("web", "serve %h/app %t/web.sock").unwrap(),
+        format!("serve {}/app {}/web.sock", home.display(), runtime_dir.display())
+    );
+    assert_eq!(expand("web", "date +%%Y-%%m").unwrap(), "date +%Y-%m");
+
+    match expand("web", "date +%Y") {
+        Err(DiakonosError::ParseError(message)) => {
+            assert_eq!(message, "unknown specifier '%Y' in \"date +%Y\"")
+        }
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+    match expand("web", "printf 100%") {
+        Err(DiakonosError::ParseError(message)) => {
+            assert_eq!(message, "incomplete specifier at end of \"printf 100%\"")
+        }
+        other => panic!("expected a parse error, got {:?}", other),
+    }
+}
+
+#[test]
+fn unit_durations_take_unit_suffixes_or_bare_seconds() {
+    let restart_sec = |value: &str| {
+        let unit = UnitFile::parse(
+            &format!("[unit]\n[service]\nExecStart = \"true\"\nRestartSec = {}\n", value),
+            "restart.service",
+        )
+        .unwrap();
+        unit.service.restart_sec.unwrap()
+    };
+
+    assert_eq!(restart_sec("\"500ms\""), Duration::from_millis(500));
+    assert_eq!(restart_sec("\"30s\""), Duration::from_secs(30));
+    assert_eq!(restart_sec("\"5min\""), Duration::from_secs(300));
+    assert_eq!(restart_sec("\"1h\""), Duration::from_secs(3600));
+    assert_eq!(restart_sec("\"1h 30min\""), Duration::from_secs(5400));
+    // Bare seconds, as a number or a string, as before
+    assert_eq!(restart_sec("0"), Duration::ZERO);
+    assert_eq!(restart_sec("7"), Duration::from_secs(7));
+    assert_eq!(restart_sec("\"7\""), Duration::from_secs(7));
+
+    let unit = UnitFile::parse(
+        concat!(
+            "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"true\"\nTimeoutStartSec = \"2min\"\n",
+            "RuntimeMaxSec = \"1h\"\nRestartMaxDelaySec = 600\nRestartResetSec = \"90s\"\n",
+            "[timer]\nOnUnitActiveSec = \"15min\"\n",
+        ),
+        "job.service",
+    )
+    .unwrap();
+    assert_eq!(unit.start_timeout(), Some(Duration::from_secs(120)));
+    assert_eq!(unit.runtime_max(), Some(Duration::from_secs(3600)));
+    assert_eq!(unit.service.restart_max_delay_sec, Some(Duration::from_secs(600)));
+    assert_eq!(unit.service.restart_reset_sec, Some(Duration::from_secs(90)));
+    assert_eq!(unit.timer_interval(), Some(Duration::from_secs(900)));
+
+    // Units sent to the daemon, such as transient ones, keep their durations
+    let json = serde_json::to_string(&unit).unwrap();
+    assert_eq!(serde_json::from_str::<UnitFile>(&json).unwrap(), unit);
+
+    let error = UnitFile::parse("[unit]\n[service]\nExecStart = \"true\"\nRestartSec = -1\n", "negative.service")
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("must not be negative"), "{}", error);
+
+    // Limits can be lifted, but other durations can't be infinite
+    for lifted in ["0", "\"infinity\""] {
+        let unit = UnitFile::parse(
+            &format!(
+                "[unit]\n[service]\nExecStart = \"true\"\nTimeoutStartSec = {0}\nRuntimeMaxSec = {0}\n",
+                lifted
+            ),
+            "slow.service",
+        )
+        .unwrap();
+        assert_eq!(unit.start_timeout(), None);
+        assert_eq!(unit.runtime_max(), None);
+    }
+    let error = UnitFile::parse(
+        "[unit]\n[service]\nExecStart = \"true\"\nRestartSec = \"infinity\"\n",
+        "restart.service",
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(error.contains("invalid duration 'infinity'"), "{}", error);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn an_infinite_start_timeout_waits_for_readiness_however_long_it_takes() {
+    let load = |timeout: &str| {
+        format!(
+            "[unit]\n[service]\nType = \"oneshot\"\nRemainAfterExit = true\nExecStart = \"sleep 1.5\"\nTimeoutStartSec = {}\n",
+            timeout
+        )
+    };
+    let daemon = TestDaemon::start(&[("bounded", &load("1")), ("unbounded", &load("\"infinity\""))]).await;
+
+    assert_error(daemon.start_service("bounded").await);
+    assert_eq!(daemon.state("bounded").await, ServiceState::Failed);
+
+    assert_ok(daemon.start_service("unbounded").await);
+    assert_eq!(daemon.state("unbounded").await, ServiceState::Running);
+}
+
+#[test]
+fn drop_ins_override_the_unit_in_name_order() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("web.service");
+    std::fs::write(
+        &path,
+        "[unit]\nDescription = \"web\"\n[service]\nExecStart = \"sleep 30\"\nRestart = \"no\"\nEnvironment = [\"A=1\", \"B=2\"]\n",
+    )
+    .unwrap();
+    let drop_ins = dir.path().join("web.service.d");
+    std::fs::create_dir(&drop_ins).unwrap();
+    std::fs::write(
+        drop_ins.join("10-restart.toml"),
+        "[service]\nRestart = \"always\"\nRestartSec = 1\n",
+    )
+    .unwrap();
+    std::fs::write(
+        drop_ins.join("20-prod.toml"),
+        "[service]\nRestartSec = \"2s\"\n[service.Environment]\nA = \"prod\"\n",
+    )
+    .unwrap();
+    std::fs::write(drop_ins.join("README"), "not a drop-in").unwrap();
+
+    let unit = UnitFile::from_file(&path).unwrap();
+    assert_eq!(unit.unit.description.as_deref(), Some("web"));
+    assert_eq!(unit.service.exec_start, ["sleep 30"]);
+    assert_eq!(unit.service.restart, Some(diakonos::unit::RestartPolicy::Always));
+    // The later drop-in wins, and replaces a setting as a whole
+    assert_eq!(unit.service.restart_sec, Some(Duration::from_secs(2)));
+    assert_eq!(unit.service.environment, Some(vec!["A=prod".to_string()]));
+
+    // A drop-in may supply what the unit file lacks
+    std::fs::write(&path, "[unit]\n[service]\n").unwrap();
+    std::fs::write(drop_ins.join("00-exec.toml"), "[service]\nExecStart = \"sleep 1\"\n").unwrap();
+    assert_eq!(UnitFile::from_file(&path).unwrap().service.exec_start, ["sleep 1"]);
+
+    // Errors name the drop-in at fault
+    std::fs::write(drop_ins.join("30-typo.toml"), "[service]\nRestrat = \"always\"\n").unwrap();
+    let error = UnitFile::from_file(&path).unwrap_err().to_string();
+    assert!(error.contains("30-typo.toml"), "{}", error);
+    assert!(error.contains("did you mean `Restart`?"), "{}", error);
+    std::fs::write(drop_ins.join("30-typo.toml"), "[service]\nRestartSec = \n").unwrap();
+    let error = UnitFile::from_file(&path).unwrap_err().to_string();
+    assert!(error.contains("30-typo.toml:2:"), "{}", error);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn config_set_writes_an_override_drop_in_and_reloads() {
+    let unit = "[unit]\nDescription = \"web\"\n[service]\nExecStart = \"sleep 30\"\nRestart = \"no\"\n";
+    let daemon = TestDaemon::start(&[("web", unit)]).await;
+    let get = |key: &str| Request::GetConfig {
+        service: "web".to_string(),
+        key: key.to_string(),
+    };
+    let set = |key: &str, value: &str| Request::SetConfig {
+        service: "web".to_string(),
+        key: key.to_string(),
+        value: value.to_string(),
+    };
+    let value = |response: Response| match response {
+        Response::Config { value, .. } => value,
+        other => panic!("unexpected response: {:?}", other),
+    };
+
+    assert_eq!(value(daemon.request(get("Restart")).await).as_deref(), Some("no"));
+    assert_eq!(value(daemon.request(get("RestartSec")).await), None);
+
+    assert_ok(daemon.request(set("Restart", "always")).await);
+    assert_ok(daemon.request(set("service.RestartSec", "5min")).await);
+    assert_ok(daemon.request(set("Description", "\"front end\"")).await);
+    assert_eq!(value(daemon.request(get("Restart")).await).as_deref(), Some("always"));
+    assert_eq!(value(daemon.request(get("RestartSec")).await).as_deref(), Some("5m"));
+    assert_eq!(value(daemon.request(get("unit.Description")).await).as_deref(), Some("front end"));
+
+    // The unit file is left as it was; the settings are in the drop-in
+    let services = daemon.path("services");
+    assert_eq!(std::fs::read_to_string(services.join("web.service")).unwrap(), unit);
+    let drop_in = std::fs::read_to_string(services.join("web.service.d/override.toml")).unwrap();
+    let drop_in: toml::Table = toml::from_str(&drop_in).unwrap();
+    assert_eq!(drop_in["service"]["Restart"].as_str(), Some("always"));
+    assert_eq!(drop_in["service"]["RestartSec"].as_str(), Some("5min"));
+    assert_eq!(drop_in["unit"]["Description"].as_str(), Some("front end"));
+
+    // A running service keeps its settings until it is restarted
+    assert_ok(daemon.start_service("web").await);
+    let message = assert_ok(daemon.request(set("Restart", "on-failure")).await);
+    assert!(message.ends_with("restart it to apply"), "{}", message);
+    assert_eq!(value(daemon.request(get("Restart")).await).as_deref(), Some("always"));
+    assert_ok(daemon.stop_service("web").await);
+
+    // Invalid values and unknown settings are refused without writing anything
+    let before = std::fs::read_to_string(services.join("web.service.d/override.toml")).unwrap();
+    let message = assert_error(daemon.request(set("Restart", "sometimes")).await);
+    assert!(message.contains("unknown variant `sometimes`"), "{}", message);
+    let message = assert_error(daemon.request(set("Restat", "always")).await);
+    assert!(message.contains("`Restat` (did you mean `Restart`?)"), "{}", message);
+    let message = assert_error(daemon.request(get("nosuch.Restart")).await);
+    assert!(message.contains("there is no section `nosuch`"), "{}", message);
+    assert_eq!(std::fs::read_to_string(services.join("web.service.d/override.toml")).unwrap(), before);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn actions_wake_the_supervisor_instead_of_waiting_a_full_interval() {
+    let daemon = TestDaemon::start_with(&[("crashes", &simple("false"))], |config| {
+        config.supervision_interval = 60;
+    })
+    .await;
+
+    // Dies right after starting; seen within a second or so, not a minute
+    assert_ok(daemon.start_service("crashes").await);
+    daemon
+        .wait_for_state("crashes", ServiceState::Failed, Duration::from_secs(3))
+        .await;
+}
+
+/// Whether `pid` is running, not counting a zombie awaiting its parent
+fn process_is_alive(pid: i32) -> bool {
+    std::fs::read_to_string(format!("/proc/{}/stat", pid))
+        .is_ok_and(|stat| !stat.rsplit(") ").next().unwrap_or("").starts_with('Z'))
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn kill_modes_choose_which_processes_a_stop_signals() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let unit = |mode: &str| {
+        format!(
+            "[unit]\n[service]\nExecStart = \"./leader.sh %n\"\nKillMode = \"{}\"\n",
+            mode
+        )
+    };
+    let daemon = TestDaemon::start(&[
+        ("process", &unit("process")),
+        ("mixed", &unit("mixed")),
+        ("group", &unit("control-group")),
+    ])
+    .await;
+
+    // Exits on SIGTERM without stopping its child
+    let script = daemon.path("services").join("leader.sh");
+    std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\ntrap 'exit 0' TERM\nsleep 300 &\necho $! > {}/$1.child\nwait\n",
+            daemon.path("").display()
+        ),
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let mut children = Vec::new();
+    for name in ["process", "mixed", "group"] {
+        assert_ok(daemon.start_service(name).await);
+        let pid_file = daemon.path(&format!("{}.child", name));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let pid: i32 = loop {
+            match std::fs::read_to_string(&pid_file).map(|pid| pid.trim().parse()) {
+                Ok(Ok(pid)) => break pid,
+                _ => assert!(Instant::now() < deadline, "{} never started its child", name),
+            }
+            sleep(Duration::from_millis(20)).await;
+        };
+        children.push((name, pid));
+    }
+
+    for (name, pid) in &children {
+        assert_ok(daemon.stop_service(name).await);
+        let survived = process_is_alive(*pid);
+        if survived {
+            let _ = nix::sys::signal::kill(nix::unistd::Pid::from_raw(*pid), Signal::SIGKILL);
+        }
+        // Only `process` leaves the leader's children running
+        assert_eq!(survived, *name == "process", "child of {}", name);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn pid_requests_return_the_main_process_or_none() {
+    let daemon = TestDaemon::start(&[("web", &simple("sleep 30"))]).await;
+    let pid = |response| match response {
+        Response::Pid { pid, .. } => pid,
+        other => panic!("unexpected response: {:?}", other),
+    };
+    let request = || Request::Pid {
+        service: "web".to_string(),
+    };
+
+    assert_eq!(pid(daemon.request(request()).await), None);
+
+    assert_ok(daemon.start_service("web").await);
+    let running = pid(daemon.request(request()).await);
+    assert!(running.is_some());
+    assert_eq!(running, daemon.stats("web").await.unwrap().pid);
+
+    assert_ok(daemon.stop_service("web").await);
+    assert_eq!(pid(daemon.request(request()).await), None);
+
+    let message = assert_error(
+        daemon
+            .request(Request::Pid {
+                service: "nope".to_string(),
+            })
+            .await,
+    );
+    assert!(message.contains("not found"), "{}", message);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn vars_file_values_are_substituted_into_units() {
+    let mut vars_file = PathBuf::new();
+    let daemon = TestDaemon::start_with(
+        &[(
+            "web",
+            "[unit]\n[service]\nExecStart = \"sleep 30\"\n\
+             Environment = [\"VERSION=${VERSION}\", \"HOST_PATH=${PATH}\", \"UNIT=%n-${VERSION}\"]\n",
+        )],
+        |config| {
+            vars_file = config.service_dir.with_file_name("vars.toml");
+            std::fs::write(&vars_file, "VERSION = \"1.4.2\"\n").unwrap();
+            config.vars_file = Some(vars_file.clone());
+        },
+    )
+    .await;
+
+    let environment = || async {
+        match daemon.request(Request::Describe { service: "web".to_string() }).await {
+            Response::Describe { description } => description.environment,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    };
+
+    // Names the vars file doesn't define are left for the process
+    assert_eq!(
+        environment().await,
+        ["VERSION=1.4.2", "HOST_PATH=${PATH}", "UNIT=web-1.4.2"]
+    );
+
+    std::fs::write(&vars_file, "VERSION = \"1.5.0\"\n").unwrap();
+    let message = assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+    assert!(message.contains("updated: web"), "{}", message);
+    assert_eq!(environment().await[0], "VERSION=1.5.0");
+
+    // A broken vars file fails the reload rather than loading units without it
+    std::fs::write(&vars_file, "VERSION = 2\n").unwrap();
+    let message = assert_error(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+    assert!(message.contains("vars file"), "{}", message);
+    assert_eq!(environment().await[0], "VERSION=1.5.0");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn control_requests_are_recorded_in_the_audit_log() {
+    let daemon = TestDaemon::start(&[("web", &simple("sleep 30"))]).await;
+
+    assert_ok(daemon.start_service("web").await);
+    // Queries aren't audited
+    assert_eq!(daemon.state("web").await, ServiceState::Running);
+    assert_ok(daemon.stop_service("web").await);
+    assert_error(daemon.stop_service("nope").await);
+
+    let content = std::fs::read_to_string(daemon.path("audit.log")).unwrap();
+    let entries: Vec<serde_json::Value> = content
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let summary: Vec<_> = entries
+        .iter()
+        .map(|entry| {
+            (
+                entry["operation"].as_str().unwrap(),
+                entry["service"].as_str().unwrap(),
+                entry["success"].as_bool().unwrap(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            ("start", "web", true),
+            ("stop", "web", true),
+            ("stop", "nope", false)
+        ]
+    );
+
+    let uid = nix::unistd::getuid().as_raw();
+    for entry in &entries {
+        assert_eq!(entry["uid"], uid);
+        assert_eq!(entry["pid"], std::process::id());
+        assert!(entry["timestamp"].as_str().unwrap().ends_with('Z'));
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dependency_graphs_cover_every_service_and_render_as_dot() {
+    let daemon = TestDaemon::start(&[
+        (
+            "web",
+            "[unit]\nRequires = [\"db.service\"]\nWants = [\"cache\"]\nAfter = [\"db\"]\n\
+             [service]\nExecStart = \"sleep 30\"\n",
+        ),
+        ("db", &simple("sleep 30")),
+        ("worker", "[unit]\nRequires = [\"queue\"]\n[service]\nExecStart = \"sleep 30\"\n"),
+    ])
+    .await;
+
+    let graph = match daemon.request(Request::DependencyGraph).await {
+        Response::DependencyGraph { graph } => graph,
+        other => panic!("unexpected response: {:?}", other),
+    };
+    assert_eq!(graph.services, ["db", "web", "worker"]);
+    let edges: Vec<_> = graph
+        .edges
+        .iter()
+        .map(|edge| (edge.from.as_str(), edge.to.as_str(), edge.relation))
+        .collect();
+    assert_eq!(
+        edges,
+        [
+            ("web", "cache", Relation::Wants),
+            ("web", "db", Relation::Requires),
+            ("web", "db", Relation::After),
+            ("worker", "queue", Relation::Requires),
+        ]
+    );
+
+    let dot = graph.to_dot();
+    assert!(dot.starts_with("digraph services {"), "{}", dot);
+    assert!(dot.contains("\"web\" -> \"db\" [style=solid];"), "{}", dot);
+    assert!(dot.contains("\"web\" -> \"cache\" [style=dashed];"), "{}", dot);
+    assert!(dot.contains("\"web\" -> \"db\" [style=dotted];"), "{}", dot);
+    // Units that aren't loaded stand out
+    assert!(dot.contains("\"queue\" [color=red, fontcolor=red];"), "{}", dot);
+    assert!(!dot.contains("\"db\" [color=red"), "{}", dot);
+    assert!(dot.trim_end().ends_with('}'));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn diagnostics_report_dependencies_on_units_that_are_not_loaded() {
+    let daemon = TestDaemon::start(&[
+        (
+            "web",
+            "[unit]\nRequires = [\"databse\"]\nAfter = [\"cache\", \"database\"]\n[service]\nExecStart = \"sleep 30\"\n",
+        ),
+        ("database", &simple("sleep 30")),
+    ])
+    .await;
+    let diagnostics = || async {
+        match daemon.request(Request::Diagnostics).await {
+            Response::Diagnostics { diagnostics } => diagnostics,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    };
+
+    let found = diagnostics().await;
+    let problems: Vec<_> = found
+        .iter()
+        .map(|d| (d.service.as_str(), d.dependency.as_str(), d.relation))
+        .collect();
+    assert_eq!(problems, [("web", "cache", Relation::After), ("web", "databse", Relation::Requires)]);
+    assert_eq!(
+        found[1].message,
+        "requires 'databse', which isn't loaded (did you mean 'database'?); starting it will fail"
+    );
+
+    std::fs::write(
+        daemon.path("services").join("web.service"),
+        "[unit]\nRequires = [\"database\"]\n[service]\nExecStart = \"sleep 30\"\n",
+    )
+    .unwrap();
+    daemon.request(Request::Reload { dry_run: false, soft: false }).await;
+    assert!(diagnostics().await.is_empty());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn soft_reloads_apply_changed_units_at_the_next_restart() {
+    let unit = |greeting: &str| {
+        format!(
+            "[unit]\n[service]\nExecStart = \"sleep 30\"\nEnvironment = [\"GREETING={}\"]\n\
+             Restart = \"always\"\nRestartSec = 0\n",
+            greeting
+        )
+    };
+    let daemon = TestDaemon::start(&[("web", &unit("hello"))]).await;
+    assert_ok(daemon.start_service("web").await);
+    let pid = daemon.stats("web").await.unwrap().pid.unwrap();
+
+    std::fs::write(daemon.path("services").join("web.service"), unit("hi")).unwrap();
+    let message = assert_ok(daemon.request(Request::Reload { dry_run: false, soft: true }).await);
+    assert!(message.contains("apply at next restart: web"), "{}", message);
+
+    // The running process keeps its configuration; the new one waits
+    assert_eq!(daemon.stats("web").await.unwrap().pid, Some(pid));
+    let describe = || async {
+        match daemon.request(Request::Describe { service: "web".to_string() }).await {
+            Response::Describe { description } => description,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    };
+    let description = describe().await;
+    assert_eq!(description.environment, ["GREETING=hello"]);
+    assert_eq!(description.pending.unwrap().environment, ["GREETING=hi"]);
+
+    // Any restart applies it, including the supervisor's
+    assert_ok(
+        daemon
+            .request(Request::Signal {
+                service: "web".to_string(),
+                signal: "KILL".to_string(),
+            })
+            .await,
+    );
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let restarted = loop {
+        match daemon.stats("web").await.unwrap().pid {
+            Some(new_pid) if new_pid != pid => break new_pid,
+            _ => {
+                assert!(Instant::now() < deadline, "service was never restarted");
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    };
+    assert!(process_environment(restarted).contains(&"GREETING=hi".to_string()));
+    let description = describe().await;
+    assert_eq!(description.environment, ["GREETING=hi"]);
+    assert!(description.pending.is_none());
+
+    // Reverting the file before a restart drops the staged unit
+    std::fs::write(daemon.path("services").join("web.service"), unit("hey")).unwrap();
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: true }).await);
+    std::fs::write(daemon.path("services").join("web.service"), unit("hi")).unwrap();
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: true }).await);
+    assert!(describe().await.pending.is_none());
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn describe_shows_the_limits_in_effect_for_the_process() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let daemon = TestDaemon::start(&[("web", &simple("./limited.sh"))]).await;
+    let script = daemon.path("services").join("limited.sh");
+    std::fs::write(&script, "#!/bin/sh\nulimit -S -n 123\nexec sleep 30\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let limits = || async {
+        match daemon.request(Request::Describe { service: "web".to_string() }).await {
+            Response::Describe { description } => description.limits,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    };
+
+    // Nothing runs, so nothing is in effect
+    assert!(limits().await.is_empty());
+
+    assert_ok(daemon.start_service("web").await);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let limits = loop {
+        let limits = limits().await;
+        let nofile = limits.iter().find(|limit| limit.name == "NOFILE").map(|limit| limit.soft);
+        if nofile == Some(Some(123)) {
+            break limits;
+        }
+        assert!(Instant::now() < deadline, "limit never took effect: {:?}", limits);
+        sleep(Duration::from_millis(50)).await;
+    };
+    assert!(limits.iter().any(|limit| limit.name == "NPROC"), "{:?}", limits);
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sigusr2_re_executes_the_daemon_without_stopping_services() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let service_dir = dir.path().join("services");
+    std::fs::create_dir(&service_dir).unwrap();
+    let script = service_dir.join("tick.sh");
+    std::fs::write(&script, "#!/bin/sh\nwhile true; do echo tick; sleep 0.1; done\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(service_dir.join("tick.service"), simple("./tick.sh")).unwrap();
+
+    let config_file = dir.path().join("config.toml");
+    std::fs::write(
+        &config_file,
+        format!(
+            "log_dir = {:?}\naudit_log = {:?}\nsupervision_interval = 1\n",
+            dir.path().join("logs"),
+            dir.path().join("audit.log")
+        ),
+    )
+    .unwrap();
+    let socket = dir.path().join("daemon.sock");
+
+    // A real daemon, since re-executing replaces the whole process
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_diakonos"))
+        .arg("--config")
+        .arg(&config_file)
+        .arg("--socket")
+        .arg(&socket)
+        .arg("--service-dir")
+        .arg(&service_dir)
+        .args(["--quiet", "start", "tick"])
+        .env("HOME", dir.path())
+        .env("DIAKONOS_NO_VERSION_CHECK", "1")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let mut config = DaemonConfig {
+        service_dir,
+        ..DaemonConfig::default()
+    };
+    config.set_socket_path(socket);
+    let client = Client::new(config.clone());
+    let daemon_pid: i32 = std::fs::read_to_string(&config.pid_file).unwrap().trim().parse().unwrap();
+    let service_pid = || async {
+        match client.send_request(Request::Pid { service: "tick".to_string() }).await.unwrap() {
+            Response::Pid { pid, .. } => pid,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    };
+    let pid = service_pid().await.unwrap();
+
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(daemon_pid), Signal::SIGUSR2).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while !std::fs::read_to_string(&config.log_file).unwrap().contains("Adopted service tick") {
+        assert!(Instant::now() < deadline, "daemon never re-executed");
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    // Same daemon process and socket, same service process, still logged
+    assert!(process_is_alive(daemon_pid));
+    assert_eq!(service_pid().await, Some(pid));
+    let log = dir.path().join("logs").join("tick.log");
+    let lines = std::fs::read_to_string(&log).unwrap().lines().count();
+    sleep(Duration::from_millis(500)).await;
+    assert!(std::fs::read_to_string(&log).unwrap().lines().count() > lines);
+
+    // The new daemon notices the adopted process exit
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(pid as i32), Signal::SIGKILL).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match client.send_request(Request::Status { service: "tick".to_string() }).await.unwrap() {
+            Response::Status { state: ServiceState::Failed, .. } => break,
+            _ => {
+                assert!(Instant::now() < deadline, "exit of the adopted process went unnoticed");
+                sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+    assert!(!process_is_alive(pid as i32));
+
+    let _ = client.send_request(Request::Shutdown).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sighup_reloads_the_service_directory_without_stopping_services() {
+    let dir = tempfile::tempdir().unwrap();
+    let service_dir = dir.path().join("services");
+    std::fs::create_dir(&service_dir).unwrap();
+    std::fs::write(service_dir.join("web.service"), simple("sleep 30")).unwrap();
+    let socket = dir.path().join("daemon.sock");
+
+    // A real daemon, since the signal goes to the whole process
+    let status = std::process::Command::new(env!("CARGO_BIN_EXE_diakonos"))
+        .arg("--socket")
+        .arg(&socket)
+        .arg("--service-dir")
+        .arg(&service_dir)
+        .args(["--quiet", "start", "web"])
+        .env("HOME", dir.path())
+        .env("DIAKONOS_NO_VERSION_CHECK", "1")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let mut config = DaemonConfig {
+        service_dir: service_dir.clone(),
+        ..DaemonConfig::default()
+    };
+    config.set_socket_path(socket);
+    let client = Client::new(config.clone());
+    let daemon_pid: i32 = std::fs::read_to_string(&config.pid_file).unwrap().trim().parse().unwrap();
+    let status = |service: &str| {
+        let request = Request::Status { service: service.to_string() };
+        let client = &client;
+        async move {
+            match client.send_request(request).await.unwrap() {
+                Response::Status { state, .. } => Some(state),
+                _ => None,
+            }
+        }
+    };
+    let pid = match client.send_request(Request::Pid { service: "web".to_string() }).await.unwrap() {
+        Response::Pid { pid, .. } => pid.unwrap(),
+        other => panic!("unexpected response: {:?}", other),
+    };
+    assert_eq!(status("api").await, None);
+
+    std::fs::write(service_dir.join("api.service"), simple("sleep 30")).unwrap();
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(daemon_pid), Signal::SIGHUP).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while status("api").await.is_none() {
+        assert!(Instant::now() < deadline, "SIGHUP didn't load the new unit");
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    assert_eq!(status("api").await, Some(ServiceState::Stopped));
+    assert_eq!(status("web").await, Some(ServiceState::Running));
+    assert!(process_is_alive(pid as i32));
+    assert!(std::fs::read_to_string(&config.log_file).unwrap().contains("Received SIGHUP"));
+
+    let _ = client.send_request(Request::Shutdown).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn syslog_services_send_their_output_to_the_system_log() {
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let unit = "[unit]\n[service]\nExecStart = \"./hello.sh\"\nStandardOutput = \"syslog\"\nSyslogIdentifier = \"%n-app\"\nSyslogLevel = \"warning\"\n";
+    let mut syslog = None;
+    let daemon = TestDaemon::start_with(&[("web", unit)], |config| {
+        config.syslog_socket = config.socket_path.with_file_name("syslog.sock");
+        syslog = Some(UnixDatagram::bind(&config.syslog_socket).unwrap());
+    })
+    .await;
+    let syslog = syslog.unwrap();
+    syslog.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    let script = daemon.path("services").join("hello.sh");
+    std::fs::write(&script, "#!/bin/sh\necho hello from web\nexec sleep 30\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    assert_ok(daemon.start_service("web").await);
+    let mut message = [0; 256];
+    let length = syslog.recv(&mut message).unwrap();
+    let message = String::from_utf8_lossy(&message[..length]);
+
+    // Facility daemon (3) and severity warning (4)
+    let pid = daemon.stats("web").await.unwrap().pid.unwrap();
+    assert_eq!(message, format!("<28>web-app[{}]: hello from web", pid));
+
+    let request = Request::Logs {
+        services: vec!["web".to_string()],
+        lines: None,
+        since: None,
+        follow: false,
+    };
+    let error = assert_error(daemon.request(request).await);
+    assert!(error.contains("syslog as 'web-app'"), "{}", error);
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn wait_for_holds_the_response_until_the_service_reaches_the_state() {
+    let daemon = TestDaemon::start(&[("web", &simple("sleep 30"))]).await;
+    let wait_for = |state, timeout| Request::WaitFor {
+        service: "web".to_string(),
+        target_state: state,
+        timeout,
+    };
+
+    match daemon.request(wait_for(ServiceState::Running, Duration::from_millis(300))).await {
+        Response::TimedOut { message } => assert!(message.contains("still stopped"), "{}", message),
+        other => panic!("expected a timeout, got {:?}", other),
+    }
+
+    let waiting = daemon.request(wait_for(ServiceState::Running, Duration::from_secs(5)));
+    let starting = async {
+        sleep(Duration::from_millis(300)).await;
+        daemon.start_service("web").await
+    };
+    let (waited, started) = tokio::join!(waiting, starting);
+    assert_ok(started);
+    assert_eq!(assert_ok(waited), "Service 'web' is running");
+
+    let error = assert_error(
+        daemon
+            .request(Request::WaitFor {
+                service: "missing".to_string(),
+                target_state: ServiceState::Running,
+                timeout: Duration::from_secs(1),
+            })
+            .await,
+    );
+    assert!(error.contains("not found"), "{}", error);
+
+    assert_ok(daemon.stop_service("web").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn boot_starts_the_services_wanted_by_the_default_target() {
+    let wanted_by = |targets: &str| format!("{}[install]\nWantedBy = {}\n", simple("sleep 30"), targets);
+    let daemon = TestDaemon::start_with(
+        &[
+            ("everyday", &wanted_by("\"default.target\"")),
+            ("repair", &wanted_by("[\"maintenance.target\", \"rescue.target\"]")),
+            ("always", "[unit]\n[service]\nExecStart = \"sleep 30\"\nAutoStart = true\n"),
+            ("manual", &simple("sleep 30")),
+        ],
+        |config| config.default_target = "maintenance.target".to_string(),
+    )
+    .await;
+
+    daemon.wait_for_state("repair", ServiceState::Running, Duration::from_secs(5)).await;
+    daemon.wait_for_state("always", ServiceState::Running, Duration::from_secs(5)).await;
+    assert_eq!(daemon.state("everyday").await, ServiceState::Stopped);
+    assert_eq!(daemon.state("manual").await, ServiceState::Stopped);
+
+    let typo = format!("{}[install]\nWantdBy = \"default.target\"\n", simple("true"));
+    let error = UnitFile::parse(&typo, "typo.service").unwrap_err().to_string();
+    assert!(error.contains("did you mean `WantedBy`"), "{}", error);
+
+    assert_ok(daemon.stop_service("repair").await);
+    assert_ok(daemon.stop_service("always").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn attached_services_run_in_the_foreground_without_the_daemon() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = DaemonConfig {
+        service_dir: dir.path().to_path_buf(),
+        socket_path: dir.path().join("diakonos.sock"),
+        environment: vec!["GREETING=hello".to_string(), "NAME=nobody".to_string()],
+        ..DaemonConfig::default()
+    };
+    let script = dir.path().join("greet.sh");
+    std::fs::write(&script, "#!/bin/sh\necho \"$GREETING $NAME\" > greeting\nexit 3\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(
+        dir.path().join("greet.service"),
+        "[unit]\n[service]\nType = \"oneshot\"\nExecStart = [\"true\", \"./greet.sh\", \"touch never\"]\nWorkingDirectory = \".\"\nEnvironment = [\"NAME=web\"]\n",
+    )
+    .unwrap();
+
+    let status = diakonos::attach::run_attached(&config, "greet", vec!["GREETING=hi".to_string()])
+        .await
+        .unwrap();
+    assert_eq!(status.code(), Some(3));
+    let greeting = std::fs::read_to_string(dir.path().join("greeting")).unwrap();
+    assert_eq!(greeting, "hi web\n");
+    assert!(!dir.path().join("never").exists(), "ran past a failed command");
+
+    // No daemon was involved
+    assert!(!config.socket_path.exists());
+
+    let error = diakonos::attach::run_attached(&config, "missing", Vec::new()).await.unwrap_err();
+    assert!(matches!(error, DiakonosError::ServiceNotFound(_)), "{}", error);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn standard_input_feeds_a_file_or_text_to_the_service() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let oneshot = |input: &str, output: &str| {
+        format!(
+            "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"./copy.sh {}\"\nStandardInput = \"{}\"\n",
+            output, input
+        )
+    };
+    let daemon = TestDaemon::start(&[
+        ("from-file", &oneshot("file:input.txt", "from-file.out")),
+        ("from-text", &oneshot("text:name = %n", "from-text.out")),
+        ("from-null", &oneshot("null", "from-null.out")),
+    ])
+    .await;
+    let services = daemon.path("services");
+    let script = services.join("copy.sh");
+    std::fs::write(&script, "#!/bin/sh\ncat > \"$(dirname \"$0\")/$1\"\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(services.join("input.txt"), "port = 8080\n").unwrap();
+
+    for name in ["from-file", "from-text", "from-null"] {
+        assert_ok(daemon.start_service(name).await);
+        daemon.wait_for_state(name, ServiceState::Stopped, Duration::from_secs(5)).await;
+    }
+    let output = |name: &str| std::fs::read_to_string(services.join(format!("{}.out", name))).unwrap();
+    assert_eq!(output("from-file"), "port = 8080\n");
+    assert_eq!(output("from-text"), "name = from-text\n");
+    assert_eq!(output("from-null"), "");
+
+    let error = UnitFile::parse(&format!("{}StandardInput = \"tty\"\n", simple("cat")), "bad.service")
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("expected null, file:PATH or text:TEXT"), "{}", error);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn aliases_refer_to_their_service_and_cannot_collide() {
+    let daemon = TestDaemon::start(&[
+        ("postgres", "[unit]\nAlias = [\"db\", \"pg\"]\n[service]\nExecStart = \"sleep 30\"\n"),
+        ("web", "[unit]\nRequires = [\"db\"]\n[service]\nExecStart = \"sleep 30\"\n"),
+        // Already an alias of postgres, and the name of a unit
+        ("replica", "[unit]\nAlias = [\"db\"]\n[service]\nExecStart = \"sleep 30\"\n"),
+        ("cache", "[unit]\nAlias = [\"web\"]\n[service]\nExecStart = \"sleep 30\"\n"),
+    ])
+    .await;
+
+    let mut services = match daemon.request(Request::List).await {
+        Response::List { services } => services.into_iter().map(|(name, _)| name).collect::<Vec<_>>(),
+        other => panic!("unexpected response: {:?}", other),
+    };
+    services.sort();
+    assert_eq!(services, ["postgres", "web"]);
+
+    // A dependency on an alias is a dependency on the service
+    assert_eq!(daemon.dependencies("web").await, ["postgres"]);
+    assert_ok(daemon.start_service("web").await);
+    match daemon.request(Request::Status { service: "pg".to_string() }).await {
+        Response::Status { service, state } => {
+            assert_eq!(service, "postgres");
+            assert_eq!(state, ServiceState::Running);
+        }
+        other => panic!("unexpected response: {:?}", other),
+    }
+    match daemon.request(Request::Diagnostics).await {
+        Response::Diagnostics { diagnostics } => assert!(diagnostics.is_empty(), "{:?}", diagnostics),
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    assert_ok(daemon.stop_service("web").await);
+    assert_ok(daemon.stop_service("db").await);
+    assert_eq!(daemon.state("postgres").await, ServiceState::Stopped);
+}
+
+#[tokio::test]
+async fn doctor_reports_broken_units_cycles_and_leftover_daemon_files() {
+    let dir = tempfile::tempdir().unwrap();
+    let service_dir = dir.path().join("services");
+    std::fs::create_dir(&service_dir).unwrap();
+
+    let units = [
+        ("web", "[unit]\nRequires = [\"db\"]\n[service]\nExecStart = \"/bin/true\"\n"),
+        ("ping", "[unit]\nWants = [\"pong\"]\n[service]\nExecStart = \"/bin/true\"\n"),
+        ("pong", "[unit]\nRequires = [\"ping\"]\n[service]\nExecStart = \"/bin/true\"\n"),
+        ("broken", "[service]\nExecStart = \n"),
+    ];
+    for (name, unit) in units {
+        std::fs::write(service_dir.join(format!("{}.service", name)), unit).unwrap();
+    }
+
+    // Left behind by a daemon that is long gone
+    let pid_file = dir.path().join("daemon.pid");
+    std::fs::write(&pid_file, "999999999").unwrap();
+
+    let config = DaemonConfig {
+        socket_path: dir.path().join("daemon.sock"),
+        pid_file,
+        service_dir,
+        log_dir: dir.path().join("logs"),
+        ..DaemonConfig::default()
+    };
+
+    let checks = run_checks(&config).await;
+    let find = |text: &str| {
+        checks
+            .iter()
+            .find(|check| check.message.contains(text))
+            .unwrap_or_else(|| panic!("no check mentions {:?} in {:#?}", text, checks))
+    };
+
+    assert_eq!(find("names no running process").outcome, Outcome::Warn);
+    assert_eq!(find("not running").outcome, Outcome::Pass);
+    assert_eq!(find("is readable").outcome, Outcome::Pass);
+
+    let broken = find("broken.service");
+    assert_eq!(broken.outcome, Outcome::Fail);
+    assert!(broken.hint.is_some());
+
+    assert_eq!(find("3 unit file(s) load").outcome, Outcome::Pass);
+    assert_eq!(find("requires 'db'").outcome, Outcome::Fail);
+
+    let cycle = find("Dependency cycle");
+    assert_eq!(cycle.outcome, Outcome::Fail);
+    assert!(cycle.message.contains("ping") && cycle.message.contains("pong"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn services_are_stopped_after_their_runtime_max() {
+    let daemon = TestDaemon::start(&[
+        (
+            "recycled",
+            "[unit]\n[service]\nExecStart = \"sleep 30\"\nRuntimeMaxSec = 1\nRestart = \"on-failure\"\nRestartSec = 0\n",
+        ),
+        ("once", "[unit]\n[service]\nExecStart = \"sleep 30\"\nRuntimeMaxSec = 1\n"),
+        ("unlimited", "[unit]\n[service]\nExecStart = \"sleep 30\"\n"),
+    ])
+    .await;
+    for name in ["recycled", "once", "unlimited"] {
+        assert_ok(daemon.start_service(name).await);
+    }
+    let first_pid = daemon.stats("recycled").await.unwrap().pid;
+
+    // Running out its time counts as a failure
+    daemon.wait_for_state("once", ServiceState::Failed, Duration::from_secs(5)).await;
+
+    // ... which `Restart = "on-failure"` answers with a fresh process
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let stats = daemon.stats("recycled").await.unwrap();
+        if stats.restart_count > 0 && stats.state == ServiceState::Running {
+            assert_ne!(stats.pid, first_pid);
+            break;
+        }
+        assert!(Instant::now() < deadline, "service was never restarted");
+        sleep(Duration::from_millis(100)).await;
+    }
+
+    assert_eq!(daemon.state("unlimited").await, ServiceState::Running);
+    for name in ["recycled", "unlimited"] {
+        assert_ok(daemon.stop_service(name).await);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn unneeded_dependencies_stop_with_the_last_service_that_needed_them() {
+    let daemon = TestDaemon::start(&[
+        ("web", "[unit]\nRequires = [\"cache\", \"db\"]\n[service]\nExecStart = \"sleep 30\"\n"),
+        ("worker", "[unit]\nWants = [\"cache\"]\n[service]\nExecStart = \"sleep 30\"\n"),
+        (
+            "cache",
+            "[unit]\nRequires = [\"tunnel\"]\nStopWhenUnneeded = true\n[service]\nExecStart = \"sleep 30\"\n",
+        ),
+        ("tunnel", "[unit]\nStopWhenUnneeded = true\n[service]\nExecStart = \"sleep 30\"\n"),
+        ("db", "[unit]\n[service]\nExecStart = \"sleep 30\"\n"),
+    ])
+    .await;
+    assert_ok(daemon.start_service("web").await);
+    assert_ok(daemon.start_service("worker").await);
+    for name in ["cache", "tunnel", "db"] {
+        assert_eq!(daemon.state(name).await, ServiceState::Running, "{}", name);
+    }
+
+    // The worker still wants the cache
+    assert_ok(daemon.stop_service("web").await);
+    assert_eq!(daemon.state("cache").await, ServiceState::Running);
+
+    // Then nothing does, nor the tunnel the cache required
+    assert_ok(daemon.stop_service("worker").await);
+    assert_eq!(daemon.state("cache").await, ServiceState::Stopped);
+    assert_eq!(daemon.state("tunnel").await, ServiceState::Stopped);
+    assert_eq!(daemon.state("db").await, ServiceState::Running);
+
+    assert_ok(daemon.stop_service("db").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn requires_implies_after_unless_turned_off() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // Whether the migration had finished when each service started
+    let units = [
+        (
+            "migrate",
+            "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"./migrate.sh\"\nRemainAfterExit = true\nWorkingDirectory = \".\"\n",
+        ),
+        (
+            "web",
+            "[unit]\nRequires = [\"migrate\"]\n[service]\nExecStart = \"./seen.sh web\"\nWorkingDirectory = \".\"\n",
+        ),
+        (
+            "api",
+            "[unit]\nRequires = [\"migrate\"]\nAfter = [\"migrate\"]\n[service]\nExecStart = \"./seen.sh api\"\nWorkingDirectory = \".\"\n",
+        ),
+    ];
+    let scripts = [
+        ("migrate.sh", "#!/bin/sh\nsleep 1\ntouch migrated\n"),
+        (
+            "seen.sh",
+            "#!/bin/sh\nif [ -e migrated ]; then echo after > $1.seen; else echo alongside > $1.seen; fi\nexec sleep 30\n",
+        ),
+    ];
+
+    for implicit in [true, false] {
+        let daemon = TestDaemon::start_with(&units, |config| config.implicit_after_requires = implicit).await;
+        let service_dir = daemon.path("services");
+        for (name, script) in scripts {
+            let path = service_dir.join(name);
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let seen = |service: &str| {
+            let path = service_dir.join(format!("{}.seen", service));
+            async move {
+                let deadline = Instant::now() + Duration::from_secs(5);
+                loop {
+                    if let Ok(seen) = std::fs::read_to_string(&path) {
+                        if !seen.is_empty() {
+                            return seen.trim().to_string();
+                        }
+                    }
+                    assert!(Instant::now() < deadline, "{} never started", path.display());
+                    sleep(Duration::from_millis(50)).await;
+                }
+            }
+        };
+
+        // An explicit After always waits for the migration
+        assert_ok(daemon.start_service("api").await);
+        assert_eq!(seen("api").await, "after");
+
+        assert_ok(daemon.stop_service("migrate").await);
+        std::fs::remove_file(service_dir.join("migrated")).unwrap();
+
+        // Requires alone only does while it implies After
+        assert_ok(daemon.start_service("web").await);
+        let expected = if implicit { "after" } else { "alongside" };
+        assert_eq!(seen("web").await, expected, "implicit_after_requires = {}", implicit);
+
+        for name in ["api", "web", "migrate"] {
+            assert_ok(daemon.stop_service(name).await);
+        }
+    }
+}
+
+#[test]
+fn a_daemon_that_fails_to_start_reports_why() {
+    let dir = tempfile::tempdir().unwrap();
+    let service_dir = dir.path().join("services");
+    std::fs::create_dir(&service_dir).unwrap();
+
+    // Too long for a Unix socket address, so binding it fails once daemonized
+    let socket_dir = dir.path().join("s".repeat(120));
+    std::fs::create_dir(&socket_dir).unwrap();
+
+    let started = Instant::now();
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_diakonos"))
+        .arg("--socket")
+        .arg(socket_dir.join("daemon.sock"))
+        .arg("--service-dir")
+        .arg(&service_dir)
+        .arg("list")
+        .env("HOME", dir.path())
+        .output()
+        .unwrap();
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("Failed to bind socket"), "{}", stderr);
+    assert!(started.elapsed() < Duration::from_secs(4), "waited for the timeout");
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn environment_values_may_span_lines_and_be_given_as_a_table() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let daemon = TestDaemon::start(&[
+        (
+            "listed",
+            concat!(
+                "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"./dump.sh listed PEM QUOTED\"\nWorkingDirectory = \".\"\n",
+                "Environment = [\"PEM=-----BEGIN KEY-----\\nabc\\n-----END KEY-----\", ",
+                "\"QUOTED=it's \\\"quoted\\\" = 100%% ${UNDEFINED} $HOME \\\\ ü\"]\n",
+            ),
+        ),
+        (
+            "table",
+            concat!(
+                "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"./dump.sh table JSON EMPTY\"\nWorkingDirectory = \".\"\n",
+                "[service.Environment]\nJSON = '''\n{\"a\": 1,\n \"b\": [true, \"x=y\"]}'''\nEMPTY = \"\"\n",
+            ),
+        ),
+    ])
+    .await;
+    let service_dir = daemon.path("services");
+    let script = service_dir.join("dump.sh");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\nprefix=$1\nshift\nfor name in \"$@\"; do printenv \"$name\" > \"$prefix.$name\"; done\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    assert_ok(daemon.start_service("listed").await);
+    assert_ok(daemon.start_service("table").await);
+
+    let value = |file: &str| std::fs::read_to_string(service_dir.join(file)).unwrap();
+    assert_eq!(value("listed.PEM"), "-----BEGIN KEY-----\nabc\n-----END KEY-----\n");
+    assert_eq!(value("listed.QUOTED"), "it's \"quoted\" = 100% ${UNDEFINED} $HOME \\ ü\n");
+    assert_eq!(value("table.JSON"), "{\"a\": 1,\n \"b\": [true, \"x=y\"]}\n");
+    assert_eq!(value("table.EMPTY"), "\n");
+
+    let error = UnitFile::parse(
+        "[unit]\n[service]\nExecStart = \"true\"\nEnvironment = { \"A=B\" = \"x\" }\n",
+        "bad.service",
+    )
+    .unwrap_err()
+    .to_string();
+    assert!(error.contains("invalid environment variable name 'A=B'"), "{}", error);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn start_tail_follows_the_new_output_until_interrupted() {
+    use std::io::BufRead;
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let service_dir = dir.path().join("services");
+    std::fs::create_dir(&service_dir).unwrap();
+    let script = service_dir.join("talk.sh");
+    std::fs::write(&script, "#!/bin/sh\nrun=$(($(cat runs 2>/dev/null || echo 0) + 1))\necho $run > runs\necho run $run\nexec sleep 30\n").unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    std::fs::write(service_dir.join("talk.service"), format!("{}WorkingDirectory = \".\"\n", simple("./talk.sh"))).unwrap();
+    let socket = dir.path().join("daemon.sock");
+
+    let diakonos = |args: &[&str]| {
+        let mut command = std::process::Command::new(env!("CARGO_BIN_EXE_diakonos"));
+        command
+            .arg("--socket")
+            .arg(&socket)
+            .arg("--service-dir")
+            .arg(&service_dir)
+            .args(args)
+            .env("HOME", dir.path())
+            .env("DIAKONOS_NO_VERSION_CHECK", "1");
+        command
+    };
+    // An earlier run's output isn't shown again
+    assert!(diakonos(&["--quiet", "start", "talk"]).status().unwrap().success());
+    assert!(diakonos(&["--quiet", "stop", "talk"]).status().unwrap().success());
+
+    let mut tail = diakonos(&["start", "talk", "--tail"])
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdout = std::io::BufReader::new(tail.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.contains("started"), "{}", line);
+    line.clear();
+    stdout.read_line(&mut line).unwrap();
+
+    // Ctrl-C ends the tail, not the service
+    nix::sys::signal::kill(nix::unistd::Pid::from_raw(tail.id() as i32), Signal::SIGINT).unwrap();
+    assert!(tail.wait().unwrap().success());
+    assert!(line.ends_with(" run 2\n"), "{}", line);
+
+    let mut config = DaemonConfig {
+        service_dir: service_dir.clone(),
+        ..DaemonConfig::default()
+    };
+    config.set_socket_path(socket.clone());
+    let client = Client::new(config);
+    match client.send_request(Request::Status { service: "talk".to_string() }).await.unwrap() {
+        Response::Status { state, .. } => assert_eq!(state, ServiceState::Running),
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    let _ = client.send_request(Request::Shutdown).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn state_colors_follow_the_color_flag_and_the_configured_theme() {
+    let dir = tempfile::tempdir().unwrap();
+    let service_dir = dir.path().join("services");
+    std::fs::create_dir(&service_dir).unwrap();
+    std::fs::write(service_dir.join("web.service"), simple("sleep 30")).unwrap();
+    let socket = dir.path().join("daemon.sock");
+    let config_file = dir.path().join("config.toml");
+    std::fs::write(&config_file, "[colors]\nstopped = \"bold yellow\"\n").unwrap();
+
+    let list = |args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_diakonos"))
+            .arg("--socket")
+            .arg(&socket)
+            .arg("--service-dir")
+            .arg(&service_dir)
+            .arg("--config")
+            .arg(&config_file)
+            .args(args)
+            .arg("list")
+            .env("HOME", dir.path())
+            .env("DIAKONOS_NO_VERSION_CHECK", "1")
+            .output()
+            .unwrap();
+        (
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+            String::from_utf8_lossy(&output.stderr).into_owned(),
+        )
+    };
+
+    let (themed, _) = list(&["--color", "always"]);
+    assert!(themed.contains("\x1b[1;33mstopped"), "{}", themed);
+    // Output piped elsewhere is plain unless asked otherwise
+    for args in [&["--color", "never"][..], &[]] {
+        let (plain, _) = list(args);
+        assert!(plain.contains("stopped") && !plain.contains('\x1b'), "{}", plain);
+    }
+
+    std::fs::write(&config_file, "[colors]\nfailed = \"pink\"\n").unwrap();
+    let (_, error) = list(&[]);
+    assert!(error.contains("unknown color or style 'pink'"), "{}", error);
+
+    let mut config = DaemonConfig {
+        service_dir: service_dir.clone(),
+        ..DaemonConfig::default()
+    };
+    config.set_socket_path(socket.clone());
+    let _ = Client::new(config).send_request(Request::Shutdown).await;
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn describe_prints_json_and_plain_for_scripts() {
+    let dir = tempfile::tempdir().unwrap();
+    let service_dir = dir.path().join("services");
+    std::fs::create_dir(&service_dir).unwrap();
+    std::fs::write(
+        service_dir.join("web.service"),
+        "[unit]\nDescription = \"Web server\"\n[service]\nExecStart = \"sleep 30\"\nEnvironment = [\"PORT=8080\", \"MODE=prod\"]\n",
+    )
+    .unwrap();
+    let socket = dir.path().join("daemon.sock");
+
+    let diakonos = |args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_diakonos"))
+            .arg("--socket")
+            .arg(&socket)
+            .arg("--service-dir")
+            .arg(&service_dir)
+            .args(args)
+            .env("HOME", dir.path())
+            .env("DIAKONOS_NO_VERSION_CHECK", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    diakonos(&["-q", "start", "web"]);
+    let describe = |format: &str| diakonos(&["-o", format, "describe", "web"]);
+
+    let json: serde_json::Value = serde_json::from_str(&describe("json")).unwrap();
+    assert_eq!(json["name"], "web");
+    assert_eq!(json["state"], "running");
+    assert_eq!(json["description"], "Web server");
+    assert_eq!(json["exec_start"], serde_json::json!(["sleep 30"]));
+
+    let plain = describe("plain");
+    let fields: Vec<(&str, &str)> = plain.lines().filter_map(|line| line.split_once('\t')).collect();
+    assert_eq!(fields.len(), plain.lines().count(), "{}", plain);
+    let values = |key: &str| -> Vec<&str> {
+        fields.iter().filter(|(k, _)| *k == key).map(|(_, v)| *v).collect()
+    };
+    assert_eq!(values("name"), ["web"]);
+    assert_eq!(values("state"), ["running"]);
+    assert_eq!(values("pid"), [json["pid"].to_string()]);
+    assert_eq!(values("exec_start"), ["sleep 30"]);
+    assert!(values("environment").contains(&"PORT=8080"), "{}", plain);
+    assert!(values("environment").contains(&"MODE=prod"), "{}", plain);
+    assert!(!plain.contains('\x1b'), "{}", plain);
+
+    let mut config = DaemonConfig {
+        service_dir,
+        ..DaemonConfig::default()
+    };
+    config.set_socket_path(socket);
+    let _ = Client::new(config).send_request(Request::Shutdown).await;
+}
+
+#[test]
+fn each_service_directory_gets_its_own_daemon_unless_told_otherwise() {
+    let dir = tempfile::tempdir().unwrap();
+    let daemon_dir = dir.path().join(".diakonos");
+    let config_file = dir.path().join("config.toml");
+    std::fs::write(&config_file, "").unwrap();
+    for project in ["a", "b"] {
+        std::fs::create_dir(dir.path().join(project)).unwrap();
+    }
+
+    let diakonos = |service_dir: &str, args: &[&str]| {
+        let output = std::process::Command::new(env!("CARGO_BIN_EXE_diakonos"))
+            .arg("--config")
+            .arg(&config_file)
+            .arg("--service-dir")
+            .arg(dir.path().join(service_dir))
+            .args(args)
+            .env("HOME", dir.path())
+            .env("DIAKONOS_NO_VERSION_CHECK", "1")
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+        String::from_utf8(output.stdout).unwrap()
+    };
+    // The socket of the daemon for `service_dir`, started and killed again
+    let socket = |service_dir: &str, args: &[&str]| {
+        diakonos(service_dir, &[args, &["list"]].concat());
+        let status = diakonos(service_dir, &[args, &["-o", "plain", "daemon-status"]].concat());
+        let field = |key: &str| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix('\t'))
+                .unwrap()
+                .to_string()
+        };
+        diakonos(service_dir, &[args, &["kill"]].concat());
+
+        let pid = Path::new("/proc").join(field("pid"));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while pid.exists() {
+            assert!(Instant::now() < deadline, "the daemon didn't exit");
+            std::thread::sleep(Duration::from_millis(20));
+        }
+        PathBuf::from(field("socket_path"))
+    };
+
+    let a = socket("a", &[]);
+    let b = socket("b", &[]);
+    assert_ne!(a, b);
+    for socket in [&a, &b] {
+        assert!(socket.starts_with(daemon_dir.join("daemons")), "{}", socket.display());
+    }
+    assert_eq!(socket("a", &[]), a);
+
+    // `--socket` picks the daemon whatever the directory
+    let chosen = dir.path().join("chosen.sock");
+    assert_eq!(socket("a", &["--socket", chosen.to_str().unwrap()]), chosen);
+
+    // As does a config file setting the socket, even where it would be by default
+    let default = daemon_dir.join("daemon.sock");
+    std::fs::write(&config_file, format!("socket_path = {:?}\n", default)).unwrap();
+    assert_eq!(socket("a", &[]), default);
+    assert_eq!(socket("b", &[]), default);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn stop_all_stops_dependents_first_and_keeps_them_stopped() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = tempfile::tempdir().unwrap();
+    let script = dir.path().join("trap.sh");
+    std::fs::write(
+        &script,
+        "#!/bin/sh\ntrap 'echo $1 >> stopped; exit 0' TERM\nwhile true; do sleep 0.1; done\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let unit = |name: &str, requires: &str| {
+        format!(
+            "[unit]\nRequires = [{}]\n[service]\nExecStart = \"./trap.sh {}\"\nWorkingDirectory = \".\"\nRestart = \"always\"\nRestartSec = 1\n",
+            requires, name
+        )
+    };
+    std::fs::write(dir.path().join("web.service"), unit("web", "\"db\"")).unwrap();
+    std::fs::write(dir.path().join("db.service"), unit("db", "")).unwrap();
+
+    let manager = Arc::new(ServiceManager::new(&DaemonConfig {
+        service_dir: dir.path().to_path_buf(),
+        log_dir: dir.path().join("logs"),
+        supervision_interval: 1,
+        ..DaemonConfig::default()
+    }));
+    manager.load_all_services().await.unwrap();
+    manager.start_service("web").await.unwrap();
+    let supervisor = tokio::spawn(Arc::clone(&manager).supervise());
+    sleep(Duration::from_millis(300)).await;
+
+    manager.stop_all().await;
+    assert_eq!(std::fs::read_to_string(dir.path().join("stopped")).unwrap(), "web\ndb\n");
+
+    // Past RestartSec, and nothing came back
+    sleep(Duration::from_millis(2500)).await;
+    for (name, state) in manager.list_services().await {
+        assert_eq!(state, ServiceState::Stopped, "{}", name);
+    }
+    supervisor.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn a_foreground_daemon_writes_its_pid_file_and_serves() {
+    let dir = tempfile::tempdir().unwrap();
+    let service_dir = dir.path().join("services");
+    std::fs::create_dir(&service_dir).unwrap();
+    std::fs::write(service_dir.join("sleeper.service"), simple("sleep 30")).unwrap();
+    let socket = dir.path().join("daemon.sock");
+
+    let mut daemon = std::process::Command::new(env!("CARGO_BIN_EXE_diakonos"))
+        .arg("--socket")
+        .arg(&socket)
+        .arg("--service-dir")
+        .arg(&service_dir)
+        .arg("--foreground")
+        .env("HOME", dir.path())
+        .stderr(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut config = DaemonConfig {
+        service_dir,
+        ..DaemonConfig::default()
+    };
+    config.set_socket_path(socket.clone());
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !socket.exists() {
+        assert!(Instant::now() < deadline, "foreground daemon never bound its socket");
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    let pid = std::fs::read_to_string(&config.pid_file).unwrap();
+    assert_eq!(pid.trim(), daemon.id().to_string());
+    let client = Client::new(config);
+    let start = Request::Start {
+        service: "sleeper".to_string(),
+        wait: None,
+        env: Vec::new(),
+        no_supervise: false,
+    };
+    assert_ok(client.send_request(start).await.unwrap());
+
+    let _ = client.send_request(Request::Shutdown).await;
+    assert!(daemon.wait().unwrap().success());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn apply_brings_services_in_line_with_the_service_directory() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let enabled = "[unit]\n[service]\nExecStart = \"sleep 30\"\nAutoStart = true\n";
+    let web = "[unit]\nRequires = [\"db\"]\n[service]\nExecStart = \"sleep 30\"\nAutoStart = true\n";
+    let job = "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"./job.sh\"\nWorkingDirectory = \".\"\nAutoStart = true\n";
+    let daemon = TestDaemon::start_with(
+        &[
+            ("db", enabled),
+            ("web", web),
+            ("job", job),
+            ("old", &simple("sleep 30")),
+            ("manual", &simple("sleep 30")),
+        ],
+        |config| {
+            let script = config.service_dir.join("job.sh");
+            std::fs::write(&script, "#!/bin/sh\necho run >> runs\n").unwrap();
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        },
+    )
+    .await;
+    daemon.wait_for_state("web", ServiceState::Running, Duration::from_secs(5)).await;
+    let runs = daemon.path("services").join("runs");
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !runs.exists() {
+        assert!(Instant::now() < deadline, "job never ran on boot");
+        sleep(Duration::from_millis(50)).await;
+    }
+
+    assert_ok(daemon.start_service("old").await);
+    assert_ok(daemon.start_service("manual").await);
+    assert_ok(daemon.stop_service("web").await);
+    let db_pid = daemon.stats("db").await.unwrap().pid;
+    std::fs::write(
+        daemon.path("services").join("db.service"),
+        format!("{}Environment = [\"MODE=new\"]\n", enabled),
+    )
+    .unwrap();
+    std::fs::remove_file(daemon.path("services").join("old.service")).unwrap();
+
+    let message = assert_ok(daemon.request(Request::Apply).await);
+    assert_eq!(message, "Applied (stopped: old; removed: old; restarted: db; started: web)");
+    assert_eq!(daemon.state("db").await, ServiceState::Running);
+    assert_ne!(daemon.stats("db").await.unwrap().pid, db_pid);
+    assert_eq!(daemon.state("web").await, ServiceState::Running);
+    assert_eq!(daemon.state("manual").await, ServiceState::Running);
+    assert!(daemon.stats("old").await.is_none());
+
+    // Nothing left to do, and the completed oneshot isn't run again
+    assert_eq!(assert_ok(daemon.request(Request::Apply).await), "Applied (no changes)");
+    assert_eq!(std::fs::read_to_string(&runs).unwrap(), "run\n");
+
+    // A unit that doesn't parse keeps its service as it was, and fails the apply
+    std::fs::write(daemon.path("services").join("web.service"), "[unit]\n[service\n").unwrap();
+    let message = assert_error(daemon.request(Request::Apply).await);
+    assert!(message.contains("failed to parse: web"), "{}", message);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn no_supervise_keeps_the_supervisor_from_restarting_until_started_again() {
+    let daemon = TestDaemon::start(&[(
+        "flaky",
+        "[unit]\n[service]\nExecStart = \"sleep 0.2\"\nRestart = \"always\"\nRestartSec = 0\n",
+    )])
+    .await;
+    let start = |no_supervise| Request::Start {
+        service: "flaky".to_string(),
+        wait: None,
+        env: Vec::new(),
+        no_supervise,
+    };
+    let unsupervised = || async {
+        match daemon.request(Request::Describe { service: "flaky".to_string() }).await {
+            Response::Describe { description } => description.unsupervised,
+            other => panic!("unexpected response: {:?}", other),
+        }
+    };
+
+    assert_ok(daemon.request(start(true)).await);
+    assert!(unsupervised().await);
+    daemon
+        .wait_for_state("flaky", ServiceState::Stopped, Duration::from_secs(3))
+        .await;
+    sleep(Duration::from_secs(1)).await;
+    let stats = daemon.stats("flaky").await.unwrap();
+    assert_eq!(stats.state, ServiceState::Stopped);
+    assert_eq!(stats.restart_count, 0);
+
+    // Starting it again without the flag hands it back to the supervisor
+    assert_ok(daemon.request(start(false)).await);
+    assert!(!unsupervised().await);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while daemon.stats("flaky").await.unwrap().restart_count == 0 {
+        assert!(Instant::now() < deadline, "the supervisor never restarted it");
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_ok(daemon.stop_service("flaky").await);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn history_records_failures_and_restarts_and_survives_the_daemon() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let flaky = "[unit]\n[service]\nExecStart = \"./flaky.sh\"\nWorkingDirectory = \".\"\n\
+                 Restart = \"on-failure\"\nRestartSec = 0\n";
+    let daemon = TestDaemon::start_with(&[("flaky", flaky), ("web", &simple("sleep 30"))], |config| {
+        let script = config.service_dir.join("flaky.sh");
+        std::fs::write(&script, "#!/bin/sh\nsleep 0.3\nexit 3\n").unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+    })
+    .await;
+    assert_ok(daemon.start_service("flaky").await);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while daemon.stats("flaky").await.unwrap().restart_count == 0 {
+        assert!(Instant::now() < deadline, "the supervisor never restarted it");
+        sleep(Duration::from_millis(50)).await;
+    }
+    assert_ok(daemon.stop_service("flaky").await);
+
+    let history = |service: Option<&str>, since| Request::History {
+        service: service.map(str::to_string),
+        since,
+    };
+    let transitions = match daemon.request(history(Some("flaky"), None)).await {
+        Response::History { transitions } => transitions,
+        other => panic!("unexpected response: {:?}", other),
+    };
+    assert!(transitions.len() >= 2, "{:?}", transitions);
+    assert_eq!(transitions[0].old_state, ServiceState::Running);
+    assert_eq!(transitions[0].new_state, ServiceState::Failed);
+    assert_eq!(transitions[0].exit_reason.as_deref(), Some("exit status: 3"));
+    assert_eq!(transitions[1].old_state, ServiceState::Failed);
+    assert_eq!(transitions[1].new_state, ServiceState::Running);
+    assert_eq!(
+        transitions[1].exit_reason.as_deref(),
+        Some("restarted by the supervisor (restart #1)")
+    );
+
+    // Other services' transitions aren't included, and old ones fall outside `since`
+    match daemon.request(history(Some("web"), None)).await {
+        Response::History { transitions } => assert!(transitions.is_empty(), "{:?}", transitions),
+        other => panic!("unexpected response: {:?}", other),
+    }
+    sleep(Duration::from_secs(1)).await;
+    match daemon.request(history(None, Some(Duration::from_millis(500)))).await {
+        Response::History { transitions } => assert!(transitions.is_empty(), "{:?}", transitions),
+        other => panic!("unexpected response: {:?}", other),
+    }
+
+    // What was recorded is read back by the next daemon
+    let reopened = History::open(&daemon.path("history.jsonl")).unwrap();
+    let recorded = reopened.query(Some("flaky"), None);
+    assert_eq!(recorded.len(), transitions.len());
+    assert_eq!(recorded[0].timestamp, transitions[0].timestamp);
+}
+
+#[cfg(not(feature = "sandbox"))]
+#[tokio::test(flavor = "multi_thread")]
+async fn sandboxing_needs_the_sandbox_feature() {
+    let daemon = TestDaemon::start(&[(
+        "isolated",
+        "[unit]\n[service]\nExecStart = \"sleep 30\"\nPrivateTmp = true\n",
+    )])
+    .await;
+
+    let message = assert_error(daemon.start_service("isolated").await);
+    assert!(message.contains("built without the `sandbox` feature"), "{}", message);
+    assert_eq!(daemon.state("isolated").await, ServiceState::Failed);
+}
+
+#[cfg(feature = "sandbox")]
+#[tokio::test(flavor = "multi_thread")]
+async fn sandboxed_services_get_a_private_tmp_and_read_only_paths() {
+    if !nix::unistd::geteuid().is_root() {
+        eprintln!("skipping: sandboxing needs root");
+        return;
+    }
+
+    let daemon = TestDaemon::start(&[]).await;
+    let read_only = daemon.path("read-only");
+    std::fs::create_dir(&read_only).unwrap();
+    let in_tmp = std::env::temp_dir().join(format!("diakonos-private-tmp-{}", std::process::id()));
+    let units = [
+        (
+            "private-tmp",
+            format!(
+                "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"touch {}\"\nPrivateTmp = true\n",
+                in_tmp.display()
+            ),
+        ),
+        (
+            "read-only",
+            format!(
+                "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"touch {}\"\nReadOnlyPaths = [\"{}\"]\n",
+                read_only.join("file").display(),
+                read_only.display()
+            ),
+        ),
+    ];
+    for (name, unit) in units {
+        std::fs::write(daemon.path("services").join(format!("{}.service", name)), unit).unwrap();
+    }
+    assert_ok(daemon.request(Request::Reload { dry_run: false, soft: false }).await);
+
+    // The service writes to a /tmp of its own, which the host never sees
+    assert_ok(daemon.start_service("private-tmp").await);
+    daemon
+        .wait_for_state("private-tmp", ServiceState::Stopped, Duration::from_secs(5))
+        .await;
+    assert!(!in_tmp.exists());
+
+    // A oneshot's start waits for it, so its failure to write is the start's
+    assert_error(daemon.start_service("read-only").await);
+    assert_eq!(daemon.state("read-only").await, ServiceState::Failed);
+    assert!(!read_only.join("file").exists());
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn max_concurrent_starts_caps_services_starting_at_once() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let job = "[unit]\n[service]\nType = \"oneshot\"\nExecStart = \"./job.sh\"\nWorkingDirectory = \".\"\n";
+    let all = "[unit]\nWants = [\"a\", \"b\", \"c\", \"d\", \"e\"]\n[service]\nExecStart = \"sleep 30\"\n";
+    let daemon = TestDaemon::start_with(
+        &[("a", job), ("b", job), ("c", job), ("d", job), ("e", job), ("all", all)],
+        |config| {
+            config.max_concurrent_starts = 2;
+            let script = config.service_dir.join("job.sh");
+            std::fs::write(&script, "#!/bin/sh\necho + >> events\nsleep 0.5\necho - >> events\n").unwrap();
+            std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        },
+    )
+    .await;
+
+    assert_ok(daemon.start_service("all").await);
+    assert_eq!(daemon.state("all").await, ServiceState::Running);
+
+    // Every job ran, never more than two at a time, but two did overlap
+    let events = std::fs::read_to_string(daemon.path("services").join("events")).unwrap();
+    assert_eq!(events.matches('+').count(), 5, "{}", events);
+    let (mut running, mut most) = (0, 0);
+    for event in events.lines() {
+        running += if event == "+" { 1 } else { -1 };
+        most = most.max(running);
+    }
+    assert_eq!(most, 2, "{}", events);
+    assert_ok(daemon.stop_service("all").await);
+}